@@ -1,16 +1,62 @@
-use libcontainer_rs::{container::Container, filesystem::OverlayDriver};
+use std::env;
+use std::process;
+
+use libcontainer_rs::{container::Container, filesystem::OverlayDriver, runtime::ExitCode, syscall::{Command, ExecType}};
 use color_eyre::{Result};
 use simple_logger::SimpleLogger;
 
-fn main() -> Result<()> {
-    SimpleLogger::new().init()?;
+/// Usage: alpine [IMAGE [COMMAND [ARGS...]]]
+///
+/// Runs a command (default: a login shell) in a container of an image (default: tests/alpine-3.15.3),
+/// and exits with the exit code of the command
+fn main() {
+    SimpleLogger::new().init().unwrap();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let code = match run(&args) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            // The container failed before running the command, e.g. it was not found (127)
+            err.downcast_ref::<ExitCode>().map_or(1, |code| *code as i32)
+        },
+    };
+    process::exit(code);
+}
 
-    let fs = OverlayDriver::new(vec![&String::from("tests/alpine-3.15.3")], &String::from("./alpine-rootfs"));
+/// Runs the container
+/// # Returns
+/// The exit code of the command
+fn run(args: &[String]) -> Result<i32> {
+    let image = args.first().cloned().unwrap_or_else(|| String::from("tests/alpine-3.15.3"));
+    let fs = OverlayDriver::new(vec![&image], &String::from("./alpine-rootfs"));
     let mut container = Container::new(Box::new(fs))?;
-    println!("Starting container");
-    container.start()?;
-    println!("Execute sh in container");
-    container.execute_in_container(String::from("/bin/sh"), vec![], None, None)?;
-    container.wait_for_container()?;
-    Ok(())
-}
\ No newline at end of file
+    let command = match args.get(1) {
+        Some(command) => Command {
+            command: command.clone(),
+            args: args[2..].to_vec(),
+            env: vec![String::from("PATH=/bin:/usr/bin:/sbin:/usr/sbin")],
+            exec_type: ExecType::REPLACE,
+            argv0: None,
+            login: false,
+            path_search: true,
+            process_group: None,
+            stream_stdout: false,
+        },
+        None => {
+            println!("Starting container running a login shell");
+            Command {
+                command: String::from("/bin/sh"),
+                args: vec![],
+                env: vec![],
+                exec_type: ExecType::REPLACE,
+                argv0: None,
+                login: true,
+                path_search: true,
+                process_group: None,
+                stream_stdout: false,
+            }
+        },
+    };
+    container.spawn_command(command)?;
+    container.wait_for_container().map(|exit| exit.code())
+}
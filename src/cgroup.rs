@@ -0,0 +1,425 @@
+/*
+ * The MIT License
+ * Copyright (c) 2022 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use color_eyre::{Result, eyre};
+use nix::unistd::Pid;
+
+/// Mount point of the cgroup v2 hierarchy
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// Parent cgroup of every container
+const CGROUP_PARENT: &str = "libcontainer-rs";
+/// Controllers enabled for accounting, when available
+const ACCOUNTING_CONTROLLERS: [&str; 4] = ["cpu", "memory", "io", "pids"];
+/// Retries of enabling a controller while the cgroup is busy
+pub const DELEGATION_RETRIES: u32 = 3;
+/// Delay before the first retry of enabling a controller, doubled after each one
+pub const DELEGATION_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Returns whether the host uses the cgroup v2 (unified) hierarchy
+pub fn is_cgroup_v2() -> bool {
+    Path::new(CGROUP_ROOT).join("cgroup.controllers").exists()
+}
+
+/// Returns the path of the cgroup of a container, under /sys/fs/cgroup/libcontainer-rs
+/// # Arguments
+/// * `id` - ID of the container
+pub fn cgroup_path(id: &str) -> PathBuf {
+    Path::new(CGROUP_ROOT).join(CGROUP_PARENT).join(id)
+}
+
+/// Resource usage of the processes in a cgroup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CgroupStats {
+    /// CPU time used (user and system)
+    pub cpu_usage: Duration,
+    /// Memory used, in bytes (None when the memory controller is not enabled for the cgroup)
+    pub memory: Option<u64>,
+}
+
+/// A sign of memory pressure in a cgroup, see `state::StateEvent::MEMORY_PRESSURE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryPressureEvent {
+    /// The memory usage went over memory.high and the processes were throttled, with the number of times
+    /// it happened since the cgroup was created
+    HIGH(u64),
+    /// The memory usage reached memory.max, with the number of times it happened since the cgroup was
+    /// created
+    MAX(u64),
+    /// The processes were stalled waiting for memory for longer than the PSI threshold
+    STALL,
+}
+
+/// The cgroup v2 of a container, created by the host under /sys/fs/cgroup/libcontainer-rs/<id>
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+
+    /// Creates the cgroup of a container
+    /// # Arguments
+    /// * `id` - ID of the container
+    pub fn create(id: &str) -> Result<Cgroup> {
+        if !is_cgroup_v2() {
+            return Err(eyre::eyre!("cgroup v2 is not available at {}, cgroup v1 is not supported", CGROUP_ROOT));
+        }
+        let path = cgroup_path(id);
+        log::debug!("Creating cgroup {}", path.display());
+        fs::create_dir_all(&path)
+            .map_err(|err| eyre::eyre!("Could not create cgroup {}: {}", path.display(), err))?;
+        Ok(Cgroup { path })
+    }
+
+    /// Enables the accounting of the cgroup: the cpu, memory, io and pids controllers (those the host
+    /// delegates to libcontainer-rs) are enabled in its parents, so the usage files (e.g. memory.current)
+    /// exist. No limit is set, they all keep their default value ("max")
+    pub fn enable_accounting(&self) -> Result<()> {
+        let available = fs::read_to_string(Path::new(CGROUP_ROOT).join(CGROUP_PARENT).join("cgroup.controllers"))?;
+        let controllers: Vec<&str> = ACCOUNTING_CONTROLLERS.iter()
+            .copied()
+            .filter(|controller| available.split_whitespace().any(|available| available == *controller))
+            .collect();
+        self.delegate(&controllers, DELEGATION_RETRIES, DELEGATION_BACKOFF)
+    }
+
+    /// Makes controllers available in the cgroup: every cgroup from /sys/fs/cgroup/libcontainer-rs down to
+    /// its parent enables them for its children (cgroup.subtree_control). The cgroups above are left to the
+    /// host (e.g. systemd): they must already delegate the controllers to libcontainer-rs
+    ///
+    /// A cgroup with processes can't enable controllers for its children (the "no internal processes"
+    /// rule, only the root is exempt). They are not moved automatically: the delegation fails, the caller
+    /// must first move them to a child cgroup (see `create_child` and `add_process`)
+    /// # Arguments
+    /// * `controllers` - Names of the controllers, e.g. "memory"
+    /// * `retries` - Retries while a cgroup is busy, e.g. a process is moved into it concurrently
+    /// * `backoff` - Delay before the first retry, doubled after each one
+    pub fn delegate(&self, controllers: &[&str], retries: u32, backoff: Duration) -> Result<()> {
+        let root = Path::new(CGROUP_ROOT).join(CGROUP_PARENT);
+        let relative = self.path.strip_prefix(&root)
+            .map_err(|_| eyre::eyre!("Cgroup {} is not under {}", self.path.display(), root.display()))?;
+        let mut ancestor = root.clone();
+        let mut ancestors = vec![ancestor.clone()];
+        if let Some(parent) = relative.parent() {
+            for component in parent.components() {
+                ancestor.push(component);
+                ancestors.push(ancestor.clone());
+            }
+        }
+        for ancestor in ancestors {
+            enable_controllers(&ancestor, controllers, retries, backoff)?;
+        }
+        Ok(())
+    }
+
+    /// Creates a child cgroup, e.g. to apply limits to some processes of the container only. The
+    /// controllers of the cgroup are not enabled for it, see `delegate`
+    /// # Arguments
+    /// * `name` - Name of the child cgroup
+    pub fn create_child(&self, name: &str) -> Result<Cgroup> {
+        let path = self.path.join(name);
+        log::debug!("Creating cgroup {}", path.display());
+        fs::create_dir_all(&path)
+            .map_err(|err| eyre::eyre!("Could not create cgroup {}: {}", path.display(), err))?;
+        Ok(Cgroup { path })
+    }
+
+    /// Sets resource limits of the cgroup, enabling their controllers in its parents
+    /// # Arguments
+    /// * `memory` - memory.max, in bytes (None: unchanged)
+    /// * `cpu` - cpu.max, as (quota, period) in microseconds (None: unchanged)
+    /// * `pids` - pids.max (None: unchanged)
+    pub fn set_limits(&self, memory: Option<u64>, cpu: Option<(u64, u64)>, pids: Option<u64>) -> Result<()> {
+        let mut limits = vec![];
+        if let Some(memory) = memory {
+            limits.push(("memory", "memory.max", memory.to_string()));
+        }
+        if let Some((quota, period)) = cpu {
+            // Bounds enforced by the kernel
+            if !(1000..=1_000_000).contains(&period) || quota < 1000 {
+                return Err(eyre::eyre!("Invalid CPU quota {}/{}: the period must be between 1000 and 1000000 µs, the quota at least 1000 µs", quota, period));
+            }
+            limits.push(("cpu", "cpu.max", format!("{} {}", quota, period)));
+        }
+        if let Some(pids) = pids {
+            limits.push(("pids", "pids.max", pids.to_string()));
+        }
+        let controllers: Vec<&str> = limits.iter().map(|(controller, _, _)| *controller).collect();
+        self.delegate(&controllers, DELEGATION_RETRIES, DELEGATION_BACKOFF)?;
+        for (_, file, value) in limits {
+            let path = self.path.join(file);
+            fs::write(&path, &value)
+                .map_err(|err| eyre::eyre!("Could not set {} to {}: {}", path.display(), value, err))?;
+        }
+        Ok(())
+    }
+
+    /// Absolute path of the cgroup
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Moves a process into the cgroup
+    /// # Arguments
+    /// * `pid` - PID of the process
+    pub fn add_process(&self, pid: Pid) -> Result<()> {
+        fs::write(self.path.join("cgroup.procs"), pid.to_string())
+            .map_err(|err| eyre::eyre!("Could not add process {} to cgroup {}: {}", pid, self.path.display(), err))?;
+        Ok(())
+    }
+
+    /// Returns the PIDs of the processes in the cgroup
+    pub fn processes(&self) -> Result<Vec<Pid>> {
+        let procs = fs::read_to_string(self.path.join("cgroup.procs"))?;
+        Ok(procs.lines()
+            .filter_map(|pid| pid.parse().ok())
+            .map(Pid::from_raw)
+            .collect())
+    }
+
+    /// Returns the resource usage of the processes in the cgroup
+    pub fn stats(&self) -> Result<CgroupStats> {
+        let cpu_stat = fs::read_to_string(self.path.join("cpu.stat"))?;
+        // One "<key> <value>" pair per line
+        let usage = cpu_stat.lines()
+            .find_map(|line| line.strip_prefix("usage_usec "))
+            .and_then(|usage| usage.trim().parse().ok())
+            .ok_or_else(|| eyre::eyre!("No usage_usec in {}", self.path.join("cpu.stat").display()))?;
+        let memory = fs::read_to_string(self.path.join("memory.current")).ok()
+            .and_then(|memory| memory.trim().parse().ok());
+        Ok(CgroupStats {
+            cpu_usage: Duration::from_micros(usage),
+            memory,
+        })
+    }
+
+    /// Returns the number of processes (and threads) in the cgroup, and the maximum allowed (pids.max, None
+    /// if unlimited)
+    ///
+    /// Requires the pids controller to be enabled for the cgroup, see `enable_accounting`.
+    pub fn pids(&self) -> Result<(u64, Option<u64>)> {
+        let read = |file: &str| {
+            let path = self.path.join(file);
+            fs::read_to_string(&path)
+                .map(|value| value.trim().to_string())
+                .map_err(|err| eyre::eyre!("Could not read {}: {}", path.display(), err))
+        };
+        let current = read("pids.current")?.parse()?;
+        let max = match read("pids.max")?.as_str() {
+            "max" => None,
+            max => Some(max.parse()?),
+        };
+        Ok((current, max))
+    }
+
+    /// Returns how many processes of the cgroup (or of its children) were killed by the OOM killer
+    ///
+    /// Requires the memory controller to be enabled for the cgroup.
+    pub fn oom_kills(&self) -> Result<u64> {
+        read_memory_event(&self.path.join("memory.events"), "oom_kill")
+    }
+
+    /// Sets the memory.high of the cgroup, above which its processes are throttled and reclaimed from
+    /// # Arguments
+    /// * `bytes` - The threshold, in bytes
+    pub fn set_memory_high(&self, bytes: u64) -> Result<()> {
+        self.delegate(&["memory"], DELEGATION_RETRIES, DELEGATION_BACKOFF)?;
+        let path = self.path.join("memory.high");
+        fs::write(&path, bytes.to_string())
+            .map_err(|err| eyre::eyre!("Could not set {} to {}: {}", path.display(), bytes, err))?;
+        Ok(())
+    }
+
+    /// Removes the cgroup, and the child cgroups created by the container. They must not contain any process
+    pub fn remove(&self) -> Result<()> {
+        log::debug!("Removing cgroup {}", self.path.display());
+        remove_cgroup_tree(&self.path)
+    }
+
+}
+
+/// Reads a counter of a memory.events file
+/// # Arguments
+/// * `path` - The memory.events file
+/// * `event` - Name of the counter (e.g. "oom_kill")
+pub(crate) fn read_memory_event(path: &Path, event: &str) -> Result<u64> {
+    let events = fs::read_to_string(path)
+        .map_err(|err| eyre::eyre!("Could not read {}: {}", path.display(), err))?;
+    // One "<event> <count>" pair per line
+    events.lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|(name, _)| *name == event)
+        .and_then(|(_, count)| count.trim().parse().ok())
+        .ok_or_else(|| eyre::eyre!("No {} in {}", event, path.display()))
+}
+
+/// Enables controllers for the children of a cgroup. Fails if the cgroup has processes, they are not
+/// moved (see `Cgroup::delegate`)
+/// # Arguments
+/// * `path` - The cgroup
+/// * `controllers` - Names of the controllers
+/// * `retries` - Retries while the cgroup is busy
+/// * `backoff` - Delay before the first retry, doubled after each one
+fn enable_controllers(path: &Path, controllers: &[&str], retries: u32, backoff: Duration) -> Result<()> {
+    let available = fs::read_to_string(path.join("cgroup.controllers"))?;
+    let subtree_control = path.join("cgroup.subtree_control");
+    let enabled = fs::read_to_string(&subtree_control)?;
+    for controller in controllers {
+        if enabled.split_whitespace().any(|enabled| enabled == *controller) {
+            continue;
+        }
+        if !available.split_whitespace().any(|available| available == *controller) {
+            return Err(eyre::eyre!("The {} controller is not available in {}, its parent does not delegate it", controller, path.display()));
+        }
+        let mut delay = backoff;
+        for attempt in 0.. {
+            let err = match fs::write(&subtree_control, format!("+{}", controller)) {
+                Ok(()) => break,
+                Err(err) => err,
+            };
+            if err.raw_os_error() != Some(nix::libc::EBUSY) || attempt >= retries {
+                let hint = match err.raw_os_error() {
+                    Some(nix::libc::EACCES) | Some(nix::libc::EPERM) => ", delegation is not permitted",
+                    _ => "",
+                };
+                return Err(eyre::eyre!("Could not enable the {} controller in {}: {}{}", controller, path.display(), err, hint));
+            }
+            if has_processes(path)? {
+                return Err(eyre::eyre!("Could not enable the {} controller in {}: it has processes, which must be moved to a child cgroup first (\"no internal processes\" rule)", controller, path.display()));
+            }
+            log::debug!("Cgroup {} is busy, retrying in {:?}", path.display(), delay);
+            thread::sleep(delay);
+            delay *= 2;
+        }
+    }
+    Ok(())
+}
+
+/// Whether a cgroup has processes, which prevent it from enabling controllers for its children
+fn has_processes(path: &Path) -> Result<bool> {
+    let procs = fs::read_to_string(path.join("cgroup.procs"))?;
+    Ok(!procs.trim().is_empty())
+}
+
+/// Removes a cgroup after its children (the directories of a cgroup are its children, files can't be removed)
+fn remove_cgroup_tree(path: &Path) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_cgroup_tree(&entry.path())?;
+        }
+    }
+    fs::remove_dir(path)
+        .map_err(|err| eyre::eyre!("Could not remove cgroup {}: {}", path.display(), err))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::signal::{kill, Signal};
+    use nix::sys::wait::waitpid;
+    use nix::unistd::{fork, pause, ForkResult};
+
+    #[test]
+    fn test_cgroup_contains_process() {
+        if !is_cgroup_v2() {
+            return;
+        }
+        let cgroup = Cgroup::create(&format!("test-{}", Pid::this())).unwrap();
+        assert!(cgroup.path().starts_with(CGROUP_ROOT));
+        let child = match unsafe { fork().unwrap() } {
+            ForkResult::Child => {
+                pause();
+                unsafe { nix::libc::_exit(0) };
+            },
+            ForkResult::Parent { child } => child,
+        };
+        cgroup.add_process(child).unwrap();
+        assert_eq!(cgroup.processes().unwrap(), vec![child]);
+        assert!(cgroup.stats().is_ok());
+        kill(child, Signal::SIGKILL).unwrap();
+        waitpid(child, None).unwrap();
+        cgroup.remove().unwrap();
+    }
+
+    #[test]
+    fn test_delegate_to_nested_cgroup() {
+        if !is_cgroup_v2() || !fs::read_to_string(Path::new(CGROUP_ROOT).join("cgroup.controllers")).unwrap().contains("memory") {
+            return;
+        }
+        let cgroup = Cgroup::create(&format!("test-nested-{}", Pid::this())).unwrap();
+        let child = match unsafe { fork().unwrap() } {
+            ForkResult::Child => {
+                pause();
+                unsafe { nix::libc::_exit(0) };
+            },
+            ForkResult::Parent { child } => child,
+        };
+        cgroup.add_process(child).unwrap();
+        let nested = cgroup.create_child("nested").unwrap();
+        // The process is in the way of the delegation, and is not moved
+        let err = nested.delegate(&["memory"], DELEGATION_RETRIES, DELEGATION_BACKOFF).unwrap_err();
+        assert!(err.to_string().contains("no internal processes"), "{}", err);
+        assert_eq!(cgroup.processes().unwrap(), vec![child]);
+        nested.add_process(child).unwrap();
+        nested.delegate(&["memory"], DELEGATION_RETRIES, DELEGATION_BACKOFF).unwrap();
+        let controllers = fs::read_to_string(nested.path().join("cgroup.controllers")).unwrap();
+        assert!(controllers.split_whitespace().any(|controller| controller == "memory"), "{}", controllers);
+        assert!(nested.path().join("memory.max").exists());
+        kill(child, Signal::SIGKILL).unwrap();
+        waitpid(child, None).unwrap();
+        cgroup.remove().unwrap();
+    }
+
+    #[test]
+    fn test_cgroup_limits() {
+        if !is_cgroup_v2() {
+            return;
+        }
+        let cgroup = Cgroup::create(&format!("test-limits-{}", Pid::this())).unwrap();
+        assert!(cgroup.set_limits(None, Some((100, 100)), None).is_err());
+        cgroup.set_limits(Some(64 * 1024 * 1024), Some((50000, 100000)), Some(32)).unwrap();
+        let read = |file: &str| fs::read_to_string(cgroup.path().join(file)).unwrap().trim().to_string();
+        assert_eq!(read("memory.max"), "67108864");
+        assert_eq!(read("cpu.max"), "50000 100000");
+        assert_eq!(read("pids.max"), "32");
+        cgroup.remove().unwrap();
+    }
+
+    #[test]
+    fn test_read_memory_event() {
+        let path = std::env::temp_dir().join(format!("test-memory-events-{}", Pid::this()));
+        fs::write(&path, "low 0\nhigh 12\nmax 3\noom 1\noom_kill 2\noom_group_kill 0\n").unwrap();
+        assert_eq!(read_memory_event(&path, "high").unwrap(), 12);
+        assert_eq!(read_memory_event(&path, "oom").unwrap(), 1);
+        assert_eq!(read_memory_event(&path, "oom_kill").unwrap(), 2);
+        assert!(read_memory_event(&path, "oom_").is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
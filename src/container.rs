@@ -21,17 +21,280 @@
  * THE SOFTWARE.
  */
 
-use crate::filesystem::{StorageDriver, NullDriver};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::cgroup::{Cgroup, CgroupStats};
+use crate::filesystem::{StorageDriver, NullDriver, OverlayDriver};
 use crate::ipc::{self, Action, ProducerChannel};
-use crate::runtime::{Runtime, RuntimeOptions};
-use crate::syscall::{self, Command, ExecType};
+use crate::logs::{self, LogDriver};
+use crate::oci;
+use crate::runtime::{self, ExitCode, Hook, Runtime, RuntimeOptions};
+use crate::seccomp::{self, SeccompFilter, SeccompRule};
+use crate::state::{State, Status};
+use crate::syscall::{self, Command, CommandResult, ContainerExit, ExecType, Namespace};
+use crate::procfs;
 use crate::random;
 use color_eyre::{Result, eyre};
-use nix::sys::signal::{kill, Signal};
-use nix::sys::wait::waitpid;
-use nix::unistd::Pid;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::resource::Resource;
+use nix::sys::signal::{kill, raise, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::fcntl::OFlag;
+use nix::unistd::{close, getegid, geteuid, getgid, getuid, isatty, pipe, pipe2, setgroups, setresgid, setresuid, setsid, Gid, Pid, Uid};
 use log;
 
+/// Interval between the two samples of a snapshot, to compute the CPU usage
+const SNAPSHOT_INTERVAL: Duration = Duration::from_millis(200);
+/// Interval between checks of the container's init having exited, when no pidfd could be opened for it
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub use crate::procfs::{CapSet, ProcessInfo};
+
+/// A process and its CPU usage during a snapshot
+#[derive(Debug, Clone)]
+pub struct ProcessSnapshot {
+    pub process: ProcessInfo,
+    /// CPU usage, in percent of one CPU (it can exceed 100 for multi-threaded processes)
+    pub cpu_percent: f64,
+}
+
+/// Resource usage of the container, like `docker stats`
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// Usage reported by the container's cgroup (only when started with the `cgroup` option)
+    pub stats: Option<CgroupStats>,
+    /// CPU usage of the whole container, in percent of one CPU
+    pub cpu_percent: f64,
+    /// Memory used by the whole container, in bytes
+    pub memory: u64,
+    /// Processes running in the container
+    pub processes: Vec<ProcessSnapshot>,
+}
+
+/// A step of the teardown of a container, see `Container::stop`
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TeardownStep {
+    /// Send the stop signal to the container, then SIGKILL if it is still running after the timeout
+    SIGNAL,
+    /// Wait for the container's init to exit
+    REAP,
+    /// Release the container's mounts (/proc, /sys, /dev, ...), unmounted with its mount namespace once no
+    /// process is left in it. Fails while a process (e.g. one that joined the container) still is
+    UNMOUNT_FILESYSTEMS,
+    /// Unmount the root filesystem from the host (see `RuntimeOptions::host_visible_rootfs`)
+    UNMOUNT_ROOTFS,
+    /// Remove the scratch directory (see `RuntimeOptions::managed_scratch`)
+    REMOVE_SCRATCH,
+    /// Wait until the whole output has reached the log driver
+    FLUSH_LOGS,
+    /// Remove the container's cgroup
+    REMOVE_CGROUP,
+    /// Run the poststop hooks (see `RuntimeOptions::poststop_hooks`), every hook runs even if a previous
+    /// one failed
+    POSTSTOP_HOOKS,
+}
+
+/// Outcome of the teardown of a container, see `Container::stop`
+#[derive(Debug, Default)]
+pub struct TeardownReport {
+    /// The steps, in the order they were attempted, with the error of the failed ones
+    pub steps: Vec<(TeardownStep, Result<(), String>)>,
+}
+
+impl TeardownReport {
+
+    /// Whether every step succeeded
+    pub fn succeeded(&self) -> bool {
+        self.steps.iter().all(|(_, result)| result.is_ok())
+    }
+
+    /// The steps that failed
+    pub fn failed_steps(&self) -> Vec<TeardownStep> {
+        self.steps.iter()
+            .filter(|(_, result)| result.is_err())
+            .map(|(step, _)| *step)
+            .collect()
+    }
+
+    fn record(&mut self, step: TeardownStep, result: Result<()>) {
+        self.steps.push((step, result.map_err(|err| err.to_string())));
+    }
+
+}
+
+/// What the host read from the command-result channel of the container, see `syscall::CommandResult`
+#[derive(Debug, Default)]
+struct CommandResults {
+    /// Read end of the channel, until the container is reaped
+    fd: Option<RawFd>,
+    /// Whether the runtime is being replaced by a command, whose exit code becomes the container's
+    replacing: bool,
+    /// Why the command replacing the runtime could not be executed
+    exec_failure: Option<Errno>,
+    /// Exits of the commands streaming their stdout, by PID, until `wait_exited` returns them
+    exited: HashMap<i32, ContainerExit>,
+}
+
+impl CommandResults {
+
+    /// Reads the result of the next forked command, handling the results of the commands replacing the
+    /// runtime on the way
+    fn next_forked(&mut self) -> Result<CommandResult> {
+        loop {
+            if let Some(forked) = self.next()? {
+                return Ok(forked);
+            }
+        }
+    }
+
+    /// Waits for a command streaming its stdout to end
+    /// # Arguments
+    /// * `pid` - PID of the command, from its `CommandResult::STARTED`
+    fn wait_exited(&mut self, pid: i32) -> Result<ContainerExit> {
+        loop {
+            if let Some(exit) = self.exited.remove(&pid) {
+                return Ok(exit);
+            }
+            self.next()?;
+        }
+    }
+
+    /// Reads and handles the next result, blocking until it is reported
+    /// # Returns
+    /// The result, if it is the result of a forked command
+    fn next(&mut self) -> Result<Option<CommandResult>> {
+        let fd = self.fd.ok_or_else(|| eyre::eyre!("Container not started"))?;
+        match syscall::read_command_result(fd, true)? {
+            Some(result) => Ok(self.handle(result)),
+            None if self.replacing => Err(eyre::eyre!("The container's runtime was replaced by its workload, it can't execute commands")),
+            None => Err(eyre::eyre!("The container's runtime exited")),
+        }
+    }
+
+    /// Reads the results already reported, e.g. once the container exited, and closes the channel
+    fn drain(&mut self) -> Result<()> {
+        let fd = match self.fd.take() {
+            Some(fd) => fd,
+            None => return Ok(()),
+        };
+        let mut drained = Ok(());
+        loop {
+            match syscall::read_command_result(fd, false) {
+                Ok(Some(result)) => if let Some(CommandResult::FAILED(errno)) = self.handle(result) {
+                    log::warn!("A command of the container could not be executed: {}", errno);
+                },
+                Ok(None) => break,
+                Err(err) => {
+                    drained = Err(err);
+                    break;
+                },
+            }
+        }
+        close(fd)?;
+        drained
+    }
+
+    /// Records the result of a command replacing the runtime
+    /// # Returns
+    /// The result, if it is the result of a forked command
+    fn handle(&mut self, result: CommandResult) -> Option<CommandResult> {
+        match result {
+            CommandResult::REPLACING => {
+                self.replacing = true;
+                None
+            },
+            CommandResult::FAILED(errno) if self.replacing => {
+                self.replacing = false;
+                self.exec_failure = Some(errno);
+                None
+            },
+            CommandResult::EXITED(pid, exit) => {
+                self.exited.insert(pid, exit);
+                None
+            },
+            forked => Some(forked),
+        }
+    }
+
+    /// Interprets the exit code of the container's init
+    /// # Returns
+    /// The failure of the container, none if the code is its workload's
+    fn failure(&self, code: i32) -> Option<ExitCode> {
+        match self.exec_failure {
+            Some(errno) => Some(ExitCode::from_errno(errno)),
+            None if self.replacing => None,
+            None => ExitCode::from_code(code),
+        }
+    }
+
+}
+
+/// Privileges of the commands executed by the container's runtime, computed beforehand so they can be
+/// dropped to in a command forked by the host (see `Container::exec_join`)
+struct Privileges {
+    /// User of the commands
+    uid: Uid,
+    /// Group of the commands
+    gid: Gid,
+    /// Capabilities kept, as a bit mask, and the highest capability (see `RuntimeOptions::capabilities`)
+    capabilities: Option<(u64, u32)>,
+    /// Seccomp filter of the commands (see `RuntimeOptions::seccomp`)
+    seccomp: Option<SeccompFilter>,
+}
+
+impl Privileges {
+
+    /// Drops the privileges of the current process to these, in the same order as the runtime
+    ///
+    /// Note: this function does not allocate, it is safe to call in a child forked by a multithreaded
+    /// process (e.g. in `CommandExt::pre_exec`)
+    fn drop_to(&self) -> nix::Result<()> {
+        if let Some((keep, last_cap)) = self.capabilities {
+            syscall::drop_bounding_capabilities(keep, last_cap)?;
+        }
+        if self.gid != getgid() {
+            setgroups(&[self.gid])?;
+            setresgid(self.gid, self.gid, self.gid)?;
+        }
+        if self.uid != getuid() {
+            setresuid(self.uid, self.uid, self.uid)?;
+        }
+        if let Some((keep, _)) = self.capabilities {
+            syscall::limit_capabilities(keep)?;
+        }
+        // Last, as the runtime does before executing a command
+        if let Some(filter) = &self.seccomp {
+            filter.install()?;
+        }
+        Ok(())
+    }
+
+}
+
+/// A signal of the container's workload being ready, see `Container::wait_for_ready`
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadyCheck {
+    /// A file (or directory) exists, at a path inside the container
+    FILE(PathBuf),
+    /// A unix socket is listening, at the path it was bound to inside the container (e.g. /run/app.sock)
+    UNIX_SOCKET(PathBuf),
+    /// A TCP port is listening in the container's network namespace
+    TCP_PORT(u16),
+}
+
 /// The container struct
 pub struct Container {
     /// Parent process' IPC channel
@@ -40,8 +303,30 @@ pub struct Container {
     pid: Pid,
     /// Container's PID
     container_pid: Option<Pid>,
+    /// File descriptor referring to the container's init process (if supported by the kernel)
+    pidfd: Option<RawFd>,
     /// The runtime execution environment for the container
     runtime: Runtime,
+    /// Log driver receiving the container's output (if any)
+    log_driver: Option<Arc<Mutex<Box<dyn LogDriver>>>>,
+    /// Thread handing the container's output to the log driver (if any)
+    log_forwarder: Option<JoinHandle<()>>,
+    /// Master side of the container's pseudo-terminal (if any)
+    pty_master: Option<RawFd>,
+    /// The container's cgroup (if any)
+    cgroup: Option<Cgroup>,
+    /// Process executed once the container is started (if any)
+    process: Option<Command>,
+    /// Whether the container keeps running when this struct is dropped
+    detached: bool,
+    /// Whether the OOM killer killed processes of the container, read before removing its cgroup
+    oom_killed: Option<bool>,
+    /// State of the container, from its start
+    state: Option<State>,
+    /// Mount namespace of the container, e.g. "mnt:[4026532281]", until its mounts are released
+    mount_namespace: Option<PathBuf>,
+    /// Results of the commands executed by the container's runtime
+    command_results: Mutex<CommandResults>,
 }
 
 impl Container {
@@ -53,38 +338,560 @@ impl Container {
     }
 
     pub fn new(fs: Box<dyn StorageDriver>) -> Result<Self> {
-        let (producer_channel, consumer_channel) = ipc::create_ipc_channels()?;
-        let id = random::generate_random_128_id();
-        let runtime = Runtime::new(id, fs, consumer_channel, RuntimeOptions::default());
+        Container::with_options(fs, RuntimeOptions::default())
+    }
+
+    /// Create a new container with custom runtime options
+    /// # Arguments
+    /// * `fs` - Root filesystem driver
+    /// * `options` - Options of the container's execution environment
+    pub fn with_options(fs: Box<dyn StorageDriver>, options: RuntimeOptions) -> Result<Self> {
+        let (producer_channel, consumer_channel) = ipc::create_ipc_channels_with_format(options.ipc_format, options.ipc_capacity)?;
+        let id_format = options.id_format;
+        let id = unique_id(options.state_root.as_deref(), || random::generate_random_128_id(id_format))?;
+        let runtime = Runtime::new(id, fs, consumer_channel, options);
         Ok(Container {
             producer_channel,
             pid: Pid::this(),
             container_pid: None,
+            pidfd: None,
             runtime,
+            log_driver: None,
+            log_forwarder: None,
+            pty_master: None,
+            cgroup: None,
+            process: None,
+            detached: false,
+            oom_killed: None,
+            state: None,
+            mount_namespace: None,
+            command_results: Mutex::new(CommandResults::default()),
         })
     }
 
+    /// Create a container from an OCI runtime bundle, running its configured process once started
+    /// # Arguments
+    /// * `bundle` - Directory containing the config.json file and the root filesystem
+    ///
+    /// Note: the root filesystem is not modified, the changes are written to an overlay in `<bundle>/overlay`
+    pub fn from_oci_bundle(bundle: &impl AsRef<Path>) -> Result<Self> {
+        let bundle = bundle.as_ref();
+        let spec = oci::Spec::load(&bundle.join("config.json"))?;
+        let rootfs = spec.rootfs(bundle);
+        if !rootfs.exists() {
+            return Err(eyre::eyre!("Root filesystem {} does not exist", rootfs.display()));
+        }
+        let fs = OverlayDriver::new(vec![&rootfs], &bundle.join("overlay"));
+        let mut container = Container::with_options(Box::new(fs), spec.runtime_options(bundle))?;
+        container.process = spec.command();
+        Ok(container)
+    }
+
     pub fn start(&mut self) -> Result<()> {
+        // The runtime of a container is a partial copy of the host process, with a single thread and
+        // locks that may never be released: it can't safely clone again
+        if syscall::in_container() {
+            return Err(eyre::eyre!("Nested containers are not supported, a container can't be started from a container's runtime"));
+        }
+        self.runtime.options().validate()?;
+        // The derived hostname is empty if the ID has no letter nor digit
+        if self.runtime.options().namespaces.uts && self.runtime.hostname().is_empty() {
+            return Err(eyre::eyre!("The hostname derived from the ID {} is empty, set RuntimeOptions::hostname", self.runtime.id()));
+        }
+        // Otherwise the container would fail once started
+        if let Some(capabilities) = &self.runtime.options().capabilities {
+            CapSet::from_names(capabilities)?;
+        }
+        if let Some(profile) = &self.runtime.options().seccomp {
+            seccomp::check_profile(profile).map_err(|err| eyre::eyre!("Invalid seccomp profile: {}", err))?;
+        }
+        if self.runtime.options().core_dumps.is_some() {
+            runtime::host_core_dump_dir()?;
+        }
         log::info!("Starting container");
+        // When a log driver is configured, the container writes its output to a pipe read by the host
+        let output = match &self.runtime.options().log_driver {
+            Some(driver_type) => {
+                let mut driver = driver_type.build(self.runtime.id())?;
+                if let Some(prefix) = &self.runtime.options().log_prefix {
+                    let prefix = prefix.replace("{id}", self.runtime.id());
+                    driver = Box::new(logs::Prefixed::new(&prefix, driver));
+                }
+                Some((pipe()?, driver))
+            },
+            None => None,
+        };
+        let output_fds = output.as_ref().map(|(fds, _)| *fds);
+        let pty = if self.runtime.options().tty {
+            Some(syscall::open_pty()?)
+        } else {
+            None
+        };
+        let pty_fds = pty.as_ref().map(|pty| (pty.master, pty.slave));
+        if let Some(scratch) = self.scratch_dir() {
+            log::debug!("Creating scratch directory {}", scratch.display());
+            fs::create_dir_all(&scratch)
+                .map_err(|err| eyre::eyre!("Could not create the scratch directory {}: {}", scratch.display(), err))?;
+            self.runtime.set_scratch_dir(&scratch);
+        }
+        // The container waits for the host to finish its setup (e.g. the cgroup) before running
+        let (ready_read, ready_write) = pipe()?;
+        let (results_read, results_write) = syscall::command_result_channel()?;
+        if self.runtime.options().host_visible_rootfs {
+            // The container mounts the root filesystem on it, the mount propagates back to the host
+            if let Err(err) = self.runtime.share_mount_point() {
+                self.cleanup_failed_start();
+                return Err(err);
+            }
+        }
+        self.runtime.set_command_results(results_write);
+        let kill_on_parent_exit = self.runtime.options().kill_on_parent_exit;
+        let namespaces = self.runtime.options().namespaces.clone();
+        let userns = self.runtime.options().userns;
         let callback: Box<dyn FnMut() -> isize> = Box::new(|| {
+            // No allocation nor logging until the host is ready, see `syscall::create_container`
+            // If the host exits before, the pipe is closed and the container exits as well
+            if kill_on_parent_exit && syscall::set_parent_death_signal(Signal::SIGKILL).is_err() {
+                return ExitCode::SETUP as isize;
+            }
+            let ready = close(ready_write)
+                .and_then(|_| close(results_read))
+                .and_then(|_| syscall::wait_ready(ready_read));
+            if !matches!(ready, Ok(true)) {
+                return ExitCode::SETUP as isize;
+            }
+            // The host has written the ID maps
+            if userns && syscall::switch_to_userns_root().is_err() {
+                return ExitCode::SETUP as isize;
+            }
+            // Changing the credentials clears the parent death signal
+            if userns && kill_on_parent_exit && syscall::set_parent_death_signal(Signal::SIGKILL).is_err() {
+                return ExitCode::SETUP as isize;
+            }
+            if let Err(err) = setup_stdio(pty_fds, output_fds) {
+                log::error!("Could not set up the container's stdio: {}", err);
+                return ExitCode::SETUP as isize;
+            }
             let res = self.runtime.run();
             if let Err(err) = res {
-                log::error!("Container runtime error: {}", err);
-                return -1;
+                log::error!("{:#}", err);
+                let code = err.downcast_ref::<ExitCode>().copied().unwrap_or(ExitCode::RUNTIME);
+                return code as isize;
             }
             0
         });
-        let pid = syscall::create_container(callback)?;
+        let created = syscall::create_container(callback, &namespaces, userns, ExitCode::PANIC as isize);
+        let closed = close(results_write);
+        let pid = match created {
+            Ok(pid) => pid,
+            Err(err) => {
+                self.cleanup_failed_start();
+                close(results_read)?;
+                return Err(err);
+            }
+        };
+        closed?;
+        self.command_results = Mutex::new(CommandResults { fd: Some(results_read), ..Default::default() });
         self.container_pid = Some(pid);
+        self.mount_namespace = fs::read_link(format!("/proc/{}/ns/mnt", pid)).ok();
+        self.pidfd = match syscall::pidfd_open(pid) {
+            Ok(fd) => Some(fd),
+            Err(err) => {
+                log::warn!("Could not open a pidfd for the container: {}", err);
+                None
+            }
+        };
+        close(ready_read)?;
+        if let Err(err) = self.setup_host(pid) {
+            let reaped = self.reap_failed_start(pid, ready_write);
+            self.cleanup_failed_start();
+            reaped?;
+            return Err(err);
+        }
+        if let (Some(pty), Some(host)) = (&pty, self.runtime.options().host_terminal) {
+            // The workload (still waiting for the host) starts with the size of the host terminal,
+            // rather than the default 80x24
+            if isatty(host).unwrap_or(false) {
+                syscall::follow_window_size(host, pty.master)?;
+            }
+        }
+        syscall::notify_ready(ready_write)?;
+        let state = State::running(self.runtime.id(), pid.as_raw());
+        self.save_state(&state);
+        self.state = Some(state);
+        if let Some(command) = self.process.take() {
+            log::debug!("Executing configured process {:?}", command);
+            self.producer_channel.send(ipc::Message::COMMAND(command))?;
+        }
+        if let Some(((read_fd, write_fd), driver)) = output {
+            close(write_fd)?;
+            let driver = Arc::new(Mutex::new(driver));
+            self.log_forwarder = Some(logs::forward_output(read_fd, driver.clone()));
+            self.log_driver = Some(driver);
+        }
+        if let Some(pty) = pty {
+            close(pty.slave)?;
+            self.pty_master = Some(pty.master);
+        }
+        Ok(())
+    }
+
+    /// The scratch directory of the container, `<scratch_root>/<id>`, if `RuntimeOptions::managed_scratch`
+    /// is set. It exists while the container runs
+    pub fn scratch_dir(&self) -> Option<PathBuf> {
+        let options = self.runtime.options();
+        options.managed_scratch.then(|| options.scratch_root.join(self.runtime.id()))
+    }
+
+    /// The layers of the container's root filesystem, from top to bottom: the writable layer (e.g. the
+    /// upperdir of an overlay) followed by the read-only layers of the image. Empty if the storage driver
+    /// has no layers
+    pub fn layers(&self) -> Vec<PathBuf> {
+        self.runtime.layers()
+    }
+
+    /// Removes the scratch directory of the container, if any, once its filesystem is unmounted
+    fn remove_scratch_dir(&self) -> Result<()> {
+        if let Some(scratch) = self.scratch_dir() {
+            log::debug!("Removing scratch directory {}", scratch.display());
+            fs::remove_dir_all(&scratch)
+                .map_err(|err| eyre::eyre!("Could not remove the scratch directory {}: {}", scratch.display(), err))?;
+        }
+        Ok(())
+    }
+
+    /// Stops a container that `start` created but failed to set up
+    /// # Arguments
+    /// * `pid` - PID of the container, still waiting for the host
+    /// * `ready_write` - Host's end of the pipe the container waits on
+    fn reap_failed_start(&mut self, pid: Pid, ready_write: RawFd) -> Result<()> {
+        // The container exits when the pipe is closed without signaling
+        close(ready_write)?;
+        waitpid(pid, None)?;
+        if let Some(pidfd) = self.pidfd.take() {
+            close(pidfd)?;
+        }
+        self.container_pid = None;
+        self.command_results.lock().unwrap().drain()?;
+        Ok(())
+    }
+
+    /// Undoes the host-side setup of `start` when it fails
+    fn cleanup_failed_start(&mut self) {
+        if let Err(err) = self.umount_host_visible_rootfs() {
+            // Removing it would delete the files of the mounted root filesystem, as in `cleanup`
+            log::warn!("{}, not removing the scratch directory", err);
+            return;
+        }
+        if let Err(err) = self.remove_scratch_dir() {
+            log::warn!("{}", err);
+        }
+    }
+
+    /// Start the container running `command` as its init process
+    /// # Arguments
+    /// * `command` - Filename or path to the executable
+    /// * `args` - Arguments to pass to the new process
+    /// * `env` - Environment variables to set (optional)
+    ///
+    /// Unlike `start` followed by `execute_in_container`, the command is handed to the container
+    /// before `start` returns, so it is the first thing the container runs.
+    pub fn spawn(&mut self, command: String, args: Vec<String>, env: Option<Vec<String>>) -> Result<()> {
+        self.spawn_command(Command {
+            command,
+            args,
+            env: env.unwrap_or_default(),
+            exec_type: ExecType::REPLACE,
+            argv0: None,
+            login: false,
+            path_search: true,
+            process_group: None,
+            stream_stdout: false,
+        })
+    }
+
+    /// Same as `spawn`, with every option of the command (e.g. to run a login shell)
+    /// # Arguments
+    /// * `command` - The command, its `exec_type` is ignored: the init process always replaces the runtime
+    pub fn spawn_command(&mut self, command: Command) -> Result<()> {
+        self.process = Some(Command {
+            exec_type: ExecType::REPLACE,
+            ..command
+        });
+        self.start()
+    }
+
+    /// Unmounts the root filesystem and its shared mount point from the host (see `host_visible_rootfs`)
+    fn umount_host_visible_rootfs(&mut self) -> Result<()> {
+        if self.runtime.options().host_visible_rootfs {
+            self.runtime.unshare_mount_point()
+                .map_err(|err| eyre::eyre!("Could not unmount the container's root filesystem from the host: {}", err))?;
+        }
+        Ok(())
+    }
+
+    /// Persists the state of the container, if a state directory is configured. The container keeps
+    /// running if it fails
+    fn save_state(&self, state: &State) {
+        if let Some(state_root) = &self.runtime.options().state_root {
+            if let Err(err) = state.save(state_root) {
+                log::warn!("Could not save the state of the container: {}", err);
+            }
+        }
+    }
+
+    /// Host-side setup of the container, done before it starts running
+    fn setup_host(&mut self, pid: Pid) -> Result<()> {
+        let options = self.runtime.options();
+        if options.userns {
+            // The container waits for the host, so the maps are written before it does anything
+            let uid_map = match options.uid_map.as_slice() {
+                [] => vec![(0, geteuid().as_raw(), 1)],
+                maps => maps.to_vec(),
+            };
+            let gid_map = match options.gid_map.as_slice() {
+                [] => vec![(0, getegid().as_raw(), 1)],
+                maps => maps.to_vec(),
+            };
+            syscall::setup_userns_mappings(pid, &uid_map, &gid_map)?;
+        }
+        let limits = options.has_resource_limits();
+        let accounting = options.has_accounting();
+        if accounting || options.delegate_cgroup || options.readonly_cgroup.is_some() {
+            let cgroup = Cgroup::create(self.runtime.id())?;
+            if accounting {
+                // Only the usage of the disabled controllers is missing
+                if let Err(err) = cgroup.enable_accounting() {
+                    log::warn!("Could not enable the accounting of the container's cgroup: {}", err);
+                }
+            }
+            // Set before the container runs anything, unlike the accounting the limits are required
+            if limits {
+                let res = cgroup.set_limits(options.memory_limit, options.cpu_quota, options.pids_limit)
+                    .and_then(|_| match options.memory_high {
+                        Some(high) => cgroup.set_memory_high(high),
+                        None => Ok(()),
+                    });
+                if let Err(err) = res {
+                    let _ = cgroup.remove();
+                    return Err(err);
+                }
+            }
+            cgroup.add_process(pid)?;
+            self.cgroup = Some(cgroup);
+        }
         Ok(())
     }
 
+    /// File descriptor that becomes readable when the container exits, to `poll` it along with other
+    /// file descriptors (None if the kernel does not support pidfds, before Linux 5.3)
+    ///
+    /// The file descriptor is closed by `wait_for_container`.
+    pub fn pidfd(&self) -> Option<RawFd> {
+        self.pidfd
+    }
+
+    /// Absolute path of the container's cgroup, under /sys/fs/cgroup (only when started with the `cgroup` option)
+    pub fn cgroup_path(&self) -> Option<&Path> {
+        self.cgroup.as_ref().map(|cgroup| cgroup.path())
+    }
+
+    /// Resource usage reported by the container's cgroup (only when started with the `enable_accounting`
+    /// option, or one implying it)
+    pub fn stats(&self) -> Result<CgroupStats> {
+        match &self.cgroup {
+            Some(cgroup) => cgroup.stats(),
+            None => Err(eyre::eyre!("Container has no cgroup")),
+        }
+    }
+
+    /// Number of processes (and threads) of the container, from its cgroup's pids.current
+    ///
+    /// Requires the `enable_accounting` option (or one implying it), with the pids controller enabled.
+    pub fn pids_count(&self) -> Result<u64> {
+        match &self.cgroup {
+            Some(cgroup) => Ok(cgroup.pids()?.0),
+            None => Err(eyre::eyre!("Container has no cgroup")),
+        }
+    }
+
+    /// Whether the OOM killer killed processes of the container, while it is running or once it has exited
+    ///
+    /// Requires the `cgroup` option, with the memory controller enabled for the container's cgroup.
+    pub fn was_oom_killed(&self) -> Result<bool> {
+        match (&self.cgroup, self.oom_killed) {
+            (Some(cgroup), _) => Ok(cgroup.oom_kills()? > 0),
+            (None, Some(oom_killed)) => Ok(oom_killed),
+            (None, None) => Err(eyre::eyre!("Container has no cgroup with the memory controller")),
+        }
+    }
+
+    /// Number of bytes written by the container to its root filesystem (e.g. the upperdir of an overlay)
+    pub fn disk_usage(&self) -> Result<u64> {
+        self.runtime.disk_usage()
+    }
+
+    /// Live state of the container as a JSON document, like `docker inspect`: its ID, PID, status,
+    /// runtime options, namespaces, cgroup, root filesystem and resource usage.
+    ///
+    /// The fields that are not available (e.g. the cgroup of a container started without one) are null.
+    pub fn inspect(&self) -> Result<serde_json::Value> {
+        let namespaces = match self.container_pid {
+            Some(pid) => Some(procfs::namespace_ids(pid)?),
+            None => None,
+        };
+        let stats = self.stats().ok().map(|stats| serde_json::json!({
+            "cpu_usage_usec": stats.cpu_usage.as_micros() as u64,
+            "memory": stats.memory,
+        }));
+        Ok(serde_json::json!({
+            "id": self.id(),
+            "pid": self.container_pid.map(|pid| pid.as_raw()),
+            "status": if self.container_pid.is_some() { "running" } else { "stopped" },
+            "options": serde_json::to_value(self.runtime.options())?,
+            "namespaces": namespaces,
+            "cgroup": self.cgroup_path(),
+            "mount_point": self.runtime.mount_point().ok(),
+            "stats": stats,
+            "disk_usage": self.disk_usage().ok(),
+        }))
+    }
+
+    /// Processes running in the container
+    pub fn list_processes(&self) -> Result<Vec<ProcessInfo>> {
+        let pid = match &self.container_pid {
+            Some(pid) => *pid,
+            None => return Err(eyre::eyre!("Container not started"))
+        };
+        // The processes may exit while they are listed
+        Ok(procfs::namespace_processes(pid)?.into_iter()
+            .filter_map(|process| procfs::process_info(process).ok())
+            .collect())
+    }
+
+    /// Capability sets of the container's init process, e.g. to verify that capabilities were dropped
+    pub fn capabilities(&self) -> Result<CapSet> {
+        let pid = match &self.container_pid {
+            Some(pid) => *pid,
+            None => return Err(eyre::eyre!("Container not started"))
+        };
+        procfs::process_capabilities(pid)
+    }
+
+    /// Environment variables ("KEY=VALUE") in effect in the container's init process
+    ///
+    /// These are the variables the process was executed with (e.g. the command started with `spawn`),
+    /// later changes made by the process itself are not visible.
+    pub fn environment(&self) -> Result<Vec<String>> {
+        let pid = match &self.container_pid {
+            Some(pid) => *pid,
+            None => return Err(eyre::eyre!("Container not started"))
+        };
+        procfs::process_environment(pid)
+    }
+
+    /// Resource usage of the container and of each of its processes
+    ///
+    /// The CPU usage is sampled twice, so this takes a short time (200 ms) to return.
+    /// Without a cgroup, the figures of the whole container are the sum of its processes'.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let before = self.list_processes()?;
+        let stats_before = self.stats().ok();
+        let start = Instant::now();
+        thread::sleep(SNAPSHOT_INTERVAL);
+        let after = self.list_processes()?;
+        let stats = self.stats().ok();
+        let interval = start.elapsed().as_secs_f64();
+        let percent = |before: Duration, after: Duration| {
+            after.saturating_sub(before).as_secs_f64() * 100.0 / interval
+        };
+        let processes: Vec<ProcessSnapshot> = after.into_iter()
+            .map(|process| {
+                // A process started during the interval used all its CPU time in it
+                let previous = before.iter()
+                    .find(|previous| previous.pid == process.pid)
+                    .map_or(Duration::ZERO, |previous| previous.cpu_time);
+                ProcessSnapshot {
+                    cpu_percent: percent(previous, process.cpu_time),
+                    process,
+                }
+            })
+            .collect();
+        let (cpu_percent, memory) = match (stats_before, stats) {
+            (Some(before), Some(after)) => (
+                percent(before.cpu_usage, after.cpu_usage),
+                after.memory.unwrap_or_else(|| processes.iter().map(|p| p.process.memory).sum()),
+            ),
+            _ => (
+                processes.iter().map(|p| p.cpu_percent).sum(),
+                processes.iter().map(|p| p.process.memory).sum(),
+            ),
+        };
+        Ok(Snapshot { stats, cpu_percent, memory, processes })
+    }
+
+    /// Change a resource limit of the running container, e.g. to raise RLIMIT_NOFILE of a service
+    /// # Arguments
+    /// * `resource` - The resource to limit
+    /// * `soft` - Soft limit (None: unlimited)
+    /// * `hard` - Hard limit (None: unlimited)
+    ///
+    /// Note: the limit applies to the container's init process, and to the processes it starts afterwards
+    pub fn set_limit(&self, resource: Resource, soft: Option<u64>, hard: Option<u64>) -> Result<()> {
+        let pid = match &self.container_pid {
+            Some(pid) => *pid,
+            None => return Err(eyre::eyre!("Container not started"))
+        };
+        log::debug!("Setting limit {:?} of container with PID {} to {:?}/{:?}", resource, pid, soft, hard);
+        syscall::set_process_limit(pid, resource, soft, hard)
+    }
+
+    /// Master side of the container's pseudo-terminal, to read the container's output and write its input
+    /// (only when started with the `tty` option). It is closed once the container is waited for
+    pub fn pty(&self) -> Option<RawFd> {
+        self.pty_master
+    }
+
+    /// Resize the container's pseudo-terminal, so programs running inside can re-render
+    /// # Arguments
+    /// * `rows` - Number of rows
+    /// * `cols` - Number of columns
+    pub fn resize_pty(&self, rows: u16, cols: u16) -> Result<()> {
+        match self.pty_master {
+            Some(master) => syscall::set_window_size(master, rows, cols),
+            None => Err(eyre::eyre!("Container has no pseudo-terminal"))
+        }
+    }
+
+    /// ID of the container
+    pub fn id(&self) -> &str {
+        self.runtime.id()
+    }
+
+    /// Lines of output kept by the log driver (only the `RINGBUFFER` driver keeps them)
+    pub fn logs(&self) -> Vec<String> {
+        match &self.log_driver {
+            Some(driver) => driver.lock().unwrap().lines(),
+            None => vec![],
+        }
+    }
+
+    /// Let the container keep running when this struct is dropped, instead of stopping it
+    ///
+    /// Note: unless `RuntimeOptions::kill_on_parent_exit` is disabled, the container is still killed
+    /// when the thread that started it exits
+    pub fn detach(&mut self) {
+        self.detached = true;
+    }
+
     /// Forcefully stop the container
     /// Warning: This will immediately kill the container and all its processes, data will be lost
+    ///
+    /// Note: the container is signaled directly, not through the IPC channel, which may be full when the
+    /// container doesn't receive its messages
     pub fn force_stop(&mut self) -> Result<()> {
         assert!(self.pid == Pid::this());
         log::info!("Forcefully stopping container");
-        self.producer_channel.send(ipc::Message::ACTION(Action::STOP))?;
         // Send a signal to the container thread to stop it IMMEDIATELY
         match &self.container_pid {
             Some(pid) => {
@@ -97,34 +904,2005 @@ impl Container {
     }
 
     /// Wait for the container to finish
-    pub fn wait_for_container(&mut self) -> Result<()> {
-        // Check we call from the parent process
+    ///
+    /// When the container failed before running its workload (e.g. a mount failed, or its command could not
+    /// be executed), the error carries the corresponding `ExitCode`, which can be retrieved with
+    /// `downcast_ref::<ExitCode>()`. Once the workload runs, any exit code is its own.
+    /// # Returns
+    /// How the workload ended: its exit code, or the signal that killed it
+    pub fn wait_for_container(&mut self) -> Result<ContainerExit> {
+        let status = self.wait()?;
+        match ContainerExit::from_wait_status(status) {
+            // The container failed before running its workload
+            Some(ContainerExit::EXITED(code)) => match self.command_results.lock().unwrap().failure(code) {
+                Some(failure) => Err(eyre::eyre!(failure)),
+                None => Ok(ContainerExit::EXITED(code)),
+            },
+            Some(exit) => Ok(exit),
+            None => Err(eyre::eyre!("Unexpected status of the container: {:?}", status)),
+        }
+    }
+
+    /// Same as `wait_for_container`, but when the container is killed by a signal, the same signal is
+    /// raised in the current process once the container is cleaned up, e.g. so a CLI tool running a
+    /// container terminates like it (or runs its own handler of the signal)
+    pub fn wait_and_reraise_signal(&mut self) -> Result<()> {
+        let status = self.wait()?;
+        match status {
+            WaitStatus::Signaled(_, signal, _) => {
+                log::debug!("Container killed by {}, raising it", signal);
+                raise(signal)?;
+                Ok(())
+            },
+            WaitStatus::Exited(_, code) => match self.command_results.lock().unwrap().failure(code) {
+                Some(failure) => Err(eyre::eyre!(failure)),
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Stops the container step by step, reporting the outcome of each step instead of stopping at the
+    /// first failure: every step is attempted even if a previous one failed (except removing the scratch
+    /// directory while the root filesystem is still mounted in it). If the container could be neither
+    /// stopped nor killed, it is still running: the report ends with the failed `REAP` step, and nothing is
+    /// cleaned up, `stop` can be called again
+    /// # Arguments
+    /// * `timeout` - Time given to the container to exit after the stop signal, before it is killed
+    /// # Returns
+    /// The outcome of the steps, an error only if the container is not running
+    pub fn stop(&mut self, timeout: Duration) -> Result<TeardownReport> {
         assert!(self.pid == Pid::this());
         let pid = match &self.container_pid {
             Some(pid) => *pid,
             None => return Err(eyre::eyre!("Container not started"))
         };
-        log::debug!("Waiting for container to finish with PID {}", pid);
-        waitpid(pid, None)?;
-        Ok(())
+        let mut report = TeardownReport::default();
+        let (signaled, exiting) = self.signal_stop(pid, timeout);
+        report.record(TeardownStep::SIGNAL, signaled);
+        // Waiting for a container that was not killed could block forever
+        if !exiting && !syscall::has_exited(pid).unwrap_or(false) {
+            report.record(TeardownStep::REAP, Err(eyre::eyre!("Skipped, the container could not be killed and is still running")));
+        } else {
+            report.record(TeardownStep::REAP, self.reap().map(|_| ()));
+            report.steps.extend(self.cleanup().steps);
+        }
+        for (step, result) in &report.steps {
+            if let Err(err) = result {
+                log::warn!("Teardown step {:?} failed: {}", step, err);
+            }
+        }
+        Ok(report)
     }
 
-    /// Order the container to execute a new process
+    /// Sends the stop signal to the container, and SIGKILL if it has not exited after `timeout`, or if
+    /// the stop signal could not be sent or waited for
+    /// # Returns
+    /// The outcome of the step, and whether the container exited or was killed
+    fn signal_stop(&self, pid: Pid, timeout: Duration) -> (Result<()>, bool) {
+        let signal = self.runtime.options().stop_signal;
+        log::info!("Stopping container with {}", signal);
+        let signaled = kill(pid, signal).map_err(|err| eyre::eyre!("Could not send {}: {}", signal, err));
+        let exited = match &signaled {
+            Ok(()) => self.wait_exit(pid, timeout).unwrap_or_else(|err| {
+                log::warn!("Could not wait for the container to exit: {}", err);
+                false
+            }),
+            Err(_) => false,
+        };
+        if !exited {
+            log::info!("Container still running after {:?}, killing it", timeout);
+            match kill(pid, Signal::SIGKILL) {
+                // Exited in the meantime
+                Ok(()) | Err(Errno::ESRCH) => (),
+                Err(err) => return (Err(eyre::eyre!("Could not kill the container: {}", err)), false),
+            }
+        }
+        (signaled, true)
+    }
+
+    /// Waits for the container's init to exit, without reaping it
     /// # Arguments
-    /// * `command` - Filename or path to the executable
-    /// * `args` - Arguments to pass to the new process
-    /// * `env` - Environment variables to set (optional)
-    /// * `exec_type` - Type of execution (optional, default: FORK, see `ExecType`)
-    pub fn execute_in_container(&self, command: String, args: Vec<String>, env: Option<Vec<String>>, exec_type: Option<ExecType>) -> Result<()> {
+    /// * `pid` - PID of the container's init
+    /// * `timeout` - Maximum time to wait
+    /// # Returns
+    /// Whether it exited before the timeout
+    fn wait_exit(&self, pid: Pid, timeout: Duration) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.pidfd {
+                // The pidfd becomes readable once the process exits
+                Some(pidfd) => {
+                    let mut fds = [PollFd::new(pidfd, PollFlags::POLLIN)];
+                    match poll(&mut fds, remaining.as_millis().min(i32::MAX as u128) as i32) {
+                        Ok(ready) => return Ok(ready == 1),
+                        Err(Errno::EINTR) => continue,
+                        Err(err) => return Err(err.into()),
+                    }
+                },
+                None => {
+                    if syscall::has_exited(pid)? {
+                        return Ok(true);
+                    }
+                    if remaining.is_zero() {
+                        return Ok(false);
+                    }
+                    thread::sleep(remaining.min(EXIT_POLL_INTERVAL));
+                },
+            }
+        }
+    }
+
+    /// Waits for the container's init process to exit and cleans up after it
+    /// # Returns
+    /// The status of the init process
+    fn wait(&mut self) -> Result<WaitStatus> {
+        let status = self.reap()?;
+        for (step, result) in self.cleanup().steps {
+            if let Err(err) = result {
+                log::warn!("Teardown step {:?} failed: {}", step, err);
+            }
+        }
+        Ok(status)
+    }
+
+    /// Waits for the container's init process to exit
+    /// # Returns
+    /// The status of the init process
+    fn reap(&mut self) -> Result<WaitStatus> {
+        // Check we call from the parent process
         assert!(self.pid == Pid::this());
-        let command = Command {
-            command,
-            args,
-            env: env.unwrap_or(vec![]),
-            exec_type: exec_type.unwrap_or(ExecType::REPLACE)
+        let pid = match &self.container_pid {
+            Some(pid) => *pid,
+            None => return Err(eyre::eyre!("Container not started"))
         };
-        log::debug!("Executing command inside container {:?}", command);
-        self.producer_channel.send(ipc::Message::COMMAND(command))
+        log::debug!("Waiting for container to finish with PID {}", pid);
+        let status = waitpid(pid, None)?;
+        self.container_pid = None;
+        // Every result of the init is in the channel once it exited
+        if let Err(err) = self.command_results.lock().unwrap().drain() {
+            log::warn!("Could not read the results of the container's commands: {}", err);
+        }
+        if let Some(state) = &mut self.state {
+            state.pid = None;
+            state.status = Status::STOPPED;
+        }
+        if let Some(state_root) = &self.runtime.options().state_root {
+            if let Ok(mut state) = State::load(state_root, self.runtime.id()) {
+                state.pid = None;
+                state.status = Status::STOPPED;
+                self.save_state(&state);
+            }
+        }
+        if let Some(pidfd) = self.pidfd.take() {
+            close(pidfd)?;
+        }
+        Ok(status)
     }
 
+    /// Cleans up the host once the container's init has exited, attempting every step
+    fn cleanup(&mut self) -> TeardownReport {
+        let mut report = TeardownReport::default();
+        let released = match self.mount_namespace.take() {
+            Some(namespace) => match procfs::mount_namespace_in_use(&namespace) {
+                Ok(true) => Err(eyre::eyre!("A process is still in the mount namespace {} of the container", namespace.display())),
+                Ok(false) => Ok(()),
+                Err(err) => Err(err),
+            },
+            None => Ok(()),
+        };
+        report.record(TeardownStep::UNMOUNT_FILESYSTEMS, released);
+        if let Some(master) = self.pty_master.take() {
+            syscall::unfollow_window_size(master);
+            if let Err(err) = close(master) {
+                log::warn!("Could not close the pseudo-terminal of the container: {}", err);
+            }
+        }
+        let unmounted = self.umount_host_visible_rootfs();
+        let still_mounted = unmounted.is_err();
+        report.record(TeardownStep::UNMOUNT_ROOTFS, unmounted);
+        // Removing it would delete the files of the mounted root filesystem
+        if still_mounted && self.scratch_dir().is_some() {
+            report.record(TeardownStep::REMOVE_SCRATCH, Err(eyre::eyre!("Skipped, the root filesystem is still mounted")));
+        } else {
+            report.record(TeardownStep::REMOVE_SCRATCH, self.remove_scratch_dir());
+        }
+        let flushed = match self.log_forwarder.take() {
+            Some(forwarder) => forwarder.join().map_err(|_| eyre::eyre!("The log forwarder panicked")),
+            None => Ok(()),
+        };
+        report.record(TeardownStep::FLUSH_LOGS, flushed);
+        let removed = match self.cgroup.take() {
+            Some(cgroup) => {
+                // The counters are lost with the cgroup
+                self.oom_killed = cgroup.oom_kills().ok().map(|kills| kills > 0);
+                cgroup.remove()
+            },
+            None => Ok(()),
+        };
+        report.record(TeardownStep::REMOVE_CGROUP, removed);
+        report.record(TeardownStep::POSTSTOP_HOOKS, self.run_poststop_hooks());
+        report
+    }
+
+    /// Runs the poststop hooks, see `RuntimeOptions::poststop_hooks`
+    fn run_poststop_hooks(&self) -> Result<()> {
+        let hooks = &self.runtime.options().poststop_hooks;
+        let state = match &self.state {
+            Some(state) if !hooks.is_empty() => serde_json::to_vec(state)?,
+            _ => return Ok(()),
+        };
+        let mut failures = vec![];
+        for hook in hooks {
+            log::debug!("Running poststop hook {:?}", hook);
+            if let Err(err) = run_hook(hook, &state) {
+                failures.push(format!("{}: {}", hook.path.display(), err));
+            }
+        }
+        match failures.is_empty() {
+            true => Ok(()),
+            false => Err(eyre::eyre!("Poststop hooks failed: {}", failures.join(", "))),
+        }
+    }
+
+    /// Runs a function in namespaces of the container, e.g. to read its network configuration from Rust
+    /// code instead of executing a command. See `syscall::enter_namespaces`, the function runs in a new
+    /// thread and the namespaces of the caller are left untouched
+    /// # Arguments
+    /// * `namespaces` - The namespaces of the container to join
+    /// * `f` - The function
+    /// # Returns
+    /// The result of the function
+    pub fn enter<F, R>(&self, namespaces: &[Namespace], f: F) -> Result<R>
+    where
+        F: FnOnce() -> R + Send,
+        R: Send,
+    {
+        let pid = match &self.container_pid {
+            Some(pid) => *pid,
+            None => return Err(eyre::eyre!("Container not started"))
+        };
+        syscall::enter_namespaces(pid, namespaces, f)
+    }
+
+    /// Wait until a TCP port is listening inside the container
+    /// # Arguments
+    /// * `port` - The TCP port
+    /// * `timeout` - Maximum time to wait
+    pub fn wait_for_port(&self, port: u16, timeout: Duration) -> Result<()> {
+        let pid = match &self.container_pid {
+            Some(pid) => *pid,
+            None => return Err(eyre::eyre!("Container not started"))
+        };
+        log::debug!("Waiting for port {} to be listening in container with PID {}", port, pid);
+        procfs::wait_for_port(pid, port, timeout)
+    }
+
+    /// Wait until the container's workload is ready: a file appears, or a unix socket or a TCP port is listening
+    /// # Arguments
+    /// * `check` - The readiness signal
+    /// * `timeout` - Maximum time to wait
+    pub fn wait_for_ready(&self, check: ReadyCheck, timeout: Duration) -> Result<()> {
+        let pid = match &self.container_pid {
+            Some(pid) => *pid,
+            None => return Err(eyre::eyre!("Container not started"))
+        };
+        log::debug!("Waiting for {:?} in container with PID {}", check, pid);
+        match check {
+            ReadyCheck::FILE(path) => {
+                // The root directory of the init, seen from the host
+                let root = PathBuf::from(format!("/proc/{}/root", pid));
+                let file = root.join(path.strip_prefix("/").unwrap_or(&path));
+                procfs::wait_until(timeout, &format!("{} does not exist", path.display()), || {
+                    // Until the container switches to its rootfs, its root is the host's
+                    let host_root = fs::metadata("/")?;
+                    let switched = fs::metadata(&root)
+                        .map(|root| (root.dev(), root.ino()) != (host_root.dev(), host_root.ino()))
+                        .unwrap_or(false);
+                    Ok(switched && fs::symlink_metadata(&file).is_ok())
+                })
+            },
+            ReadyCheck::UNIX_SOCKET(path) => {
+                procfs::wait_until(timeout, &format!("Unix socket {} is not listening", path.display()), || {
+                    Ok(procfs::listening_unix_sockets(pid)?.contains(&path))
+                })
+            },
+            ReadyCheck::TCP_PORT(port) => procfs::wait_for_port(pid, port, timeout),
+        }
+    }
+
+    /// Order the container to execute a new process
+    /// # Arguments
+    /// * `command` - Filename or path to the executable
+    /// * `args` - Arguments to pass to the new process
+    /// * `env` - Environment variables to set (optional)
+    /// * `exec_type` - Type of execution (optional, default: FORK, see `ExecType`)
+    ///
+    /// Note: fails if the container is not started, no runtime would receive the command. A forked command
+    /// is waited for until it started: if it could not be executed, the error carries the corresponding
+    /// `ExitCode` (e.g. `ExitCode::NOT_FOUND`)
+    pub fn execute_in_container(&self, command: String, args: Vec<String>, env: Option<Vec<String>>, exec_type: Option<ExecType>) -> Result<()> {
+        assert!(self.pid == Pid::this());
+        let command = Command {
+            command,
+            args,
+            env: env.unwrap_or(vec![]),
+            exec_type: exec_type.unwrap_or(ExecType::REPLACE),
+            argv0: None,
+            login: false,
+            path_search: true,
+            process_group: None,
+            stream_stdout: false,
+        };
+        self.send_command(command, None).map(|_| ())
+    }
+
+    /// Same as `execute_in_container` with `ExecType::FORK`, running the command in a new process group,
+    /// which `signal_process_group` can signal without affecting the rest of the container
+    /// # Arguments
+    /// * `group` - Name of the process group. Reusing a name replaces the group it refers to
+    /// * `command` - Command to execute
+    /// * `args` - Arguments to pass to the command
+    /// * `env` - Environment variables to set (optional)
+    pub fn execute_in_process_group(&self, group: &str, command: String, args: Vec<String>, env: Option<Vec<String>>) -> Result<()> {
+        assert!(self.pid == Pid::this());
+        let command = Command {
+            command,
+            args,
+            env: env.unwrap_or(vec![]),
+            exec_type: ExecType::FORK,
+            argv0: None,
+            login: false,
+            path_search: true,
+            process_group: Some(group.to_string()),
+            stream_stdout: false,
+        };
+        self.send_command(command, None).map(|_| ())
+    }
+
+    /// Sends a command to the runtime
+    /// # Arguments
+    /// * `command` - The command
+    /// * `stdout` - Stdout handed to the command, if it streams it (see `Command::stream_stdout`)
+    /// # Returns
+    /// The PID of the command once it started, if it is forked
+    fn send_command(&self, command: Command, stdout: Option<RawFd>) -> Result<Option<i32>> {
+        if self.container_pid.is_none() {
+            return Err(eyre::eyre!("Container not started, could not execute {}: call start first", command.command));
+        }
+        if self.runtime.options().check_pids_limit && matches!(command.exec_type, ExecType::FORK) {
+            self.check_pids_limit(&command.command)?;
+        }
+        log::debug!("Executing command inside container {:?}", command);
+        let forked = matches!(command.exec_type, ExecType::FORK);
+        let name = command.command.clone();
+        // Held until the result is read, the runtime handles the commands in order and each forked one
+        // gets a single result
+        let mut results = self.command_results.lock().unwrap();
+        if let Some(stdout) = stdout {
+            let channel = results.fd.ok_or_else(|| eyre::eyre!("Container not started"))?;
+            syscall::send_fd(channel, stdout)?;
+        }
+        self.producer_channel.send(ipc::Message::COMMAND(command))?;
+        if !forked {
+            return Ok(None);
+        }
+        match results.next_forked()? {
+            CommandResult::FAILED(errno) => Err(eyre::eyre!("Could not execute {}: {}", name, errno)
+                .wrap_err(ExitCode::from_errno(errno))),
+            CommandResult::STARTED(pid) => Ok(Some(pid)),
+            result => Err(eyre::eyre!("Unexpected result of {}: {:?}", name, result)),
+        }
+    }
+
+    /// Sends a signal to the processes of the container, through its runtime
+    /// # Arguments
+    /// * `group` - Process group from `execute_in_process_group`, none for all but the runtime (requires a
+    ///   PID namespace)
+    /// * `signal` - The signal to send
+    ///
+    /// Note: the signal is sent asynchronously, the runtime logs a warning if the group doesn't exist
+    pub fn signal_process_group(&self, group: Option<&str>, signal: Signal) -> Result<()> {
+        assert!(self.pid == Pid::this());
+        if self.container_pid.is_none() {
+            return Err(eyre::eyre!("Container not started"));
+        }
+        if group.is_none() && !self.runtime.options().namespaces.pid {
+            return Err(eyre::eyre!("Can't signal every process of a container without a PID namespace, they can't be told from the host's"));
+        }
+        self.producer_channel.send(ipc::Message::ACTION(Action::SIGNAL {
+            group: group.map(|name| name.to_string()),
+            signal,
+        }))
+    }
+
+    /// Runs a command in the container and hands each line of its stdout to a callback as soon as it is
+    /// printed, instead of buffering the whole output
+    /// # Arguments
+    /// * `command` - Filename or path to the executable, searched in the PATH of the container
+    /// * `args` - Arguments to pass to the command
+    /// * `callback` - Called with every line of the stdout, in order, without the newline
+    /// # Returns
+    /// How the command ended, once its stdout is closed and it exited
+    ///
+    /// Note: the command is forked by the container's runtime, as `execute_in_container` with
+    /// `ExecType::FORK`: it gets the user, capabilities, seccomp filters, cgroup and namespaces of the
+    /// container's commands, and its stderr goes to the container's output. The runtime must still be
+    /// running, not replaced by the workload (e.g. the container was created with `start`, not `spawn`)
+    pub fn run_command_streaming<F>(&self, command: String, args: Vec<String>, mut callback: F) -> Result<ContainerExit>
+    where
+        F: FnMut(&str),
+    {
+        assert!(self.pid == Pid::this());
+        log::debug!("Running {} {:?} in the container, streaming its output", command, args);
+        let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC)?;
+        let command = Command {
+            command,
+            args,
+            env: vec![],
+            exec_type: ExecType::FORK,
+            argv0: None,
+            login: false,
+            path_search: true,
+            process_group: None,
+            stream_stdout: true,
+        };
+        let started = self.send_command(command, Some(write_fd));
+        // The runtime received its own copy
+        close(write_fd)?;
+        let pid = match started {
+            Ok(Some(pid)) => pid,
+            Ok(None) => unreachable!("The command is forked"),
+            Err(err) => {
+                close(read_fd)?;
+                return Err(err);
+            },
+        };
+        // Closed once read, a command still writing to it gets SIGPIPE
+        let stdout = unsafe { fs::File::from_raw_fd(read_fd) };
+        let mut streamed = Ok(());
+        for line in BufReader::new(stdout).lines() {
+            match line {
+                Ok(line) => callback(&line),
+                Err(err) => {
+                    streamed = Err(err);
+                    break;
+                },
+            }
+        }
+        // The runtime reaps it whatever happened, and reports its exit
+        let exit = self.command_results.lock().unwrap().wait_exited(pid)?;
+        streamed?;
+        Ok(exit)
+    }
+
+    /// Runs a command from the host in some of the namespaces of the container, e.g. a network tool in its
+    /// network namespace only, which still sees the processes and files of the host
+    /// # Arguments
+    /// * `namespaces` - The namespaces of the container to join, each at most once
+    /// * `command` - Filename or path to the executable
+    /// * `args` - Arguments to pass to the command
+    /// # Returns
+    /// The exit status and the stdout and stderr of the command
+    ///
+    /// Note: joining the mount namespace, the command gets the environment and working directory of the
+    /// container's init, and its PATH to find the command. Otherwise it gets the host's. Either way, it runs
+    /// with the user, group, capabilities and seccomp profile of the container's commands (not the rules
+    /// added with `add_seccomp_rules`). The user and time namespaces can't be joined, the command is forked
+    /// by a multithreaded process: containers with `RuntimeOptions::userns` are refused
+    pub fn exec_join(&self, namespaces: &[Namespace], command: String, args: Vec<String>) -> Result<process::Output> {
+        assert!(self.pid == Pid::this());
+        // The other namespaces belong to the user namespace, they can't be joined without joining it first
+        if self.runtime.options().userns {
+            return Err(eyre::eyre!("Can't join the namespaces of a container with a user namespace, it can't be joined by a multithreaded process"));
+        }
+        for (index, namespace) in namespaces.iter().enumerate() {
+            if namespaces[..index].contains(namespace) {
+                return Err(eyre::eyre!("The {} namespace is listed twice", namespace.name()));
+            }
+            if *namespace == Namespace::TIME {
+                return Err(eyre::eyre!("The time namespace can't be joined by a multithreaded process"));
+            }
+        }
+        log::debug!("Running {} {:?} in the {:?} namespaces of the container", command, args, namespaces);
+        let child = self.spawn_joined(namespaces, &command, &args, Stdio::piped(), Stdio::piped())?;
+        Ok(child.wait_with_output()?)
+    }
+
+    /// Forks a command in namespaces of the container, see `exec_join`
+    fn spawn_joined(&self, namespaces: &[Namespace], command: &str, args: &[String], stdout: Stdio, stderr: Stdio) -> Result<process::Child> {
+        let mut cmd = process::Command::new(command);
+        cmd.args(args)
+            .stdin(Stdio::null())
+            .stdout(stdout)
+            .stderr(stderr);
+        // The files of the container are only reachable in its mount namespace
+        if namespaces.contains(&Namespace::MNT) {
+            let environment = self.environment()?;
+            cmd.env_clear()
+                .envs(environment.iter().filter_map(|variable| variable.split_once('=')))
+                .current_dir(&self.runtime.options().cwd);
+        }
+        let privileges = self.privileges()?;
+        // Right before executing the command, in the child
+        unsafe {
+            cmd.pre_exec(move || privileges.drop_to().map_err(|err| io::Error::from_raw_os_error(err as i32)));
+        }
+        self.enter(namespaces, || cmd.spawn())?
+            .map_err(|err| eyre::eyre!("Could not execute {}: {}", command, err))
+    }
+
+    /// The privileges of the commands executed by the container's runtime, see `Privileges`
+    fn privileges(&self) -> Result<Privileges> {
+        let options = self.runtime.options();
+        // The names are resolved in the container's /etc/passwd and /etc/group
+        let (uid, gid) = self.enter(&[Namespace::MNT], || -> Result<(Uid, Gid)> {
+            Ok((options.uid()?, options.gid()?))
+        })??;
+        let capabilities = match options.kept_capabilities()? {
+            Some(keep) => Some((keep, syscall::last_capability()?)),
+            None => None,
+        };
+        let seccomp = match &options.seccomp {
+            Some(profile) => Some(SeccompFilter::compile(profile)?),
+            None => None,
+        };
+        Ok(Privileges { uid, gid, capabilities, seccomp })
+    }
+
+    /// Fails if a new process would exceed the pids.max of the container's cgroup
+    /// # Arguments
+    /// * `command` - The command that would be executed, for the error message
+    fn check_pids_limit(&self, command: &str) -> Result<()> {
+        let cgroup = self.cgroup.as_ref()
+            .ok_or_else(|| eyre::eyre!("check_pids_limit requires the container to have a cgroup"))?;
+        if let (current, Some(max)) = cgroup.pids()? {
+            if current >= max {
+                return Err(eyre::eyre!(
+                    "Not executing {}: the container has reached its limit of {} processes (pids.max of {})",
+                    command, max, cgroup.path().display()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Tightens the seccomp filter of a running container: the runtime installs a new filter with these
+    /// rules (the syscalls not matching any are allowed), applying to the commands executed afterwards
+    /// # Arguments
+    /// * `rules` - The rules, e.g. `ERRNO(EPERM)` for a syscall
+    ///
+    /// Note: seccomp filters stack and can't be removed, so only tightening is supported: a syscall is
+    /// allowed only if every filter allows it, an `ALLOW` rule can't undo a previous rule.
+    /// Note: the processes already running are not affected. The runtime must still be running, i.e. the
+    /// container was started with `start` and no command replaced it. Blocking the syscalls the runtime
+    /// uses (e.g. clone) prevents it from executing new commands.
+    pub fn add_seccomp_rules(&self, rules: Vec<SeccompRule>) -> Result<()> {
+        assert!(self.pid == Pid::this());
+        if self.container_pid.is_none() {
+            return Err(eyre::eyre!("Container not started"));
+        }
+        seccomp::check_rules(&rules)?;
+        log::debug!("Adding seccomp rules to the container {:?}", rules);
+        self.producer_channel.send(ipc::Message::ACTION(Action::SECCOMP(rules)))
+    }
+
+}
+
+impl Drop for Container {
+
+    /// Stop the container, unless it was detached, so it is not orphaned (e.g. if the thread waiting
+    /// for it panics)
+    fn drop(&mut self) {
+        if self.detached || self.pid != Pid::this() {
+            return;
+        }
+        if let Some(pid) = self.container_pid {
+            log::info!("Container dropped while running, stopping it");
+            let _ = kill(pid, Signal::SIGKILL);
+            if let Err(err) = self.wait_for_container() {
+                log::warn!("Could not stop container with PID {}: {}", pid, err);
+            }
+        }
+        if let Some(state_root) = &self.runtime.options().state_root {
+            if let Err(err) = State::remove(state_root, self.runtime.id()) {
+                log::warn!("Could not remove the state of the container: {}", err);
+            }
+        }
+    }
+
+}
+
+/// Times a new ID is generated when it collides with the ID of an existing container
+const ID_RETRIES: usize = 5;
+
+/// Generates an ID not used by the containers of the state directory
+/// # Arguments
+/// * `state_root` - The state directory (if any, otherwise the first ID is used)
+/// * `generate` - Generator of random IDs
+fn unique_id(state_root: Option<&Path>, mut generate: impl FnMut() -> String) -> Result<String> {
+    let state_root = match state_root {
+        Some(state_root) => state_root,
+        None => return Ok(generate()),
+    };
+    for _ in 0..=ID_RETRIES {
+        let id = generate();
+        if !State::exists(state_root, &id) {
+            return Ok(id);
+        }
+        log::warn!("Container ID {} is already used in {}, generating a new one", id, state_root.display());
+    }
+    Err(eyre::eyre!("Could not generate an unused container ID in {}", state_root.display()))
+}
+
+/// Runs a hook on the host and waits for it to exit
+/// # Arguments
+/// * `hook` - The hook
+/// * `state` - The state of the container, as JSON, written to the hook's stdin
+fn run_hook(hook: &Hook, state: &[u8]) -> Result<()> {
+    let env = hook.env.iter().filter_map(|var| var.split_once('='));
+    let mut child = process::Command::new(&hook.path)
+        .args(&hook.args)
+        .env_clear()
+        .envs(env)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // The hook may exit without reading it
+        let _ = stdin.write_all(state);
+    }
+    let status = child.wait()?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(eyre::eyre!("{}", status)),
+    }
+}
+
+/// Sets up the stdin, stdout and stderr of the container process
+/// # Arguments
+/// * `pty_fds` - Master and slave sides of the container's pseudo-terminal
+/// * `output_fds` - Read and write ends of the pipe forwarding the output to the log driver
+fn setup_stdio(pty_fds: Option<(RawFd, RawFd)>, output_fds: Option<(RawFd, RawFd)>) -> Result<()> {
+    match pty_fds {
+        Some((master, slave)) => {
+            close(master)?;
+            syscall::attach_to_pty(slave)?;
+        },
+        // The container gets its own session either way, apart from the host's process groups
+        None => {
+            setsid()?;
+        },
+    }
+    // The log driver takes over the output, even when there is a pseudo-terminal
+    if let Some((read_fd, write_fd)) = output_fds {
+        close(read_fd)?;
+        logs::redirect_output(write_fd)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use crate::filesystem::{BindMount, DeviceNode};
+    use crate::cgroup::MemoryPressureEvent;
+    use crate::logs::LogDriverType;
+    use crate::runtime::ListMerge;
+    use crate::syscall::{IoPriority, TimeOffsets};
+    use crate::seccomp::{SeccompAction, SeccompArg, SeccompCompare, SeccompProfile};
+    use crate::state::StateEvent;
+    use nix::sys::signal::{SigmaskHow, SigSet};
+    use nix::sys::utsname::uname;
+
+    #[test]
+    fn test_spawn_runs_command() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_spawn");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.log_driver = Some(LogDriverType::RINGBUFFER(10));
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn("echo".to_string(), vec!["hello from spawn".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        container.wait_for_container().unwrap();
+        assert_eq!(container.logs(), vec!["hello from spawn"]);
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn test_pidfd_readable_after_exit() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_pidfd");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut container = Container::new(Box::new(fs)).unwrap();
+        container.spawn("sleep".to_string(), vec!["0.2".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        let pidfd = container.pidfd().unwrap();
+        let mut fds = [PollFd::new(pidfd, PollFlags::POLLIN)];
+        assert_eq!(poll(&mut fds, 0).unwrap(), 0);
+        assert_eq!(poll(&mut fds, 5000).unwrap(), 1);
+        assert!(fds[0].revents().unwrap().contains(PollFlags::POLLIN));
+        container.wait_for_container().unwrap();
+        assert!(container.pidfd().is_none());
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_reports_cpu_usage() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_snapshot");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut container = Container::new(Box::new(fs)).unwrap();
+        let busy_loop = "while :; do :; done".to_string();
+        container.spawn("sh".to_string(), vec!["-c".to_string(), busy_loop], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        let snapshot = container.snapshot().unwrap();
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        assert!(snapshot.cpu_percent > 0.0);
+        assert_eq!(snapshot.processes.len(), 1);
+        assert!(snapshot.processes[0].cpu_percent > 0.0);
+        assert!(snapshot.memory > 0);
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn test_cgroup_resource_limits() {
+        if !crate::cgroup::is_cgroup_v2() {
+            return;
+        }
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_cgroup_limits");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.memory_limit = Some(128 * 1024 * 1024);
+        options.cpu_quota = Some((20000, 100000));
+        options.pids_limit = Some(16);
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn("sleep".to_string(), vec!["10".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        let cgroup = container.cgroup_path().unwrap().to_path_buf();
+        let read = |file: &str| fs::read_to_string(cgroup.join(file)).unwrap().trim().to_string();
+        assert_eq!(read("memory.max"), "134217728");
+        assert_eq!(read("cpu.max"), "20000 100000");
+        assert_eq!(read("pids.max"), "16");
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        assert!(!cgroup.exists());
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_cgroup_path() {
+        if !crate::cgroup::is_cgroup_v2() {
+            return;
+        }
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_cgroup_path");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.cgroup = true;
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        assert!(container.cgroup_path().is_none());
+        container.spawn("sleep".to_string(), vec!["10".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        let cgroup = container.cgroup_path().unwrap().to_path_buf();
+        assert!(cgroup.starts_with("/sys/fs/cgroup"));
+        let pid = container.container_pid.unwrap().to_string();
+        let procs = fs::read_to_string(cgroup.join("cgroup.procs")).unwrap();
+        assert!(procs.lines().any(|line| line == pid), "{} not in {}", pid, procs);
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_memory_pressure_event() {
+        if !crate::cgroup::is_cgroup_v2() {
+            return;
+        }
+        let base = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_memory_pressure");
+        let state_root = base.join("state");
+        let mut events = crate::state::watch(&state_root).unwrap();
+        let fs = OverlayDriver::new(vec![&"/"], &base.join("overlay"));
+        let mut options = RuntimeOptions::default();
+        options.memory_high = Some(16 * 1024 * 1024);
+        options.state_root = Some(state_root);
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        let id = container.id().to_string();
+        // tail keeps the whole input in memory when it has no newline
+        container.spawn("sh".to_string(), vec!["-c".to_string(), "head -c 67108864 /dev/zero | tail; sleep 10".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        // The breaches before the container is seen by the watcher are reported as well
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut breached = false;
+        while !breached && Instant::now() < deadline {
+            breached = match events.next_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Some(StateEvent::MEMORY_PRESSURE(event_id, MemoryPressureEvent::HIGH(breaches))) => event_id == id && breaches > 0,
+                Some(_) => false,
+                None => break,
+            };
+        }
+        assert!(breached, "No memory pressure event");
+        container.force_stop().unwrap();
+        let _ = container.wait_for_container();
+        let _ = fs::remove_dir_all(base);
+    }
+
+    #[test]
+    fn test_delegated_cgroup_is_writable() {
+        if !crate::cgroup::is_cgroup_v2() {
+            return;
+        }
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_delegate_cgroup");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.delegate_cgroup = true;
+        options.log_driver = Some(LogDriverType::RINGBUFFER(10));
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        let script = "mkdir /sys/fs/cgroup/child && echo $$ > /sys/fs/cgroup/child/cgroup.procs && cat /proc/self/cgroup";
+        container.spawn("sh".to_string(), vec!["-c".to_string(), script.to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        container.wait_for_container().unwrap();
+        assert!(container.logs().contains(&"0::/child".to_string()));
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn test_log_prefix() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_log_prefix");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.log_driver = Some(LogDriverType::RINGBUFFER(10));
+        options.log_prefix = Some("[{id}] ".to_string());
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        let prefix = format!("[{}] ", container.id());
+        container.spawn("sh".to_string(), vec!["-c".to_string(), "echo one; echo two >&2; echo three".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        container.wait_for_container().unwrap();
+        let mut logs = container.logs();
+        logs.sort();
+        assert_eq!(logs, vec![format!("{}one", prefix), format!("{}three", prefix), format!("{}two", prefix)]);
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_set_limit_on_running_container() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_set_limit");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut container = Container::new(Box::new(fs)).unwrap();
+        container.spawn("sleep".to_string(), vec!["10".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        let limits = format!("/proc/{}/limits", container.container_pid.unwrap());
+        let open_files = || fs::read_to_string(&limits).unwrap().lines()
+            .find(|line| line.starts_with("Max open files"))
+            .map(|line| line.split_whitespace().skip(3).take(2).collect::<Vec<&str>>().join(" "))
+            .unwrap();
+        // Raising the hard limit would require CAP_SYS_RESOURCE
+        container.set_limit(Resource::RLIMIT_NOFILE, Some(64), Some(4096)).unwrap();
+        assert_eq!(open_files(), "64 4096");
+        container.set_limit(Resource::RLIMIT_NOFILE, Some(1024), Some(4096)).unwrap();
+        assert_eq!(open_files(), "1024 4096");
+        assert!(container.set_limit(Resource::RLIMIT_NOFILE, Some(2048), Some(1024)).is_err());
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn test_proc_sys_is_readonly() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_proc_sys");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.log_driver = Some(LogDriverType::RINGBUFFER(10));
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        let script = "(echo test > /proc/sys/kernel/hostname) 2>&- && echo writable || echo readonly; grep Name /proc/self/status";
+        container.spawn("sh".to_string(), vec!["-c".to_string(), script.to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        container.wait_for_container().unwrap();
+        assert_eq!(container.logs(), vec!["readonly", "Name:\tgrep"]);
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn test_host_environment_not_inherited() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_environment");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.env = vec!["CUSTOM=value".to_string(), "PATH=/bin:/usr/bin".to_string()];
+        options.log_driver = Some(LogDriverType::RINGBUFFER(100));
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.start().unwrap();
+        // The environment of the commands, looked up with the PATH of the options
+        let script = "env; echo done";
+        container.execute_in_container("sh".to_string(), vec!["-c".to_string(), script.to_string()], None, Some(ExecType::FORK)).unwrap();
+        let start = Instant::now();
+        while !container.logs().contains(&"done".to_string()) && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(20));
+        }
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        let logs = container.logs();
+        assert!(logs.contains(&"CUSTOM=value".to_string()));
+        // The options take precedence over the defaults
+        assert_eq!(logs.iter().filter(|line| line.starts_with("PATH=")).collect::<Vec<_>>(), ["PATH=/bin:/usr/bin"]);
+        // The test binary was started by cargo, which sets CARGO_MANIFEST_DIR
+        assert!(!logs.iter().any(|line| line.contains("CARGO_MANIFEST_DIR")));
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn test_merged_environment() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_merged_environment");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut base = RuntimeOptions::default();
+        base.env = vec!["GREETING=base".to_string(), "PATH=/bin:/usr/bin".to_string()];
+        base.log_driver = Some(LogDriverType::RINGBUFFER(10));
+        let mut overrides = RuntimeOptions::default();
+        overrides.env = vec!["GREETING=override".to_string()];
+        let options = RuntimeOptions::merge(base, overrides, ListMerge::APPEND);
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn("sh".to_string(), vec!["-c".to_string(), "echo $GREETING".to_string()], None).unwrap();
+        container.wait_for_container().unwrap();
+        assert_eq!(container.logs(), vec!["override"]);
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_drop_stops_container() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_drop");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut container = Container::new(Box::new(fs)).unwrap();
+        container.spawn("sleep".to_string(), vec!["30".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        let pid = container.container_pid.unwrap();
+        assert!(kill(pid, None).is_ok());
+        drop(container);
+        // The container was killed and reaped
+        assert_eq!(kill(pid, None), Err(nix::errno::Errno::ESRCH));
+        // The container may be stopped before it creates its overlay
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_disk_usage_counts_written_files() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_disk_usage");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut container = Container::new(Box::new(fs)).unwrap();
+        container.spawn("sh".to_string(), vec!["-c".to_string(), "yes | head -c 1048576 > /big".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        container.wait_for_container().unwrap();
+        // Besides the file, the runtime writes a few small files (e.g. /etc/hostname)
+        let usage = container.disk_usage().unwrap();
+        assert!((1048576..1048576 + 64 * 1024).contains(&usage), "disk usage is {}", usage);
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn test_run_is_writable_tmpfs() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_run");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.run_size = Some("1m".to_string());
+        options.log_driver = Some(LogDriverType::RINGBUFFER(10));
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        let script = "echo 1 > /run/test.pid && cat /run/test.pid && stat -f -c %T /run && stat -c %a /run";
+        container.spawn("sh".to_string(), vec!["-c".to_string(), script.to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        container.wait_for_container().unwrap();
+        assert_eq!(container.logs(), vec!["1", "tmpfs", "755"]);
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn test_shm_mount() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_shm");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.shm_mode = 0o1770;
+        options.log_driver = Some(LogDriverType::RINGBUFFER(10));
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        let script = "stat -c %a /dev/shm && grep ' /dev/shm ' /proc/self/mounts | cut -d ' ' -f 4";
+        container.spawn("sh".to_string(), vec!["-c".to_string(), script.to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        container.wait_for_container().unwrap();
+        let logs = container.logs();
+        assert_eq!(logs[0], "1770");
+        let flags: Vec<&str> = logs[1].split(',').collect();
+        for flag in ["nosuid", "nodev", "noexec"] {
+            assert!(flags.contains(&flag), "{:?}", logs);
+        }
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn test_start_without_etc() {
+        let base = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_no_etc");
+        let rootfs = base.join("rootfs");
+        for dir in ["proc", "sys", "dev"] {
+            fs::create_dir_all(rootfs.join(dir)).unwrap();
+        }
+        let fs = OverlayDriver::new(vec![&rootfs], &base.join("overlay"));
+        let mut options = RuntimeOptions::default();
+        options.hostname = Some("no-etc".to_string());
+        options.log_driver = Some(LogDriverType::RINGBUFFER(100));
+        // The programs of the host, without its /etc
+        options.mounts = ["/bin", "/sbin", "/lib", "/lib64", "/usr"].iter()
+            .filter(|dir| Path::new(dir).exists())
+            .map(|dir| BindMount { source: PathBuf::from(dir), destination: PathBuf::from(dir), readonly: true })
+            .collect();
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn("sh".to_string(), vec!["-c".to_string(), "hostname; cat /etc/hostname".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        container.wait_for_container().unwrap();
+        assert_eq!(container.logs(), vec!["no-etc", "no-etc"]);
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_bind_mount_file() {
+        let base = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_bind_file");
+        fs::create_dir_all(&base).unwrap();
+        fs::write(base.join("app.conf"), "key=value\n").unwrap();
+        let fs = OverlayDriver::new(vec![&"/"], &base.join("overlay"));
+        let mut options = RuntimeOptions::default();
+        options.log_driver = Some(LogDriverType::RINGBUFFER(10));
+        options.mounts = vec![BindMount {
+            source: base.join("app.conf"),
+            destination: PathBuf::from("/etc/libcontainer-rs/app.conf"),
+            readonly: true,
+        }];
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn("cat".to_string(), vec!["/etc/libcontainer-rs/app.conf".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        container.wait_for_container().unwrap();
+        assert_eq!(container.logs(), vec!["key=value"]);
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_inspect() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_inspect");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut container = Container::new(Box::new(fs)).unwrap();
+        container.spawn("sleep".to_string(), vec!["10".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        let pid = container.container_pid.unwrap();
+        let state = container.inspect().unwrap();
+        assert_eq!(state["id"], container.id());
+        assert_eq!(state["pid"], pid.as_raw());
+        assert_eq!(state["status"], "running");
+        assert!(state["namespaces"]["mnt"].is_u64());
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        assert_eq!(container.inspect().unwrap()["status"], "stopped");
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_mount_failure_exit_code() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_mount_failure");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.mounts = vec![BindMount {
+            source: PathBuf::from("/does-not-exist"),
+            destination: PathBuf::from("/data"),
+            readonly: false,
+        }];
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.start().unwrap();
+        let err = container.wait_for_container().unwrap_err();
+        assert_eq!(err.downcast_ref::<ExitCode>(), Some(&ExitCode::MOUNT));
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_workload_exit_code() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_workload_exit_code");
+        let env = Some(vec!["PATH=/bin:/usr/bin".to_string()]);
+        let mut container = Container::new(Box::new(OverlayDriver::new(vec![&"/"], &target))).unwrap();
+        container.spawn("sh".to_string(), vec!["-c".to_string(), "exit 7".to_string()], env.clone()).unwrap();
+        assert_eq!(container.wait_for_container().unwrap(), ContainerExit::EXITED(7));
+        let mut container = Container::new(Box::new(OverlayDriver::new(vec![&"/"], &target))).unwrap();
+        container.spawn("sleep".to_string(), vec!["10".to_string()], env).unwrap();
+        container.force_stop().unwrap();
+        assert_eq!(container.wait_for_container().unwrap(), ContainerExit::SIGNALED(Signal::SIGKILL));
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_console_is_the_pty() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_console");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.tty = true;
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        let script = "echo console-output > /dev/console; sleep 10";
+        container.spawn("sh".to_string(), vec!["-c".to_string(), script.to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        let master = container.pty().unwrap();
+        let mut output = String::new();
+        let mut buffer = [0u8; 256];
+        while !output.contains("console-output") {
+            let mut fds = [PollFd::new(master, PollFlags::POLLIN)];
+            assert_eq!(poll(&mut fds, 5000).unwrap(), 1, "Nothing written to the console");
+            let read = nix::unistd::read(master, &mut buffer).unwrap();
+            output.push_str(&String::from_utf8_lossy(&buffer[..read]));
+        }
+        container.force_stop().unwrap();
+        let _ = container.wait_for_container();
+        // Closed with the container
+        assert_eq!(container.pty(), None);
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_pty_gets_host_window_size() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_host_window_size");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let host = syscall::open_pty().unwrap();
+        syscall::set_window_size(host.slave, 37, 101).unwrap();
+        let mut options = RuntimeOptions::default();
+        options.tty = true;
+        options.host_terminal = Some(host.slave);
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn("sh".to_string(), vec!["-c".to_string(), "stty size; sleep 10".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        let master = container.pty().unwrap();
+        let mut output = String::new();
+        let mut buffer = [0u8; 256];
+        while !output.contains('\n') {
+            let mut fds = [PollFd::new(master, PollFlags::POLLIN)];
+            assert_eq!(poll(&mut fds, 5000).unwrap(), 1, "Nothing written to the terminal");
+            let read = nix::unistd::read(master, &mut buffer).unwrap();
+            output.push_str(&String::from_utf8_lossy(&buffer[..read]));
+        }
+        assert_eq!(output.trim(), "37 101");
+        container.force_stop().unwrap();
+        let _ = container.wait_for_container();
+        close(host.master).unwrap();
+        close(host.slave).unwrap();
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_resize_pty() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_resize_pty");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.tty = true;
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        // stty reads the size of its terminal with TIOCGWINSZ, once resized
+        let script = "stty -echo; echo ready; read line; stty size; sleep 10";
+        container.spawn("sh".to_string(), vec!["-c".to_string(), script.to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        let master = container.pty().unwrap();
+        let mut output = String::new();
+        let mut read_line = || {
+            let mut buffer = [0u8; 256];
+            while !output.contains('\n') {
+                let mut fds = [PollFd::new(master, PollFlags::POLLIN)];
+                assert_eq!(poll(&mut fds, 5000).unwrap(), 1, "Nothing written to the terminal");
+                let read = nix::unistd::read(master, &mut buffer).unwrap();
+                output.push_str(&String::from_utf8_lossy(&buffer[..read]));
+            }
+            let (line, rest) = output.split_once('\n').unwrap();
+            let line = line.trim().to_string();
+            output = rest.to_string();
+            line
+        };
+        assert_eq!(read_line(), "ready");
+        container.resize_pty(42, 132).unwrap();
+        nix::unistd::write(master, b"\n").unwrap();
+        assert_eq!(read_line(), "42 132");
+        container.force_stop().unwrap();
+        let _ = container.wait_for_container();
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_managed_scratch_dir() {
+        let root = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_scratch_root");
+        let fs = OverlayDriver::in_scratch_dir(vec![&"/"]);
+        let mut options = RuntimeOptions::default();
+        options.managed_scratch = true;
+        options.scratch_root = root.clone();
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        let scratch = container.scratch_dir().unwrap();
+        assert_eq!(scratch, root.join(container.id()));
+        container.start().unwrap();
+        container.wait_for_ready(ReadyCheck::FILE(PathBuf::from("/bin/sh")), Duration::from_secs(5)).unwrap();
+        assert!(scratch.join("upper").exists());
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        assert!(!scratch.exists());
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_capabilities");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut container = Container::new(Box::new(fs)).unwrap();
+        let args = ["--bounding-set", "-sys_admin", "sleep", "10"].iter().map(|arg| arg.to_string()).collect();
+        container.spawn("setpriv".to_string(), args, Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        // setpriv replaces itself with sleep once the capability is dropped
+        procfs::wait_until(Duration::from_secs(5), "sleep not executed", || {
+            Ok(procfs::process_info(container.container_pid.unwrap())?.command == "sleep")
+        }).unwrap();
+        let caps = container.capabilities().unwrap();
+        let bounding = CapSet::names(caps.bounding);
+        assert!(!bounding.contains(&"CAP_SYS_ADMIN".to_string()), "{:?}", bounding);
+        assert!(bounding.contains(&"CAP_CHOWN".to_string()), "{:?}", bounding);
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_drop_capabilities() {
+        assert_eq!(CapSet::from_names(&["CAP_CHOWN".to_string(), "cap_net_bind_service".to_string()]).unwrap(), 1 | 1 << 10);
+        assert!(CapSet::from_names(&["CAP_NOT_A_CAPABILITY".to_string()]).is_err());
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_drop_capabilities");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.capabilities = Some(vec!["CAP_NET_BIND_SERVICE".to_string()]);
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn("sleep".to_string(), vec!["10".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        procfs::wait_until(Duration::from_secs(5), "sleep not executed", || {
+            Ok(procfs::process_info(container.container_pid.unwrap())?.command == "sleep")
+        }).unwrap();
+        let caps = container.capabilities().unwrap();
+        for set in [caps.bounding, caps.permitted, caps.effective] {
+            assert_eq!(CapSet::names(set), vec!["CAP_NET_BIND_SERVICE".to_string()]);
+        }
+        assert_eq!(caps.inheritable & !(1 << 10), 0);
+        // Commands forked by the host get the same capabilities
+        let output = container.exec_join(&[Namespace::NET], "grep".to_string(), vec!["CapEff".to_string(), "/proc/self/status".to_string()]).unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap().split_whitespace().last(), Some("0000000000000400"));
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_group() {
+        // users is GID 100 in the host's /etc/group, 4242 has no entry
+        for (group, gid) in [("users", "100"), ("4242", "4242")] {
+            let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_group");
+            let fs = OverlayDriver::new(vec![&"/"], &target);
+            let mut options = RuntimeOptions::default();
+            options.group = group.to_string();
+            options.log_driver = Some(LogDriverType::RINGBUFFER(10));
+            let mut container = Container::with_options(Box::new(fs), options).unwrap();
+            container.spawn("id".to_string(), vec!["-g".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+            container.wait_for_container().unwrap();
+            assert_eq!(container.logs(), vec![gid.to_string()]);
+            let _ = fs::remove_dir_all(target);
+        }
+    }
+
+    #[test]
+    fn test_user_namespace() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_user_namespace");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.userns = true;
+        options.log_driver = Some(LogDriverType::RINGBUFFER(10));
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn("cat".to_string(), vec!["/proc/self/uid_map".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        container.wait_for_container().unwrap();
+        // The container's root is the user running the test
+        let logs = container.logs();
+        assert_eq!(logs[0].split_whitespace().collect::<Vec<&str>>(), ["0", geteuid().to_string().as_str(), "1"]);
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_pin_scheduling() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_pin_scheduling");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.nice = Some(5);
+        options.pin_scheduling = true;
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        // CAP_SYS_NICE is dropped, even root can only go back to 5 but not below
+        let script = "renice 8 -p $$ && renice 5 -p $$ && touch /tmp/lowered; \
+            renice 3 -p $$ || touch /tmp/denied; touch /tmp/done; sleep 10";
+        let args = ["-c", script].iter().map(|arg| arg.to_string()).collect();
+        container.spawn("sh".to_string(), args, Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        container.wait_for_ready(ReadyCheck::FILE(PathBuf::from("/tmp/done")), Duration::from_secs(5)).unwrap();
+        for file in ["/tmp/lowered", "/tmp/denied"] {
+            container.wait_for_ready(ReadyCheck::FILE(PathBuf::from(file)), Duration::from_millis(100)).unwrap();
+        }
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_stop_reports_unmount_failure() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_teardown_report");
+        let fs = OverlayDriver::new(vec![&"/"], &target)
+            .with_umount_retries(0, Duration::ZERO, false);
+        let mut options = RuntimeOptions::default();
+        options.host_visible_rootfs = true;
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn("sleep".to_string(), vec!["10".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        // Keeps the root filesystem busy in the host
+        let held = fs::File::open(target.join("merge/bin")).unwrap();
+        let report = container.stop(Duration::from_secs(5)).unwrap();
+        assert_eq!(report.failed_steps(), vec![TeardownStep::UNMOUNT_ROOTFS], "{:?}", report);
+        assert_eq!(report.steps.len(), 8);
+        assert!(container.stop(Duration::from_secs(5)).is_err());
+        drop(held);
+        container.runtime.unshare_mount_point().unwrap();
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_poststop_hooks() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_poststop_hooks");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let output = env::temp_dir().join("libcontainer-rs-poststop-state.json");
+        let mut options = RuntimeOptions::default();
+        options.poststop_hooks = vec![
+            Hook {
+                path: PathBuf::from("/bin/sh"),
+                args: vec!["-c".to_string(), format!("cat > {}", output.display())],
+                env: vec![],
+            },
+            Hook { path: PathBuf::from("/bin/false"), args: vec![], env: vec![] },
+        ];
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn("sleep".to_string(), vec!["10".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        let report = container.stop(Duration::from_secs(5)).unwrap();
+        assert_eq!(report.failed_steps(), vec![TeardownStep::POSTSTOP_HOOKS], "{:?}", report);
+        let state: State = serde_json::from_slice(&fs::read(&output).unwrap()).unwrap();
+        assert_eq!((state.id.as_str(), state.pid, state.status), (container.runtime.id(), None, Status::STOPPED));
+        let _ = fs::remove_file(output);
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_environment() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_environment");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.env = vec!["GREETING=hello".to_string()];
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        assert!(container.environment().is_err());
+        container.spawn("sleep".to_string(), vec!["10".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        // The init executes the command shortly after starting
+        let start = Instant::now();
+        let mut environment = container.environment().unwrap();
+        while !environment.contains(&"GREETING=hello".to_string()) {
+            assert!(start.elapsed() < Duration::from_secs(5), "Unexpected environment {:?}", environment);
+            thread::sleep(Duration::from_millis(10));
+            environment = container.environment().unwrap();
+        }
+        assert!(environment.contains(&"PATH=/bin:/usr/bin".to_string()));
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        assert!(container.environment().is_err());
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_container_marker() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_container_marker");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.container_marker = "oci".to_string();
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn("sleep".to_string(), vec!["10".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        procfs::wait_until(Duration::from_secs(5), "container=oci not set", || {
+            Ok(container.environment()?.contains(&"container=oci".to_string()))
+        }).unwrap();
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_time_namespace_offsets() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_time_namespace");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.log_driver = Some(LogDriverType::RINGBUFFER(10));
+        let offset = 10 * 24 * 3600;
+        options.namespaces.time = Some(TimeOffsets { monotonic: 0, boottime: offset });
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        // /proc/uptime is the boot-time clock of the reader's time namespace
+        container.spawn("cat".to_string(), vec!["/proc/uptime".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        let host = nix::time::clock_gettime(nix::time::ClockId::CLOCK_BOOTTIME).unwrap().tv_sec();
+        container.wait_for_container().unwrap();
+        let uptime: f64 = container.logs()[0].split_whitespace().next().unwrap().parse().unwrap();
+        let difference = uptime as i64 - host;
+        assert!((difference - offset).abs() < 5, "Unexpected boot-time difference {}", difference);
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_reject_nested_container() {
+        // `start` is rejected by the flag it checks first, without allocating (unsafe in the child of a
+        // multithreaded process, see `syscall::create_container`)
+        let callback = || if syscall::in_container() { 0 } else { 1 };
+        let pid = syscall::create_container(callback, &syscall::Namespaces::default(), false, ExitCode::PANIC as isize).unwrap();
+        assert_eq!(waitpid(pid, None).unwrap(), WaitStatus::Exited(pid, 0));
+        assert!(!syscall::in_container());
+    }
+
+    #[test]
+    fn test_stop_signal() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_stop_signal");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut container = Container::new(Box::new(fs)).unwrap();
+        container.start().unwrap();
+        let pid = container.container_pid.unwrap();
+        // Blocked once the init reads it from a signalfd, see `syscall::handle_signals`
+        procfs::wait_until(Duration::from_secs(5), "the stop signal to be blocked", || {
+            let status = fs::read_to_string(format!("/proc/{}/status", pid))?;
+            let blocked = status.lines()
+                .find_map(|line| line.strip_prefix("SigBlk:"))
+                .map(|mask| u64::from_str_radix(mask.trim(), 16))
+                .transpose()?;
+            Ok(matches!(blocked, Some(mask) if mask & (1 << (Signal::SIGTERM as i32 - 1)) != 0))
+        }).unwrap();
+        kill(pid, Signal::SIGTERM).unwrap();
+        // The event loop returns and the init exits normally
+        assert_eq!(waitpid(pid, None).unwrap(), WaitStatus::Exited(pid, 0));
+        container.container_pid = None;
+        if let Some(pidfd) = container.pidfd.take() {
+            close(pidfd).unwrap();
+        }
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_minimal_devices() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_minimal_devices");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.log_driver = Some(LogDriverType::RINGBUFFER(10));
+        options.devices = vec![DeviceNode::char("/dev/null", 1, 3, 0o666), DeviceNode::char("/dev/zero", 1, 5, 0o666)];
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        let script = "echo discarded > /dev/null && head -c 4 /dev/zero | wc -c && ls /dev | grep -E '^(null|zero|full|random|urandom|tty|console)$'";
+        container.spawn("sh".to_string(), vec!["-c".to_string(), script.to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        container.wait_for_container().unwrap();
+        assert_eq!(container.logs(), vec!["4", "null", "zero"]);
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_was_oom_killed() {
+        if !crate::cgroup::is_cgroup_v2() {
+            return;
+        }
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_oom_killed");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.cgroup = true;
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.start().unwrap();
+        let cgroup = container.cgroup_path().unwrap().to_path_buf();
+        // Only when the memory controller is enabled for the container's cgroup
+        if !cgroup.join("memory.max").exists() {
+            container.force_stop().unwrap();
+            let _ = container.wait_for_container();
+            let _ = fs::remove_dir_all(target);
+            return;
+        }
+        fs::write(cgroup.join("memory.max"), "16M").unwrap();
+        let _ = fs::write(cgroup.join("memory.swap.max"), "0");
+        assert!(!container.was_oom_killed().unwrap());
+        // tail keeps the whole "line" in memory, /dev/zero has no newline
+        container.execute_in_container("tail".to_string(), vec!["/dev/zero".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()]), Some(ExecType::REPLACE)).unwrap();
+        container.wait_for_container().unwrap();
+        assert!(container.was_oom_killed().unwrap());
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_host_visible_rootfs() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_host_visible");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.host_visible_rootfs = true;
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn("sleep".to_string(), vec!["10".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        let merge = target.join("merge").display().to_string();
+        let host_mounts = |fstype: &str| fs::read_to_string("/proc/self/mounts").unwrap()
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>())
+            .any(|fields| fields.get(1) == Some(&merge.as_str()) && (fstype.is_empty() || fields.get(2) == Some(&fstype)));
+        // Mounted by the container, only the root filesystem propagates to the host (not /proc, /dev, ...)
+        assert!(host_mounts("overlay"));
+        let nested = format!("{}/", merge);
+        assert!(!fs::read_to_string("/proc/self/mounts").unwrap()
+            .lines()
+            .any(|line| line.split_whitespace().nth(1).map(|path| path.starts_with(&nested)).unwrap_or(false)));
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        assert!(!host_mounts(""));
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_in_memory_overlay() {
+        let base = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_in_memory");
+        let rootfs = base.join("rootfs");
+        for dir in ["proc", "sys", "dev"] {
+            fs::create_dir_all(rootfs.join(dir)).unwrap();
+        }
+        fs::write(rootfs.join("greeting"), "hello\n").unwrap();
+        let target = base.join("overlay");
+        let fs = OverlayDriver::in_memory(vec![&rootfs], &target, Some("64m"));
+        let mut options = RuntimeOptions::default();
+        options.log_driver = Some(LogDriverType::RINGBUFFER(10));
+        options.mounts = ["/bin", "/sbin", "/lib", "/lib64", "/usr"].iter()
+            .filter(|dir| Path::new(dir).exists())
+            .map(|dir| BindMount { source: PathBuf::from(dir), destination: PathBuf::from(dir), readonly: true })
+            .collect();
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        let script = "cat /greeting; echo data > /data; cat /data";
+        container.spawn("sh".to_string(), vec!["-c".to_string(), script.to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        container.wait_for_container().unwrap();
+        assert_eq!(container.logs(), vec!["hello", "data"]);
+        // Neither the target nor the layer have a trace of the container
+        assert_eq!(fs::read_dir(&target).unwrap().count(), 0);
+        assert!(!rootfs.join("data").exists());
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_login_shell() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_login_shell");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.log_driver = Some(LogDriverType::RINGBUFFER(100));
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn_command(Command {
+            command: "/bin/sh".to_string(),
+            args: vec!["-c".to_string(), "echo \"$0\"".to_string()],
+            env: vec!["PATH=/bin:/usr/bin".to_string()],
+            exec_type: ExecType::REPLACE,
+            argv0: None,
+            login: true,
+            path_search: true,
+            process_group: None,
+            stream_stdout: false,
+        }).unwrap();
+        container.wait_for_container().unwrap();
+        // The login profiles may print something before
+        assert_eq!(container.logs().last().map(String::as_str), Some("-sh"));
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_state_create_event() {
+        let base = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_state_events");
+        let state_root = base.join("state");
+        let mut events = crate::state::watch(&state_root).unwrap();
+        let fs = OverlayDriver::new(vec![&"/"], &base.join("overlay"));
+        let mut options = RuntimeOptions::default();
+        options.state_root = Some(state_root.clone());
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        let id = container.id().to_string();
+        container.spawn("true".to_string(), vec![], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(events.next_timeout(timeout), Some(StateEvent::CREATED(id.clone())));
+        container.wait_for_container().unwrap();
+        assert_eq!(events.next_timeout(timeout), Some(StateEvent::UPDATED(id.clone())));
+        assert_eq!(State::load(&state_root, &id).unwrap().status, Status::STOPPED);
+        drop(container);
+        assert_eq!(events.next_timeout(timeout), Some(StateEvent::REMOVED(id)));
+        let _ = fs::remove_dir_all(base);
+    }
+
+    #[test]
+    fn test_enter_uts_namespace() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_enter");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.hostname = Some("entered".to_string());
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn("sleep".to_string(), vec!["10".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        // The hostname is set once the container runs
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut hostname = String::new();
+        while hostname != "entered" && Instant::now() < deadline {
+            hostname = container.enter(&[Namespace::UTS], || uname().nodename().to_string()).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(hostname, "entered");
+        // The namespace of the test is left untouched
+        assert_ne!(uname().nodename(), "entered");
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_accounting_without_limits() {
+        let controllers = Path::new(crate::cgroup::CGROUP_ROOT).join("cgroup.controllers");
+        // The memory controller may be bound to a cgroup v1 hierarchy
+        if !matches!(fs::read_to_string(controllers), Ok(controllers) if controllers.split_whitespace().any(|c| c == "memory")) {
+            return;
+        }
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_accounting");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.enable_accounting = true;
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn("sleep".to_string(), vec!["10".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        let cgroup = container.cgroup_path().unwrap().to_path_buf();
+        let memory: u64 = fs::read_to_string(cgroup.join("memory.current")).unwrap().trim().parse().unwrap();
+        assert!(memory > 0);
+        assert!(container.stats().unwrap().memory.is_some());
+        assert_eq!(fs::read_to_string(cgroup.join("memory.max")).unwrap().trim(), "max");
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_unique_id_skips_existing_state() {
+        let state_root = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_unique_id");
+        State::running("taken", 1).save(&state_root).unwrap();
+        let mut ids = vec!["free", "taken"];
+        let id = unique_id(Some(&state_root), || ids.pop().unwrap().to_string()).unwrap();
+        assert_eq!(id, "free");
+        assert!(unique_id(Some(&state_root), || "taken".to_string()).is_err());
+        fs::remove_dir_all(state_root).unwrap();
+    }
+
+    #[test]
+    fn test_idle_io_priority() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_io_priority");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.io_priority = Some(IoPriority::IDLE);
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn("sleep".to_string(), vec!["10".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        let pid = container.container_pid.unwrap();
+        // Set by the container once it runs
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while syscall::io_priority(pid).unwrap() != Some(IoPriority::IDLE) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(syscall::io_priority(pid).unwrap(), Some(IoPriority::IDLE));
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_reraise_signal() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_reraise_signal");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        // The init of a PID namespace can't be killed by SIGINT, only by SIGKILL
+        options.namespaces.pid = false;
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn("sleep".to_string(), vec!["10".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        // Without signal forwarding, SIGINT terminates the runtime as well as sleep once it replaced it
+        kill(container.container_pid.unwrap(), Signal::SIGINT).unwrap();
+        // Blocked in this thread only, no handler is installed: the raised SIGINT stays pending
+        let mut sigint = SigSet::empty();
+        sigint.add(Signal::SIGINT);
+        let previous = sigint.thread_swap_mask(SigmaskHow::SIG_BLOCK).unwrap();
+        let res = container.wait_and_reraise_signal();
+        let timeout = nix::libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        let received = unsafe { nix::libc::sigtimedwait(sigint.as_ref(), std::ptr::null_mut(), &timeout) };
+        previous.thread_set_mask().unwrap();
+        res.unwrap();
+        assert_eq!(received, Signal::SIGINT as i32);
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_pids_limit_error() {
+        if !crate::cgroup::is_cgroup_v2() {
+            return;
+        }
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_pids_limit");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.enable_accounting = true;
+        options.check_pids_limit = true;
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.start().unwrap();
+        let cgroup = container.cgroup_path().unwrap().to_path_buf();
+        // Only when the pids controller is enabled for the container's cgroup
+        if !cgroup.join("pids.max").exists() {
+            container.force_stop().unwrap();
+            let _ = container.wait_for_container();
+            let _ = fs::remove_dir_all(target);
+            return;
+        }
+        let count = container.pids_count().unwrap();
+        assert!(count >= 1);
+        fs::write(cgroup.join("pids.max"), count.to_string()).unwrap();
+        let err = container.execute_in_container("true".to_string(), vec![], Some(vec!["PATH=/bin:/usr/bin".to_string()]), Some(ExecType::FORK)).unwrap_err();
+        assert!(err.to_string().contains("limit of"), "{}", err);
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_readonly_cgroup() {
+        let controllers = Path::new(crate::cgroup::CGROUP_ROOT).join("cgroup.controllers");
+        // The memory controller may be bound to a cgroup v1 hierarchy
+        if !matches!(fs::read_to_string(controllers), Ok(controllers) if controllers.split_whitespace().any(|c| c == "memory")) {
+            return;
+        }
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_readonly_cgroup");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.enable_accounting = true;
+        options.readonly_cgroup = Some(PathBuf::from("/sys/fs/cgroup"));
+        options.log_driver = Some(LogDriverType::RINGBUFFER(10));
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        let script = "test -n \"$(cat /sys/fs/cgroup/memory.current)\" && echo readable; { echo 1M > /sys/fs/cgroup/memory.max; } 2>/dev/null || echo read-only";
+        container.spawn("sh".to_string(), vec!["-c".to_string(), script.to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        container.wait_for_container().unwrap();
+        assert_eq!(container.logs(), vec!["readable", "read-only"]);
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_exec_without_path_search() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_path_search");
+        let run = |command: &str| {
+            let fs = OverlayDriver::new(vec![&"/"], &target);
+            let mut container = Container::new(Box::new(fs)).unwrap();
+            container.spawn_command(Command {
+                command: command.to_string(),
+                args: vec!["-c".to_string(), "true".to_string()],
+                env: vec!["PATH=/bin:/usr/bin".to_string()],
+                exec_type: ExecType::REPLACE,
+                argv0: None,
+                login: false,
+                path_search: false,
+                process_group: None,
+                stream_stdout: false,
+            }).unwrap();
+            container.wait_for_container()
+        };
+        // Not searched in the PATH
+        let err = run("sh").unwrap_err();
+        assert_eq!(err.downcast_ref::<ExitCode>(), Some(&ExitCode::NOT_FOUND));
+        run("/bin/sh").unwrap();
+        let _ = fs::remove_dir_all(&target);
+    }
+
+    #[test]
+    fn test_workload_exit_codes() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_workload_exit_codes");
+        for code in [125, 126, 127] {
+            let fs = OverlayDriver::new(vec![&"/"], &target);
+            let mut container = Container::new(Box::new(fs)).unwrap();
+            let args = vec!["-c".to_string(), format!("exit {}", code)];
+            container.spawn("sh".to_string(), args, Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+            // Not a failure of the container, the workload ran
+            assert_eq!(container.wait_for_container().unwrap(), ContainerExit::EXITED(code));
+        }
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_forked_command_not_found() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_forked_command_not_found");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut container = Container::new(Box::new(fs)).unwrap();
+        container.start().unwrap();
+        let err = container.execute_in_container("/does/not/exist".to_string(), vec![], None, Some(ExecType::FORK)).unwrap_err();
+        assert_eq!(err.downcast_ref::<ExitCode>(), Some(&ExitCode::NOT_FOUND));
+        // The runtime goes on
+        container.execute_in_container("true".to_string(), vec![], Some(vec!["PATH=/bin:/usr/bin".to_string()]), Some(ExecType::FORK)).unwrap();
+        container.force_stop().unwrap();
+        assert_eq!(container.wait_for_container().unwrap(), ContainerExit::EXITED(0));
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_command_not_found() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_command_not_found");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut container = Container::new(Box::new(fs)).unwrap();
+        container.spawn("/does/not/exist".to_string(), vec![], None).unwrap();
+        let err = container.wait_for_container().unwrap_err();
+        assert_eq!(err.downcast_ref::<ExitCode>(), Some(&ExitCode::NOT_FOUND));
+        assert_eq!(ExitCode::NOT_FOUND as i32, 127);
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_core_dumps() {
+        let base = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_core_dumps");
+        let cores = base.join("cores");
+        fs::create_dir_all(&cores).unwrap();
+        let fs = OverlayDriver::new(vec![&"/"], &base.join("overlay"));
+        let mut options = RuntimeOptions::default();
+        options.core_dumps = Some(cores.clone());
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        // The init of the PID namespace can't be killed by its own signals, a child crashes instead
+        let script = "sh -c 'kill -SEGV $$'; true";
+        let res = container.spawn("sh".to_string(), vec!["-c".to_string(), script.to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()]));
+        // Depends on the host's kernel.core_pattern, which the container can't change
+        match runtime::host_core_dump_dir() {
+            Ok(_) => {
+                res.unwrap();
+                container.wait_for_container().unwrap();
+                assert_eq!(fs::read_dir(&cores).unwrap().count(), 1);
+            },
+            Err(_) => {
+                let err = res.unwrap_err().to_string();
+                assert!(err.contains("core_pattern"), "{}", err);
+            },
+        }
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_wait_for_ready_file() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_ready_file");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut container = Container::new(Box::new(fs)).unwrap();
+        let script = "sleep 0.5; touch /ready; sleep 10";
+        container.spawn("sh".to_string(), vec!["-c".to_string(), script.to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        assert!(container.wait_for_ready(ReadyCheck::FILE(PathBuf::from("/ready")), Duration::from_millis(100)).is_err());
+        container.wait_for_ready(ReadyCheck::FILE(PathBuf::from("/ready")), Duration::from_secs(5)).unwrap();
+        // Created in the container's root filesystem, not in the host's
+        assert!(!Path::new("/ready").exists());
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_add_seccomp_rules() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_seccomp_rules");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut container = Container::new(Box::new(fs)).unwrap();
+        container.start().unwrap();
+        let run = |script: &str| container.execute_in_container("sh".to_string(), vec!["-c".to_string(), script.to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()]), Some(ExecType::FORK)).unwrap();
+        run("hostname before && touch /allowed");
+        container.wait_for_ready(ReadyCheck::FILE(PathBuf::from("/allowed")), Duration::from_secs(5)).unwrap();
+        container.add_seccomp_rules(vec![SeccompRule {
+            syscall: "sethostname".to_string(),
+            action: SeccompAction::ERRNO(nix::libc::EPERM),
+            args: vec![],
+        }]).unwrap();
+        run("hostname after || touch /blocked");
+        container.wait_for_ready(ReadyCheck::FILE(PathBuf::from("/blocked")), Duration::from_secs(5)).unwrap();
+        assert!(container.add_seccomp_rules(vec![SeccompRule {
+            syscall: "not_a_syscall".to_string(),
+            action: SeccompAction::KILL,
+            args: vec![],
+        }]).is_err());
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_execute_before_start() {
+        let fs = OverlayDriver::new(vec![&"/"], &"/tmp/test_execute_before_start");
+        let container = Container::new(Box::new(fs)).unwrap();
+        let err = container.execute_in_container("true".to_string(), vec![], None, Some(ExecType::FORK)).unwrap_err();
+        assert!(err.to_string().contains("Container not started"), "{}", err);
+        assert!(container.execute_in_process_group("group", "true".to_string(), vec![], None).is_err());
+    }
+
+    #[test]
+    fn test_seccomp_profile() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_seccomp_profile");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.seccomp = Some(SeccompProfile {
+            default_action: SeccompAction::ALLOW,
+            rules: vec![SeccompRule {
+                syscall: "sethostname".to_string(),
+                action: SeccompAction::ERRNO(nix::libc::EPERM),
+                args: vec![],
+            }],
+        });
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn("sh".to_string(), vec!["-c".to_string(), "hostname blocked || exit 5".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        assert_eq!(container.wait_for_container().unwrap(), ContainerExit::EXITED(5));
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_invalid_seccomp_profile() {
+        let fs = OverlayDriver::new(vec![&"/"], &"/tmp/test_invalid_seccomp_profile");
+        let mut options = RuntimeOptions::default();
+        options.seccomp = Some(SeccompProfile {
+            default_action: SeccompAction::ALLOW,
+            rules: vec![SeccompRule {
+                syscall: "kill".to_string(),
+                action: SeccompAction::ERRNO(nix::libc::EPERM),
+                args: vec![SeccompArg { index: 6, value: 0, value_two: 0, op: SeccompCompare::EQ }],
+            }],
+        });
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        // Rejected before the container is created
+        let err = container.start().unwrap_err();
+        assert!(err.to_string().contains("seccomp"), "{}", err);
+        assert!(container.container_pid.is_none());
+    }
+
+    #[test]
+    fn test_run_command_streaming() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_streaming");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut container = Container::new(Box::new(fs)).unwrap();
+        // The runtime executes the command, it must not be replaced
+        container.start().unwrap();
+        let mut lines = vec![];
+        // Printed one second apart, the first lines arrive before the command exits
+        let start = Instant::now();
+        let script = "echo one; echo two; sleep 1; echo three; exit 3";
+        let status = container.run_command_streaming("sh".to_string(), vec!["-c".to_string(), script.to_string()], |line| {
+            lines.push((line.to_string(), start.elapsed()));
+        }).unwrap();
+        assert_eq!(status, ContainerExit::EXITED(3));
+        let received: Vec<&str> = lines.iter().map(|(line, _)| line.as_str()).collect();
+        assert_eq!(received, vec!["one", "two", "three"]);
+        assert!(lines[1].1 < Duration::from_millis(900), "{:?}", lines);
+        // It runs in the container's PID namespace
+        let mut pid = String::new();
+        container.run_command_streaming("sh".to_string(), vec!["-c".to_string(), "echo $$".to_string()], |line| pid = line.to_string()).unwrap();
+        assert_ne!(pid.parse::<i32>().unwrap(), 0);
+        assert!(pid.parse::<i32>().unwrap() < 100, "{}", pid);
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_exec_join_network_namespace() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_exec_join");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut container = Container::new(Box::new(fs)).unwrap();
+        container.spawn("sleep".to_string(), vec!["10".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        // The interfaces of the container's network namespace, and the init of the host
+        let script = "tail -n +3 /proc/net/dev | cut -d : -f 1 | tr -d ' '; cat /proc/1/comm";
+        let output = container.exec_join(&[Namespace::NET], "sh".to_string(), vec!["-c".to_string(), script.to_string()]).unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let lines: Vec<&str> = stdout.lines().collect();
+        assert_eq!(lines[..lines.len() - 1], ["lo"]);
+        assert_eq!(lines[lines.len() - 1], fs::read_to_string("/proc/1/comm").unwrap().trim());
+        assert!(container.exec_join(&[Namespace::NET, Namespace::NET], "true".to_string(), vec![]).is_err());
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_exec_join_user_namespace() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_exec_join_userns");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut options = RuntimeOptions::default();
+        options.userns = true;
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn("sleep".to_string(), vec!["10".to_string()], Some(vec!["PATH=/bin:/usr/bin".to_string()])).unwrap();
+        assert!(container.exec_join(&[Namespace::NET], "true".to_string(), vec![]).is_err());
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        let _ = fs::remove_dir_all(target);
+    }
+
+    #[test]
+    fn test_signal_process_group() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_process_group");
+        let fs = OverlayDriver::new(vec![&"/"], &target);
+        let mut container = Container::new(Box::new(fs)).unwrap();
+        container.start().unwrap();
+        let env = Some(vec!["PATH=/bin:/usr/bin".to_string()]);
+        let script = |name: &str| vec!["-c".to_string(), format!(
+            "trap 'touch /{0}_signaled; exit' USR1; touch /{0}_ready; while true; do sleep 0.1; done", name
+        )];
+        container.execute_in_process_group("worker", "sh".to_string(), script("worker"), env.clone()).unwrap();
+        container.execute_in_container("sh".to_string(), script("other"), env, Some(ExecType::FORK)).unwrap();
+        for name in ["worker", "other"] {
+            container.wait_for_ready(ReadyCheck::FILE(PathBuf::from(format!("/{}_ready", name))), Duration::from_secs(5)).unwrap();
+        }
+        container.signal_process_group(Some("worker"), Signal::SIGUSR1).unwrap();
+        container.wait_for_ready(ReadyCheck::FILE(PathBuf::from("/worker_signaled")), Duration::from_secs(5)).unwrap();
+        // Neither the other command nor the runtime got it
+        assert!(container.wait_for_ready(ReadyCheck::FILE(PathBuf::from("/other_signaled")), Duration::from_millis(500)).is_err());
+        let pid = container.container_pid.unwrap();
+        let running = || waitpid(pid, Some(nix::sys::wait::WaitPidFlag::WNOHANG)).unwrap() == WaitStatus::StillAlive;
+        assert!(running());
+        container.signal_process_group(None, Signal::SIGUSR1).unwrap();
+        container.wait_for_ready(ReadyCheck::FILE(PathBuf::from("/other_signaled")), Duration::from_secs(5)).unwrap();
+        assert!(running());
+        container.force_stop().unwrap();
+        container.wait_for_container().unwrap();
+        let _ = fs::remove_dir_all(target);
+    }
 }
@@ -0,0 +1,144 @@
+/*
+ * The MIT License
+ * Copyright (c) 2022 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::fs;
+use std::path::Path;
+
+use nix::sys::utsname::uname;
+use serde::Serialize;
+
+use crate::cgroup;
+
+/// Namespaces that can be probed in /proc/self/ns
+const NAMESPACES: [&str; 8] = ["cgroup", "ipc", "mnt", "net", "pid", "time", "user", "uts"];
+/// First kernel version supporting idmapped mounts (mount_setattr with MOUNT_ATTR_IDMAP)
+const IDMAPPED_MOUNTS_KERNEL: (u32, u32) = (5, 12);
+
+/// cgroup hierarchy used by the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum CgroupVersion {
+    /// Only the legacy hierarchies are mounted
+    V1,
+    /// The legacy hierarchies are mounted, with the unified hierarchy at /sys/fs/cgroup/unified
+    HYBRID,
+    /// Only the unified hierarchy is mounted
+    V2,
+    /// No cgroup hierarchy is mounted
+    NONE,
+}
+
+/// Features supported by this build of the library and by the host, similar to `runc features`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Features {
+    /// Version of the library
+    pub version: String,
+    /// Architecture the library was built for
+    pub arch: String,
+    /// Release of the running kernel
+    pub kernel: String,
+    /// Namespaces supported by the kernel, as named in /proc/self/ns
+    pub namespaces: Vec<String>,
+    /// cgroup hierarchy of the host
+    pub cgroup: CgroupVersion,
+    /// Whether the kernel supports overlay filesystems
+    pub overlay: bool,
+    /// Whether the kernel supports seccomp filters
+    pub seccomp: bool,
+    /// Whether the kernel supports idmapped mounts
+    pub idmapped_mounts: bool,
+}
+
+impl Features {
+
+    /// Returns whether a namespace is supported
+    /// # Arguments
+    /// * `namespace` - Name of the namespace, as named in /proc/self/ns (e.g. "mnt")
+    pub fn supports_namespace(&self, namespace: &str) -> bool {
+        self.namespaces.iter().any(|ns| ns == namespace)
+    }
+
+}
+
+/// Probes the features supported by this build of the library and by the host
+pub fn features() -> Features {
+    let kernel = uname().release().to_string();
+    let filesystems = fs::read_to_string("/proc/filesystems").unwrap_or_default();
+    Features {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        arch: std::env::consts::ARCH.to_string(),
+        namespaces: NAMESPACES.iter()
+            .filter(|ns| Path::new("/proc/self/ns").join(ns).exists())
+            .map(|ns| ns.to_string())
+            .collect(),
+        cgroup: cgroup_version(),
+        overlay: supports_filesystem(&filesystems, "overlay"),
+        // The Seccomp field is only present when the kernel is built with CONFIG_SECCOMP
+        seccomp: fs::read_to_string("/proc/self/status")
+            .map(|status| status.lines().any(|line| line.starts_with("Seccomp:")))
+            .unwrap_or(false),
+        idmapped_mounts: matches!(kernel_version(&kernel), Some(version) if version >= IDMAPPED_MOUNTS_KERNEL),
+        kernel,
+    }
+}
+
+/// Returns the cgroup hierarchy of the host
+fn cgroup_version() -> CgroupVersion {
+    let root = Path::new(cgroup::CGROUP_ROOT);
+    if cgroup::is_cgroup_v2() {
+        CgroupVersion::V2
+    } else if root.join("unified/cgroup.controllers").exists() {
+        CgroupVersion::HYBRID
+    } else if root.exists() && fs::read_dir(root).map(|mut entries| entries.next().is_some()).unwrap_or(false) {
+        CgroupVersion::V1
+    } else {
+        CgroupVersion::NONE
+    }
+}
+
+/// Returns whether a filesystem is listed in the contents of /proc/filesystems
+fn supports_filesystem(filesystems: &str, fstype: &str) -> bool {
+    filesystems.lines().any(|line| line.split_whitespace().last() == Some(fstype))
+}
+
+/// Parses the major and minor version of a kernel release (e.g. "5.15.0-91-generic")
+pub(crate) fn kernel_version(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_features() {
+        let features = features();
+        assert!(features.supports_namespace("mnt"));
+        assert!(!features.kernel.is_empty());
+        assert_eq!(kernel_version("5.15.0-91-generic"), Some((5, 15)));
+        assert_eq!(kernel_version("6.1"), Some((6, 1)));
+    }
+
+}
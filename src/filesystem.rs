@@ -22,12 +22,29 @@
  */
 
 use color_eyre::eyre::{Result, self};
+use nix::errno::Errno;
+use nix::fcntl::{open, openat, OFlag};
 use nix::mount::{umount2, MntFlags, mount, MsFlags};
-use nix::sys::stat::{mknod, SFlag, Mode, makedev};
-use std::path::{PathBuf, Path};
-use std::{fs, os};
+use nix::sys::stat::{fstat, mkdirat, mknod, SFlag, Mode, makedev};
+use nix::sys::statfs::{statfs, FsType, CGROUP2_SUPER_MAGIC, PROC_SUPER_MAGIC};
+use nix::time::{clock_gettime, ClockId};
+use nix::unistd::{close, fchownat, FchownatFlags, Gid, Uid};
+use serde::{Serialize, Deserialize};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Component, PathBuf, Path};
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+use std::{fs, io, os};
 use sys_mount::{Mount, FilesystemType, MountFlags, Unmount, UnmountFlags};
 
+use crate::procfs;
+use crate::syscall;
+
 pub trait StorageDriver {
 
     /// Mounts the filesystem
@@ -40,6 +57,27 @@ pub trait StorageDriver {
     /// e.g. /mnt/my-container/my-fs
     fn root(&self) -> Result<&Path>;
 
+    /// Returns the number of bytes written to the filesystem by the container
+    fn disk_usage(&self) -> Result<u64> {
+        Err(eyre::eyre!("The storage driver does not track its disk usage"))
+    }
+
+    /// Sets the scratch directory allocated for the container (see `RuntimeOptions::managed_scratch`),
+    /// before mounting. Drivers with a target of their own ignore it
+    fn set_scratch_dir(&mut self, _dir: &Path) {}
+
+    /// Returns the layers composing the filesystem, from top to bottom: the writable layer (if any)
+    /// followed by the read-only layers of the image
+    fn layers(&self) -> Vec<PathBuf> {
+        vec![]
+    }
+
+    /// Returns the path where the filesystem will be mounted, before mounting it. Required by
+    /// `RuntimeOptions::host_visible_rootfs`, where the host prepares the mount point
+    fn mount_target(&self) -> Result<PathBuf> {
+        Err(eyre::eyre!("The storage driver does not know its mount point before mounting"))
+    }
+
 }
 
 pub struct NullDriver {
@@ -66,11 +104,40 @@ impl StorageDriver for NullDriver {
 }
 
 /// An overlayfs filesystem driver
-/// Note: 
+/// Note: the layers are ordered top-down, so a file present in several layers is taken from the first
+/// one. Listing them from the base image up makes older layers shadow newer ones, use
+/// `with_layers_bottom_up` to keep that order
 pub struct OverlayDriver {
+    /// Lowerdirs, from top to bottom
     layers: Vec<PathBuf>,
     target: PathBuf,
-    mount: Option<Mount>
+    mount: Option<Mount>,
+    /// Shared mount of the layers (if any), kept mounted while the driver exists
+    _shared_lower: Option<Arc<SharedLower>>,
+    /// Retries of the overlay mount when it fails with a transient error
+    mount_retries: u32,
+    /// Delay before the first retry, doubled after each one
+    mount_backoff: Duration,
+    /// Remove the leftovers of a previous mount from the workdir before mounting
+    clean_workdir: bool,
+    /// SELinux context of the files of the overlay (if any)
+    selinux_label: Option<String>,
+    /// Keep every directory of the overlay, including a copy of the layers, inside a tmpfs
+    in_memory: bool,
+    /// Maximum size of the tmpfs of an in-memory overlay (if any)
+    tmpfs_size: Option<String>,
+    /// The tmpfs of an in-memory overlay, once mounted
+    tmpfs: Option<Mount>,
+    /// Flags of the unmount (default: none, a busy overlay fails to unmount)
+    umount_flags: UnmountFlags,
+    /// Retries of the unmount while the overlay is busy
+    umount_retries: u32,
+    /// Delay before the first retry of the unmount, doubled after each one
+    umount_backoff: Duration,
+    /// Detach (lazily unmount) the overlay when it is still busy after the retries
+    lazy_umount_fallback: bool,
+    /// The target is the scratch directory of the container, see `in_scratch_dir`
+    scratch_target: bool,
 }
 
 impl OverlayDriver {
@@ -78,15 +145,242 @@ impl OverlayDriver {
     const MERGE_DIR: &'static str = "merge";
     const UPPER_DIR: &'static str = "upper";
     const WORK_DIR: &'static str = "workdir";
+    /// Records the mount namespace of the current mount, to tell whether the workdir is stale
+    const OWNER_FILE: &'static str = "owner";
+    const LOWER_DIR: &'static str = "lower";
+    const MOUNT_RETRIES: u32 = 3;
+    const MOUNT_BACKOFF: Duration = Duration::from_millis(10);
+    const UMOUNT_RETRIES: u32 = 3;
+    const UMOUNT_BACKOFF: Duration = Duration::from_millis(100);
 
+    /// Overlay of layers, mounted at `<target>/merge`
+    /// # Arguments
+    /// * `layers` - The lowerdirs, from top (highest priority) to bottom, as in the `lowerdir=` option of overlayfs
+    /// * `target` - Directory of the overlay
     pub fn new(layers: Vec<&impl AsRef<Path>>, target: &impl AsRef<Path>) -> Self {
         return OverlayDriver {
             layers: layers.iter().map(
                 |layer| layer.as_ref().to_path_buf()
             ).collect(),
             target:  target.as_ref().to_path_buf(),
-            mount: None
+            mount: None,
+            _shared_lower: None,
+            mount_retries: Self::MOUNT_RETRIES,
+            mount_backoff: Self::MOUNT_BACKOFF,
+            clean_workdir: true,
+            selinux_label: None,
+            in_memory: false,
+            tmpfs_size: None,
+            tmpfs: None,
+            umount_flags: UnmountFlags::empty(),
+            umount_retries: Self::UMOUNT_RETRIES,
+            umount_backoff: Self::UMOUNT_BACKOFF,
+            lazy_umount_fallback: true,
+            scratch_target: false,
+        };
+    }
+
+    /// Same as `new`, with the layers ordered bottom-up (the base image first, then each layer built on
+    /// top of it, as in a Dockerfile or an image manifest), reversed into the order of overlayfs
+    /// # Arguments
+    /// * `layers` - The lowerdirs, from bottom to top (highest priority)
+    /// * `target` - Directory of the overlay
+    pub fn with_layers_bottom_up(layers: Vec<&impl AsRef<Path>>, target: &impl AsRef<Path>) -> Self {
+        Self::new(layers.into_iter().rev().collect(), target)
+    }
+
+    /// The lowerdirs of the overlay, from top to bottom, as configured (`StorageDriver::layers` adds the
+    /// upperdir on top of them)
+    pub fn lowerdirs(&self) -> &[PathBuf] {
+        &self.layers
+    }
+
+    /// The upperdir of the overlay, receiving the writes of the container
+    pub fn upper_dir(&self) -> PathBuf {
+        self.target.join(Self::UPPER_DIR)
+    }
+
+    /// Overlay that lives entirely in memory: a tmpfs is mounted on the target, the layers are copied
+    /// into it and the upperdir, workdir and merge dir are created inside, so nothing is written to disk
+    /// and unmounting the overlay leaves the target empty.
+    ///
+    /// The layers are copied on every mount, so this is meant for small images and ephemeral containers.
+    /// # Arguments
+    /// * `layers` - The layers of the image, copied into the tmpfs
+    /// * `target` - Mount point of the tmpfs
+    /// * `size` - Maximum size of the tmpfs (e.g. "512m"), or None for the kernel's default
+    pub fn in_memory(layers: Vec<&impl AsRef<Path>>, target: &impl AsRef<Path>, size: Option<&str>) -> Self {
+        let mut driver = Self::new(layers, target);
+        driver.in_memory = true;
+        driver.tmpfs_size = size.map(|size| size.to_string());
+        driver
+    }
+
+    /// Overlay whose directories live in the scratch directory the container allocates, removed once it
+    /// stops, instead of a target chosen by the caller. Requires `RuntimeOptions::managed_scratch`
+    /// # Arguments
+    /// * `layers` - The layers of the image
+    pub fn in_scratch_dir(layers: Vec<&impl AsRef<Path>>) -> Self {
+        let mut driver = Self::new(layers, &"");
+        driver.scratch_target = true;
+        driver
+    }
+
+    /// Overlay using layers already mounted by a `LowerCache`, instead of specifying every layer
+    /// # Arguments
+    /// * `lower` - The shared mount of the layers
+    /// * `target` - Directory of the overlay
+    pub fn with_shared_lower(lower: Arc<SharedLower>, target: &impl AsRef<Path>) -> Self {
+        OverlayDriver {
+            layers: vec![lower.path().to_path_buf()],
+            target: target.as_ref().to_path_buf(),
+            mount: None,
+            _shared_lower: Some(lower),
+            mount_retries: Self::MOUNT_RETRIES,
+            mount_backoff: Self::MOUNT_BACKOFF,
+            clean_workdir: true,
+            selinux_label: None,
+            in_memory: false,
+            tmpfs_size: None,
+            tmpfs: None,
+            umount_flags: UnmountFlags::empty(),
+            umount_retries: Self::UMOUNT_RETRIES,
+            umount_backoff: Self::UMOUNT_BACKOFF,
+            lazy_umount_fallback: true,
+            scratch_target: false,
+        }
+    }
+
+    /// Sets how many times the overlay mount is retried when it fails with a transient error (ELOOP or
+    /// EBUSY), which can happen while parallel containers are setting up the same layers
+    /// # Arguments
+    /// * `retries` - Maximum number of retries, 0 to fail on the first error
+    /// * `backoff` - Delay before the first retry, doubled after each one
+    pub fn with_mount_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.mount_retries = retries;
+        self.mount_backoff = backoff;
+        self
+    }
+
+    /// Sets the flags of the unmount (default: none). With `UnmountFlags::DETACH` the overlay is lazily
+    /// unmounted: it disappears right away, but stays alive (and keeps its resources) while it is busy
+    /// # Arguments
+    /// * `flags` - The unmount flags
+    pub fn with_umount_flags(mut self, flags: UnmountFlags) -> Self {
+        self.umount_flags = flags;
+        self
+    }
+
+    /// Sets how many times the unmount is retried while the overlay is busy (EBUSY, e.g. a file is still
+    /// open), and whether it is then detached instead of failing (default: 3 retries, then detached)
+    /// # Arguments
+    /// * `retries` - Maximum number of retries, 0 to fail on the first error
+    /// * `backoff` - Delay before the first retry, doubled after each one
+    /// * `lazy_fallback` - Detach the overlay if it is still busy after the retries
+    pub fn with_umount_retries(mut self, retries: u32, backoff: Duration, lazy_fallback: bool) -> Self {
+        self.umount_retries = retries;
+        self.umount_backoff = backoff;
+        self.lazy_umount_fallback = lazy_fallback;
+        self
+    }
+
+    /// Sets whether the workdir is emptied before mounting (default: true).
+    ///
+    /// After an unclean shutdown the workdir keeps the scratch entries of the previous mount (e.g.
+    /// `work/incompat/...`), which can make the next mount fail. Only the workdir is emptied, the upperdir
+    /// (the container's changes) is kept. It is emptied only when the previous mount recorded its mount
+    /// namespace and no process is left in it: the overlay may still be mounted in another container.
+    /// # Arguments
+    /// * `clean` - Empty the workdir before mounting
+    pub fn with_workdir_cleanup(mut self, clean: bool) -> Self {
+        self.clean_workdir = clean;
+        self
+    }
+
+    /// Mounts the overlay with an SELinux context, which every file of the container gets instead of the
+    /// labels of the layers, e.g. "system_u:object_r:container_file_t:s0:c1,c2"
+    /// # Arguments
+    /// * `label` - The SELinux context
+    pub fn with_selinux_label(mut self, label: &str) -> Self {
+        self.selinux_label = Some(label.to_string());
+        self
+    }
+
+    /// Unmounts with the configured flags, retrying while the filesystem is busy
+    /// # Arguments
+    /// * `path` - Mount point, for the logs
+    /// * `unmount` - Unmounts the filesystem with the given flags
+    fn unmount_busy(&self, path: &Path, unmount: impl Fn(UnmountFlags) -> io::Result<()>) -> io::Result<()> {
+        match retry_transient(self.umount_retries, self.umount_backoff, || unmount(self.umount_flags)) {
+            Err(err) if err.raw_os_error() == Some(nix::libc::EBUSY) && self.lazy_umount_fallback
+                && !self.umount_flags.contains(UnmountFlags::DETACH) => {
+                log::warn!("{} is still busy after {} retries, detaching it", path.display(), self.umount_retries);
+                unmount(self.umount_flags | UnmountFlags::DETACH)
+            },
+            res => res,
+        }
+    }
+
+    /// Mounts the tmpfs of an in-memory overlay and copies the layers into it
+    /// # Returns
+    /// The copies of the layers, in the same order
+    fn mount_in_memory(&mut self) -> Result<Vec<PathBuf>> {
+        let data = match &self.tmpfs_size {
+            Some(size) => format!("mode=755,size={}", size),
+            None => "mode=755".to_string(),
+        };
+        let tmpfs = mount_filesystem("tmpfs", &self.target, "tmpfs", MountFlags::NOSUID, Some(data.as_str()))?;
+        self.tmpfs = Some(tmpfs);
+        let lowerdir = self.target.join(Self::LOWER_DIR);
+        fs::create_dir(&lowerdir)?;
+        let mut layers = Vec::with_capacity(self.layers.len());
+        for (index, layer) in self.layers.iter().enumerate() {
+            let copy = lowerdir.join(index.to_string());
+            log::debug!("Copying layer {} to {}", layer.display(), copy.display());
+            copy_tree(layer, &copy)?;
+            layers.push(copy);
+        }
+        Ok(layers)
+    }
+
+    /// Removes the contents of the workdir, unless the overlay may still be mounted
+    /// # Arguments
+    /// * `workdir` - The workdir
+    /// * `mergedir` - Mount point of the overlay
+    /// * `owner` - File recording the mount namespace of the previous mount
+    fn clean_workdir(workdir: &Path, mergedir: &Path, owner: &Path) -> Result<()> {
+        // The merge dir is on the same device as its parent unless something is mounted on it
+        let parent = fs::metadata(mergedir.parent().unwrap_or(mergedir))?;
+        if fs::metadata(mergedir)?.dev() != parent.dev() {
+            log::warn!("Not cleaning {}: the overlay is still mounted", workdir.display());
+            return Ok(());
+        }
+        // Mounted in another mount namespace (e.g. another container), it isn't visible here
+        let namespace = match fs::read_to_string(owner) {
+            Ok(namespace) => PathBuf::from(namespace),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                if fs::read_dir(workdir)?.next().is_some() {
+                    log::warn!("Not cleaning {}: it has no owner, the overlay may still be mounted", workdir.display());
+                }
+                return Ok(());
+            },
+            Err(err) => return Err(err.into()),
         };
+        if procfs::mount_namespace_in_use(&namespace)? {
+            log::warn!("Not cleaning {}: the overlay may still be mounted in {}", workdir.display(), namespace.display());
+            return Ok(());
+        }
+        for entry in fs::read_dir(workdir)? {
+            let entry = entry?;
+            let path = entry.path();
+            log::debug!("Removing stale overlay workdir entry {}", path.display());
+            if entry.file_type()?.is_dir() {
+                fs::remove_dir_all(&path)?;
+            } else {
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
     }
 
 }
@@ -99,14 +393,14 @@ impl StorageDriver for OverlayDriver {
     /// directory on top (upperdir). The writes to the resulting filesystem will be saved in the upperdir.
     /// 
     /// This is how the Overlayfs directories will look like
-    /// ```
+    /// ```text
     ///     lowerdirs = the container image
     ///     upperdir = <target>/upper
     ///     workdir = <target>/work
     ///     target = <target>/merge
     /// ```
     /// As a tree,
-    /// ```
+    /// ```text
     ///     <target>/
     ///         upperdir/
     ///         workdir/
@@ -118,9 +412,21 @@ impl StorageDriver for OverlayDriver {
     /// are stored in memory, and changes are lost when rebooting.
     /// 
     fn mount(&mut self) -> Result<()> {
+        if self.target.as_os_str().is_empty() {
+            return Err(eyre::eyre!("The overlay has no target, is RuntimeOptions::managed_scratch set?"));
+        }
+        // Otherwise the kernel only reports ENOENT, without telling which one
+        if let Some(missing) = self.layers.iter().find(|layer| !layer.is_dir()) {
+            return Err(eyre::eyre!("Lowerdir {} does not exist or is not a directory", missing.display()));
+        }
         if !&self.target.exists() {
             fs::create_dir(&self.target)?;
         }
+        let layers = if self.in_memory {
+            self.mount_in_memory()?
+        } else {
+            self.layers.clone()
+        };
         // Before mounting, create the Overlay directories
         let mergedir = self.target.join(Self::MERGE_DIR);
         let upperdir = self.target.join(Self::UPPER_DIR);
@@ -133,37 +439,74 @@ impl StorageDriver for OverlayDriver {
         }
         if !&workdir.exists() {
             fs::create_dir(&workdir)?;
+        } else if self.clean_workdir {
+            Self::clean_workdir(&workdir, &mergedir, &self.target.join(Self::OWNER_FILE))?;
+        }
+        let userxattr = syscall::in_user_namespace();
+        if userxattr {
+            // The trusted.* xattrs can't be set in a user namespace, overlayfs uses user.overlay.* instead
+            log::warn!("Mounting overlay with userxattr: whiteouts of images using trusted.overlay.* xattrs are ignored");
         }
-        let data = format!("lowerdir={},upperdir={},workdir={}", 
-            self.layers.iter().map(
-                |layer| layer.display().to_string()
-            ).collect::<Vec<String>>().join(":"),  // lowerdir=layer1:layer2:...
-            upperdir.display(), // upperdir=upper
-            workdir.display() // workdir=work
-        );
-        let mount = Mount::new(
-            "overlay", 
+        let data = overlay_options(&layers, &upperdir, &workdir, userxattr, self.selinux_label.as_deref());
+        let mount = mount_filesystem_with_retries(
+            "overlay",
             mergedir,
-            FilesystemType::from("overlay"), 
+            "overlay",
             MountFlags::NOSUID,
-            Some(data.as_str())
+            Some(data.as_str()),
+            self.mount_retries,
+            self.mount_backoff,
         )?;
         self.mount = Some(mount);
+        let namespace = fs::read_link("/proc/self/ns/mnt")?;
+        fs::write(self.target.join(Self::OWNER_FILE), namespace.to_string_lossy().as_bytes())?;
         Ok(())
     }
 
     /// Unmount the overlayfs that was used by the container
+    ///
+    /// Every step is attempted even if a previous one fails, the error lists all the failed steps.
     fn umount(&mut self) -> Result<()> {
+        let mut errors = vec![];
+        let mergedir = self.target.join(Self::MERGE_DIR);
         // If the container is umounted from the host, this will fail.
-        if let Some(mount) = self.mount.take() {
-            mount.unmount(UnmountFlags::DETACH)?;
+        let unmounted = match self.mount.take() {
+            Some(mount) => {
+                let unmounted = self.unmount_busy(&mergedir, |flags| mount.unmount(flags));
+                // Still mounted, it can be unmounted again later
+                if unmounted.is_err() {
+                    self.mount = Some(mount);
+                }
+                unmounted
+            },
+            // When calling from the host, instead we have to use the umount2 syscall
+            None => self.unmount_busy(&mergedir, |flags| umount2(&mergedir, mnt_flags(flags)).map_err(io::Error::from)),
+        };
+        match unmounted {
+            // No longer mounted anywhere, the workdir is no longer in use
+            Ok(()) => match fs::remove_file(self.target.join(Self::OWNER_FILE)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    errors.push(eyre::eyre!("Could not remove the owner of {}: {}", self.target.display(), err));
+                },
+                _ => {},
+            },
+            Err(err) => errors.push(eyre::eyre!("Could not unmount {}: {}", mergedir.display(), err)),
         }
-        // When calling from the host, instead we have to use the umount2 syscall
-        else {
-            let mergedir = self.target.join(Self::MERGE_DIR);
-            umount2(&mergedir, MntFlags::MNT_DETACH)?;
+        let unmounted = match self.tmpfs.take() {
+            Some(tmpfs) => {
+                let unmounted = self.unmount_busy(&self.target, |flags| tmpfs.unmount(flags));
+                if unmounted.is_err() {
+                    self.tmpfs = Some(tmpfs);
+                }
+                unmounted
+            },
+            None if self.in_memory => self.unmount_busy(&self.target, |flags| umount2(&self.target, mnt_flags(flags)).map_err(io::Error::from)),
+            None => Ok(()),
+        };
+        if let Err(err) = unmounted {
+            errors.push(eyre::eyre!("Could not unmount {}: {}", self.target.display(), err));
         }
-        Ok(())
+        combine_errors(&format!("Could not unmount the overlay {}", self.target.display()), errors)
     }
 
     /// Return the root path of the filesystem
@@ -174,6 +517,701 @@ impl StorageDriver for OverlayDriver {
         }
     }
 
+    /// Return the merge directory. An in-memory overlay is mounted in a tmpfs of its own, on top of the target
+    fn mount_target(&self) -> Result<PathBuf> {
+        if self.target.as_os_str().is_empty() {
+            return Err(eyre::eyre!("The overlay has no target, is RuntimeOptions::managed_scratch set?"));
+        }
+        if self.in_memory {
+            return Err(eyre::eyre!("The in-memory overlay is mounted in a tmpfs, not on its merge directory"));
+        }
+        Ok(self.target.join(Self::MERGE_DIR))
+    }
+
+    /// Return the size of the files in the upperdir, where the changes are written
+    /// (for an upperdir in a tmpfs, this is memory)
+    fn disk_usage(&self) -> Result<u64> {
+        let upperdir = self.target.join(Self::UPPER_DIR);
+        if !upperdir.exists() {
+            return Ok(0);
+        }
+        directory_size(&upperdir)
+    }
+
+    /// Use the scratch directory as the target, for an overlay created with `in_scratch_dir`
+    fn set_scratch_dir(&mut self, dir: &Path) {
+        if self.scratch_target {
+            self.target = dir.to_path_buf();
+        }
+    }
+
+    /// Return the upperdir, followed by the lowerdirs
+    fn layers(&self) -> Vec<PathBuf> {
+        let mut layers = vec![self.upper_dir()];
+        layers.extend_from_slice(&self.layers);
+        layers
+    }
+
+}
+
+/// Read-only mounts of the layer sets used by several containers, so the layers are stacked only once
+///
+/// The layers are mounted (in the host) as a read-only overlay, used as the only lowerdir of the
+/// containers' overlays. The mount is shared by every `OverlayDriver` using the same layers, and
+/// unmounted once the last one is dropped.
+/// ```text
+///     <dir>/
+///         <hash of the layers>/   (lowerdir = layer1:layer2:...)
+/// ```
+pub struct LowerCache {
+    dir: PathBuf,
+    mounts: Mutex<HashMap<Vec<PathBuf>, Weak<SharedLower>>>,
+}
+
+impl LowerCache {
+
+    /// Creates a cache mounting the layer sets under `dir`
+    pub fn new(dir: &impl AsRef<Path>) -> Self {
+        LowerCache {
+            dir: dir.as_ref().to_path_buf(),
+            mounts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the shared mount of a layer set, mounting it if no container uses it yet
+    /// # Arguments
+    /// * `layers` - The layers, from top to bottom
+    pub fn get(&self, layers: Vec<&impl AsRef<Path>>) -> Result<Arc<SharedLower>> {
+        let layers: Vec<PathBuf> = layers.iter().map(|layer| layer.as_ref().to_path_buf()).collect();
+        let mut mounts = self.mounts.lock().unwrap();
+        if let Some(lower) = mounts.get(&layers).and_then(Weak::upgrade) {
+            return Ok(lower);
+        }
+        let lower = Arc::new(SharedLower::mount(&self.dir, &layers)?);
+        mounts.retain(|_, lower| lower.strong_count() > 0);
+        mounts.insert(layers, Arc::downgrade(&lower));
+        Ok(lower)
+    }
+
+}
+
+/// A layer set mounted by a `LowerCache`, unmounted when dropped
+#[derive(Debug)]
+pub struct SharedLower {
+    path: PathBuf,
+    mounted: bool,
+}
+
+impl SharedLower {
+
+    fn mount(dir: &Path, layers: &[PathBuf]) -> Result<Self> {
+        // An overlay needs at least two lowerdirs when there is no upperdir
+        if let [layer] = layers {
+            return Ok(SharedLower { path: layer.clone(), mounted: false });
+        }
+        let mut hasher = DefaultHasher::new();
+        layers.hash(&mut hasher);
+        let path = dir.join(format!("{:016x}", hasher.finish()));
+        fs::create_dir_all(&path)?;
+        let data = format!("lowerdir={}", layers.iter()
+            .map(|layer| layer.display().to_string())
+            .collect::<Vec<String>>().join(":"));
+        log::debug!("Mounting shared layers {} at {}", data, path.display());
+        mount_filesystem("overlay", &path, "overlay", MountFlags::RDONLY | MountFlags::NOSUID, Some(data.as_str()))?;
+        Ok(SharedLower { path, mounted: true })
+    }
+
+    /// Path of the mounted layers
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+}
+
+impl Drop for SharedLower {
+
+    fn drop(&mut self) {
+        if !self.mounted {
+            return;
+        }
+        log::debug!("Unmounting shared layers at {}", self.path.display());
+        if let Err(err) = umount2(&self.path, MntFlags::MNT_DETACH).map_err(eyre::Report::from)
+            .and_then(|_| fs::remove_dir(&self.path).map_err(eyre::Report::from)) {
+            log::warn!("Could not unmount shared layers at {}: {}", self.path.display(), err);
+        }
+    }
+
+}
+
+/// Returns the size of the files in a directory and its subdirectories, without following symlinks
+fn directory_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let metadata = path.symlink_metadata()?;
+        if metadata.is_dir() {
+            size += directory_size(&path)?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Builds the mount options of an overlayfs
+/// # Arguments
+/// * `layers` - The lowerdirs, from top to bottom
+/// * `upperdir` - The upperdir
+/// * `workdir` - The workdir
+/// * `userxattr` - Use the user.overlay.* xattrs instead of trusted.overlay.* (required in a user namespace)
+/// * `selinux_label` - SELinux context of every file of the overlay (if any)
+fn overlay_options(layers: &[PathBuf], upperdir: &Path, workdir: &Path, userxattr: bool, selinux_label: Option<&str>) -> String {
+    let mut data = format!("lowerdir={},upperdir={},workdir={}",
+        layers.iter().map(
+            |layer| layer.display().to_string()
+        ).collect::<Vec<String>>().join(":"),  // lowerdir=layer1:layer2:...
+        upperdir.display(), // upperdir=upper
+        workdir.display() // workdir=work
+    );
+    if userxattr {
+        data.push_str(",userxattr");
+    }
+    if let Some(label) = selinux_label {
+        // The context contains commas, it must be quoted
+        data.push_str(&format!(",context=\"{}\"", label));
+    }
+    data
+}
+
+/// Two stacked overlayfs: a base overlay, and a session overlay using the base one as its only lowerdir
+///
+/// Changes made through the session overlay can be discarded with `discard_session`, while the changes
+/// made to the base overlay persist.
+/// ```text
+///     <base>/
+///         upper/ workdir/ merge/      (lowerdirs = the container image)
+///     <session>/
+///         upper/ workdir/ merge/      (lowerdir = <base>/merge)
+/// ```
+pub struct StackedOverlayDriver {
+    base: OverlayDriver,
+    session: OverlayDriver,
+}
+
+impl StackedOverlayDriver {
+
+    pub fn new(layers: Vec<&impl AsRef<Path>>, base: &impl AsRef<Path>, session: &impl AsRef<Path>) -> Self {
+        let base_root = base.as_ref().join(OverlayDriver::MERGE_DIR);
+        StackedOverlayDriver {
+            base: OverlayDriver::new(layers, base),
+            session: OverlayDriver::new(vec![&base_root], session),
+        }
+    }
+
+    /// Return the root path of the base overlay, where persistent changes can be made
+    ///
+    /// Note: only while the session overlay is not mounted, overlayfs doesn't support changes to a
+    /// lowerdir of a mounted overlay
+    pub fn base_root(&self) -> Result<&Path> {
+        self.base.root()
+    }
+
+    /// Discard every change made through the session overlay, and mount a fresh one
+    ///
+    /// The changes are removed even if the session can't be unmounted (e.g. it was not mounted). If any
+    /// step fails, the fresh session is not mounted and the error lists all the failed steps.
+    pub fn discard_session(&mut self) -> Result<()> {
+        let mut errors = vec![];
+        if let Err(err) = self.session.umount() {
+            errors.push(err);
+        }
+        for dir in [OverlayDriver::UPPER_DIR, OverlayDriver::WORK_DIR] {
+            let path = self.session.target.join(dir);
+            if path.exists() {
+                if let Err(err) = fs::remove_dir_all(&path) {
+                    errors.push(eyre::eyre!("Could not remove {}: {}", path.display(), err));
+                }
+            }
+        }
+        combine_errors(&format!("Could not discard the session {}", self.session.target.display()), errors)?;
+        self.session.mount()
+    }
+
+}
+
+impl StorageDriver for StackedOverlayDriver {
+
+    /// Mount the base overlay, then the session overlay on top of it
+    fn mount(&mut self) -> Result<()> {
+        self.base.mount()?;
+        self.session.mount()
+    }
+
+    /// Unmount the session overlay, then the base overlay (even if the session can't be unmounted)
+    fn umount(&mut self) -> Result<()> {
+        let errors = [self.session.umount(), self.base.umount()].into_iter()
+            .filter_map(|res| res.err())
+            .collect();
+        combine_errors("Could not unmount the stacked overlay", errors)
+    }
+
+    /// Return the root path of the session overlay
+    fn root(&self) -> Result<&Path> {
+        self.session.root()
+    }
+
+    /// Return the size of the changes made to both overlays
+    fn disk_usage(&self) -> Result<u64> {
+        Ok(self.base.disk_usage()? + self.session.disk_usage()?)
+    }
+
+    /// Return the upperdir of the session, followed by the layers of the base overlay (its merge dir,
+    /// the only lowerdir of the session, is not a layer of its own)
+    fn layers(&self) -> Vec<PathBuf> {
+        let mut layers = vec![self.session.upper_dir()];
+        layers.extend(StorageDriver::layers(&self.base));
+        layers
+    }
+
+}
+
+/// A directory holding a prepared root filesystem (e.g. an immutable base image on disk), bind mounted
+/// on the target, without any writable layer
+pub struct BindMountDriver {
+    /// The root filesystem
+    pub source: PathBuf,
+    /// Mount point, created if missing
+    pub target: PathBuf,
+    /// Mount it read-only
+    pub readonly: bool,
+    mounted: bool,
+}
+
+impl BindMountDriver {
+
+    /// Bind mount of a root filesystem
+    /// # Arguments
+    /// * `source` - The root filesystem
+    /// * `target` - Mount point
+    /// * `readonly` - Mount it read-only
+    pub fn new(source: &impl AsRef<Path>, target: &impl AsRef<Path>, readonly: bool) -> Self {
+        BindMountDriver {
+            source: source.as_ref().to_path_buf(),
+            target: target.as_ref().to_path_buf(),
+            readonly,
+            mounted: false,
+        }
+    }
+
+}
+
+/// Remounts a bind mount and each of its submounts read-only (MS_REC is ignored by a remount). Their other
+/// flags are kept, they can't be cleared when they are locked (e.g. in a user namespace)
+/// # Arguments
+/// * `target` - Mount point of the bind mount (symlinks are resolved)
+fn remount_readonly_recursive(target: &Path) -> Result<()> {
+    let target = fs::canonicalize(target)?;
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    let kept = [
+        ("nosuid", MsFlags::MS_NOSUID),
+        ("nodev", MsFlags::MS_NODEV),
+        ("noexec", MsFlags::MS_NOEXEC),
+        ("noatime", MsFlags::MS_NOATIME),
+        ("nodiratime", MsFlags::MS_NODIRATIME),
+        ("relatime", MsFlags::MS_RELATIME),
+    ];
+    for (mount_point, options) in procfs::mounts_below(&mountinfo, &target) {
+        let mut flags = MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY;
+        for (option, flag) in kept {
+            if options.split(',').any(|set| set == option) {
+                flags |= flag;
+            }
+        }
+        mount(None::<&str>, &mount_point, None::<&str>, flags, None::<&str>)
+            .map_err(|err| eyre::eyre!("Could not remount {} read-only: {}", mount_point.display(), err))?;
+    }
+    Ok(())
+}
+
+impl StorageDriver for BindMountDriver {
+
+    /// Bind mount the source, with its submounts, on the target
+    fn mount(&mut self) -> Result<()> {
+        if !self.source.is_dir() {
+            return Err(eyre::eyre!("{} does not exist or is not a directory", self.source.display()));
+        }
+        if !self.target.exists() {
+            fs::create_dir_all(&self.target)?;
+        }
+        if self.readonly {
+            // Read-only from the start, with the submounts, instead of remounted read-only afterwards
+            match syscall::clone_mount_readonly(&self.source) {
+                Ok(mount_fd) => {
+                    let res = syscall::move_mount(mount_fd, &self.target);
+                    close(mount_fd)?;
+                    res.map_err(|err| eyre::eyre!("Could not bind mount {} on {}: {}", self.source.display(), self.target.display(), err))?;
+                    self.mounted = true;
+                    return Ok(());
+                },
+                Err(err) if matches!(err.downcast_ref::<Errno>(), Some(Errno::ENOSYS) | Some(Errno::EPERM)) => {
+                    log::debug!("Could not bind mount {} read-only at once ({}), remounting it", self.source.display(), err);
+                },
+                Err(err) => return Err(err),
+            }
+        }
+        mount(
+            Some(&self.source),
+            &self.target,
+            None::<&str>,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            None::<&str>,
+        ).map_err(|err| eyre::eyre!("Could not bind mount {} on {}: {}", self.source.display(), self.target.display(), err))?;
+        self.mounted = true;
+        if self.readonly {
+            // The flags of the first mount, other than MS_REC, are ignored for a bind mount, and MS_REC is
+            // ignored by a remount: each submount is remounted on its own
+            if let Err(err) = remount_readonly_recursive(&self.target) {
+                let _ = self.umount();
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Detach the bind mount, even if it is busy (it is unmounted once no longer used)
+    fn umount(&mut self) -> Result<()> {
+        if !self.mounted {
+            return Ok(());
+        }
+        umount2(&self.target, MntFlags::MNT_DETACH)
+            .map_err(|err| eyre::eyre!("Could not unmount {}: {}", self.target.display(), err))?;
+        self.mounted = false;
+        Ok(())
+    }
+
+    /// Return the target, where the source is mounted
+    fn root(&self) -> Result<&Path> {
+        Ok(&self.target)
+    }
+
+    /// Return the source, the only layer
+    fn layers(&self) -> Vec<PathBuf> {
+        vec![self.source.clone()]
+    }
+
+}
+
+/// A host directory or file bind mounted inside the container
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BindMount {
+    /// Path in the host
+    pub source: PathBuf,
+    /// Path inside the container
+    pub destination: PathBuf,
+    /// Mount it read-only
+    pub readonly: bool,
+}
+
+/// Opens `path` relative to `root` with O_PATH, creating the missing directories.
+///
+/// The path is resolved one component at a time, and symlinks and `..` components are rejected, so a
+/// malicious container image can't redirect the path outside of `root` (e.g. with a symlink to /etc).
+/// # Arguments
+/// * `root` - Directory the path can't escape from
+/// * `path` - Path relative to `root` (a leading `/` refers to `root`)
+pub fn open_beneath(root: &Path, path: &Path) -> Result<fs::File> {
+    open_beneath_as(root, path, false)
+}
+
+/// Same as [`open_beneath`], but the last component is created as an empty file if it is missing
+/// # Arguments
+/// * `root` - Directory the path can't escape from
+/// * `path` - Path relative to `root` (a leading `/` refers to `root`)
+pub fn open_file_beneath(root: &Path, path: &Path) -> Result<fs::File> {
+    open_beneath_as(root, path, true)
+}
+
+/// Opens `path` relative to `root`, see [`open_beneath`]
+/// # Arguments
+/// * `root` - Directory the path can't escape from
+/// * `path` - Path relative to `root` (a leading `/` refers to `root`)
+/// * `file` - Create the last component as an empty file instead of a directory
+fn open_beneath_as(root: &Path, path: &Path, file: bool) -> Result<fs::File> {
+    let flags = OFlag::O_PATH | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+    // The file takes care of closing the descriptor
+    let mut current = unsafe {
+        fs::File::from_raw_fd(open(root, flags | OFlag::O_DIRECTORY, Mode::empty())?)
+    };
+    let mut components = path.components().peekable();
+    while let Some(component) = components.next() {
+        let name = match component {
+            Component::Normal(name) => name,
+            Component::RootDir | Component::CurDir => continue,
+            _ => return Err(eyre::eyre!("Path {} escapes from {}", path.display(), root.display())),
+        };
+        let fd = match openat(current.as_raw_fd(), name, flags, Mode::empty()) {
+            Err(Errno::ENOENT) if file && components.peek().is_none() => {
+                let created = openat(
+                    current.as_raw_fd(),
+                    name,
+                    OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_WRONLY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+                    Mode::from_bits_truncate(0o644),
+                )?;
+                close(created)?;
+                openat(current.as_raw_fd(), name, flags, Mode::empty())?
+            },
+            Err(Errno::ENOENT) => {
+                mkdirat(current.as_raw_fd(), name, Mode::from_bits_truncate(0o755))?;
+                openat(current.as_raw_fd(), name, flags, Mode::empty())?
+            },
+            res => res?,
+        };
+        current = unsafe { fs::File::from_raw_fd(fd) };
+        let file_type = SFlag::from_bits_truncate(fstat(fd)?.st_mode) & SFlag::S_IFMT;
+        if file_type == SFlag::S_IFLNK {
+            return Err(eyre::eyre!("Path {} contains a symlink ({:?}), refusing to follow it", path.display(), name));
+        }
+    }
+    Ok(current)
+}
+
+/// Bind mounts a host directory or file inside the container's root filesystem, before switching to it
+/// # Arguments
+/// * `root` - The container's root filesystem (in the host)
+/// * `bind` - The bind mount
+pub fn bind_mount(root: &Path, bind: &BindMount) -> Result<()> {
+    // A file can only be mounted on a file, and a directory on a directory
+    let is_dir = fs::metadata(&bind.source)
+        .map_err(|err| eyre::eyre!("Could not bind mount {}: {}", bind.source.display(), err))?
+        .is_dir();
+    let open = if is_dir { open_beneath } else { open_file_beneath };
+    // Mount on the file descriptor of the resolved destination, so it can't be swapped in the meantime
+    let destination = open(root, &bind.destination)?;
+    let target = format!("/proc/self/fd/{}", destination.as_raw_fd());
+    if bind.readonly {
+        // Read-only from the start, with the submounts, instead of remounted read-only afterwards
+        match syscall::clone_mount_readonly(&bind.source) {
+            Ok(mount_fd) => {
+                let res = syscall::move_mount_onto(mount_fd, destination.as_raw_fd());
+                close(mount_fd)?;
+                return res;
+            },
+            Err(err) if matches!(err.downcast_ref::<Errno>(), Some(Errno::ENOSYS) | Some(Errno::EPERM)) => {
+                log::debug!("Could not bind mount {} read-only at once ({}), remounting it", bind.source.display(), err);
+            },
+            Err(err) => return Err(err),
+        }
+    }
+    mount(
+        Some(&bind.source),
+        target.as_str(),
+        None::<&str>,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None::<&str>,
+    )?;
+    if bind.readonly {
+        // The descriptor still refers to the directory below the new mount, open the mount itself
+        let mounted = open(root, &bind.destination)?;
+        remount_readonly_recursive(Path::new(&format!("/proc/self/fd/{}", mounted.as_raw_fd())))?;
+    }
+    Ok(())
+}
+
+/// Bind mounts (recursively) the host's /dev inside the container's root filesystem, before switching to it
+/// # Arguments
+/// * `root` - The container's root filesystem (in the host)
+///
+/// Warning: this gives the container access to every device of the host (disks, loop devices, ...)
+/// and to the devices plugged in later. Only use it for privileged containers.
+pub fn bind_host_devices(root: &Path) -> Result<()> {
+    bind_mount(root, &BindMount {
+        source: PathBuf::from("/dev"),
+        destination: PathBuf::from("/dev"),
+        readonly: false,
+    })
+}
+
+/// Mounts a filesystem, explaining the most likely cause of the failure in the error
+/// # Arguments
+/// * `source` - Source of the mount (a device, a directory or the name of the filesystem)
+/// * `target` - Path of the mount point
+/// * `fstype` - Type of the filesystem (e.g. "overlay")
+/// * `flags` - Mount flags
+/// * `data` - Filesystem-specific options
+pub fn mount_filesystem(source: &str, target: impl AsRef<Path>, fstype: &str, flags: MountFlags, data: Option<&str>) -> Result<Mount> {
+    mount_filesystem_with_retries(source, target, fstype, flags, data, 0, Duration::ZERO)
+}
+
+/// Same as [`mount_filesystem`], retrying the mount when it fails with a transient error
+/// # Arguments
+/// * `source`, `target`, `fstype`, `flags`, `data` - Arguments of the mount
+/// * `retries` - Maximum number of retries
+/// * `backoff` - Delay before the first retry, doubled after each one
+pub fn mount_filesystem_with_retries(source: &str, target: impl AsRef<Path>, fstype: &str, flags: MountFlags, data: Option<&str>, retries: u32, backoff: Duration) -> Result<Mount> {
+    let target = target.as_ref();
+    retry_transient(retries, backoff, || {
+        Mount::new(source, target, FilesystemType::from(fstype), flags, data)
+    }).map_err(|err| {
+        let filesystems = fs::read_to_string("/proc/filesystems").unwrap_or_default();
+        let cause = mount_failure_cause(&err, source, target, fstype, data, &filesystems)
+            .or_else(|| last_kernel_message(fstype));
+        match cause {
+            Some(cause) => eyre::eyre!("Could not mount {} on {}: {} ({})", fstype, target.display(), err, cause),
+            None => eyre::eyre!("Could not mount {} on {}: {}", fstype, target.display(), err),
+        }
+    })
+}
+
+/// Returns the errors of the steps of a cleanup, which goes on after a failed step, as a single error
+/// # Arguments
+/// * `context` - What was being cleaned up
+/// * `errors` - The errors of the failed steps, if any
+fn combine_errors(context: &str, errors: Vec<eyre::Report>) -> Result<()> {
+    if errors.is_empty() {
+        return Ok(());
+    }
+    let errors: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+    Err(eyre::eyre!("{}: {}", context, errors.join("; ")))
+}
+
+/// Copies a directory tree, keeping the permissions and owners of the files, the symlinks, and the
+/// device nodes, fifos and sockets
+/// # Arguments
+/// * `source` - Directory to copy
+/// * `destination` - Path of the copy, which must not exist
+fn copy_tree(source: &Path, destination: &Path) -> Result<()> {
+    let metadata = fs::symlink_metadata(source)?;
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        fs::create_dir(destination)?;
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &destination.join(entry.file_name()))?;
+        }
+    } else if file_type.is_symlink() {
+        os::unix::fs::symlink(fs::read_link(source)?, destination)?;
+    } else if file_type.is_file() {
+        fs::copy(source, destination)?;
+    } else {
+        let kind = SFlag::from_bits_truncate(metadata.mode() & SFlag::S_IFMT.bits());
+        mknod(destination, kind, Mode::from_bits_truncate(metadata.mode()), metadata.rdev())?;
+    }
+    fchownat(
+        None,
+        destination,
+        Some(Uid::from_raw(metadata.uid())),
+        Some(Gid::from_raw(metadata.gid())),
+        FchownatFlags::NoFollowSymlink,
+    )?;
+    if !file_type.is_symlink() {
+        // Set after the owner, which clears the setuid and setgid bits
+        fs::set_permissions(destination, fs::Permissions::from_mode(metadata.mode()))?;
+    }
+    Ok(())
+}
+
+/// Runs an operation, retrying it with an exponential backoff while it fails with ELOOP or EBUSY.
+///
+/// Both are returned by overlay mounts racing with the setup of the same layers by another container,
+/// other errors (e.g. EINVAL for bad options) are permanent and returned right away. EBUSY is also
+/// returned by the unmount of a filesystem still in use.
+/// # Arguments
+/// * `retries` - Maximum number of retries
+/// * `backoff` - Delay before the first retry, doubled after each one
+/// * `operation` - The operation
+fn retry_transient<T>(retries: u32, backoff: Duration, mut operation: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut delay = backoff;
+    for attempt in 0.. {
+        match operation() {
+            Err(err) if attempt < retries && matches!(err.raw_os_error(), Some(nix::libc::ELOOP) | Some(nix::libc::EBUSY)) => {
+                log::debug!("Transient failure ({}), retrying in {:?}", err, delay);
+                thread::sleep(delay);
+                delay *= 2;
+            },
+            res => return res,
+        }
+    }
+    unreachable!()
+}
+
+/// Converts unmount flags to the flags of the umount2 syscall
+fn mnt_flags(flags: UnmountFlags) -> MntFlags {
+    let mut mnt_flags = MntFlags::empty();
+    mnt_flags.set(MntFlags::MNT_FORCE, flags.contains(UnmountFlags::FORCE));
+    mnt_flags.set(MntFlags::MNT_DETACH, flags.contains(UnmountFlags::DETACH));
+    mnt_flags.set(MntFlags::MNT_EXPIRE, flags.contains(UnmountFlags::EXPIRE));
+    mnt_flags
+}
+
+/// Guesses the cause of a failed mount from the error and the system configuration
+/// # Arguments
+/// * `err` - Error returned by the mount syscall
+/// * `source`, `target`, `fstype`, `data` - Arguments of the failed mount
+/// * `filesystems` - Contents of /proc/filesystems, the filesystems supported by the kernel
+/// # Returns
+/// A description of the likely cause, or None if there is no obvious one
+fn mount_failure_cause(err: &io::Error, source: &str, target: &Path, fstype: &str, data: Option<&str>, filesystems: &str) -> Option<String> {
+    // Each line is "[nodev]\t<fstype>"
+    let supported = filesystems.lines().any(|line| line.split_whitespace().last() == Some(fstype));
+    if !filesystems.is_empty() && !supported {
+        return Some(format!("the kernel does not support {} filesystems, is the {} module loaded? (modprobe {})", fstype, fstype, fstype));
+    }
+    let errno = Errno::from_i32(err.raw_os_error().unwrap_or(0));
+    match errno {
+        Errno::ENOENT | Errno::ENOTDIR => {
+            if !target.is_dir() {
+                return Some(format!("the mount point {} is not a directory", target.display()));
+            }
+            // The directories used by the filesystem are passed as a source or as options (e.g. lowerdir=)
+            let mut paths = vec![source];
+            if let Some(data) = data {
+                for option in data.split(',') {
+                    if let Some((_, value)) = option.split_once('=') {
+                        paths.extend(value.split(':'));
+                    }
+                }
+            }
+            paths.into_iter()
+                .find(|path| path.starts_with('/') && !Path::new(path).exists())
+                .map(|path| format!("{} does not exist", path))
+        },
+        Errno::EINVAL if fstype == "overlay" => Some(
+            "invalid overlay options: upperdir and workdir must be on the same filesystem, and \
+            upperdir can't be on another overlay".to_string()
+        ),
+        Errno::EINVAL => data.map(|data| format!("invalid options for {}: {}", fstype, data)),
+        Errno::EPERM | Errno::EACCES => Some("mounting requires CAP_SYS_ADMIN in the mount namespace".to_string()),
+        Errno::EBUSY => Some(format!("{} is busy or already mounted", target.display())),
+        _ => None,
+    }
+}
+
+/// Returns the last message logged by the kernel about a filesystem in the last few seconds (if readable)
+/// # Arguments
+/// * `fstype` - Type of the filesystem, overlayfs logs its messages as "overlayfs: ..."
+fn last_kernel_message(fstype: &str) -> Option<String> {
+    let kmsg = open("/dev/kmsg", OFlag::O_RDONLY | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC, Mode::empty()).ok()?;
+    let kmsg = unsafe { fs::File::from_raw_fd(kmsg) };
+    let now = clock_gettime(ClockId::CLOCK_MONOTONIC).ok()?;
+    let now = now.tv_sec() as u64 * 1_000_000 + now.tv_nsec() as u64 / 1000;
+    let mut message = None;
+    let mut buf = [0u8; 8192];
+    // Each read returns one record, "<priority>,<sequence>,<timestamp in us>,<flags>;<message>"
+    while let Ok(len) = nix::unistd::read(kmsg.as_raw_fd(), &mut buf) {
+        if len == 0 {
+            break;
+        }
+        let record = String::from_utf8_lossy(&buf[..len]);
+        let (header, text) = match record.split_once(';') {
+            Some(record) => record,
+            None => continue,
+        };
+        let timestamp = header.split(',').nth(2).and_then(|ts| ts.parse::<u64>().ok()).unwrap_or(0);
+        if now.saturating_sub(timestamp) < 5_000_000 && text.starts_with(fstype) {
+            message = Some(text.trim_end().to_string());
+        }
+    }
+    message
 }
 
 pub fn mount_rootfs_private() -> Result<()> {
@@ -187,56 +1225,388 @@ pub fn mount_rootfs_private() -> Result<()> {
     Ok(())
 }
 
+/// Bind mounts `mount_point` on itself as a shared mount, so a mount made on it from a mount namespace
+/// created afterwards, while its copy is still shared, propagates back to the current one
+/// # Arguments
+/// * `mount_point` - Directory to share, created if it doesn't exist
+pub fn share_mount_point(mount_point: &Path) -> Result<()> {
+    fs::create_dir_all(mount_point)?;
+    mount(Some(mount_point), mount_point, None::<&str>, MsFlags::MS_BIND, None::<&str>)?;
+    if let Err(err) = mount(None::<&str>, mount_point, None::<&str>, MsFlags::MS_SHARED, None::<&str>) {
+        let _ = umount2(mount_point, MntFlags::empty());
+        return Err(eyre::eyre!("Could not make {} a shared mount: {}", mount_point.display(), err));
+    }
+    Ok(())
+}
+
+/// Unmounts a mount point shared by `share_mount_point`, once nothing is mounted on it
+pub fn unshare_mount_point(mount_point: &Path) -> Result<()> {
+    umount2(mount_point, MntFlags::empty())
+        .map_err(|err| eyre::eyre!("Could not unmount the shared mount point {}: {}", mount_point.display(), err))
+}
+
+/// Returns the number of mounts stacked at `mount_point` in the current mount namespace (0 if it doesn't
+/// exist)
+pub fn stacked_mounts(mount_point: &Path) -> Result<usize> {
+    if !mount_point.exists() {
+        return Ok(0);
+    }
+    let mount_point = mount_point.canonicalize()?;
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    Ok(procfs::mounts_below(&mountinfo, &mount_point).iter()
+        .filter(|(path, _)| path == &mount_point)
+        .count())
+}
+
+/// Returns whether a filesystem of the given type is mounted at `target` (and not just in a parent directory)
+fn is_mounted(target: &Path, fstype: FsType) -> bool {
+    let parent = target.parent().unwrap_or(target);
+    match (fs::metadata(target), fs::metadata(parent), statfs(target)) {
+        (Ok(target_metadata), Ok(parent_metadata), Ok(stat)) => {
+            target_metadata.dev() != parent_metadata.dev() && stat.filesystem_type() == fstype
+        },
+        _ => false,
+    }
+}
+
+/// Mounts /proc, unless the procfs of the current PID namespace is already mounted there
 pub fn mount_procfs() -> Result<()> {
-   Mount::new(
+    // A procfs of another PID namespace shows other processes, /proc/self is not the current process there
+    let own_procfs = fs::read_link("/proc/self")
+        .map(|pid| pid.as_os_str() == std::process::id().to_string().as_str())
+        .unwrap_or(false);
+    if is_mounted(Path::new("/proc"), PROC_SUPER_MAGIC) && own_procfs {
+        log::debug!("/proc is already mounted");
+        return Ok(());
+    }
+   mount_filesystem(
         "proc",
         "/proc",
-        FilesystemType::from("proc"),
+        "proc",
         MountFlags::NOSUID | MountFlags::NODEV | MountFlags::NOEXEC,
         None
     )?;
     Ok(())
 }
 
+/// Mounts /sys on top of a tmpfs, even if a sysfs is already mounted there: whatever was mounted below
+/// /sys (e.g. the host's sysfs and its submounts) is masked
 pub fn mount_sysfs() -> Result<()> {
-    Mount::new(
+    mount_filesystem(
         "tmpfs",
         "/sys",
-        FilesystemType::from("tmpfs"),
+        "tmpfs",
         MountFlags::NOSUID | MountFlags::NODEV | MountFlags::NOEXEC,
         None
     )?;
-    Mount::new(
+    mount_filesystem(
         "sysfs",
         "/sys",
-        FilesystemType::from("sysfs"),
+        "sysfs",
         MountFlags::NOSUID | MountFlags::NODEV | MountFlags::NOEXEC,
         None
     )?;
     Ok(())
 }
 
-pub fn mount_devfs() -> Result<()> {
-    Mount::new(
+/// Mounts a fresh tmpfs at /dev, with the given device nodes
+/// # Arguments
+/// * `devices` - Device nodes to create, see `DeviceNode::default_devices`
+pub fn mount_devfs(devices: &[DeviceNode], shm_mode: u32) -> Result<()> {
+    // Mounted with nodev: only the given device nodes can be opened, see `create_dev_devices`
+    mount_filesystem(
         "tmpfs",
         "/dev",
-        FilesystemType::from("tmpfs"),
+        "tmpfs",
         MountFlags::NOSUID | MountFlags::NODEV | MountFlags::NOEXEC,
-        None
+        Some("mode=755")
+    )?;
+    create_dev_devices(devices, shm_mode)?;
+    Ok(())
+}
+
+/// Clones the mounts of the host's device nodes, to bind mount them where they can't be created (e.g. in a
+/// user namespace), before switching to the container's root filesystem
+/// # Arguments
+/// * `devices` - Device nodes to clone, the host's must be the same devices
+/// # Returns
+/// The detached mounts (see `syscall::clone_mount`), with the path of their device node in the container
+pub fn clone_device_nodes(devices: &[DeviceNode]) -> Result<Vec<(PathBuf, RawFd)>> {
+    let mut mounts = vec![];
+    let res = devices.iter().try_for_each(|device| {
+        let metadata = match fs::metadata(&device.path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                log::debug!("Skipping {}: it does not exist in the host", device.path.display());
+                return Ok(());
+            },
+            Err(err) => return Err(err.into()),
+        };
+        let kind = match device.kind {
+            DeviceKind::CHAR => metadata.file_type().is_char_device(),
+            DeviceKind::BLOCK => metadata.file_type().is_block_device(),
+        };
+        if !kind || metadata.rdev() != makedev(device.major, device.minor) {
+            return Err(eyre::eyre!("{} of the host is not the device {}:{}", device.path.display(), device.major, device.minor));
+        }
+        mounts.push((device.path.clone(), syscall::clone_mount(&device.path)?));
+        Ok(())
+    });
+    if let Err(err) = res {
+        for (_, mount_fd) in mounts {
+            let _ = close(mount_fd);
+        }
+        return Err(err);
+    }
+    Ok(mounts)
+}
+
+/// Bind mounts device nodes of the host in the container's /dev
+/// # Arguments
+/// * `devices` - Detached mounts of the device nodes (see `clone_device_nodes`), closed once attached
+pub fn mount_device_nodes(devices: Vec<(PathBuf, RawFd)>) -> Result<()> {
+    for (path, mount_fd) in devices {
+        // The mount point must be a file, like the device node
+        fs::File::create(&path)?;
+        syscall::move_mount(mount_fd, &path)?;
+        close(mount_fd)?;
+    }
+    Ok(())
+}
+
+/// Makes a terminal the container's /dev/console, replacing the device node of the host's console
+/// # Arguments
+/// * `console` - Detached mount of the terminal (see `syscall::clone_mount`), e.g. of the slave side of
+///   the container's pseudo-terminal. It is closed once attached
+pub fn mount_console(console: RawFd) -> Result<()> {
+    let path = Path::new("/dev/console");
+    // The device node is bind mounted on itself (see `create_dev_devices`), or the host's with `userns`
+    match umount2(path, MntFlags::MNT_DETACH) {
+        Ok(()) | Err(Errno::EINVAL) | Err(Errno::ENOENT) => {},
+        Err(err) => return Err(err.into()),
+    }
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {},
+    }
+    // The mount point must be a file, like the terminal
+    fs::File::create(path)?;
+    syscall::move_mount(console, &path)?;
+    close(console)?;
+    Ok(())
+}
+
+/// Attaches a detached mount (see `syscall::clone_mount`) read-only at a directory
+/// # Arguments
+/// * `mount_fd` - The detached mount, closed once attached
+/// * `target` - Path of the mount point, created if it does not exist
+pub fn attach_readonly(mount_fd: RawFd, target: &Path) -> Result<()> {
+    if !target.exists() {
+        fs::create_dir_all(target)?;
+    }
+    syscall::move_mount(mount_fd, &target)?;
+    close(mount_fd)?;
+    mount(
+        None::<&str>,
+        target,
+        None::<&str>,
+        MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        None::<&str>,
+    )?;
+    Ok(())
+}
+
+/// Makes a file or directory (and everything below it) read-only, by bind mounting it on itself
+/// # Arguments
+/// * `path` - The path, ignored if it does not exist
+pub fn remount_readonly(path: &Path) -> Result<()> {
+    if !path.exists() {
+        log::debug!("Skipping read-only path {}: it does not exist", path.display());
+        return Ok(());
+    }
+    mount(Some(path), path, None::<&str>, MsFlags::MS_BIND | MsFlags::MS_REC, None::<&str>)?;
+    mount(
+        None::<&str>,
+        path,
+        None::<&str>,
+        MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        None::<&str>,
+    )?;
+    Ok(())
+}
+
+/// Mounts the cgroup v2 hierarchy of the current cgroup namespace at /sys/fs/cgroup, writable, unless it is
+/// already mounted there
+pub fn mount_cgroupfs() -> Result<()> {
+    if is_mounted(Path::new("/sys/fs/cgroup"), CGROUP2_SUPER_MAGIC) {
+        log::debug!("/sys/fs/cgroup is already mounted");
+        return Ok(());
+    }
+    // nsdelegate makes the cgroup namespace a delegation boundary (only applied from the initial namespace)
+    mount_filesystem(
+        "cgroup2",
+        "/sys/fs/cgroup",
+        "cgroup2",
+        MountFlags::NOSUID | MountFlags::NODEV | MountFlags::NOEXEC,
+        Some("nsdelegate")
+    )?;
+    Ok(())
+}
+
+/// Mounts a fresh tmpfs at `target`
+/// # Arguments
+/// * `target` - Path of the mount point, created if it does not exist
+/// * `mode` - Permissions of the root of the tmpfs, e.g. 0o1777 to make it writable by every user
+/// * `size` - Size of the tmpfs, e.g. "64m" (default: half of the RAM)
+pub fn mount_tmpfs(target: &impl AsRef<Path>, mode: u32, size: Option<&str>) -> Result<()> {
+    let target = target.as_ref();
+    if !target.exists() {
+        fs::create_dir_all(target)?;
+    }
+    let data = match size {
+        Some(size) => format!("mode={:o},size={}", mode, size),
+        None => format!("mode={:o}", mode),
+    };
+    mount_filesystem(
+        "tmpfs",
+        target,
+        "tmpfs",
+        MountFlags::NOSUID | MountFlags::NODEV,
+        Some(data.as_str())
+    )?;
+    Ok(())
+}
+
+/// Type of a device node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeviceKind {
+    /// Character device
+    CHAR,
+    /// Block device
+    BLOCK,
+}
+
+/// A device node created in the container's /dev
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceNode {
+    /// Path of the device node, e.g. /dev/null
+    pub path: PathBuf,
+    /// Type of the device
+    pub kind: DeviceKind,
+    /// Major number of the device
+    pub major: u64,
+    /// Minor number of the device
+    pub minor: u64,
+    /// Permissions of the device node, e.g. 0o666
+    pub mode: u32,
+}
+
+impl DeviceNode {
+
+    /// A character device node
+    /// # Arguments
+    /// * `path` - Path of the device node
+    /// * `major` - Major number of the device
+    /// * `minor` - Minor number of the device
+    /// * `mode` - Permissions of the device node
+    pub fn char(path: &str, major: u64, minor: u64, mode: u32) -> Self {
+        DeviceNode { path: PathBuf::from(path), kind: DeviceKind::CHAR, major, minor, mode }
+    }
+
+    /// The standard device nodes of a container: /dev/null, /dev/zero, /dev/full, /dev/random,
+    /// /dev/urandom, /dev/tty and /dev/console
+    pub fn default_devices() -> Vec<DeviceNode> {
+        vec![
+            DeviceNode::char("/dev/null", 1, 3, 0o666),
+            DeviceNode::char("/dev/zero", 1, 5, 0o666),
+            DeviceNode::char("/dev/full", 1, 7, 0o666),
+            DeviceNode::char("/dev/random", 1, 8, 0o666),
+            DeviceNode::char("/dev/urandom", 1, 9, 0o666),
+            DeviceNode::char("/dev/tty", 5, 0, 0o666),
+            DeviceNode::char("/dev/console", 5, 1, 0o600),
+        ]
+    }
+
+    /// Creates the device node, only if the kernel provides that device
+    pub fn create(&self) -> Result<()> {
+        // The devices registered by the kernel are listed in sysfs (if it is mounted)
+        let (sysfs, file_type) = match self.kind {
+            DeviceKind::CHAR => (PathBuf::from("/sys/dev/char"), SFlag::S_IFCHR),
+            DeviceKind::BLOCK => (PathBuf::from("/sys/dev/block"), SFlag::S_IFBLK),
+        };
+        if sysfs.exists() && !sysfs.join(format!("{}:{}", self.major, self.minor)).exists() {
+            log::debug!("Skipping {}: device {}:{} is not available", self.path.display(), self.major, self.minor);
+            return Ok(());
+        }
+        mknod(&self.path, file_type, Mode::from_bits_truncate(self.mode), makedev(self.major, self.minor))?;
+        // The mode given to mknod is masked by the umask
+        fs::set_permissions(&self.path, fs::Permissions::from_mode(self.mode))?;
+        Ok(())
+    }
+
+}
+
+/// Creates a character device node, only if the kernel provides that device
+/// # Arguments
+/// * `path` - Path of the device node
+/// * `mode` - Permissions of the device node
+/// * `major` - Major number of the device
+/// * `minor` - Minor number of the device
+pub fn create_char_device(path: &impl AsRef<Path>, mode: Mode, major: u64, minor: u64) -> Result<()> {
+    DeviceNode {
+        path: path.as_ref().to_path_buf(),
+        kind: DeviceKind::CHAR,
+        major,
+        minor,
+        mode: mode.bits(),
+    }.create()
+}
+
+/// Creates a symlink, only if its target exists
+/// # Arguments
+/// * `target` - Path the symlink points to
+/// * `link` - Path of the symlink
+pub fn create_symlink(target: &impl AsRef<Path>, link: &impl AsRef<Path>) -> Result<()> {
+    if !target.as_ref().exists() {
+        log::debug!("Skipping {}: {} does not exist", link.as_ref().display(), target.as_ref().display());
+        return Ok(());
+    }
+    os::unix::fs::symlink(target, link)?;
+    Ok(())
+}
+
+/// Makes a device node of a filesystem mounted with nodev usable, by bind mounting it on itself without nodev
+/// # Arguments
+/// * `path` - The device node
+fn allow_device_node(path: &Path) -> Result<()> {
+    mount(Some(path), path, None::<&str>, MsFlags::MS_BIND, None::<&str>)?;
+    // A bind mount keeps the flags of the filesystem until remounted
+    mount(
+        None::<&str>,
+        path,
+        None::<&str>,
+        MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+        None::<&str>,
     )?;
-    create_dev_devices()?;
     Ok(())
 }
 
-pub fn create_dev_devices() -> Result<()> {
-    // Create some special devices
-    mknod("/dev/null", SFlag::S_IFCHR, Mode::S_IRGRP, makedev(1, 3))?;
-    mknod("/dev/zero", SFlag::S_IFCHR, Mode::S_IRGRP, makedev(1, 5))?;
-    mknod("/dev/full", SFlag::S_IFCHR, Mode::S_IRGRP, makedev(1, 7))?;
-    mknod("/dev/random", SFlag::S_IFCHR, Mode::S_IRGRP, makedev(1, 8))?;
-    mknod("/dev/urandom", SFlag::S_IFCHR, Mode::S_IRGRP, makedev(1, 9))?;
-    mknod("/dev/tty", SFlag::S_IFCHR, Mode::S_IRUSR, makedev(5, 0))?;
-    mknod("/dev/console", SFlag::S_IFCHR, Mode::S_IRUSR, makedev(5, 1))?;
+/// Populates /dev: the device nodes, the standard symlinks, /dev/mqueue, /dev/pts and /dev/shm
+/// # Arguments
+/// * `devices` - Device nodes to create, each one bind mounted on itself without nodev (/dev is mounted
+///   with nodev, so the device nodes created later in the container can't be opened)
+/// * `shm_mode` - Permissions of /dev/shm (e.g. 0o1777), mounted nosuid, nodev and noexec
+pub fn create_dev_devices(devices: &[DeviceNode], shm_mode: u32) -> Result<()> {
+    if shm_mode & !0o7777 != 0 {
+        return Err(eyre::eyre!("Invalid mode {:o} for /dev/shm", shm_mode));
+    }
+    for device in devices {
+        device.create()?;
+        // Not created if the kernel doesn't provide the device
+        if fs::symlink_metadata(&device.path).is_ok() {
+            allow_device_node(&device.path)?;
+        }
+    }
     // Create stdin, stdout and stderr
     os::unix::fs::symlink(
         "/proc/self/fd/0",
@@ -250,11 +1620,8 @@ pub fn create_dev_devices() -> Result<()> {
         "/proc/self/fd/2",
         "/dev/stderr"
     )?;
-    // Crete /dev/core
-    os::unix::fs::symlink(
-        "/proc/kcore",
-        "/dev/core"
-    )?;
+    // Crete /dev/core (the kernel may be built without /proc/kcore)
+    create_symlink(&"/proc/kcore", &"/dev/core")?;
     // Create /dev/fd
     os::unix::fs::symlink(
         "/proc/self/fd",
@@ -262,20 +1629,20 @@ pub fn create_dev_devices() -> Result<()> {
     )?;
     // Create /dev/mqueue
     fs::create_dir("/dev/mqueue")?;
-    Mount::new(
+    mount_filesystem(
         "mqueue",
         "/dev/mqueue",
-        FilesystemType::from("mqueue"),
+        "mqueue",
         MountFlags::NOSUID | MountFlags::NODEV | MountFlags::NOEXEC,
         None
     )?;
     // Create /dev/pts
     fs::create_dir("/dev/pts")?;    
     // Mount /dev/pts
-    Mount::new(
+    mount_filesystem(
         "devpts",
         "/dev/pts",
-        FilesystemType::from("devpts"),
+        "devpts",
         MountFlags::NOSUID | MountFlags::NODEV | MountFlags::NOEXEC,
         Some("newinstance,ptmxmode=0666,mode=0620")
     )?;
@@ -285,22 +1652,27 @@ pub fn create_dev_devices() -> Result<()> {
     )?;
     // Create /dev/shm
     fs::create_dir("/dev/shm")?;
-    // Mount /dev/shm
-    Mount::new(
+    // Mount /dev/shm, without setuid binaries nor device nodes
+    mount_filesystem(
         "shm",
         "/dev/shm",
-        FilesystemType::from("tmpfs"),
+        "tmpfs",
         MountFlags::NOSUID | MountFlags::NODEV | MountFlags::NOEXEC,
-        Some("mode=1777,size=65536k")
+        Some(format!("mode={:o},size=65536k", shm_mode).as_str())
     )?;
     Ok(())
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
     use std::path::PathBuf;
     use std::fs;
     use std::env;
+    use nix::sched::{unshare, CloneFlags};
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+    use std::os::unix::ffi::OsStrExt;
 
     #[test]
     fn test_overlay_filesystem_mount() {
@@ -314,4 +1686,438 @@ mod tests {
         fs.umount().unwrap();
         fs::remove_dir_all(target);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_stacked_overlay_discard_session() {
+        let tests = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests");
+        let image = tests.join("test_stacked_image");
+        let base = tests.join("test_stacked_base");
+        let session = tests.join("test_stacked_session");
+        fs::create_dir_all(&image).unwrap();
+        let mut fs = StackedOverlayDriver::new(vec![&image], &base, &session);
+        // The base overlay can't be changed once it is the lowerdir of the session overlay
+        fs.base.mount().unwrap();
+        fs::write(fs.base_root().unwrap().join("base-file"), "persists").unwrap();
+        fs.session.mount().unwrap();
+        fs::write(fs.root().unwrap().join("session-file"), "discarded").unwrap();
+        fs.discard_session().unwrap();
+        assert!(fs.root().unwrap().join("base-file").exists());
+        assert!(!fs.root().unwrap().join("session-file").exists());
+        fs.umount().unwrap();
+        for dir in [image, base, session] {
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_discard_session_combines_errors() {
+        let tests = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests");
+        let image = tests.join("test_cleanup_image");
+        let base = tests.join("test_cleanup_base");
+        let readonly = tests.join("test_cleanup_readonly");
+        let session = readonly.join("session");
+        fs::create_dir_all(&image).unwrap();
+        mount_tmpfs(&readonly, 0o755, None).unwrap();
+        let mut fs = StackedOverlayDriver::new(vec![&image], &base, &session);
+        fs.mount().unwrap();
+        // The upperdir and workdir of the session can't be removed anymore
+        mount(Some(&readonly), &readonly, None::<&str>, MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY, None::<&str>).unwrap();
+        let err = fs.discard_session().unwrap_err().to_string();
+        assert!(err.contains(&session.join("upper").display().to_string()), "{}", err);
+        assert!(err.contains(&session.join("workdir").display().to_string()), "{}", err);
+        // The session was unmounted anyway
+        let merge = session.join("merge").display().to_string();
+        assert!(!fs::read_to_string("/proc/self/mounts").unwrap().lines()
+            .any(|line| line.split_whitespace().nth(1) == Some(merge.as_str())));
+        fs.base.umount().unwrap();
+        umount2(&readonly, MntFlags::MNT_DETACH).unwrap();
+        for dir in [image, base, readonly] {
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_bind_mount_rejects_symlink_escape() {
+        let root = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_bind_root");
+        fs::create_dir_all(&root).unwrap();
+        os::unix::fs::symlink("/tmp", root.join("escape")).unwrap();
+        let bind = BindMount {
+            source: PathBuf::from("/tmp"),
+            destination: PathBuf::from("/escape/data"),
+            readonly: false,
+        };
+        assert!(bind_mount(&root, &bind).is_err());
+        assert!(!PathBuf::from("/tmp/data").exists());
+        assert!(open_beneath(&root, Path::new("/../etc")).is_err());
+        open_beneath(&root, Path::new("/created/nested")).unwrap();
+        assert!(root.join("created/nested").is_dir());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_missing_lowerdir() {
+        let tests = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests");
+        let target = tests.join("test_missing_lowerdir");
+        let missing = tests.join("test_missing_lowerdir_layer");
+        let mut fs = OverlayDriver::new(vec![&PathBuf::from("/"), &missing, &PathBuf::from("/does-not-exist")], &target);
+        let err = fs.mount().unwrap_err().to_string();
+        assert!(err.contains(&missing.display().to_string()), "{}", err);
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn test_layers() {
+        let fs = OverlayDriver::new(vec![&"/layers/top", &"/layers/middle", &"/layers/bottom"], &"/overlay");
+        let lowers = [PathBuf::from("/layers/top"), PathBuf::from("/layers/middle"), PathBuf::from("/layers/bottom")];
+        assert_eq!(fs.lowerdirs(), &lowers);
+        let layers = fs.layers();
+        assert_eq!(layers[0], PathBuf::from("/overlay/upper"));
+        assert_eq!(layers[1..], lowers);
+        let stacked = StackedOverlayDriver::new(vec![&"/layers/top"], &"/base", &"/session");
+        assert_eq!(stacked.layers(), vec![
+            PathBuf::from("/session/upper"),
+            PathBuf::from("/base/upper"),
+            PathBuf::from("/layers/top"),
+        ]);
+    }
+
+    #[test]
+    fn test_layers_bottom_up() {
+        let tests = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests");
+        let base = tests.join("test_layers_bottom_up_base");
+        let update = tests.join("test_layers_bottom_up_update");
+        for (layer, version) in [(&base, "1"), (&update, "2")] {
+            fs::create_dir_all(layer).unwrap();
+            fs::write(layer.join("version"), version).unwrap();
+        }
+        fs::write(base.join("base"), "base").unwrap();
+        for (name, mut fs) in [
+            ("test_layers_top_down", OverlayDriver::new(vec![&update, &base], &tests.join("test_layers_top_down"))),
+            ("test_layers_bottom_up", OverlayDriver::with_layers_bottom_up(vec![&base, &update], &tests.join("test_layers_bottom_up"))),
+        ] {
+            assert_eq!(fs.lowerdirs(), &[update.clone(), base.clone()]);
+            fs.mount().unwrap();
+            let merge = tests.join(name).join(OverlayDriver::MERGE_DIR);
+            // The newer layer shadows the base, which still provides the files it does not have
+            assert_eq!(fs::read_to_string(merge.join("version")).unwrap(), "2");
+            assert_eq!(fs::read_to_string(merge.join("base")).unwrap(), "base");
+            fs.umount().unwrap();
+            fs::remove_dir_all(tests.join(name)).unwrap();
+        }
+        fs::remove_dir_all(base).unwrap();
+        fs::remove_dir_all(update).unwrap();
+    }
+
+    #[test]
+    fn test_bind_mount_driver() {
+        let tests = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests");
+        let source = tests.join("test_bind_mount_driver_source");
+        let target = tests.join("test_bind_mount_driver");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("file"), "data").unwrap();
+        let mut fs = BindMountDriver::new(&source, &target, true);
+        fs.mount().unwrap();
+        assert_eq!(fs.root().unwrap(), target);
+        assert_eq!(fs::read_to_string(target.join("file")).unwrap(), "data");
+        let err = fs::write(target.join("file"), "changed").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(nix::libc::EROFS));
+        fs.umount().unwrap();
+        assert!(!target.join("file").exists());
+        fs::remove_dir_all(target).unwrap();
+        fs::remove_dir_all(source).unwrap();
+    }
+
+    #[test]
+    fn test_readonly_bind_mount_recursive() {
+        let tests = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests");
+        let source = tests.join("test_rbind_source");
+        let root = tests.join("test_rbind_root");
+        fs::create_dir_all(source.join("nested")).unwrap();
+        fs::create_dir_all(&root).unwrap();
+        mount_tmpfs(&source.join("nested"), 0o755, None).unwrap();
+        bind_mount(&root, &BindMount {
+            source: source.clone(),
+            destination: PathBuf::from("/data"),
+            readonly: true,
+        }).unwrap();
+        for dir in [root.join("data"), root.join("data/nested")] {
+            let err = fs::write(dir.join("file"), "").unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(nix::libc::EROFS), "{}", dir.display());
+        }
+        // The source itself stays writable
+        fs::write(source.join("nested/file"), "").unwrap();
+        umount2(&root.join("data"), MntFlags::MNT_DETACH).unwrap();
+        umount2(&source.join("nested"), MntFlags::MNT_DETACH).unwrap();
+        for dir in [source, root] {
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_skip_unavailable_devices() {
+        let dev = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_dev");
+        fs::create_dir_all(&dev).unwrap();
+        create_symlink(&"/proc/does-not-exist", &dev.join("core")).unwrap();
+        assert!(fs::symlink_metadata(dev.join("core")).is_err());
+        if PathBuf::from("/sys/dev/char").exists() {
+            create_char_device(&dev.join("missing"), Mode::S_IRUSR, 4095, 4095).unwrap();
+            assert!(fs::symlink_metadata(dev.join("missing")).is_err());
+        }
+        create_char_device(&dev.join("null"), Mode::S_IRUSR, 1, 3).unwrap();
+        assert!(dev.join("null").exists());
+        fs::remove_dir_all(dev).unwrap();
+    }
+
+    #[test]
+    fn test_bind_host_devices() {
+        let root = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_host_dev");
+        fs::create_dir_all(&root).unwrap();
+        // Mount in a child process, with its own mount namespace
+        match unsafe { fork().unwrap() } {
+            ForkResult::Child => {
+                let res = unshare(CloneFlags::CLONE_NEWNS)
+                    .map_err(eyre::Report::from)
+                    .and_then(|_| mount_rootfs_private())
+                    .and_then(|_| bind_host_devices(&root));
+                let visible = |dir: &Path| fs::read_dir(dir).map(|entries| entries.count()).unwrap_or(0);
+                let code = match res {
+                    Ok(_) if visible(&root.join("dev")) == visible(Path::new("/dev")) => 0,
+                    _ => 1,
+                };
+                unsafe { nix::libc::_exit(code) };
+            },
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            },
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_mount_tmpfs_hides_previous_contents() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_tmp");
+        fs::create_dir_all(&target).unwrap();
+        fs::write(target.join("stale"), "stale state").unwrap();
+        mount_tmpfs(&target, 0o1777, Some("1m")).unwrap();
+        assert_eq!(fs::read_dir(&target).unwrap().count(), 0);
+        umount2(&target, MntFlags::MNT_DETACH).unwrap();
+        assert!(target.join("stale").exists());
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn test_overlay_cleans_stale_workdir() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_stale_workdir");
+        // Leftovers of an unclean shutdown, and a change of the container
+        let workdir = target.join(OverlayDriver::WORK_DIR);
+        fs::create_dir_all(workdir.join("work/incompat/volatile")).unwrap();
+        fs::write(workdir.join("work/incompat/volatile/junk"), "junk").unwrap();
+        fs::write(workdir.join("junk"), "junk").unwrap();
+        fs::create_dir_all(target.join(OverlayDriver::UPPER_DIR)).unwrap();
+        fs::write(target.join(OverlayDriver::UPPER_DIR).join("data"), "data").unwrap();
+        // No process is left in the namespace of the previous mount
+        fs::write(target.join(OverlayDriver::OWNER_FILE), "mnt:[0]").unwrap();
+        let mut fs = OverlayDriver::new(vec![&"/tmp"], &target);
+        fs.mount().unwrap();
+        assert!(!workdir.join("junk").exists());
+        assert!(!workdir.join("work/incompat").exists());
+        assert_eq!(fs::read_to_string(target.join(OverlayDriver::MERGE_DIR).join("data")).unwrap(), "data");
+        fs.umount().unwrap();
+        assert!(!target.join(OverlayDriver::OWNER_FILE).exists());
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn test_overlay_keeps_owned_workdir() {
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_owned_workdir");
+        let workdir = target.join(OverlayDriver::WORK_DIR);
+        let mergedir = target.join(OverlayDriver::MERGE_DIR);
+        let owner = target.join(OverlayDriver::OWNER_FILE);
+        fs::create_dir_all(&workdir).unwrap();
+        fs::create_dir_all(&mergedir).unwrap();
+        fs::write(workdir.join("junk"), "junk").unwrap();
+        // Without an owner, the overlay may be mounted anywhere
+        OverlayDriver::clean_workdir(&workdir, &mergedir, &owner).unwrap();
+        assert!(workdir.join("junk").exists());
+        // Still mounted in a namespace with processes, here the test's own
+        fs::write(&owner, fs::read_link("/proc/self/ns/mnt").unwrap().to_string_lossy().as_bytes()).unwrap();
+        OverlayDriver::clean_workdir(&workdir, &mergedir, &owner).unwrap();
+        assert!(workdir.join("junk").exists());
+        fs::write(&owner, "mnt:[0]").unwrap();
+        OverlayDriver::clean_workdir(&workdir, &mergedir, &owner).unwrap();
+        assert!(!workdir.join("junk").exists());
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn test_umount_busy_overlay() {
+        let tests = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests");
+        let target = tests.join("test_umount_busy");
+        // A lower layer nothing else writes to, unlike the host's /tmp
+        let lower = tests.join("test_umount_busy_lower");
+        fs::create_dir_all(&lower).unwrap();
+        let mut fs = OverlayDriver::new(vec![&lower], &target)
+            .with_umount_retries(1, Duration::from_millis(10), false);
+        fs.mount().unwrap();
+        let held = fs::File::create(fs.root().unwrap().join("held")).unwrap();
+        // Without DETACH, the busy overlay is reported and stays mounted
+        let err = fs.umount().unwrap_err();
+        assert!(err.to_string().contains("busy"), "{}", err);
+        assert!(fs.root().is_ok());
+        // With DETACH, it is lazily unmounted while the file is still open
+        let mut fs = fs.with_umount_flags(UnmountFlags::DETACH);
+        fs.umount().unwrap();
+        assert!(!target.join(OverlayDriver::MERGE_DIR).join("held").exists());
+        drop(held);
+        fs::remove_dir_all(target).unwrap();
+        fs::remove_dir_all(lower).unwrap();
+    }
+
+    #[test]
+    fn test_retry_transient_mount_failure() {
+        let mut attempts = 0;
+        let res = retry_transient(3, Duration::from_millis(1), || {
+            attempts += 1;
+            if attempts == 1 {
+                Err(io::Error::from_raw_os_error(nix::libc::ELOOP))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(res.unwrap(), 2);
+        // Permanent errors are not retried
+        let mut attempts = 0;
+        let res: io::Result<()> = retry_transient(3, Duration::from_millis(1), || {
+            attempts += 1;
+            Err(io::Error::from_raw_os_error(nix::libc::EINVAL))
+        });
+        assert!(res.is_err());
+        assert_eq!(attempts, 1);
+        // Transient errors are returned once the retries are exhausted
+        let mut attempts = 0;
+        let res: io::Result<()> = retry_transient(2, Duration::from_millis(1), || {
+            attempts += 1;
+            Err(io::Error::from_raw_os_error(nix::libc::EBUSY))
+        });
+        assert!(res.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn test_mount_failure_cause_missing_overlay_module() {
+        // /proc/filesystems of a kernel without the overlay module loaded
+        let filesystems = "nodev\tsysfs\nnodev\ttmpfs\nnodev\tproc\n\text4\n";
+        let err = io::Error::from_raw_os_error(nix::libc::ENODEV);
+        let cause = mount_failure_cause(&err, "overlay", Path::new("/tmp"), "overlay", None, filesystems).unwrap();
+        assert!(cause.contains("does not support overlay"));
+        assert!(cause.contains("modprobe overlay"));
+        // With the module loaded, a missing lowerdir is reported instead
+        let filesystems = "nodev\toverlay\n";
+        let err = io::Error::from_raw_os_error(nix::libc::ENOENT);
+        let data = "lowerdir=/nonexistent/layer,upperdir=/tmp,workdir=/tmp";
+        let cause = mount_failure_cause(&err, "overlay", Path::new("/tmp"), "overlay", Some(data), filesystems).unwrap();
+        assert_eq!(cause, "/nonexistent/layer does not exist");
+    }
+
+    #[test]
+    fn test_overlay_selinux_label() {
+        let label = "system_u:object_r:container_file_t:s0:c1,c2";
+        let layers = vec![PathBuf::from("/lower")];
+        let options = overlay_options(&layers, Path::new("/upper"), Path::new("/work"), false, Some(label));
+        assert!(options.ends_with(",context=\"system_u:object_r:container_file_t:s0:c1,c2\""));
+        // Only on SELinux hosts
+        if !Path::new("/sys/fs/selinux/enforce").exists() {
+            return;
+        }
+        let target = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_selinux_label");
+        let mut fs = OverlayDriver::new(vec![&"/tmp"], &target).with_selinux_label(label);
+        fs.mount().unwrap();
+        let merge = target.join(OverlayDriver::MERGE_DIR);
+        fs::write(merge.join("file"), "data").unwrap();
+        let context = xattr_value(&merge.join("file"), "security.selinux");
+        fs.umount().unwrap();
+        fs::remove_dir_all(target).unwrap();
+        assert_eq!(context.trim_end_matches('\0'), label);
+    }
+
+    /// Reads an extended attribute of a file
+    fn xattr_value(path: &Path, name: &str) -> String {
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        let name = std::ffi::CString::new(name).unwrap();
+        let mut value = [0u8; 256];
+        let len = unsafe {
+            nix::libc::getxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr() as *mut nix::libc::c_void, value.len())
+        };
+        assert!(len >= 0, "Could not read the extended attribute: {}", Errno::last());
+        String::from_utf8_lossy(&value[..len as usize]).into_owned()
+    }
+
+    #[test]
+    fn test_overlay_userxattr_in_user_namespace() {
+        let layers = vec![PathBuf::from("/lower")];
+        let options = overlay_options(&layers, Path::new("/upper"), Path::new("/work"), syscall::in_user_namespace(), None);
+        assert!(!options.contains("userxattr"));
+        match unsafe { fork().unwrap() } {
+            ForkResult::Child => {
+                let ok = unshare(CloneFlags::CLONE_NEWUSER).is_ok()
+                    && syscall::in_user_namespace()
+                    && overlay_options(&layers, Path::new("/upper"), Path::new("/work"), syscall::in_user_namespace(), None)
+                        .ends_with(",userxattr");
+                unsafe { nix::libc::_exit(if ok { 0 } else { 1 }) };
+            },
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            },
+        }
+    }
+
+    #[test]
+    fn test_mount_procfs_already_mounted() {
+        let mounts_at = |path: &str| fs::read_to_string("/proc/self/mounts").unwrap().lines()
+            .filter(|line| line.split_whitespace().nth(1) == Some(path))
+            .count();
+        let (proc_before, sys_before) = (mounts_at("/proc"), mounts_at("/sys"));
+        // In a mount namespace of its own, in case it mounts /proc again, with the PID namespace of the host
+        let namespaces = syscall::Namespaces { uts: false, ipc: false, pid: false, net: false, time: None };
+        let pid = syscall::create_container(|| {
+            let ok = mount_rootfs_private().is_ok()
+                && mount_procfs().is_ok()
+                && mounts_at("/proc") == proc_before
+                // Masked even though a sysfs may already be mounted
+                && mount_sysfs().is_ok()
+                && mounts_at("/sys") == sys_before + 2;
+            if ok { 0 } else { 1 }
+        }, &namespaces, false, 125).unwrap();
+        assert_eq!(waitpid(pid, None).unwrap(), WaitStatus::Exited(pid, 0));
+    }
+
+    #[test]
+    fn test_lower_cache_shares_layers() {
+        let base = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_lower_cache");
+        let (top, bottom) = (base.join("top"), base.join("bottom"));
+        fs::create_dir_all(&top).unwrap();
+        fs::create_dir_all(&bottom).unwrap();
+        fs::write(top.join("file"), "top").unwrap();
+        fs::write(bottom.join("file"), "bottom").unwrap();
+        let cache = LowerCache::new(&base.join("lower"));
+        let lower = cache.get(vec![&top, &bottom]).unwrap();
+        let mut first = OverlayDriver::with_shared_lower(cache.get(vec![&top, &bottom]).unwrap(), &base.join("first"));
+        let mut second = OverlayDriver::with_shared_lower(cache.get(vec![&top, &bottom]).unwrap(), &base.join("second"));
+        // Mounted once, used by both containers
+        assert_eq!(Arc::strong_count(&lower), 3);
+        assert_eq!(fs::read_dir(base.join("lower")).unwrap().count(), 1);
+        first.mount().unwrap();
+        second.mount().unwrap();
+        assert_eq!(fs::read_to_string(first.root().unwrap().join("file")).unwrap(), "top");
+        assert_eq!(fs::read_to_string(second.root().unwrap().join("file")).unwrap(), "top");
+        first.umount().unwrap();
+        second.umount().unwrap();
+        drop(first);
+        drop(second);
+        assert_eq!(Arc::strong_count(&lower), 1);
+        let path = lower.path().to_path_buf();
+        drop(lower);
+        assert!(!path.exists());
+        fs::remove_dir_all(base).unwrap();
+    }
+}
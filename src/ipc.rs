@@ -21,28 +21,115 @@
  * THE SOFTWARE.
  */
 
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
 use color_eyre::{Result, eyre};
-use ipc_channel::{self, ipc::{IpcSender, IpcReceiver}};
+use ipc_channel::{self, ipc::{IpcError, IpcSender, IpcReceiver, TryRecvError}};
+use nix::errno::Errno;
+use nix::libc::c_int;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::Signal;
+use nix::sys::socket::{recv, socketpair, AddressFamily, MsgFlags, SockFlag, SockType};
+use nix::fcntl::OFlag;
+use nix::unistd::{close, pipe2, read, write};
 use serde::{Serialize, Deserialize};
 
+use crate::seccomp::SeccompRule;
 use crate::syscall::Command; 
 
+/// Serialization format and transport of the messages
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum IpcFormat {
+    /// bincode over `ipc_channel`, tied to the serde representation of the Rust types
+    #[default]
+    BINCODE,
+    /// JSON over a unix socket (SOCK_SEQPACKET), one message per packet, which other languages or versions
+    /// of the library can read. Messages are limited to the size of the socket buffer (net.core.wmem_default)
+    JSON,
+}
+
 /// Creates the IPC channel pairs (producer, consumer)
 /// # Returns
 /// A tuple containing the producer and consumer channels
 pub fn create_ipc_channels() -> Result<(ProducerChannel, ConsumerChannel)> {
-    let (inner_sender, inner_receiver) = ipc_channel::ipc::channel::<Message>()?;
-    Ok((ProducerChannel{inner_sender}, ConsumerChannel{inner_receiver}))
+    create_ipc_channels_with_format(IpcFormat::BINCODE, None)
+}
+
+/// Creates IPC channel pairs (producer, consumer) that hold at most `capacity` messages in flight
+///
+/// Once `capacity` messages have been sent and not yet received by the container, `ProducerChannel::send`
+/// blocks until the container receives one of them. This bounds the memory used by a fast producer, at
+/// the cost of an acknowledgement message per message received, and of a producer that may block
+/// forever if the container stops receiving messages.
+/// # Arguments
+/// * `capacity` - Maximum number of messages in flight (at least 1)
+/// # Returns
+/// A tuple containing the producer and consumer channels
+pub fn create_bounded_ipc_channels(capacity: usize) -> Result<(ProducerChannel, ConsumerChannel)> {
+    create_ipc_channels_with_format(IpcFormat::BINCODE, Some(capacity))
+}
+
+/// Creates IPC channel pairs (producer, consumer) using the given format
+/// # Arguments
+/// * `format` - Serialization format and transport of the messages
+/// * `capacity` - Maximum number of messages in flight, see `create_bounded_ipc_channels` (None: unbounded)
+/// # Returns
+/// A tuple containing the producer and consumer channels
+pub fn create_ipc_channels_with_format(format: IpcFormat, capacity: Option<usize>) -> Result<(ProducerChannel, ConsumerChannel)> {
+    let (inner_sender, inner_receiver) = match format {
+        IpcFormat::BINCODE => {
+            let (sender, receiver) = ipc_channel::ipc::channel::<Message>()?;
+            (Sender::BINCODE(sender), Receiver::BINCODE(receiver))
+        },
+        IpcFormat::JSON => {
+            let (sender, receiver) = JsonSocket::pair()?;
+            (Sender::JSON(sender), Receiver::JSON(receiver))
+        },
+    };
+    let (flow_control, ack_sender) = match capacity {
+        Some(0) => return Err(eyre::eyre!("The capacity of the IPC channel must be at least 1")),
+        Some(capacity) => {
+            let (ack_sender, ack_receiver) = ipc_channel::ipc::channel::<()>()?;
+            let flow_control = FlowControl {
+                capacity,
+                in_flight: Cell::new(0),
+                ack_receiver,
+            };
+            (Some(flow_control), Some(ack_sender))
+        },
+        None => (None, None),
+    };
+    Ok((
+        ProducerChannel{inner_sender, flow_control},
+        ConsumerChannel{inner_receiver, ack_sender}
+    ))
 }
 
 /// Actions that can be performed by the container
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Action {
-    STOP
+    STOP,
+    /// Install a seccomp filter in the runtime, with these rules (default: allow), applying to the
+    /// commands executed afterwards
+    SECCOMP(Vec<SeccompRule>),
+    /// Send a signal to the process group of that name (see `Command::process_group`), or to every process
+    /// of the container but the runtime if none
+    SIGNAL {
+        group: Option<String>,
+        #[serde(with = "crate::runtime::signal_name")]
+        signal: Signal,
+    },
 }
 
 /// A message to be sent to the container
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
     /// Action to be performed by the container
     ACTION(Action),
@@ -50,9 +137,244 @@ pub enum Message {
     COMMAND(Command)
 }
 
+/// Bookkeeping of the messages sent and not yet received, for bounded channels
+struct FlowControl {
+    capacity: usize,
+    in_flight: Cell<usize>,
+    /// The consumer acknowledges every message it receives
+    ack_receiver: IpcReceiver<()>,
+}
+
+impl FlowControl {
+
+    /// Blocks until there is room for a new message
+    fn reserve(&self) -> Result<()> {
+        self.receive_acks()?;
+        while self.in_flight.get() >= self.capacity {
+            log::debug!("IPC channel is full, waiting for the container to receive a message");
+            self.ack_receiver.recv().map_err(|_| eyre::eyre!("Error waiting for the container to receive a message"))?;
+            self.in_flight.set(self.in_flight.get() - 1);
+        }
+        self.in_flight.set(self.in_flight.get() + 1);
+        Ok(())
+    }
+
+    /// Reserves room for a new message, without blocking
+    /// # Returns
+    /// Whether there was room for the message
+    fn try_reserve(&self) -> Result<bool> {
+        self.receive_acks()?;
+        if self.in_flight.get() >= self.capacity {
+            return Ok(false);
+        }
+        self.in_flight.set(self.in_flight.get() + 1);
+        Ok(true)
+    }
+
+    /// Gives back the room reserved for a message that could not be sent
+    fn release(&self) {
+        self.in_flight.set(self.in_flight.get() - 1);
+    }
+
+    /// Accounts for the messages already received, without blocking
+    fn receive_acks(&self) -> Result<()> {
+        while self.in_flight.get() > 0 {
+            match self.ack_receiver.try_recv() {
+                Ok(()) => self.in_flight.set(self.in_flight.get() - 1),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::IpcError(IpcError::Disconnected)) => {
+                    return Err(eyre::eyre!("The container closed the IPC channel"));
+                },
+                Err(TryRecvError::IpcError(err)) => return Err(eyre::eyre!("Error receiving an acknowledgement: {}", err)),
+            }
+        }
+        Ok(())
+    }
+
+}
+
+/// Failure of a send attempt
+#[allow(clippy::upper_case_acronyms)]
+enum SendError {
+    /// The send may succeed later, e.g. once the container receives the messages in flight
+    TRANSIENT(eyre::Report),
+    /// The send will never succeed, e.g. the container closed the channel
+    PERMANENT(eyre::Report),
+}
+
+/// Sending end of the transport of the messages, see `IpcFormat`
+#[allow(clippy::upper_case_acronyms)]
+enum Sender {
+    BINCODE(IpcSender<Message>),
+    JSON(JsonSocket),
+}
+
+impl Sender {
+
+    /// Sends a message, telling the failures that may go away from the permanent ones
+    fn send(&self, msg: &Message) -> std::result::Result<(), SendError> {
+        match self {
+            Sender::BINCODE(sender) => sender.send(msg.clone()).map_err(|err| {
+                let transient = match err.as_ref() {
+                    bincode::ErrorKind::Io(err) => matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted)
+                        || err.raw_os_error() == Some(nix::libc::ENOBUFS),
+                    _ => false,
+                };
+                if transient {
+                    SendError::TRANSIENT(err.into())
+                } else {
+                    SendError::PERMANENT(err.into())
+                }
+            }),
+            Sender::JSON(socket) => socket.send(msg),
+        }
+    }
+
+}
+
+/// Receiving end of the transport of the messages, see `IpcFormat`
+#[allow(clippy::upper_case_acronyms)]
+enum Receiver {
+    BINCODE(IpcReceiver<Message>),
+    JSON(JsonSocket),
+    /// The messages of a BINCODE receiver, received by a thread, see `Relay`
+    RELAYED(Relay),
+}
+
+/// Receives the messages of an `ipc_channel` receiver in a thread, so their arrival can be polled: an
+/// `IpcReceiver` has no file descriptor of its own
+struct Relay {
+    /// Messages received by the thread, or the error that stopped it
+    queue: Arc<Mutex<VecDeque<Result<Message, String>>>>,
+    /// Read end of a pipe, with a byte written by the thread for each entry of the queue
+    notify: RawFd,
+}
+
+impl Relay {
+
+    /// Starts receiving the messages in a thread
+    /// # Returns
+    /// The relay, or the receiver given back with the error if the relay could not be started
+    fn start(receiver: IpcReceiver<Message>) -> std::result::Result<Relay, (IpcReceiver<Message>, eyre::Report)> {
+        let (notify, notify_write) = match pipe2(OFlag::O_CLOEXEC) {
+            Ok(pipe) => pipe,
+            Err(err) => return Err((receiver, err.into())),
+        };
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let relayed = queue.clone();
+        // Taken by the thread once running, left here to be given back if it could not be spawned
+        let handover = Arc::new(Mutex::new(Some(receiver)));
+        let taken = handover.clone();
+        let spawned = thread::Builder::new().spawn(move || {
+            let receiver = match taken.lock().unwrap().take() {
+                Some(receiver) => receiver,
+                None => return,
+            };
+            loop {
+                let received = receiver.recv().map_err(|err| format!("Error receiving message: {:?}", err));
+                let failed = received.is_err();
+                relayed.lock().unwrap().push_back(received);
+                // The receiving end is gone, nobody is left to receive the next ones either
+                if write(notify_write, &[1]).is_err() || failed {
+                    let _ = close(notify_write);
+                    break;
+                }
+            }
+        });
+        if let Err(err) = spawned {
+            let _ = close(notify);
+            let _ = close(notify_write);
+            let receiver = handover.lock().unwrap().take().expect("The relay thread was not spawned");
+            return Err((receiver, err.into()));
+        }
+        Ok(Relay { queue, notify })
+    }
+
+    /// Receives the next message, waiting for the thread to receive one
+    fn receive(&self) -> Result<Message> {
+        loop {
+            match read(self.notify, &mut [0]) {
+                Ok(1) => break,
+                Ok(_) => return Err(eyre::eyre!("The IPC channel was closed")),
+                Err(Errno::EINTR) => continue,
+                Err(err) => return Err(eyre::eyre!("Error receiving message: {}", err)),
+            }
+        }
+        match self.queue.lock().unwrap().pop_front() {
+            Some(Ok(msg)) => Ok(msg),
+            Some(Err(err)) => Err(eyre::eyre!(err)),
+            None => Err(eyre::eyre!("Error receiving message")),
+        }
+    }
+
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        let _ = close(self.notify);
+    }
+}
+
+/// One end of a unix socket carrying JSON messages, one message per packet
+struct JsonSocket {
+    fd: RawFd,
+}
+
+impl JsonSocket {
+
+    /// Creates a connected pair of sockets
+    fn pair() -> Result<(JsonSocket, JsonSocket)> {
+        let (sender, receiver) = socketpair(AddressFamily::Unix, SockType::SeqPacket, None, SockFlag::SOCK_CLOEXEC)?;
+        Ok((JsonSocket { fd: sender }, JsonSocket { fd: receiver }))
+    }
+
+    /// Sends a message as a single packet
+    fn send(&self, msg: &Message) -> std::result::Result<(), SendError> {
+        let data = serde_json::to_vec(msg).map_err(|err| SendError::PERMANENT(err.into()))?;
+        // MSG_NOSIGNAL is not known by nix: no SIGPIPE if the container closed its end
+        let res = unsafe { nix::libc::send(self.fd, data.as_ptr() as *const nix::libc::c_void, data.len(), nix::libc::MSG_NOSIGNAL) };
+        match Errno::result(res) {
+            Ok(_) => Ok(()),
+            Err(err @ (Errno::EAGAIN | Errno::EINTR | Errno::ENOBUFS)) => Err(SendError::TRANSIENT(err.into())),
+            Err(err) => Err(SendError::PERMANENT(err.into())),
+        }
+    }
+
+    /// Receives a message, waiting at most `timeout`
+    /// # Arguments
+    /// * `timeout` - Maximum time to wait (None: wait forever)
+    /// # Returns
+    /// The message received, or None if no message was received in time (or the wait was interrupted)
+    fn receive(&self, timeout: Option<Duration>) -> Result<Option<Message>> {
+        let timeout = timeout.map(|timeout| timeout.as_millis().min(c_int::MAX as u128) as c_int).unwrap_or(-1);
+        match poll(&mut [PollFd::new(self.fd, PollFlags::POLLIN)], timeout) {
+            Ok(0) | Err(Errno::EINTR) => return Ok(None),
+            Ok(_) => {},
+            Err(err) => return Err(eyre::eyre!("Error receiving message: {}", err)),
+        }
+        // The size of the next packet, without receiving it
+        let size = recv(self.fd, &mut [], MsgFlags::MSG_PEEK | MsgFlags::MSG_TRUNC)?;
+        let mut data = vec![0u8; size];
+        match recv(self.fd, &mut data, MsgFlags::empty()) {
+            Ok(0) => Err(eyre::eyre!("The IPC channel was closed")),
+            Ok(_) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(Errno::EINTR) => Ok(None),
+            Err(err) => Err(eyre::eyre!("Error receiving message: {}", err)),
+        }
+    }
+
+}
+
+impl Drop for JsonSocket {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
+
 /// The channel to be used by processes outside the container
 pub struct ProducerChannel {
-    inner_sender: IpcSender<Message>
+    inner_sender: Sender,
+    flow_control: Option<FlowControl>,
 }
 
 impl ProducerChannel {
@@ -60,16 +382,69 @@ impl ProducerChannel {
     /// Sends a message to the container
     /// # Arguments
     /// * `message` - Message to be sent
+    ///
+    /// Note: on bounded channels, this blocks while the channel is full
     pub fn send(&self, msg: Message) -> Result<()> {
+        if let Some(flow_control) = &self.flow_control {
+            flow_control.reserve()?;
+        }
         log::debug!("Sending message: {:?}", msg);
-        self.inner_sender.send(msg)?;
-        Ok(())
+        self.inner_sender.send(&msg).map_err(|err| match err {
+            SendError::TRANSIENT(err) | SendError::PERMANENT(err) => err,
+        })
+    }
+
+    /// Sends a message to the container, retrying while the failure is transient (e.g. the bounded
+    /// channel is full), instead of blocking or failing immediately
+    /// # Arguments
+    /// * `msg` - Message to be sent
+    /// * `retries` - Maximum number of retries
+    /// * `backoff` - Time to wait before the first retry, doubled after each retry
+    ///
+    /// Note: permanent failures (e.g. the container closed the channel) are returned without retrying
+    pub fn send_with_retry(&self, msg: Message, retries: u32, backoff: Duration) -> Result<()> {
+        let mut delay = backoff;
+        for attempt in 0.. {
+            match self.try_send(&msg) {
+                Ok(()) => return Ok(()),
+                Err(SendError::TRANSIENT(err)) if attempt < retries => {
+                    log::debug!("Could not send message ({}), retrying in {:?}", err, delay);
+                    thread::sleep(delay);
+                    delay *= 2;
+                },
+                Err(SendError::TRANSIENT(err)) => {
+                    return Err(eyre::eyre!("Could not send message after {} retries: {}", retries, err));
+                },
+                Err(SendError::PERMANENT(err)) => return Err(err),
+            }
+        }
+        unreachable!()
+    }
+
+    /// Makes a single attempt to send a message, without blocking on bounded channels
+    fn try_send(&self, msg: &Message) -> std::result::Result<(), SendError> {
+        if let Some(flow_control) = &self.flow_control {
+            match flow_control.try_reserve() {
+                Ok(true) => {},
+                Ok(false) => return Err(SendError::TRANSIENT(eyre::eyre!("The IPC channel is full"))),
+                Err(err) => return Err(SendError::PERMANENT(err)),
+            }
+        }
+        log::debug!("Sending message: {:?}", msg);
+        let sent = self.inner_sender.send(msg);
+        if sent.is_err() {
+            if let Some(flow_control) = &self.flow_control {
+                flow_control.release();
+            }
+        }
+        sent
     }
 }
 
 /// The channel to be used by processes inside the container
 pub struct ConsumerChannel {
-    inner_receiver: IpcReceiver<Message>
+    inner_receiver: Receiver,
+    ack_sender: Option<IpcSender<()>>,
 }
 
 impl ConsumerChannel {
@@ -78,6 +453,185 @@ impl ConsumerChannel {
     /// # Returns
     /// The message received
     pub fn receive(&self) -> Result<Message> {
-        self.inner_receiver.recv().map_err(|_| eyre::eyre!("Error receiving message"))
+        let msg = match &self.inner_receiver {
+            Receiver::BINCODE(receiver) => receiver.recv().map_err(|_| eyre::eyre!("Error receiving message"))?,
+            Receiver::JSON(socket) => loop {
+                // None when interrupted by a signal
+                if let Some(msg) = socket.receive(None)? {
+                    break msg;
+                }
+            },
+            Receiver::RELAYED(relay) => relay.receive()?,
+        };
+        if let Some(ack_sender) = &self.ack_sender {
+            ack_sender.send(())?;
+        }
+        Ok(msg)
+    }
+
+    /// Receives a message for the container, waiting at most `timeout`
+    /// # Arguments
+    /// * `timeout` - Maximum time to wait
+    /// # Returns
+    /// The message received, or None if no message was received in time
+    pub fn receive_timeout(&self, timeout: Duration) -> Result<Option<Message>> {
+        let msg = match &self.inner_receiver {
+            Receiver::BINCODE(receiver) => match receiver.try_recv_timeout(timeout) {
+                Ok(msg) => msg,
+                Err(TryRecvError::Empty) => return Ok(None),
+                // Interrupted by a signal
+                Err(TryRecvError::IpcError(IpcError::Io(err))) if err.kind() == io::ErrorKind::Interrupted => return Ok(None),
+                Err(TryRecvError::IpcError(_)) => return Err(eyre::eyre!("Error receiving message")),
+            },
+            Receiver::JSON(socket) => match socket.receive(Some(timeout))? {
+                Some(msg) => msg,
+                None => return Ok(None),
+            },
+            Receiver::RELAYED(relay) => {
+                let timeout = timeout.as_millis().min(c_int::MAX as u128) as c_int;
+                match poll(&mut [PollFd::new(relay.notify, PollFlags::POLLIN)], timeout) {
+                    Ok(0) | Err(Errno::EINTR) => return Ok(None),
+                    Ok(_) => relay.receive()?,
+                    Err(err) => return Err(eyre::eyre!("Error receiving message: {}", err)),
+                }
+            },
+        };
+        if let Some(ack_sender) = &self.ack_sender {
+            ack_sender.send(())?;
+        }
+        Ok(Some(msg))
+    }
+
+    /// Receives a message for the container, unless another file descriptor becomes readable first, e.g.
+    /// a signalfd. The wait is not interrupted by signals.
+    ///
+    /// Note: with `IpcFormat::BINCODE`, the messages are received by a thread from the first call on
+    /// # Arguments
+    /// * `fd` - The other file descriptor
+    /// # Returns
+    /// The message received, or None if `fd` is readable
+    pub fn receive_unless_readable(&mut self, fd: RawFd) -> Result<Option<Message>> {
+        // Only the thread can wait on the receiver, it has no file descriptor. The placeholder has no
+        // socket either (closing -1 fails, and is ignored), and is replaced whether the relay starts or not
+        let placeholder = Receiver::JSON(JsonSocket { fd: -1 });
+        self.inner_receiver = match std::mem::replace(&mut self.inner_receiver, placeholder) {
+            Receiver::BINCODE(receiver) => match Relay::start(receiver) {
+                Ok(relay) => Receiver::RELAYED(relay),
+                Err((receiver, err)) => {
+                    // Still usable by receive(), only not pollable
+                    self.inner_receiver = Receiver::BINCODE(receiver);
+                    return Err(err);
+                }
+            },
+            receiver => receiver,
+        };
+        let channel = match &self.inner_receiver {
+            Receiver::JSON(socket) => socket.fd,
+            Receiver::RELAYED(relay) => relay.notify,
+            Receiver::BINCODE(_) => unreachable!(),
+        };
+        loop {
+            let mut fds = [PollFd::new(fd, PollFlags::POLLIN), PollFd::new(channel, PollFlags::POLLIN)];
+            match poll(&mut fds, -1) {
+                Ok(_) => {},
+                Err(Errno::EINTR) => continue,
+                Err(err) => return Err(eyre::eyre!("Error receiving message: {}", err)),
+            }
+            // The other file descriptor first, e.g. a stop signal is not delayed by a flood of messages
+            if matches!(fds[0].revents(), Some(events) if !events.is_empty()) {
+                return Ok(None);
+            }
+            if matches!(fds[1].revents(), Some(events) if !events.is_empty()) {
+                return self.receive().map(Some);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscall::ExecType;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_bounded_channel_blocks_when_full() {
+        let (producer, consumer) = create_bounded_ipc_channels(2).unwrap();
+        producer.send(Message::ACTION(Action::STOP)).unwrap();
+        producer.send(Message::ACTION(Action::STOP)).unwrap();
+        // Full, a send blocks until the container receives a message
+        assert!(producer.send_with_retry(Message::ACTION(Action::STOP), 0, Duration::ZERO).is_err());
+        let handle = thread::spawn(move || {
+            producer.send(Message::ACTION(Action::STOP)).unwrap();
+            producer
+        });
+        consumer.receive().unwrap();
+        let _producer = handle.join().unwrap();
+        for _ in 0..2 {
+            assert!(matches!(consumer.receive().unwrap(), Message::ACTION(Action::STOP)));
+        }
+    }
+
+    #[test]
+    fn test_send_with_retry_once_drained() {
+        let (producer, consumer) = create_bounded_ipc_channels(1).unwrap();
+        producer.send(Message::ACTION(Action::STOP)).unwrap();
+        assert!(producer.send_with_retry(Message::ACTION(Action::STOP), 0, Duration::from_millis(10)).is_err());
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            consumer.receive().unwrap();
+            consumer
+        });
+        producer.send_with_retry(Message::ACTION(Action::STOP), 10, Duration::from_millis(10)).unwrap();
+        // Once the container is gone, the failure is permanent and returned without retrying
+        drop(handle.join().unwrap());
+        let start = std::time::Instant::now();
+        assert!(producer.send_with_retry(Message::ACTION(Action::STOP), 10, Duration::from_secs(1)).is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_json_channel() {
+        let (producer, consumer) = create_ipc_channels_with_format(IpcFormat::JSON, None).unwrap();
+        let command = Command {
+            command: "echo".to_string(),
+            args: vec!["hello".to_string()],
+            env: vec!["PATH=/bin".to_string()],
+            exec_type: ExecType::FORK,
+            argv0: None,
+            login: false,
+            path_search: true,
+            process_group: None,
+            stream_stdout: false,
+        };
+        producer.send(Message::COMMAND(command)).unwrap();
+        match consumer.receive_timeout(Duration::from_secs(1)).unwrap() {
+            Some(Message::COMMAND(received)) => {
+                assert_eq!(received.command, "echo");
+                assert_eq!(received.args, vec!["hello"]);
+                assert_eq!(received.env, vec!["PATH=/bin"]);
+                assert!(matches!(received.exec_type, ExecType::FORK));
+            },
+            other => panic!("Unexpected message {:?}", other),
+        }
+        assert!(consumer.receive_timeout(Duration::from_millis(10)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_receive_unless_readable() {
+        for format in [IpcFormat::BINCODE, IpcFormat::JSON] {
+            let (producer, mut consumer) = create_ipc_channels_with_format(format, Some(1)).unwrap();
+            let (other_read, other_write) = nix::unistd::pipe().unwrap();
+            producer.send(Message::ACTION(Action::STOP)).unwrap();
+            assert!(matches!(consumer.receive_unless_readable(other_read).unwrap(), Some(Message::ACTION(Action::STOP))));
+            // Acknowledged, there is room for the next one
+            producer.send_with_retry(Message::ACTION(Action::STOP), 10, Duration::from_millis(10)).unwrap();
+            assert!(matches!(consumer.receive_timeout(Duration::from_secs(1)).unwrap(), Some(Message::ACTION(Action::STOP))));
+            write(other_write, &[1]).unwrap();
+            assert!(consumer.receive_unless_readable(other_read).unwrap().is_none());
+            close(other_read).unwrap();
+            close(other_write).unwrap();
+        }
     }
 }
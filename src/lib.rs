@@ -21,17 +21,32 @@
  * THE SOFTWARE.
  */
 
+/// cgroup v2 management for the containers
+pub mod cgroup;
 /// Public API for building a container
 pub mod container;
+/// Features supported by the library and the host
+pub mod features;
 /// Filesystem drivers and utilities
 pub mod filesystem;
 /// IPC for communication between the container and the host
 pub mod ipc;
+/// Log drivers for the container's output
+pub mod logs;
+/// OCI runtime bundles (config.json) support
+pub mod oci;
+/// Random IDs of the containers
+pub mod random;
+/// Runtime options and execution environment of the container
+pub mod runtime;
+/// Seccomp profiles restricting the syscalls of the container
+pub mod seccomp;
+/// Persisted state of the containers
+pub mod state;
 /// Syscalls used by the container
 pub mod syscall;
 
-mod random;
-mod runtime;
+mod procfs;
 
 #[cfg(test)]
 mod tests {
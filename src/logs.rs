@@ -0,0 +1,266 @@
+/*
+ * The MIT License
+ * Copyright (c) 2022 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use color_eyre::{Result, eyre};
+use nix::unistd::{dup2, Pid};
+use serde::{Serialize, Deserialize};
+use syslog::{Facility, Formatter3164, Logger, LoggerBackend};
+
+/// A log driver receives every line printed by the container to its stdout and stderr
+pub trait LogDriver: Send {
+
+    /// Handles a line printed by the container
+    fn log(&mut self, line: &str) -> Result<()>;
+
+    /// Returns the lines kept by the driver, if it keeps any
+    fn lines(&self) -> Vec<String> {
+        vec![]
+    }
+
+}
+
+/// Log driver to use for the container's output
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LogDriverType {
+    /// Keep the last N lines in memory
+    RINGBUFFER(usize),
+    /// Forward the lines to the host's syslog, using the container ID as the tag
+    SYSLOG,
+    /// Forward the lines to the host's stdout, e.g. to aggregate the output of several containers (see
+    /// `RuntimeOptions::log_prefix`)
+    STDOUT,
+}
+
+impl LogDriverType {
+
+    /// Creates the log driver for a container
+    /// # Arguments
+    /// * `id` - ID of the container
+    pub fn build(&self, id: &str) -> Result<Box<dyn LogDriver>> {
+        match self {
+            LogDriverType::RINGBUFFER(capacity) => Ok(Box::new(RingBuffer::new(*capacity))),
+            LogDriverType::SYSLOG => Ok(Box::new(Syslog::new(id)?)),
+            LogDriverType::STDOUT => Ok(Box::new(Stdout {})),
+        }
+    }
+
+}
+
+/// Keeps the last lines printed by the container in memory
+pub struct RingBuffer {
+    capacity: usize,
+    lines: VecDeque<String>,
+}
+
+impl RingBuffer {
+
+    pub fn new(capacity: usize) -> Self {
+        RingBuffer {
+            capacity,
+            lines: VecDeque::with_capacity(capacity),
+        }
+    }
+
+}
+
+impl LogDriver for RingBuffer {
+
+    /// Store the line, dropping the oldest one if the buffer is full
+    fn log(&mut self, line: &str) -> Result<()> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        if self.lines.len() == self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line.to_string());
+        Ok(())
+    }
+
+    /// Return the stored lines, oldest first
+    fn lines(&self) -> Vec<String> {
+        self.lines.iter().cloned().collect()
+    }
+
+}
+
+/// Forwards the lines printed by the container to the host's syslog (or journald)
+pub struct Syslog {
+    logger: Logger<LoggerBackend, Formatter3164>,
+}
+
+impl Syslog {
+
+    /// Connects to the local syslog socket (/dev/log)
+    /// # Arguments
+    /// * `ident` - Tag of the messages, usually the container ID
+    pub fn new(ident: &str) -> Result<Self> {
+        let logger = syslog::unix(Self::formatter(ident))
+            .map_err(|err| eyre::eyre!("Could not connect to syslog: {}", err))?;
+        Ok(Syslog { logger })
+    }
+
+    /// Connects to a syslog listening on a custom unix socket
+    /// # Arguments
+    /// * `ident` - Tag of the messages, usually the container ID
+    /// * `socket` - Path to the unix datagram socket
+    pub fn with_socket(ident: &str, socket: &impl AsRef<Path>) -> Result<Self> {
+        let logger = syslog::unix_custom(Self::formatter(ident), socket)
+            .map_err(|err| eyre::eyre!("Could not connect to syslog: {}", err))?;
+        Ok(Syslog { logger })
+    }
+
+    fn formatter(ident: &str) -> Formatter3164 {
+        Formatter3164 {
+            facility: Facility::LOG_DAEMON,
+            hostname: None,
+            process: ident.to_string(),
+            pid: Pid::this().as_raw() as u32,
+        }
+    }
+
+}
+
+impl LogDriver for Syslog {
+
+    /// Send the line to syslog
+    fn log(&mut self, line: &str) -> Result<()> {
+        self.logger.info(line)
+            .map_err(|err| eyre::eyre!("Could not write to syslog: {}", err))
+    }
+
+}
+
+/// Forwards the lines printed by the container to the host's stdout
+pub struct Stdout {
+
+}
+
+impl LogDriver for Stdout {
+
+    /// Write the line to stdout, at once so the lines of several containers don't interleave
+    fn log(&mut self, line: &str) -> Result<()> {
+        io::stdout().lock().write_all(format!("{}\n", line).as_bytes())
+            .map_err(|err| eyre::eyre!("Could not write to stdout: {}", err))
+    }
+
+}
+
+/// Prepends a prefix to every line before handing it to another log driver, e.g. to tell apart the
+/// output of several containers
+pub struct Prefixed {
+    prefix: String,
+    driver: Box<dyn LogDriver>,
+}
+
+impl Prefixed {
+
+    /// # Arguments
+    /// * `prefix` - Prepended to every line as is (include a separator, e.g. "[web] ")
+    /// * `driver` - The driver receiving the prefixed lines
+    pub fn new(prefix: &str, driver: Box<dyn LogDriver>) -> Self {
+        Prefixed {
+            prefix: prefix.to_string(),
+            driver,
+        }
+    }
+
+}
+
+impl LogDriver for Prefixed {
+
+    /// Hand the prefixed line to the driver
+    fn log(&mut self, line: &str) -> Result<()> {
+        self.driver.log(&format!("{}{}", self.prefix, line))
+    }
+
+    /// Return the lines kept by the driver, prefixed
+    fn lines(&self) -> Vec<String> {
+        self.driver.lines()
+    }
+
+}
+
+/// Redirects the stdout and stderr of the current process to `fd`
+pub(crate) fn redirect_output(fd: RawFd) -> Result<()> {
+    dup2(fd, nix::libc::STDOUT_FILENO)?;
+    dup2(fd, nix::libc::STDERR_FILENO)?;
+    Ok(())
+}
+
+/// Reads lines from `fd` and hands them to the log driver, until every write end is closed
+pub(crate) fn forward_output(fd: RawFd, driver: Arc<Mutex<Box<dyn LogDriver>>>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        // The thread takes ownership of the read end of the pipe
+        let reader = BufReader::new(unsafe { File::from_raw_fd(fd) });
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            if let Err(err) = driver.lock().unwrap().log(&line) {
+                log::warn!("Could not forward container output: {}", err);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn test_syslog_driver_uses_container_id_as_tag() {
+        let socket = env::temp_dir().join(format!("libcontainer-rs-syslog-{}", Pid::this()));
+        let _ = fs::remove_file(&socket);
+        let sink = UnixDatagram::bind(&socket).unwrap();
+        let mut driver = Syslog::with_socket("0123456789ab", &socket).unwrap();
+        driver.log("hello from the container").unwrap();
+        let mut buf = [0; 1024];
+        let len = sink.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..len]);
+        assert!(message.contains("0123456789ab["));
+        assert!(message.ends_with("hello from the container"));
+        fs::remove_file(&socket).unwrap();
+    }
+
+    #[test]
+    fn test_prefixed_driver() {
+        let mut driver = Prefixed::new("[web] ", Box::new(RingBuffer::new(2)));
+        for line in ["one", "two", ""] {
+            driver.log(line).unwrap();
+        }
+        assert_eq!(driver.lines(), vec!["[web] two".to_string(), "[web] ".to_string()]);
+    }
+}
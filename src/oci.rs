@@ -0,0 +1,342 @@
+/*
+ * The MIT License
+ * Copyright (c) 2022 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use color_eyre::{Result, eyre};
+use serde::Deserialize;
+
+use crate::filesystem::BindMount;
+use crate::runtime::RuntimeOptions;
+use crate::syscall::{Command, ExecType};
+
+/// Namespaces created for every container
+const SUPPORTED_NAMESPACES: [&str; 5] = ["mount", "uts", "ipc", "pid", "network"];
+/// Mounts already set up by the runtime
+const RUNTIME_MOUNTS: [&str; 4] = ["/proc", "/sys", "/dev", "/dev/pts"];
+/// Fields of the configuration that are mapped (at least partly), by section
+const SUPPORTED_FIELDS: [(&str, &[&str]); 4] = [
+    ("", &["ociVersion", "root", "process", "hostname", "mounts", "linux", "annotations"]),
+    ("process", &["terminal", "user", "args", "env", "cwd"]),
+    ("process.user", &["uid", "gid"]),
+    ("linux", &["namespaces", "resources"]),
+];
+
+/// Reads the configuration of an OCI bundle, mapped to the runtime options and the container process.
+/// Unsupported features are logged and ignored
+/// # Arguments
+/// * `path` - Path to the config.json file, or to the bundle containing it
+/// # Returns
+/// The runtime options, and the process of the configuration (it is required)
+pub fn from_oci_spec(path: &Path) -> Result<(RuntimeOptions, Command)> {
+    let (config, bundle) = if path.is_dir() {
+        (path.join("config.json"), path)
+    } else {
+        (path.to_path_buf(), path.parent().unwrap_or(Path::new(".")))
+    };
+    let spec = Spec::load(&config)?;
+    let command = spec.command()
+        .ok_or_else(|| eyre::eyre!("The OCI configuration {} has no process to run", config.display()))?;
+    Ok((spec.runtime_options(bundle), command))
+}
+
+/// The subset of the OCI runtime specification (config.json) supported by the library
+/// See https://github.com/opencontainers/runtime-spec/blob/main/config.md
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Spec {
+    pub oci_version: String,
+    #[serde(default)]
+    pub root: Option<Root>,
+    #[serde(default)]
+    pub process: Option<Process>,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub mounts: Vec<Mount>,
+    #[serde(default)]
+    pub linux: Option<Linux>,
+}
+
+/// The container's root filesystem
+#[derive(Debug, Deserialize)]
+pub struct Root {
+    /// Path to the root filesystem, relative to the bundle
+    pub path: PathBuf,
+    #[serde(default)]
+    pub readonly: bool,
+}
+
+/// The container process
+#[derive(Debug, Deserialize)]
+pub struct Process {
+    #[serde(default)]
+    pub terminal: bool,
+    #[serde(default)]
+    pub user: User,
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: Vec<String>,
+    pub cwd: String,
+}
+
+/// The user running the container process
+#[derive(Debug, Default, Deserialize)]
+pub struct User {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// A mount inside the container
+#[derive(Debug, Deserialize)]
+pub struct Mount {
+    pub destination: PathBuf,
+    #[serde(rename = "type", default)]
+    pub mount_type: Option<String>,
+    #[serde(default)]
+    pub source: Option<PathBuf>,
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+/// Linux-specific configuration
+#[derive(Debug, Default, Deserialize)]
+pub struct Linux {
+    #[serde(default)]
+    pub namespaces: Vec<Namespace>,
+    #[serde(default)]
+    pub resources: Option<serde_json::Value>,
+}
+
+/// A namespace of the container
+#[derive(Debug, Deserialize)]
+pub struct Namespace {
+    #[serde(rename = "type")]
+    pub namespace_type: String,
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+impl Spec {
+
+    /// Reads an OCI runtime configuration
+    /// # Arguments
+    /// * `path` - Path to the config.json file
+    pub fn load(path: &impl AsRef<Path>) -> Result<Spec> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .map_err(|err| eyre::eyre!("Could not read {}: {}", path.display(), err))?;
+        let value: serde_json::Value = serde_json::from_str(&contents)
+            .map_err(|err| eyre::eyre!("Invalid OCI configuration {}: {}", path.display(), err))?;
+        for field in unsupported_fields(&value) {
+            log::warn!("Unsupported field {} of the OCI configuration, ignoring it", field);
+        }
+        serde_json::from_value(value)
+            .map_err(|err| eyre::eyre!("Invalid OCI configuration {}: {}", path.display(), err))
+    }
+
+    /// Path to the root filesystem
+    /// # Arguments
+    /// * `bundle` - Directory of the bundle, relative paths are resolved against it
+    pub fn rootfs(&self, bundle: &Path) -> PathBuf {
+        match &self.root {
+            Some(root) => bundle.join(&root.path),
+            None => bundle.join("rootfs"),
+        }
+    }
+
+    /// Maps the configuration to runtime options. Unsupported features are logged and ignored
+    /// # Arguments
+    /// * `bundle` - Directory of the bundle, relative paths are resolved against it
+    pub fn runtime_options(&self, bundle: &Path) -> RuntimeOptions {
+        let mut options = RuntimeOptions::default();
+        options.hostname = self.hostname.clone();
+        if let Some(root) = &self.root {
+            options.rootfs.readonly = root.readonly;
+        }
+        if let Some(process) = &self.process {
+            options.user = process.user.uid.to_string();
+            options.group = process.user.gid.to_string();
+            options.cwd = process.cwd.clone();
+            options.tty = process.terminal;
+        }
+        for mount in &self.mounts {
+            match (mount.mount_type.as_deref(), &mount.source) {
+                (Some("bind"), Some(source)) => options.mounts.push(BindMount {
+                    source: bundle.join(source),
+                    destination: mount.destination.clone(),
+                    readonly: mount.options.iter().any(|option| option == "ro"),
+                }),
+                _ if RUNTIME_MOUNTS.contains(&mount.destination.to_string_lossy().as_ref()) => {},
+                _ => log::warn!("Unsupported mount {}, ignoring it", mount.destination.display()),
+            }
+        }
+        if let Some(linux) = &self.linux {
+            for namespace in &linux.namespaces {
+                if !SUPPORTED_NAMESPACES.contains(&namespace.namespace_type.as_str()) {
+                    log::warn!("Unsupported namespace {}, ignoring it", namespace.namespace_type);
+                } else if let Some(path) = &namespace.path {
+                    log::warn!("Joining namespace {} is not supported, a new one is created", path.display());
+                }
+            }
+            if linux.resources.is_some() {
+                log::warn!("Resource limits are not supported, the container is only placed in its own cgroup");
+                options.cgroup = true;
+            }
+        }
+        options
+    }
+
+    /// The container process, if any
+    pub fn command(&self) -> Option<Command> {
+        let process = self.process.as_ref()?;
+        let (command, args) = process.args.split_first()?;
+        Some(Command {
+            command: command.clone(),
+            args: args.to_vec(),
+            env: process.env.clone(),
+            exec_type: ExecType::REPLACE,
+            argv0: None,
+            login: false,
+            path_search: true,
+            process_group: None,
+            stream_stdout: false,
+        })
+    }
+
+}
+
+/// Returns the fields of a configuration that are ignored, e.g. "process.capabilities"
+fn unsupported_fields(config: &serde_json::Value) -> Vec<String> {
+    let mut unsupported = vec![];
+    for (section, supported) in SUPPORTED_FIELDS {
+        let fields = section.split('.')
+            .filter(|name| !name.is_empty())
+            .try_fold(config, |value, name| value.get(name))
+            .and_then(|value| value.as_object());
+        for name in fields.into_iter().flat_map(|fields| fields.keys()) {
+            if !supported.contains(&name.as_str()) {
+                unsupported.push(if section.is_empty() { name.clone() } else { format!("{}.{}", section, name) });
+            }
+        }
+    }
+    unsupported
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container::Container;
+    use crate::filesystem::OverlayDriver;
+    use crate::logs::LogDriverType;
+    use crate::syscall::UserInfo;
+    use std::env;
+
+    const CONFIG: &str = r#"{
+        "ociVersion": "1.0.2",
+        "root": { "path": "rootfs", "readonly": true },
+        "process": {
+            "terminal": false,
+            "user": { "uid": 0, "gid": 0 },
+            "args": ["/bin/echo", "hello"],
+            "env": ["PATH=/usr/bin:/bin"],
+            "cwd": "/"
+        },
+        "hostname": "oci-container",
+        "mounts": [
+            { "destination": "/proc", "type": "proc", "source": "proc" },
+            { "destination": "/data", "type": "bind", "source": "data", "options": ["rbind", "ro"] }
+        ],
+        "linux": {
+            "namespaces": [ { "type": "pid" }, { "type": "mount" } ]
+        }
+    }"#;
+
+    #[test]
+    fn test_oci_bundle_mapping() {
+        let bundle = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_oci_bundle");
+        fs::create_dir_all(&bundle).unwrap();
+        fs::write(bundle.join("config.json"), CONFIG).unwrap();
+        let spec = Spec::load(&bundle.join("config.json")).unwrap();
+        assert_eq!(spec.rootfs(&bundle), bundle.join("rootfs"));
+        let options = spec.runtime_options(&bundle);
+        assert_eq!(options.hostname.as_deref(), Some("oci-container"));
+        assert_eq!(options.user, "0");
+        assert!(options.rootfs.readonly);
+        assert_eq!(options.mounts.len(), 1);
+        assert_eq!(options.mounts[0].source, bundle.join("data"));
+        assert!(options.mounts[0].readonly);
+        let command = spec.command().unwrap();
+        assert_eq!(command.command, "/bin/echo");
+        assert_eq!(command.args, vec!["hello"]);
+        fs::remove_dir_all(bundle).unwrap();
+    }
+
+    #[test]
+    fn test_run_oci_process() {
+        let bundle = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_run_oci_process");
+        fs::create_dir_all(bundle.join("data")).unwrap();
+        fs::write(bundle.join("data/file"), "data").unwrap();
+        let mut config: serde_json::Value = serde_json::from_str(CONFIG).unwrap();
+        config["root"] = serde_json::json!({ "path": "/" });
+        config["process"]["user"] = serde_json::json!({ "uid": 1000, "gid": 1000 });
+        config["process"]["cwd"] = serde_json::json!("/tmp");
+        config["process"]["args"] = serde_json::json!(["/bin/sh", "-c", "pwd; id -u; id -g; echo $USER; cat /data/file"]);
+        fs::write(bundle.join("config.json"), config.to_string()).unwrap();
+        let (mut options, command) = from_oci_spec(&bundle).unwrap();
+        options.log_driver = Some(LogDriverType::RINGBUFFER(10));
+        let fs = OverlayDriver::new(vec![&"/"], &bundle.join("overlay"));
+        let mut container = Container::with_options(Box::new(fs), options).unwrap();
+        container.spawn_command(command).unwrap();
+        container.wait_for_container().unwrap();
+        // The name of the user, if the image has one for the UID
+        let user = UserInfo::from_name("1000").map(|info| info.name).unwrap_or_else(|_| "1000".to_string());
+        assert_eq!(container.logs(), vec!["/tmp", "1000", "1000", user.as_str(), "data"]);
+        fs::remove_dir_all(bundle).unwrap();
+    }
+
+    #[test]
+    fn test_from_oci_spec() {
+        let bundle = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_from_oci_spec");
+        fs::create_dir_all(&bundle).unwrap();
+        let mut config: serde_json::Value = serde_json::from_str(CONFIG).unwrap();
+        config["process"]["user"] = serde_json::json!({ "uid": 1000, "gid": 100, "umask": 18 });
+        config["process"]["cwd"] = serde_json::json!("/home");
+        config["process"]["rlimits"] = serde_json::json!([]);
+        config["hooks"] = serde_json::json!({});
+        fs::write(bundle.join("config.json"), config.to_string()).unwrap();
+        assert_eq!(unsupported_fields(&config), vec!["hooks", "process.rlimits", "process.user.umask"]);
+        let (options, command) = from_oci_spec(&bundle).unwrap();
+        assert_eq!((options.user.as_str(), options.group.as_str()), ("1000", "100"));
+        assert_eq!(options.cwd, "/home");
+        assert_eq!(options.hostname.as_deref(), Some("oci-container"));
+        assert_eq!(command.command, "/bin/echo");
+        assert_eq!(command.env, vec!["PATH=/usr/bin:/bin"]);
+        assert_eq!(from_oci_spec(&bundle.join("config.json")).unwrap().0.mounts[0].source, bundle.join("data"));
+        config.as_object_mut().unwrap().remove("process");
+        fs::write(bundle.join("config.json"), config.to_string()).unwrap();
+        assert!(from_oci_spec(&bundle).is_err());
+        fs::remove_dir_all(bundle).unwrap();
+    }
+}
@@ -0,0 +1,473 @@
+/*
+ * The MIT License
+ * Copyright (c) 2022 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use color_eyre::{Result, eyre};
+use nix::unistd::{sysconf, Pid, SysconfVar};
+
+/// State of a listening socket in /proc/<pid>/net/tcp
+const TCP_LISTEN: &str = "0A";
+/// Flag of a listening socket in /proc/<pid>/net/unix (__SO_ACCEPTCON)
+const UNIX_LISTEN: u32 = 0x10000;
+/// Interval between the checks of `wait_until`
+const WAIT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A process running inside the container
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessInfo {
+    /// PID of the process, as seen by the host
+    pub pid: Pid,
+    /// Name of the executable
+    pub command: String,
+    /// CPU time used since the process started (user and system)
+    pub cpu_time: Duration,
+    /// Resident memory, in bytes
+    pub memory: u64,
+}
+
+/// Names of the capabilities, by number (see capabilities(7))
+const CAPABILITY_NAMES: [&str; 41] = [
+    "CAP_CHOWN", "CAP_DAC_OVERRIDE", "CAP_DAC_READ_SEARCH", "CAP_FOWNER", "CAP_FSETID", "CAP_KILL",
+    "CAP_SETGID", "CAP_SETUID", "CAP_SETPCAP", "CAP_LINUX_IMMUTABLE", "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST", "CAP_NET_ADMIN", "CAP_NET_RAW", "CAP_IPC_LOCK", "CAP_IPC_OWNER", "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO", "CAP_SYS_CHROOT", "CAP_SYS_PTRACE", "CAP_SYS_PACCT", "CAP_SYS_ADMIN", "CAP_SYS_BOOT",
+    "CAP_SYS_NICE", "CAP_SYS_RESOURCE", "CAP_SYS_TIME", "CAP_SYS_TTY_CONFIG", "CAP_MKNOD", "CAP_LEASE",
+    "CAP_AUDIT_WRITE", "CAP_AUDIT_CONTROL", "CAP_SETFCAP", "CAP_MAC_OVERRIDE", "CAP_MAC_ADMIN", "CAP_SYSLOG",
+    "CAP_WAKE_ALARM", "CAP_BLOCK_SUSPEND", "CAP_AUDIT_READ", "CAP_PERFMON", "CAP_BPF", "CAP_CHECKPOINT_RESTORE",
+];
+
+/// Capability sets of a process, as bit masks: bit N is set for the capability number N (e.g. 21 for
+/// CAP_SYS_ADMIN), see capabilities(7)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapSet {
+    /// Preserved across an execve
+    pub inheritable: u64,
+    /// The capabilities the process may have effective
+    pub permitted: u64,
+    /// The capabilities checked by the kernel
+    pub effective: u64,
+    /// The limit of the capabilities the process (and its children) can ever gain
+    pub bounding: u64,
+    /// Kept (in the permitted and effective sets) across an execve of a non-privileged program
+    pub ambient: u64,
+}
+
+impl CapSet {
+
+    /// Names of the capabilities of a set, e.g. `CapSet::names(caps.bounding)`
+    /// # Arguments
+    /// * `set` - One of the sets, the capabilities unknown to the library are named after their number
+    pub fn names(set: u64) -> Vec<String> {
+        (0..64)
+            .filter(|bit| set & (1 << bit) != 0)
+            .map(|bit| match CAPABILITY_NAMES.get(bit) {
+                Some(name) => name.to_string(),
+                None => format!("CAP_{}", bit),
+            })
+            .collect()
+    }
+
+    /// Bit mask of capabilities, the reverse of `names`
+    /// # Arguments
+    /// * `names` - Names of the capabilities, e.g. "CAP_NET_BIND_SERVICE" (case-insensitive)
+    /// # Returns
+    /// The bit mask, an error naming the first unknown capability
+    pub fn from_names(names: &[String]) -> Result<u64> {
+        names.iter().try_fold(0, |mask, name| {
+            match CAPABILITY_NAMES.iter().position(|known| known.eq_ignore_ascii_case(name)) {
+                Some(bit) => Ok(mask | (1 << bit)),
+                None => Err(eyre::eyre!("Unknown capability {}", name)),
+            }
+        })
+    }
+
+}
+
+/// Returns the TCP ports listening in the network namespace of a process
+/// # Arguments
+/// * `pid` - PID of the process
+pub fn listening_tcp_ports(pid: Pid) -> Result<Vec<u16>> {
+    let mut ports = vec![];
+    for table in ["tcp", "tcp6"] {
+        let path = format!("/proc/{}/net/{}", pid, table);
+        match fs::read_to_string(&path) {
+            Ok(contents) => ports.extend(parse_listening_ports(&contents)),
+            // IPv6 may be disabled
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound && table == "tcp6" => {},
+            Err(err) => return Err(eyre::eyre!("Could not read {}: {}", path, err)),
+        }
+    }
+    Ok(ports)
+}
+
+/// Parses the ports in the LISTEN state of a /proc/<pid>/net/tcp{,6} table
+fn parse_listening_ports(table: &str) -> Vec<u16> {
+    table.lines()
+        .skip(1) // Header
+        .filter_map(|line| {
+            // sl local_address rem_address st ...
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 4 || fields[3] != TCP_LISTEN {
+                return None;
+            }
+            let port = fields[1].rsplit(':').next()?;
+            u16::from_str_radix(port, 16).ok()
+        })
+        .collect()
+}
+
+/// Returns the paths of the unix sockets listening in the network namespace of a process, as they were
+/// bound (abstract sockets start with '@')
+/// # Arguments
+/// * `pid` - PID of the process
+pub fn listening_unix_sockets(pid: Pid) -> Result<Vec<PathBuf>> {
+    let path = format!("/proc/{}/net/unix", pid);
+    let table = fs::read_to_string(&path)
+        .map_err(|err| eyre::eyre!("Could not read {}: {}", path, err))?;
+    Ok(parse_listening_unix_sockets(&table))
+}
+
+/// Parses the paths of the listening sockets of a /proc/<pid>/net/unix table
+fn parse_listening_unix_sockets(table: &str) -> Vec<PathBuf> {
+    table.lines()
+        .skip(1) // Header
+        .filter_map(|line| {
+            // Num RefCount Protocol Flags Type St Inode Path, the path may contain spaces
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let flags = u32::from_str_radix(fields.get(3)?, 16).ok()?;
+            if flags & UNIX_LISTEN == 0 || fields.len() < 8 {
+                return None;
+            }
+            Some(PathBuf::from(fields[7..].join(" ")))
+        })
+        .collect()
+}
+
+/// Waits until a condition is met, checking it regularly
+/// # Arguments
+/// * `timeout` - Maximum time to wait
+/// * `condition` - Description of the condition, for the error
+/// * `check` - Returns whether the condition is met
+pub fn wait_until(timeout: Duration, condition: &str, mut check: impl FnMut() -> Result<bool>) -> Result<()> {
+    let start = Instant::now();
+    loop {
+        if check()? {
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            return Err(eyre::eyre!("{} after {:?}", condition, timeout));
+        }
+        thread::sleep(WAIT_INTERVAL);
+    }
+}
+
+/// Waits until a TCP port is listening in the network namespace of a process
+/// # Arguments
+/// * `pid` - PID of the process
+/// * `port` - The TCP port
+/// * `timeout` - Maximum time to wait
+pub fn wait_for_port(pid: Pid, port: u16, timeout: Duration) -> Result<()> {
+    wait_until(timeout, &format!("Port {} is not listening", port), || {
+        Ok(listening_tcp_ports(pid)?.contains(&port))
+    })
+}
+
+/// Returns the processes in the PID namespace of a process (including itself)
+/// # Arguments
+/// * `pid` - PID of the process, as seen by the host
+pub fn namespace_processes(pid: Pid) -> Result<Vec<Pid>> {
+    let namespace = fs::read_link(format!("/proc/{}/ns/pid", pid))?;
+    let mut processes = vec![];
+    for entry in fs::read_dir("/proc")? {
+        let process = match entry?.file_name().to_str().and_then(|name| name.parse().ok()) {
+            Some(process) => Pid::from_raw(process),
+            None => continue,
+        };
+        // The process may have exited in the meantime
+        if fs::read_link(format!("/proc/{}/ns/pid", process)).ok().as_ref() == Some(&namespace) {
+            processes.push(process);
+        }
+    }
+    Ok(processes)
+}
+
+/// Whether a process is still in a mount namespace, e.g. one where an overlay was mounted
+/// # Arguments
+/// * `namespace` - The target of a /proc/<pid>/ns/mnt symlink, e.g. "mnt:[4026531840]"
+pub fn mount_namespace_in_use(namespace: &Path) -> Result<bool> {
+    for entry in fs::read_dir("/proc")? {
+        let process = match entry?.file_name().to_str().and_then(|name| name.parse::<i32>().ok()) {
+            Some(process) => process,
+            None => continue,
+        };
+        // The process may have exited in the meantime
+        if fs::read_link(format!("/proc/{}/ns/mnt", process)).ok().as_deref() == Some(namespace) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Returns the inode numbers identifying the namespaces of a process, by namespace name (e.g. "mnt")
+/// # Arguments
+/// * `pid` - PID of the process
+pub fn namespace_ids(pid: Pid) -> Result<BTreeMap<String, u64>> {
+    let mut namespaces = BTreeMap::new();
+    for entry in fs::read_dir(format!("/proc/{}/ns", pid))? {
+        let entry = entry?;
+        // Each entry is a symlink to "<name>:[<inode>]"
+        let link = fs::read_link(entry.path())?;
+        let inode = link.to_str()
+            .and_then(|link| link.rsplit_once('['))
+            .and_then(|(_, inode)| inode.trim_end_matches(']').parse().ok());
+        if let Some(inode) = inode {
+            namespaces.insert(entry.file_name().to_string_lossy().into_owned(), inode);
+        }
+    }
+    Ok(namespaces)
+}
+
+/// Returns the devices (major, minor) of the mounts whose mount point is "/", in the order of a
+/// /proc/<pid>/mountinfo table (the last one is on top)
+pub fn root_mounts(mountinfo: &str) -> Vec<(u64, u64)> {
+    mountinfo.lines()
+        .filter_map(|line| {
+            // <id> <parent id> <major>:<minor> <root> <mount point> ...
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.get(4) != Some(&"/") {
+                return None;
+            }
+            let (major, minor) = fields.get(2)?.split_once(':')?;
+            Some((major.parse().ok()?, minor.parse().ok()?))
+        })
+        .collect()
+}
+
+/// Returns the mounts whose mount point is `path` or below it, with their mount options (e.g.
+/// "rw,nosuid,relatime"), in the order of a /proc/<pid>/mountinfo table (a mount after the one it is on)
+/// # Arguments
+/// * `mountinfo` - The mountinfo table
+/// * `path` - Absolute path, without symlinks
+pub fn mounts_below(mountinfo: &str, path: &Path) -> Vec<(PathBuf, String)> {
+    mountinfo.lines()
+        .filter_map(|line| {
+            // <id> <parent id> <major>:<minor> <root> <mount point> <options> ...
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let mount_point = PathBuf::from(unescape_mountinfo(fields.get(4)?));
+            if !mount_point.starts_with(path) {
+                return None;
+            }
+            Some((mount_point, fields.get(5)?.to_string()))
+        })
+        .collect()
+}
+
+/// Decodes the octal escapes of a mountinfo field, e.g. "\040" for a space
+fn unescape_mountinfo(field: &str) -> String {
+    let mut decoded = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(index) = rest.find('\\') {
+        decoded.push_str(&rest[..index]);
+        let escape = rest.get(index + 1..index + 4).and_then(|octal| u8::from_str_radix(octal, 8).ok());
+        match escape {
+            Some(byte) => {
+                decoded.push(byte as char);
+                rest = &rest[index + 4..];
+            },
+            None => {
+                decoded.push('\\');
+                rest = &rest[index + 1..];
+            },
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Returns the environment variables ("KEY=VALUE") of a process, as they were when it executed its program
+/// # Arguments
+/// * `pid` - PID of the process
+pub fn process_environment(pid: Pid) -> Result<Vec<String>> {
+    let path = format!("/proc/{}/environ", pid);
+    match fs::read(&path) {
+        Ok(environ) => Ok(parse_environ(&environ)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound || err.raw_os_error() == Some(nix::libc::ESRCH) => {
+            Err(eyre::eyre!("Process {} has exited", pid))
+        },
+        Err(err) => Err(eyre::eyre!("Could not read {}: {}", path, err)),
+    }
+}
+
+/// Parses the NUL-separated variables of a /proc/<pid>/environ file
+fn parse_environ(environ: &[u8]) -> Vec<String> {
+    environ.split(|byte| *byte == 0)
+        .filter(|variable| !variable.is_empty())
+        .map(|variable| String::from_utf8_lossy(variable).into_owned())
+        .collect()
+}
+
+/// Returns the capability sets of a process
+/// # Arguments
+/// * `pid` - PID of the process
+pub fn process_capabilities(pid: Pid) -> Result<CapSet> {
+    let path = format!("/proc/{}/status", pid);
+    let status = fs::read_to_string(&path)
+        .map_err(|err| eyre::eyre!("Could not read {}: {}", path, err))?;
+    parse_capabilities(&status).ok_or_else(|| eyre::eyre!("No capabilities in {}", path))
+}
+
+/// Parses the CapInh, CapPrm, CapEff, CapBnd and CapAmb lines (hexadecimal masks) of a /proc/<pid>/status file
+fn parse_capabilities(status: &str) -> Option<CapSet> {
+    let set = |key: &str| status.lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+        .and_then(|mask| u64::from_str_radix(mask.trim(), 16).ok());
+    Some(CapSet {
+        inheritable: set("CapInh")?,
+        permitted: set("CapPrm")?,
+        effective: set("CapEff")?,
+        bounding: set("CapBnd")?,
+        // Linux 4.3
+        ambient: set("CapAmb").unwrap_or(0),
+    })
+}
+
+/// Returns the command, CPU time and memory of a process
+/// # Arguments
+/// * `pid` - PID of the process
+pub fn process_info(pid: Pid) -> Result<ProcessInfo> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    let (command, ticks) = parse_stat(&stat)
+        .ok_or_else(|| eyre::eyre!("Invalid /proc/{}/stat", pid))?;
+    let ticks_per_second = sysconf(SysconfVar::CLK_TCK)?.unwrap_or(100) as u64;
+    // The second field of statm is the resident set size, in pages
+    let statm = fs::read_to_string(format!("/proc/{}/statm", pid))?;
+    let pages: u64 = statm.split_whitespace().nth(1).and_then(|rss| rss.parse().ok()).unwrap_or(0);
+    let page_size = sysconf(SysconfVar::PAGE_SIZE)?.unwrap_or(4096) as u64;
+    Ok(ProcessInfo {
+        pid,
+        command,
+        cpu_time: Duration::from_millis(ticks * 1000 / ticks_per_second),
+        memory: pages * page_size,
+    })
+}
+
+/// Parses the command and the CPU time (utime + stime, in clock ticks) of a /proc/<pid>/stat file
+fn parse_stat(stat: &str) -> Option<(String, u64)> {
+    // pid (comm) state ppid ... the command may contain spaces and parentheses
+    let start = stat.find('(')?;
+    let end = stat.rfind(')')?;
+    let command = stat[start + 1..end].to_string();
+    let fields: Vec<&str> = stat[end + 1..].split_whitespace().collect();
+    // utime and stime are the 14th and 15th fields, the 3rd is the first one after the command
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some((command, utime + stime))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_parse_listening_ports() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n\
+                     0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1 1 0 100 0 0 10 0\n\
+                     1: 0100007F:C350 0100007F:1F90 01 00000000:00000000 00:00000000 00000000     0        0 2 1 0 20 4 30 10 -1\n";
+        assert_eq!(parse_listening_ports(table), vec![8080]);
+    }
+
+    #[test]
+    fn test_parse_capabilities() {
+        let status = "Name:\tsleep\nCapInh:\t0000000000000000\nCapPrm:\t000001ffffffffff\n\
+                      CapEff:\t000001ffffffffff\nCapBnd:\t000001ffffdfffff\nCapAmb:\t0000000000000000\n";
+        let caps = parse_capabilities(status).unwrap();
+        assert_eq!(caps.permitted, 0x1ffffffffff);
+        assert_eq!(caps.bounding & (1 << 21), 0);
+        assert_eq!(CapSet::names(caps.bounding).len(), 40);
+        assert!(parse_capabilities("Name:\tsleep\n").is_none());
+    }
+
+    #[test]
+    fn test_parse_listening_unix_sockets() {
+        let table = "Num       RefCount Protocol Flags    Type St Inode Path\n\
+                     0000000000000000: 00000002 00000000 00010000 0001 01 12345 /run/app.sock\n\
+                     0000000000000000: 00000002 00000000 00000000 0001 03 12346 /run/client.sock\n\
+                     0000000000000000: 00000002 00000000 00010000 0005 01 12347 @abstract\n\
+                     0000000000000000: 00000003 00000000 00000000 0001 03 12348\n";
+        assert_eq!(parse_listening_unix_sockets(table), vec![PathBuf::from("/run/app.sock"), PathBuf::from("@abstract")]);
+    }
+
+    #[test]
+    fn test_wait_for_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        wait_for_port(Pid::this(), port, Duration::from_secs(1)).unwrap();
+        drop(listener);
+        assert!(wait_for_port(Pid::this(), port, Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn test_parse_environ() {
+        let environ = b"PATH=/bin:/usr/bin\0HOME=/\0\0\0";
+        assert_eq!(parse_environ(environ), vec!["PATH=/bin:/usr/bin", "HOME=/"]);
+    }
+
+    #[test]
+    fn test_parse_stat() {
+        let stat = "1234 (my (weird) cmd) R 1 1234 1234 0 -1 4194304 100 0 0 0 250 50 0 0 20 0 1 0 100 1000 100";
+        assert_eq!(parse_stat(stat), Some(("my (weird) cmd".to_string(), 300)));
+    }
+
+    #[test]
+    fn test_root_mounts() {
+        let mountinfo = "\
+22 1 0:52 / / rw,relatime - overlay overlay rw
+23 22 0:21 / /proc rw,nosuid - proc proc rw
+24 22 8:1 /bin /bin ro - ext4 /dev/sda1 rw
+25 22 8:1 / / rw - ext4 /dev/sda1 rw
+";
+        assert_eq!(root_mounts(mountinfo), vec![(0, 52), (8, 1)]);
+    }
+
+    #[test]
+    fn test_mounts_below() {
+        let mountinfo = "\
+22 1 0:52 / / rw,relatime - overlay overlay rw
+30 22 8:1 / /mnt/rootfs rw,relatime - ext4 /dev/sda1 rw
+31 30 0:21 / /mnt/rootfs/proc rw,nosuid,nodev - proc proc rw
+32 22 8:1 / /mnt/rootfs2 rw - ext4 /dev/sda1 rw
+33 30 8:2 / /mnt/rootfs/my\\040dir ro,noexec - ext4 /dev/sda2 rw
+";
+        assert_eq!(mounts_below(mountinfo, Path::new("/mnt/rootfs")), vec![
+            (PathBuf::from("/mnt/rootfs"), "rw,relatime".to_string()),
+            (PathBuf::from("/mnt/rootfs/proc"), "rw,nosuid,nodev".to_string()),
+            (PathBuf::from("/mnt/rootfs/my dir"), "ro,noexec".to_string()),
+        ]);
+    }
+}
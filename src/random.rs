@@ -22,9 +22,74 @@
  */
 
 use rand::Rng;
+use serde::{Serialize, Deserialize};
 
-pub fn generate_random_128_id() -> String {
+/// Alphabet of the base32 IDs (RFC 4648, lowercase so the IDs are valid hostnames)
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Format of the random IDs of the containers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IdFormat {
+    /// 32 hexadecimal digits, e.g. "3f2a9c0d5e7b41f8a6c2d9e0b1f4a7c3"
+    #[default]
+    HEX,
+    /// 26 base32 characters (a-z, 2-7), e.g. "h4xq2mzk7vdnr3ewb5tyl6jca4"
+    BASE32,
+    /// A UUID v4, e.g. "3f2a9c0d-5e7b-41f8-a6c2-d9e0b1f4a7c3"
+    UUID,
+}
+
+/// Generates a random 128 bits ID
+///
+/// The bits come from `rand::thread_rng`, a CSPRNG (ChaCha12) seeded from the OS (getrandom) and reseeded
+/// periodically, so the IDs can't be predicted from the previous ones.
+/// # Arguments
+/// * `format` - Format of the ID
+pub fn generate_random_128_id(format: IdFormat) -> String {
     let mut rng = rand::thread_rng();
-    let rand_int: i128 = rng.gen();
-    format!("{:x}", rand_int)
+    let rand_int: u128 = rng.gen();
+    format_id(rand_int, format)
+}
+
+/// Formats a 128 bits ID
+fn format_id(id: u128, format: IdFormat) -> String {
+    match format {
+        IdFormat::HEX => format!("{:032x}", id),
+        IdFormat::BASE32 => {
+            // 26 groups of 5 bits, the first one only has 3
+            (0..26).rev()
+                .map(|group| BASE32_ALPHABET[((id >> (group * 5)) & 0x1f) as usize] as char)
+                .collect()
+        },
+        IdFormat::UUID => {
+            // Version 4 and variant 1 (RFC 4122), the other 122 bits are random
+            let uuid = (id & !(0xf << 76) & !(0x3 << 62)) | (0x4 << 76) | (0x2 << 62);
+            let hex = format!("{:032x}", uuid);
+            format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_id_formats() {
+        let hex = generate_random_128_id(IdFormat::HEX);
+        assert_eq!(hex.len(), 32);
+        assert!(hex.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c)));
+        let base32 = generate_random_128_id(IdFormat::BASE32);
+        assert_eq!(base32.len(), 26);
+        assert!(base32.chars().all(|c| c.is_ascii_lowercase() || ('2'..='7').contains(&c)));
+        let uuid = generate_random_128_id(IdFormat::UUID);
+        let groups: Vec<&str> = uuid.split('-').collect();
+        assert_eq!(groups.iter().map(|group| group.len()).collect::<Vec<_>>(), vec![8, 4, 4, 4, 12]);
+        assert!(groups.iter().all(|group| group.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))));
+        // Version and variant
+        assert!(groups[2].starts_with('4'));
+        assert!(groups[3].starts_with(['8', '9', 'a', 'b']));
+        assert_eq!(format_id(u128::MAX, IdFormat::UUID), "ffffffff-ffff-4fff-bfff-ffffffffffff");
+        assert_eq!(format_id(0, IdFormat::BASE32), "a".repeat(26));
+    }
 }
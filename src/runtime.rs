@@ -21,40 +21,702 @@
  * THE SOFTWARE.
  */
 
-use std::io::Write;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
 
+use crate::cgroup;
+use crate::procfs::CapSet;
+use crate::filesystem::BindMount;
+use crate::filesystem::DeviceNode;
 use crate::filesystem::StorageDriver;
 use crate::ipc;
 use crate::ipc::Action;
 use crate::ipc::ConsumerChannel;
+use crate::ipc::IpcFormat;
 use crate::syscall;
 use crate::filesystem;
+use crate::logs::LogDriverType;
+use crate::random::IdFormat;
+use crate::seccomp::{self, SeccompAction, SeccompProfile};
 use crate::syscall::Command;
+use crate::syscall::CommandResult;
+use crate::syscall::ContainerExit;
+use crate::syscall::ExecType;
+use crate::syscall::IoPriority;
+use crate::syscall::Namespaces;
+use crate::syscall::RootfsOptions;
+use crate::syscall::SchedPolicy;
+use crate::syscall::GroupInfo;
 use crate::syscall::UserInfo;
 
 use color_eyre::Result;
-use nix::unistd::sethostname;
+use color_eyre::eyre::{self, WrapErr};
+use nix::errno::Errno;
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::signal::{kill, killpg, Signal};
+use nix::sys::resource::{setrlimit, Resource};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{chdir, close, getgid, getuid, setgid, setgroups, sethostname, setresuid, ttyname, Gid, Pid, Uid};
 use serde::Deserialize;
 use serde::Serialize;
 
+/// Exit codes of the container's init process when the container fails before running its workload.
+///
+/// A workload replacing the init process may exit with the same codes (e.g. 125), they are only taken for
+/// failures until the runtime reports it is replaced (see `syscall::CommandResult`). The failures to
+/// execute a command are reported through the same channel, `NOT_EXECUTABLE` and `NOT_FOUND` are never
+/// read from an exit code.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    /// The handshake with the host or the setup of the standard streams failed
+    SETUP = 120,
+    /// A filesystem could not be mounted (the root filesystem, a bind mount, /proc, ...)
+    MOUNT = 121,
+    /// The container could not switch to its root filesystem
+    ROOTFS = 122,
+    /// A command could not be executed
+    EXEC = 123,
+    /// Any other error of the container's runtime
+    RUNTIME = 124,
+    /// The container's runtime panicked, see `syscall::create_container`
+    PANIC = 125,
+    /// The command was found but could not be executed (EACCES), as shells report it
+    NOT_EXECUTABLE = 126,
+    /// The command was not found (ENOENT), as shells report it
+    NOT_FOUND = 127,
+}
+
+impl ExitCode {
+
+    /// Returns the failure corresponding to an exit code, if any
+    /// # Arguments
+    /// * `code` - Exit code of the container's init process
+    pub fn from_code(code: i32) -> Option<ExitCode> {
+        match code {
+            120 => Some(ExitCode::SETUP),
+            121 => Some(ExitCode::MOUNT),
+            122 => Some(ExitCode::ROOTFS),
+            123 => Some(ExitCode::EXEC),
+            124 => Some(ExitCode::RUNTIME),
+            125 => Some(ExitCode::PANIC),
+            _ => None,
+        }
+    }
+
+    /// Returns the exit code of a process that could not execute its command
+    /// # Arguments
+    /// * `err` - The error of `syscall::exec`
+    pub fn from_exec_error(err: &eyre::Report) -> ExitCode {
+        match err.downcast_ref::<Errno>() {
+            Some(errno) => ExitCode::from_errno(*errno),
+            None => ExitCode::EXEC,
+        }
+    }
+
+    /// Returns the failure corresponding to the error of a command that could not be executed, as
+    /// reported by `syscall::CommandResult::FAILED`
+    /// # Arguments
+    /// * `errno` - The error
+    pub fn from_errno(errno: Errno) -> ExitCode {
+        match errno {
+            Errno::ENOENT => ExitCode::NOT_FOUND,
+            Errno::EACCES => ExitCode::NOT_EXECUTABLE,
+            _ => ExitCode::EXEC,
+        }
+    }
+
+}
+
+impl fmt::Display for ExitCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            ExitCode::SETUP => "Could not set up the container",
+            ExitCode::MOUNT => "Could not mount the container's filesystems",
+            ExitCode::ROOTFS => "Could not switch to the container's root filesystem",
+            ExitCode::EXEC => "Could not execute the container's command",
+            ExitCode::RUNTIME => "Container runtime error",
+            ExitCode::PANIC => "The container's runtime panicked",
+            ExitCode::NOT_EXECUTABLE => "The container's command is not executable",
+            ExitCode::NOT_FOUND => "The container's command was not found",
+        };
+        write!(f, "{} (exit code {})", description, *self as i32)
+    }
+}
+
+/// Default base of the scratch directories of the containers, see `RuntimeOptions::managed_scratch`
+pub const DEFAULT_SCRATCH_ROOT: &str = "/var/lib/libcontainer-rs";
+
+/// How `RuntimeOptions::merge` combines the lists of the base and of the override
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListMerge {
+    /// The items of the override are added to the base's (once, the items already in the base are skipped)
+    APPEND,
+    /// The list of the override replaces the base's
+    REPLACE,
+}
+
+/// A command executed on the host at a point of the container's lifecycle, as the OCI hooks
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hook {
+    /// Absolute path of the executable, in the host
+    pub path: PathBuf,
+    /// Arguments of the command, not including the executable
+    pub args: Vec<String>,
+    /// Environment of the command, e.g. ["KEY=value"], the host's environment is not inherited
+    pub env: Vec<String>,
+}
+
+/// Options of the container's execution environment
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RuntimeOptions {
-    hostname: Option<String>,
-    user: String,
-    group: String,
-    cwd: String,
+    /// Hostname of the container (default: derived from the ID, see `hostname_length`)
+    pub hostname: Option<String>,
+    /// Length of the hostname derived from the ID when `hostname` is not set, at most 63 (the maximum
+    /// length of a DNS label). Default: 12
+    pub hostname_length: usize,
+    /// Format of the container's random ID, which the hostname is derived from (default: `IdFormat::HEX`)
+    pub id_format: IdFormat,
+    /// User running the commands, a name of the container's /etc/passwd or a numeric UID. Other than
+    /// root, the user has no capability: `capabilities` only bounds what its setuid programs can gain
+    pub user: String,
+    /// Group running the commands, a name of the container's /etc/group or a numeric GID
+    /// (see `GroupInfo::from_name_or_id`)
+    pub group: String,
+    /// Working directory of the container's init and of the commands, inside the container
+    pub cwd: String,
+    /// Environment variables ("KEY=VALUE") of the container's init and of every command, added to the
+    /// defaults (HOME, PATH, ...). The environment of the host process is never inherited
+    pub env: Vec<String>,
+    /// Value of the `container` environment variable, which systemd and others check to detect they run
+    /// in a container, e.g. "oci" (default: "libcontainer-rs")
+    pub container_marker: String,
+    /// Where to send the container's stdout and stderr (default: inherited from the host process)
+    pub log_driver: Option<LogDriverType>,
+    /// Prepended to every line of the container's output before it is handed to the log driver, with
+    /// `{id}` replaced by the container ID, e.g. "[{id}] " (default: None). Requires `log_driver`
+    pub log_prefix: Option<String>,
+    /// Signals received by the container's init that are forwarded to the workload, only with
+    /// `namespaces.pid`, except `stop_signal` (default: `syscall::default_forward_signals()`)
+    #[serde(with = "signal_names")]
+    pub forward_signals: Vec<Signal>,
+    /// Signal making the container's init stop, e.g. sent with `kill` when the IPC channel is unavailable.
+    /// It is not forwarded to the workload (default: SIGTERM)
+    #[serde(with = "signal_name")]
+    pub stop_signal: Signal,
+    /// Options of the bind mount of the root filesystem (default: read-write, inherited propagation)
+    pub rootfs: RootfsOptions,
+    /// Mount a fresh tmpfs at /tmp (and /var/tmp, if present in the image), so no state is shared between runs
+    pub private_tmp: bool,
+    /// Size of the private /tmp tmpfs, e.g. "64m" (default: half of the RAM)
+    pub tmp_size: Option<String>,
+    /// Mount a fresh tmpfs at /run, for the runtime state of the services (pid files, sockets, ...)
+    pub run_tmpfs: bool,
+    /// Size of the /run tmpfs, e.g. "64m" (default: half of the RAM)
+    pub run_size: Option<String>,
+    /// Maximum number of IPC messages sent to the container and not yet received (default: unbounded).
+    /// See `ipc::create_bounded_ipc_channels`
+    pub ipc_capacity: Option<usize>,
+    /// Serialization format of the messages sent to the container (default: `IpcFormat::BINCODE`)
+    pub ipc_format: IpcFormat,
+    /// SELinux label of the commands executed in the container (default: the label of the host process).
+    /// See `OverlayDriver::with_selinux_label` for the label of the container's files
+    pub selinux_label: Option<String>,
+    /// Make the root filesystem visible (e.g. to monitoring tools) at its path in the host while the
+    /// container runs. The host bind mounts the mount point on itself as a shared mount before creating
+    /// the container, which mounts the root filesystem on its copy before making its mounts private: the
+    /// root filesystem propagates back to the host, the other mounts (/proc, /dev, bind mounts, ...) stay
+    /// private to the container. The host can then read and modify the container's files. Both are
+    /// unmounted from the host by `Container::wait_for_container`. Requires a storage driver that knows
+    /// its mount point before mounting (see `StorageDriver::mount_target`) and conflicts with `userns`
+    pub host_visible_rootfs: bool,
+    /// Allocate a scratch directory for the container, `<scratch_root>/<id>`, created when it starts and
+    /// removed once it stops. Storage drivers without a target of their own use it, see
+    /// `OverlayDriver::in_scratch_dir`
+    pub managed_scratch: bool,
+    /// Base of the scratch directories (default: /var/lib/libcontainer-rs)
+    pub scratch_root: PathBuf,
+    /// Directory where the state of the container is persisted, as `<state_root>/<id>.json`, from its start
+    /// until the `Container` is dropped (default: None, not persisted). See `state::watch` to follow it
+    pub state_root: Option<PathBuf>,
+    /// Device nodes created in the container's /dev (bind mounted from the host's with `userns`), unless
+    /// `host_devices` is set (default: `DeviceNode::default_devices()`)
+    pub devices: Vec<DeviceNode>,
+    /// Permissions of the container's /dev/shm, always mounted nosuid, nodev and noexec, unless
+    /// `host_devices` is set (default: 0o1777)
+    pub shm_mode: u32,
+    /// Namespaces created for the container (default: every namespace but the time namespace)
+    pub namespaces: Namespaces,
+    /// Create a user namespace for the container, to run it rootless: the IDs of the container are mapped
+    /// to other IDs of the host (see `syscall::setup_userns_mappings`), its root is only root inside of it.
+    /// The device nodes of /dev can't be created in a user namespace, the host's `devices` are bind
+    /// mounted instead
+    pub userns: bool,
+    /// Ranges of user IDs of the user namespace, as (first ID inside the container, first ID in the host,
+    /// size), e.g. (0, 100000, 65536) (default: the container's root is the user starting it)
+    pub uid_map: Vec<(u32, u32, u32)>,
+    /// Ranges of group IDs of the user namespace, see `uid_map` (default: the container's root group is
+    /// the group of the user starting it)
+    pub gid_map: Vec<(u32, u32, u32)>,
+    /// Allocate a pseudo-terminal for the container, see `Container::pty`. It is also the container's
+    /// /dev/console (unless `host_devices` is set), as expected by init systems
+    pub tty: bool,
+    /// Terminal of the host whose window size the container's pseudo-terminal gets at start, and follows
+    /// afterwards, e.g. stdin (default: None). Ignored when it is not a terminal, or without `tty`.
+    /// A file descriptor is only meaningful in the current process, it is not serialized
+    #[serde(skip)]
+    pub host_terminal: Option<RawFd>,
+    /// Scheduling policy of the container processes (default: inherited)
+    pub sched_policy: Option<SchedPolicy>,
+    /// Nice value of the container processes (default: inherited)
+    pub nice: Option<i32>,
+    /// Pin the scheduling priority of the container processes, once `sched_policy` and `nice` are applied:
+    /// RLIMIT_NICE and RLIMIT_RTPRIO are set so they can't raise their nice value or real-time priority
+    /// above it. Processes with CAP_SYS_NICE are not bound by these limits, it is dropped from
+    /// `capabilities` (from every capability of the host process if None). An unprivileged runtime can't
+    /// pin a priority above the hard limits it inherits (see `syscall::check_pinnable_priority`)
+    pub pin_scheduling: bool,
+    /// I/O priority of the container processes, e.g. `IoPriority::IDLE` for background containers
+    /// (default: inherited)
+    pub io_priority: Option<IoPriority>,
+    /// Host directories bind mounted inside the container
+    pub mounts: Vec<BindMount>,
+    /// Place the container in its own cgroup v2, see `Container::cgroup_path`. Implies `enable_accounting`
+    pub cgroup: bool,
+    /// Place the container in its own cgroup v2 only to account its resource usage (see `Container::stats`),
+    /// with the cpu, memory, io and pids controllers enabled and no limit set. Implied by `cgroup`, the
+    /// resource limits and `check_pids_limit`, which all read the usage
+    pub enable_accounting: bool,
+    /// Maximum memory of the container, in bytes (memory.max of its cgroup). Implies `cgroup`
+    pub memory_limit: Option<u64>,
+    /// Memory usage above which the container's processes are throttled and reclaimed from, in bytes
+    /// (memory.high of its cgroup), see `state::StateEvent::MEMORY_PRESSURE`. Implies `cgroup`
+    pub memory_high: Option<u64>,
+    /// CPU time the container can use per period, as (quota, period) in microseconds (cpu.max of its
+    /// cgroup), e.g. (50000, 100000) for half a CPU. Implies `cgroup`
+    pub cpu_quota: Option<(u64, u64)>,
+    /// Maximum number of processes (and threads) of the container (pids.max of its cgroup). Implies `cgroup`
+    pub pids_limit: Option<u64>,
+    /// Refuse to execute commands with `ExecType::FORK` (see `Container::execute_in_container`) once the
+    /// container's cgroup has reached its pids.max, with a clear error instead of a fork failing with EAGAIN
+    /// in the container. Implies `enable_accounting`
+    pub check_pids_limit: bool,
+    /// Host directory receiving the core dumps of the container's processes (default: None, as configured
+    /// by the host). Core dumps are written where the host's kernel.core_pattern says, resolved inside the
+    /// container, so the directory is bind mounted there. Only an absolute pattern with a fixed directory
+    /// (e.g. /var/crash/core.%p) can be collected, the container fails to start with any other:
+    /// - a relative pattern (e.g. core): the dumps are written in the working directory of each crashing process
+    /// - a pipe to a program (e.g. systemd-coredump): the dumps are handed to the host's program
+    ///
+    /// The RLIMIT_CORE of the container is raised to unlimited.
+    pub core_dumps: Option<PathBuf>,
+    /// Mount the container's cgroup (implies `cgroup`) writable at /sys/fs/cgroup, in a new cgroup namespace,
+    /// so the container can manage its own child cgroups (e.g. to run systemd as init)
+    pub delegate_cgroup: bool,
+    /// Bind mount the container's cgroup (implies `cgroup`) read-only at this path inside the container
+    /// (e.g. /sys/fs/cgroup), so the processes can read their usage (memory.current, cpu.stat, ...) but not
+    /// change their limits. A safer alternative to `delegate_cgroup` (default: None)
+    pub readonly_cgroup: Option<PathBuf>,
+    /// Kill the container when the thread that started it exits, e.g. when the host process crashes
+    /// (default: true). Disable it to let a detached container outlive the host, see `Container::detach`
+    ///
+    /// Note: the kernel tracks the thread, not the process (PR_SET_PDEATHSIG): a container started from a
+    /// short-lived thread (e.g. a worker of a thread pool) is killed when that thread exits, even though
+    /// the host process keeps running. Disable it unless the thread that calls `Container::start` outlives
+    /// the container, e.g. the main thread
+    pub kill_on_parent_exit: bool,
+    /// Paths remounted read-only once /proc and /sys are mounted, the missing ones are ignored
+    /// (default: /proc/bus, /proc/fs, /proc/irq, /proc/sys and /proc/sysrq-trigger, as runc)
+    pub readonly_paths: Vec<PathBuf>,
+    /// Bind mount the host's /dev instead of creating a minimal one. See `filesystem::bind_host_devices`
+    /// Warning: the container gets access to every device of the host
+    pub host_devices: bool,
+    /// Seccomp profile of the commands executed in the container (see `seccomp::apply_seccomp`), installed
+    /// right before executing each of them, so the runtime itself is not filtered. More rules can be
+    /// stacked on a running container with `Container::add_seccomp_rules` (default: None)
+    pub seccomp: Option<SeccompProfile>,
+    /// Capabilities kept by the container, e.g. ["CAP_NET_BIND_SERVICE"], every other one is dropped
+    /// before executing any command, and by the commands of `Container::exec_join` (see
+    /// `syscall::drop_capabilities`). None keeps the capabilities of the host process, an empty list drops
+    /// them all (default: None)
+    pub capabilities: Option<Vec<String>>,
+    /// Hooks executed on the host once the container has stopped and its resources are released, in order.
+    /// Each one gets the state of the container (see `state::State`) as JSON on its stdin (default: none)
+    pub poststop_hooks: Vec<Hook>,
 }
 
 impl RuntimeOptions {
     pub fn default() -> RuntimeOptions {
         RuntimeOptions {
             hostname: None,
+            hostname_length: 12,
+            id_format: IdFormat::default(),
             user: "root".to_string(),
             group: "root".to_string(),
             cwd: "/".to_string(),
+            env: vec![],
+            container_marker: "libcontainer-rs".to_string(),
+            log_driver: None,
+            log_prefix: None,
+            forward_signals: syscall::default_forward_signals(),
+            stop_signal: Signal::SIGTERM,
+            rootfs: RootfsOptions::default(),
+            private_tmp: false,
+            tmp_size: None,
+            run_tmpfs: true,
+            run_size: None,
+            ipc_capacity: None,
+            ipc_format: IpcFormat::default(),
+            selinux_label: None,
+            host_visible_rootfs: false,
+            managed_scratch: false,
+            scratch_root: PathBuf::from(DEFAULT_SCRATCH_ROOT),
+            state_root: None,
+            devices: DeviceNode::default_devices(),
+            shm_mode: 0o1777,
+            namespaces: Namespaces::default(),
+            tty: false,
+            host_terminal: None,
+            sched_policy: None,
+            nice: None,
+            pin_scheduling: false,
+            io_priority: None,
+            mounts: vec![],
+            cgroup: false,
+            enable_accounting: false,
+            memory_limit: None,
+            memory_high: None,
+            cpu_quota: None,
+            pids_limit: None,
+            check_pids_limit: false,
+            delegate_cgroup: false,
+            readonly_cgroup: None,
+            core_dumps: None,
+            kill_on_parent_exit: true,
+            readonly_paths: ["/proc/bus", "/proc/fs", "/proc/irq", "/proc/sys", "/proc/sysrq-trigger"]
+                .iter().map(PathBuf::from).collect(),
+            host_devices: false,
+            seccomp: None,
+            capabilities: None,
+            userns: false,
+            uid_map: vec![],
+            gid_map: vec![],
+            poststop_hooks: vec![],
+        }
+    }
+
+    /// Layers options, e.g. the options of a container over defaults shared by every container
+    /// # Arguments
+    /// * `base` - The base options
+    /// * `overrides` - The options taking precedence
+    /// * `lists` - Whether the lists of the override are appended to the base's or replace them
+    /// # Returns
+    /// The merged options:
+    /// - an `Option` field of the override takes precedence when it is set (`Some`)
+    /// - any other field of the override takes precedence when it differs from its default value, so an
+    ///   override can't reset a field of the base to its default
+    /// - a list of the override is merged per `lists` when it differs from its default value. A variable
+    ///   of the environment is defined once, with the value of the override when both define it
+    /// - `namespaces` are merged namespace by namespace, as the other fields
+    /// - `uid_map` and `gid_map` of the override always replace the base's: appended ranges could overlap,
+    ///   which the kernel rejects
+    pub fn merge(base: RuntimeOptions, overrides: RuntimeOptions, lists: ListMerge) -> RuntimeOptions {
+        let default = RuntimeOptions::default();
+        macro_rules! option {
+            ($field:ident) => { overrides.$field.or(base.$field) };
+        }
+        macro_rules! value {
+            ($field:ident) => { merge_value(base.$field, overrides.$field, default.$field) };
+        }
+        macro_rules! list {
+            ($field:ident) => { merge_list(base.$field, overrides.$field, default.$field, lists) };
+        }
+        let env = match lists {
+            _ if overrides.env.is_empty() => base.env,
+            ListMerge::APPEND => dedup_environment(overrides.env.into_iter().chain(base.env).collect()),
+            ListMerge::REPLACE => dedup_environment(overrides.env),
+        };
+        RuntimeOptions {
+            hostname: option!(hostname),
+            hostname_length: value!(hostname_length),
+            id_format: value!(id_format),
+            user: value!(user),
+            group: value!(group),
+            cwd: value!(cwd),
+            env,
+            container_marker: value!(container_marker),
+            log_driver: option!(log_driver),
+            log_prefix: option!(log_prefix),
+            forward_signals: list!(forward_signals),
+            stop_signal: value!(stop_signal),
+            rootfs: value!(rootfs),
+            private_tmp: value!(private_tmp),
+            tmp_size: option!(tmp_size),
+            run_tmpfs: value!(run_tmpfs),
+            run_size: option!(run_size),
+            ipc_capacity: option!(ipc_capacity),
+            ipc_format: value!(ipc_format),
+            selinux_label: option!(selinux_label),
+            host_visible_rootfs: value!(host_visible_rootfs),
+            managed_scratch: value!(managed_scratch),
+            scratch_root: value!(scratch_root),
+            state_root: option!(state_root),
+            devices: list!(devices),
+            shm_mode: value!(shm_mode),
+            namespaces: Namespaces {
+                uts: merge_value(base.namespaces.uts, overrides.namespaces.uts, default.namespaces.uts),
+                ipc: merge_value(base.namespaces.ipc, overrides.namespaces.ipc, default.namespaces.ipc),
+                pid: merge_value(base.namespaces.pid, overrides.namespaces.pid, default.namespaces.pid),
+                net: merge_value(base.namespaces.net, overrides.namespaces.net, default.namespaces.net),
+                time: overrides.namespaces.time.or(base.namespaces.time),
+            },
+            tty: value!(tty),
+            host_terminal: option!(host_terminal),
+            sched_policy: option!(sched_policy),
+            nice: option!(nice),
+            pin_scheduling: value!(pin_scheduling),
+            io_priority: option!(io_priority),
+            mounts: list!(mounts),
+            cgroup: value!(cgroup),
+            enable_accounting: value!(enable_accounting),
+            memory_limit: option!(memory_limit),
+            memory_high: option!(memory_high),
+            cpu_quota: option!(cpu_quota),
+            pids_limit: option!(pids_limit),
+            check_pids_limit: value!(check_pids_limit),
+            delegate_cgroup: value!(delegate_cgroup),
+            readonly_cgroup: option!(readonly_cgroup),
+            core_dumps: option!(core_dumps),
+            kill_on_parent_exit: value!(kill_on_parent_exit),
+            readonly_paths: list!(readonly_paths),
+            host_devices: value!(host_devices),
+            seccomp: option!(seccomp),
+            capabilities: option!(capabilities),
+            userns: value!(userns),
+            uid_map: merge_list(base.uid_map, overrides.uid_map, default.uid_map, ListMerge::REPLACE),
+            gid_map: merge_list(base.gid_map, overrides.gid_map, default.gid_map, ListMerge::REPLACE),
+            poststop_hooks: list!(poststop_hooks),
+        }
+    }
+
+    /// The UID of the configured user, resolved in the /etc/passwd of the current root filesystem (the
+    /// container's, once switched to it)
+    pub fn uid(&self) -> Result<Uid> {
+        match UserInfo::from_name(self.user.as_str()) {
+            Ok(user) => Ok(user.uid),
+            // A numeric UID doesn't need an entry, and images without /etc/passwd still run as root by default
+            Err(_) if self.user == RuntimeOptions::default().user => Ok(Uid::from_raw(0)),
+            Err(err) => match self.user.parse() {
+                Ok(uid) => Ok(Uid::from_raw(uid)),
+                Err(_) => Err(err),
+            },
+        }
+    }
+
+    /// The GID of the configured group, resolved in the /etc/group of the current root filesystem
+    pub fn gid(&self) -> Result<Gid> {
+        match GroupInfo::from_name_or_id(&self.group) {
+            Ok(group) => Ok(group.gid),
+            // Images without /etc/group (e.g. built from scratch) still run as root by default
+            Err(_) if self.group == RuntimeOptions::default().group => Ok(Gid::from_raw(0)),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Whether a resource limit of the container's cgroup is set
+    pub fn has_resource_limits(&self) -> bool {
+        self.memory_limit.is_some() || self.memory_high.is_some() || self.cpu_quota.is_some()
+            || self.pids_limit.is_some()
+    }
+
+    /// Whether the container's cgroup accounts its resource usage, see `enable_accounting`
+    pub fn has_accounting(&self) -> bool {
+        self.cgroup || self.enable_accounting || self.has_resource_limits() || self.check_pids_limit
+    }
+
+    /// The capabilities kept by the commands, see `capabilities`. CAP_SYS_NICE is dropped with
+    /// `pin_scheduling`, as the pinned priority doesn't bind the processes that have it
+    /// # Returns
+    /// A bit mask (see `CapSet::from_names`), None to keep the capabilities of the host process
+    pub fn kept_capabilities(&self) -> Result<Option<u64>> {
+        let keep = match &self.capabilities {
+            Some(capabilities) => Some(CapSet::from_names(capabilities)?),
+            None => None,
+        };
+        if !self.pin_scheduling {
+            return Ok(keep);
+        }
+        let sys_nice = CapSet::from_names(&["CAP_SYS_NICE".to_string()])?;
+        Ok(Some(keep.unwrap_or(u64::MAX) & !sys_nice))
+    }
+
+    /// Checks the options for incompatible combinations, which would otherwise fail in the container, or
+    /// be silently ignored:
+    /// - `delegate_cgroup` and `readonly_cgroup`: the cgroup is mounted either writable or read-only
+    /// - `hostname` without `namespaces.uts`: the hostname of the host would change
+    /// - an empty `hostname`, or a `hostname_length` of 0 when it is derived from the ID
+    /// - `host_devices` with custom `devices` or `shm_mode`: the host's /dev is used instead
+    /// - `tmp_size` without `private_tmp`, `run_size` without `run_tmpfs`: there is no tmpfs to size
+    /// - `log_prefix` without `log_driver`: the output is not read by the host
+    /// - `uid_map` or `gid_map` without `userns`: there is no user namespace to map
+    /// - `userns` with `host_devices`: the host's device nodes, owned by unmapped IDs, would be reachable
+    ///   by a container meant to run rootless
+    /// - `delegate_cgroup` with a writable bind mount of the host's cgroup hierarchy in `mounts`: the
+    ///   container would escape its cgroup namespace through it
+    /// # Returns
+    /// An error describing every conflict found
+    pub fn validate(&self) -> Result<()> {
+        let mut conflicts = vec![];
+        if self.delegate_cgroup && self.readonly_cgroup.is_some() {
+            conflicts.push("delegate_cgroup mounts the cgroup writable, readonly_cgroup mounts it read-only");
+        }
+        if self.hostname.is_some() && !self.namespaces.uts {
+            conflicts.push("hostname requires namespaces.uts, it would be the hostname of the host");
+        }
+        if self.hostname.as_deref() == Some("") {
+            conflicts.push("hostname can't be empty");
+        }
+        if self.hostname.is_none() && self.hostname_length == 0 && self.namespaces.uts {
+            conflicts.push("hostname_length can't be 0, the hostname derived from the ID would be empty");
+        }
+        if self.host_devices && self.devices != DeviceNode::default_devices() {
+            conflicts.push("devices are not created with host_devices, the host's /dev is used");
+        }
+        if self.host_devices && self.shm_mode != RuntimeOptions::default().shm_mode {
+            conflicts.push("shm_mode is ignored with host_devices, the host's /dev/shm is used");
+        }
+        if self.host_visible_rootfs && self.userns {
+            conflicts.push("host_visible_rootfs conflicts with userns, the mounts of a user namespace don't propagate to the host");
+        }
+        if self.tmp_size.is_some() && !self.private_tmp {
+            conflicts.push("tmp_size requires private_tmp");
+        }
+        if self.run_size.is_some() && !self.run_tmpfs {
+            conflicts.push("run_size requires run_tmpfs");
         }
+        if self.log_prefix.is_some() && self.log_driver.is_none() {
+            conflicts.push("log_prefix requires log_driver");
+        }
+        if !self.userns && (!self.uid_map.is_empty() || !self.gid_map.is_empty()) {
+            conflicts.push("uid_map and gid_map require userns");
+        }
+        if self.userns && self.host_devices {
+            conflicts.push("host_devices can't be used with userns, the host's devices would be exposed to a rootless container");
+        }
+        if self.delegate_cgroup && self.mounts.iter().any(|mount| !mount.readonly && mount.source.starts_with(cgroup::CGROUP_ROOT)) {
+            conflicts.push("delegate_cgroup isolates the cgroup in a namespace, a writable mount of the host's cgroup hierarchy bypasses it");
+        }
+        if conflicts.is_empty() {
+            return Ok(());
+        }
+        Err(eyre::eyre!("Incompatible runtime options: {}", conflicts.join("; ")))
+    }
+}
+
+/// Merges a field of the options, see `RuntimeOptions::merge`
+fn merge_value<T: PartialEq>(base: T, overrides: T, default: T) -> T {
+    if overrides != default {
+        overrides
+    } else {
+        base
+    }
+}
+
+/// Merges a list of the options, see `RuntimeOptions::merge`
+fn merge_list<T: PartialEq>(base: Vec<T>, overrides: Vec<T>, default: Vec<T>, lists: ListMerge) -> Vec<T> {
+    if overrides == default {
+        return base;
+    }
+    match lists {
+        ListMerge::APPEND => {
+            let mut merged = base;
+            for item in overrides {
+                if !merged.contains(&item) {
+                    merged.push(item);
+                }
+            }
+            merged
+        },
+        ListMerge::REPLACE => overrides,
+    }
+}
+
+/// (De)serializes a signal by its name, e.g. "SIGTERM"
+pub(crate) mod signal_name {
+    use std::str::FromStr;
+    use nix::sys::signal::Signal;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(signal: &Signal, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(signal.as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Signal, D::Error> {
+        Signal::from_str(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+/// (De)serializes signals by their name, e.g. "SIGTERM"
+mod signal_names {
+    use std::str::FromStr;
+    use nix::sys::signal::Signal;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(signals: &[Signal], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(signals.iter().map(|signal| signal.as_str()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Signal>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|name| Signal::from_str(name).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+/// Maximum length of a DNS label (RFC 1035)
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// Derives a hostname from the ID of a container, keeping it a valid DNS label: lowercase letters,
+/// digits and hyphens, not starting nor ending with a hyphen
+/// # Arguments
+/// * `id` - ID of the container
+/// * `length` - Maximum length of the hostname (capped to 63)
+fn derive_hostname(id: &str, length: usize) -> String {
+    let hostname: String = id.chars()
+        .map(|c| c.to_ascii_lowercase())
+        .filter(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || *c == '-')
+        .skip_while(|c| *c == '-')
+        .take(length.min(MAX_LABEL_LENGTH))
+        .collect();
+    hostname.trim_end_matches('-').to_string()
+}
+
+/// Returns the directory of the container where the core dumps are written, for the host's kernel.core_pattern
+/// (see `RuntimeOptions::core_dumps`)
+pub(crate) fn host_core_dump_dir() -> Result<PathBuf> {
+    let pattern = fs::read_to_string("/proc/sys/kernel/core_pattern")?;
+    core_dump_dir(pattern.trim_end())
+}
+
+/// Returns the directory of the container where the core dumps are written, for a kernel.core_pattern
+fn core_dump_dir(pattern: &str) -> Result<PathBuf> {
+    if pattern.starts_with('|') {
+        return Err(eyre::eyre!("Core dumps are piped to a program of the host (core_pattern {}), they can't be collected", pattern));
     }
+    if !pattern.starts_with('/') {
+        return Err(eyre::eyre!("Core dumps are written in the working directory of the crashing process (core_pattern {}), they can't be collected", pattern));
+    }
+    let dir = Path::new(pattern).parent().unwrap_or(Path::new("/"));
+    if dir == Path::new("/") || dir.to_string_lossy().contains('%') {
+        return Err(eyre::eyre!("Unsupported core dump directory in core_pattern {}", pattern));
+    }
+    Ok(dir.to_path_buf())
 }
 
 pub struct Runtime {
@@ -65,7 +727,13 @@ pub struct Runtime {
     /// Root filesystem of the container
     fs: Box<dyn StorageDriver>,
     consumer_channel: ConsumerChannel,
-    runtime_options: RuntimeOptions
+    runtime_options: RuntimeOptions,
+    /// Process groups of the forked commands, by name (see `Command::process_group`)
+    process_groups: HashMap<String, Pid>,
+    /// Runtime's end of the command-result channel (see `syscall::command_result_channel`)
+    command_results: Option<RawFd>,
+    /// Forked commands streaming their stdout, whose exit is reported to the host
+    streamed: HashSet<i32>,
 }
 
 impl Runtime {
@@ -78,85 +746,676 @@ impl Runtime {
     pub fn new(id: String, fs: Box<dyn StorageDriver>, consumer_channel: ConsumerChannel, runtime_options: RuntimeOptions) -> Runtime {
         let hostname = runtime_options
             .hostname.clone()
-            .unwrap_or_else(|| id.clone().chars().take(12).collect());
+            .unwrap_or_else(|| derive_hostname(&id, runtime_options.hostname_length));
         Runtime {
             id: id.clone(),
             hostname: hostname,
             fs: fs,
             consumer_channel: consumer_channel,
-            runtime_options: runtime_options
+            runtime_options: runtime_options,
+            process_groups: HashMap::new(),
+            command_results: None,
+            streamed: HashSet::new(),
         }
     }
 
     /// Execute the container
     pub fn run(&mut self) -> Result<()> {
-        // Mount first the rootfs as private so the host can't access it
-        filesystem::mount_rootfs_private()?;
-        self.fs.mount()?;
-        let rootfs = self.fs.root()?;
-        syscall::switch_rootfs(&rootfs)?;
+        self.mount_rootfs().wrap_err(ExitCode::MOUNT)?;
+        // The pseudo-terminal (the init's standard input, see `syscall::attach_to_pty`) belongs to the
+        // host's devpts, which is no longer reachable after switching the rootfs
+        let console = if self.runtime_options.tty && !self.runtime_options.host_devices {
+            Some(self.clone_console().wrap_err(ExitCode::MOUNT)?)
+        } else {
+            None
+        };
+        // The host's cgroup hierarchy is no longer reachable after switching the rootfs either
+        let cgroup = if self.runtime_options.readonly_cgroup.is_some() {
+            Some(syscall::clone_mount(&cgroup::cgroup_path(&self.id)).wrap_err(ExitCode::MOUNT)?)
+        } else {
+            None
+        };
+        // Device nodes can't be created in a user namespace, the host's are bind mounted instead
+        let devices = if self.runtime_options.userns && !self.runtime_options.host_devices {
+            filesystem::clone_device_nodes(&self.runtime_options.devices).wrap_err(ExitCode::MOUNT)?
+        } else {
+            vec![]
+        };
+        syscall::switch_rootfs(&self.fs.root()?, &self.runtime_options.rootfs).wrap_err(ExitCode::ROOTFS)?;
         // Create /dev, /sys, /proc, ...
-        filesystem::mount_procfs()?;
-        filesystem::mount_sysfs()?;
-        filesystem::mount_devfs()?;
+        self.mount_filesystems(console, cgroup, devices).wrap_err(ExitCode::MOUNT)?;
+        if let Some(offsets) = &self.runtime_options.namespaces.time {
+            // The runtime stays in the host's time namespace, the commands it runs afterwards enter the new one
+            syscall::unshare_time_namespace(offsets)?;
+        }
+        if self.runtime_options.core_dumps.is_some() {
+            self.setup_core_dumps()?;
+        }
         self.setup_hostname()?;
-        self.event_loop()?;
+        // The init inherited the environment of the host process
+        syscall::replace_environment(&self.inject_env_variables(vec![]))?;
+        if let Some(label) = &self.runtime_options.selinux_label {
+            syscall::set_exec_label(label)?;
+        }
+        // The stop signal is the init's own
+        let stop_signal = self.runtime_options.stop_signal;
+        let forwarded: Vec<Signal> = self.runtime_options.forward_signals.iter()
+            .copied()
+            .filter(|signal| *signal != stop_signal)
+            .collect();
+        // Outside of a PID namespace, kill(-1, ...) would signal every process of the host
+        if self.runtime_options.namespaces.pid {
+            syscall::forward_signals(&forwarded)?;
+        } else if !forwarded.is_empty() {
+            log::warn!("Not forwarding signals to the workload: the container has no PID namespace");
+        }
+        let signals = syscall::handle_signals(stop_signal)?;
+        self.setup_scheduling()?;
+        self.setup_group()?;
+        let uid = self.runtime_options.uid()?;
+        // Last, the setup needs some of them
+        if let Some(keep) = self.runtime_options.kept_capabilities()? {
+            syscall::drop_bounding_capabilities(keep, syscall::last_capability()?)
+                .map_err(|err| eyre::eyre!("Could not drop the capabilities from the bounding set: {}", err))?;
+            // Switching to another user needs CAP_SETUID, which drops every capability left anyway
+            let setuid = if uid == getuid() { 0 } else { CapSet::from_names(&["CAP_SETUID".to_string()])? };
+            syscall::limit_capabilities(keep | setuid)
+                .map_err(|err| eyre::eyre!("Could not set the capabilities: {}", err))?;
+        }
+        self.setup_user(uid)?;
+        self.event_loop(signals)?;
         log::info!("Container thread stopped");
         Ok(())
     }
 
+    /// Mounts the root filesystem and the bind mounts, before switching to it
+    fn mount_rootfs(&mut self) -> Result<()> {
+        if self.runtime_options.host_visible_rootfs {
+            // On the mount point shared by the host, only this mount propagates back to it
+            self.fs.mount()?;
+            filesystem::mount_rootfs_private()?;
+        } else {
+            // Mount first the rootfs as private so the host can't access it
+            filesystem::mount_rootfs_private()?;
+            self.fs.mount()?;
+        }
+        let rootfs = self.fs.root()?;
+        // The host paths are no longer reachable after switching the rootfs
+        for bind in &self.runtime_options.mounts {
+            filesystem::bind_mount(rootfs, bind)?;
+        }
+        if self.runtime_options.host_devices {
+            filesystem::bind_host_devices(rootfs)?;
+        }
+        if let Some(host_dir) = &self.runtime_options.core_dumps {
+            let bind = BindMount {
+                source: host_dir.clone(),
+                destination: host_core_dump_dir()?,
+                readonly: false,
+            };
+            filesystem::bind_mount(rootfs, &bind)?;
+        }
+        Ok(())
+    }
+
+    /// Enables the core dumps of the container's processes, once the rootfs is switched, see `core_dumps`
+    fn setup_core_dumps(&self) -> Result<()> {
+        setrlimit(Resource::RLIMIT_CORE, None, None)?;
+        Ok(())
+    }
+
+    /// Clones the mount of the container's pseudo-terminal, to mount it later as /dev/console
+    fn clone_console(&self) -> Result<RawFd> {
+        // The file descriptor refers to the mount in the host's namespace, which can't be cloned, but its
+        // path (/dev/pts/<n>) resolves to the copy of that mount in the container's namespace
+        let path = ttyname(nix::libc::STDIN_FILENO)?;
+        syscall::clone_mount(&path)
+    }
+
+    /// Mounts the pseudo-filesystems and tmpfs inside the new root filesystem
+    /// # Arguments
+    /// * `console` - Detached mount of the container's terminal, mounted at /dev/console (if any)
+    /// * `cgroup` - Detached mount of the container's cgroup, mounted at `readonly_cgroup` (if any)
+    /// * `devices` - Detached mounts of the host's device nodes, mounted instead of creating them (with `userns`)
+    fn mount_filesystems(&self, console: Option<RawFd>, cgroup: Option<RawFd>, devices: Vec<(PathBuf, RawFd)>) -> Result<()> {
+        filesystem::mount_procfs()?;
+        syscall::verify_old_root_detached()?;
+        filesystem::mount_sysfs()?;
+        if self.runtime_options.delegate_cgroup {
+            // The host has already moved the container to its cgroup, which becomes the root of the namespace
+            unshare(CloneFlags::CLONE_NEWCGROUP)?;
+            filesystem::mount_cgroupfs()?;
+        }
+        if let (Some(cgroup), Some(path)) = (cgroup, &self.runtime_options.readonly_cgroup) {
+            filesystem::attach_readonly(cgroup, path)?;
+        }
+        if !self.runtime_options.host_devices {
+            let created: &[DeviceNode] = if self.runtime_options.userns { &[] } else { &self.runtime_options.devices };
+            filesystem::mount_devfs(created, self.runtime_options.shm_mode)?;
+            filesystem::mount_device_nodes(devices)?;
+            if let Some(console) = console {
+                filesystem::mount_console(console)?;
+            }
+        }
+        for path in &self.runtime_options.readonly_paths {
+            filesystem::remount_readonly(path)?;
+        }
+        if self.runtime_options.run_tmpfs {
+            self.setup_run()?;
+        }
+        if self.runtime_options.private_tmp {
+            self.setup_private_tmp()?;
+        }
+        Ok(())
+    }
+
     /// Event loop of the container
-    fn event_loop(&self) -> Result<()> {
+    /// # Arguments
+    /// * `signals` - Readable once the stop signal or SIGCHLD is received, see `syscall::handle_signals`
+    fn event_loop(&mut self, signals: RawFd) -> Result<()> {
         loop {
-            let msg = self.consumer_channel.receive()?;
+            let msg = match self.consumer_channel.receive_unless_readable(signals)? {
+                Some(msg) => msg,
+                None => match syscall::read_signal(signals)? {
+                    Some(Signal::SIGCHLD) => {
+                        if let Err(err) = self.reap_commands() {
+                            log::warn!("Could not reap the commands: {}", err);
+                        }
+                        continue;
+                    },
+                    Some(signal) => {
+                        log::info!("Received {}, stopping", signal);
+                        break;
+                    },
+                    None => continue,
+                },
+            };
             log::debug!("Received message: {:?}", msg);
             match msg {
                 ipc::Message::ACTION(Action::STOP) => break,
+                ipc::Message::ACTION(Action::SECCOMP(rules)) => {
+                    // Stacked on top of the previous filters, it can't loosen them
+                    let profile = SeccompProfile { default_action: SeccompAction::ALLOW, rules };
+                    seccomp::apply_seccomp(&profile).wrap_err(ExitCode::RUNTIME)?;
+                }
+                ipc::Message::ACTION(Action::SIGNAL { group, signal }) => {
+                    if let Err(err) = self.signal_process_group(group.as_deref(), signal) {
+                        log::warn!("{}", err);
+                    }
+                }
                 ipc::Message::COMMAND(command) => {
                     log::debug!("Executing command: {:?}", command);
-                    self.exec_command(command)?;
+                    let forked = matches!(command.exec_type, ExecType::FORK);
+                    match self.exec_command(command) {
+                        // Reported to the host, the runtime goes on
+                        Err(err) if forked => log::warn!("{:#}", err),
+                        result => result?,
+                    }
                 }
             }
         }
         Ok(())
     }
 
+    /// ID of the container
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Hostname of the container, set or derived from its ID
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// Options of the execution environment
+    pub fn options(&self) -> &RuntimeOptions {
+        &self.runtime_options
+    }
+
+    /// Number of bytes written by the container to its root filesystem
+    pub fn disk_usage(&self) -> Result<u64> {
+        self.fs.disk_usage()
+    }
+
+    /// Mounts the root filesystem in the current mount namespace
+    pub fn mount_fs(&mut self) -> Result<()> {
+        self.fs.mount()
+    }
+
+    /// Sets the channel the results of the commands are reported to, see `syscall::CommandResult`
+    /// # Arguments
+    /// * `fd` - Runtime's end of the command-result channel
+    pub fn set_command_results(&mut self, fd: RawFd) {
+        self.command_results = Some(fd);
+    }
+
+    /// Sets the scratch directory allocated for the container, see `RuntimeOptions::managed_scratch`
+    pub fn set_scratch_dir(&mut self, dir: &Path) {
+        self.fs.set_scratch_dir(dir)
+    }
+
+    /// Layers of the root filesystem, from top to bottom, see `StorageDriver::layers`
+    pub fn layers(&self) -> Vec<PathBuf> {
+        self.fs.layers()
+    }
+
+    /// Unmounts the root filesystem from the current mount namespace
+    pub fn umount_fs(&mut self) -> Result<()> {
+        self.fs.umount()
+    }
+
+    /// Makes the mount point of the root filesystem a shared mount in the host, so the root filesystem
+    /// mounted on it by the container propagates back (see `RuntimeOptions::host_visible_rootfs`)
+    pub fn share_mount_point(&self) -> Result<()> {
+        filesystem::share_mount_point(&self.fs.mount_target()?)
+    }
+
+    /// Unmounts from the host the root filesystem propagated from the container, then the shared mount
+    /// point (see `share_mount_point`). The shared mount point stays if the root filesystem can't be unmounted
+    pub fn unshare_mount_point(&mut self) -> Result<()> {
+        let mount_point = self.fs.mount_target()?;
+        let mounts = filesystem::stacked_mounts(&mount_point)?;
+        // The container may have failed before mounting the root filesystem
+        if mounts > 1 {
+            self.fs.umount()?;
+        }
+        if mounts > 0 {
+            filesystem::unshare_mount_point(&mount_point)?;
+        }
+        Ok(())
+    }
+
     /// Get the mountpoint of the container's root filesystem in the host filesystem
     pub fn mount_point(&self) -> Result<&Path> {
         Ok(self.fs.root()?)
     }
 
-    fn exec_command(&self, command: Command) -> Result<()> {
+    /// Sends a signal to a process group of the container
+    /// # Arguments
+    /// * `group` - Name of the process group, or none for every process of the container but the runtime
+    /// * `signal` - The signal to send
+    fn signal_process_group(&mut self, group: Option<&str>, signal: Signal) -> Result<()> {
+        let name = match group {
+            Some(name) => name,
+            // Without a PID namespace, -1 would be every process of the host
+            None if !self.runtime_options.namespaces.pid => {
+                return Err(eyre::eyre!("Can't signal every process without a PID namespace"));
+            },
+            // As the init of the PID namespace, the runtime is spared
+            None => return Ok(kill(Pid::from_raw(-1), signal)?),
+        };
+        let pgid = match self.process_groups.get(name) {
+            Some(pgid) => *pgid,
+            None => return Err(eyre::eyre!("Unknown process group {}", name)),
+        };
+        log::debug!("Sending {} to process group {} ({})", signal, name, pgid);
+        if let Err(err) = killpg(pgid, signal) {
+            if err == Errno::ESRCH {
+                // Every process of the group exited
+                self.process_groups.remove(name);
+            }
+            return Err(eyre::eyre!("Could not signal process group {}: {}", name, err));
+        }
+        Ok(())
+    }
+
+    fn exec_command(&mut self, command: Command) -> Result<()> {
+        let process_group = command.process_group.clone();
         let environment = self.inject_env_variables(command.env);
         let cmd = Command {
             command: command.command,
             args: command.args,
             env: environment,
             exec_type: command.exec_type,
+            argv0: command.argv0,
+            login: command.login,
+            path_search: command.path_search,
+            process_group: command.process_group,
+            stream_stdout: command.stream_stdout,
+        };
+        let stream_stdout = cmd.stream_stdout;
+        if matches!(cmd.exec_type, ExecType::REPLACE) {
+            // From then on, the exit code of the container is the command's
+            self.report_command_result(CommandResult::REPLACING);
+        }
+        let seccomp = self.runtime_options.seccomp.as_ref();
+        let executed = self.receive_stdout(stream_stdout).and_then(|stdout| {
+            let executed = syscall::exec(cmd, seccomp, stdout);
+            // The command has its own copy
+            if let Some(fd) = stdout {
+                let _ = close(fd);
+            }
+            executed
+        });
+        let pid = match executed {
+            Ok(pid) => pid,
+            Err(err) => {
+                let errno = err.downcast_ref::<Errno>().copied().unwrap_or(Errno::UnknownErrno);
+                self.report_command_result(CommandResult::FAILED(errno));
+                let code = ExitCode::from_exec_error(&err);
+                return Err(err.wrap_err(code));
+            },
         };
-        syscall::exec(cmd).map(|_| ())
+        self.report_command_result(CommandResult::STARTED(pid));
+        if stream_stdout {
+            self.streamed.insert(pid);
+        }
+        if let Some(name) = process_group {
+            // The group is named after its leader
+            self.process_groups.insert(name, Pid::from_raw(pid));
+        }
+        Ok(())
+    }
+
+    /// Receives the stdout of a command from the host, see `Command::stream_stdout`
+    fn receive_stdout(&self, stream_stdout: bool) -> Result<Option<RawFd>> {
+        match (stream_stdout, self.command_results) {
+            (false, _) => Ok(None),
+            (true, Some(channel)) => Ok(Some(syscall::receive_fd(channel)?)),
+            (true, None) => Err(eyre::eyre!("No command-result channel to receive the stdout of the command")),
+        }
+    }
+
+    /// Reaps the forked commands that exited, and reports the exit of the ones streaming their stdout
+    fn reap_commands(&mut self) -> Result<()> {
+        loop {
+            let status = match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) => return Ok(()),
+                Ok(status) => status,
+                Err(Errno::EINTR) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            if let (Some(pid), Some(exit)) = (status.pid(), ContainerExit::from_wait_status(status)) {
+                if self.streamed.remove(&pid.as_raw()) {
+                    self.report_command_result(CommandResult::EXITED(pid.as_raw(), exit));
+                }
+            }
+        }
+    }
+
+    fn report_command_result(&self, result: CommandResult) {
+        if let Some(fd) = self.command_results {
+            if let Err(err) = syscall::report_command_result(fd, result) {
+                log::warn!("Could not report {:?} to the host: {}", result, err);
+            }
+        }
     }
 
     fn inject_env_variables(&self, environment: Vec<String>) -> Vec<String> {
-        let info = UserInfo::from_name(&self.runtime_options.user).unwrap();
+        // Without an /etc/passwd entry for the user, fall back to sensible defaults
+        let (user, home, shell) = match UserInfo::from_name(&self.runtime_options.user) {
+            Ok(info) => (info.name, info.home, info.shell),
+            Err(e) => {
+                log::warn!("{}, using default HOME and SHELL", e);
+                (self.runtime_options.user.clone(), "/".to_string(), "/bin/sh".to_string())
+            }
+        };
+        // The first definition of a variable wins: the command's, the container's, then the defaults
         let mut env = environment;
-        env.push(format!("{}={}", "container", "libcontainer-rs"));
+        env.extend(self.runtime_options.env.iter().cloned());
+        env.push(format!("{}={}", "container", self.runtime_options.container_marker));
         env.push(format!("{}={}", "container_uuid", self.id));
-        env.push(format!("{}={}", "HOME", info.home));
-        env.push(format!("{}={}", "SHELL", info.shell));
-        env.push(format!("{}={}", "USER", "root"));
+        env.push(format!("{}={}", "HOME", home));
+        env.push(format!("{}={}", "SHELL", shell));
+        env.push(format!("{}={}", "USER", user));
         env.push(format!("{}={}", "HOSTNAME", self.hostname));
         env.push(format!("{}={}", "PATH", "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin"));
-        env
+        dedup_environment(env)
+    }
+
+    /// Set the scheduling policy and nice value, inherited by every command
+    ///
+    /// Note: the priority is pinned last, a real-time policy set by an unprivileged runtime needs the
+    /// RLIMIT_RTPRIO of the host process, not the pinned one
+    fn setup_scheduling(&self) -> Result<()> {
+        if self.runtime_options.pin_scheduling {
+            syscall::check_pinnable_priority(self.runtime_options.sched_policy.as_ref(), self.runtime_options.nice)?;
+        }
+        if let Some(policy) = &self.runtime_options.sched_policy {
+            syscall::set_sched_policy(policy)?;
+        }
+        if let Some(nice) = self.runtime_options.nice {
+            syscall::set_nice(nice)?;
+        }
+        if let Some(priority) = &self.runtime_options.io_priority {
+            syscall::set_io_priority(priority)?;
+        }
+        if self.runtime_options.pin_scheduling {
+            syscall::pin_scheduling_priority()?;
+        }
+        Ok(())
+    }
+
+    fn setup_private_tmp(&self) -> Result<()> {
+        let size = self.runtime_options.tmp_size.as_deref();
+        filesystem::mount_tmpfs(&"/tmp", 0o1777, size)?;
+        if Path::new("/var/tmp").exists() {
+            filesystem::mount_tmpfs(&"/var/tmp", 0o1777, size)?;
+        }
+        Ok(())
+    }
+
+    fn setup_run(&self) -> Result<()> {
+        // The mount point can't be created in a read-only rootfs
+        if self.runtime_options.rootfs.readonly && !Path::new("/run").exists() {
+            log::warn!("Not mounting /run: it does not exist in the read-only root filesystem");
+            return Ok(());
+        }
+        filesystem::mount_tmpfs(&"/run", 0o755, self.runtime_options.run_size.as_deref())
+    }
+
+    /// Switches to the configured group, resolved in the container's /etc/group. The commands inherit it
+    fn setup_group(&self) -> Result<()> {
+        let gid = self.runtime_options.gid()?;
+        if gid == getgid() {
+            return Ok(());
+        }
+        // The supplementary groups of the host process are not the container's
+        match setgroups(&[gid]) {
+            // The container can't drop them either, they keep denying it access to files
+            Err(Errno::EPERM) if syscall::setgroups_denied() => {
+                log::debug!("Not setting the supplementary groups to {}: setgroups is denied in the user namespace", gid);
+            },
+            res => res.map_err(|err| eyre::eyre!("Could not set the supplementary groups to {}: {}", gid, err))?,
+        }
+        setgid(gid)?;
+        // Changing the credentials clears the parent death signal
+        if self.runtime_options.kill_on_parent_exit {
+            syscall::set_parent_death_signal(Signal::SIGKILL)?;
+        }
+        Ok(())
+    }
+
+    /// Switches to the configured user and working directory. The commands inherit them
+    /// # Arguments
+    /// * `uid` - The UID of the user, see `RuntimeOptions::uid`
+    fn setup_user(&self, uid: Uid) -> Result<()> {
+        if uid != getuid() {
+            setresuid(uid, uid, uid)
+                .map_err(|err| eyre::eyre!("Could not switch to user {}: {}", self.runtime_options.user, err))?;
+            // Changing the credentials clears the parent death signal
+            if self.runtime_options.kill_on_parent_exit {
+                syscall::set_parent_death_signal(Signal::SIGKILL)?;
+            }
+        }
+        chdir(self.runtime_options.cwd.as_str())
+            .map_err(|err| eyre::eyre!("Could not change the working directory to {}: {}", self.runtime_options.cwd, err))?;
+        Ok(())
     }
 
     fn setup_hostname(&self) -> Result<()> {
+        // Without a UTS namespace, the hostname is the host's
+        if !self.runtime_options.namespaces.uts {
+            return Ok(());
+        }
         // Syscall to set the hostname
         sethostname(self.hostname.as_str())?;
-        // Write hostname to /etc/hostname
-        let mut hostname_file = std::fs::File::create("/etc/hostname")?;
-        hostname_file.write_all(self.hostname.as_bytes())?;
+        // A read-only rootfs can't be written to
+        if self.runtime_options.rootfs.readonly {
+            return Ok(());
+        }
+        // Write hostname to /etc/hostname. Only sethostname is critical, a minimal image may not have /etc
+        let res = fs::create_dir_all("/etc").and_then(|_| fs::write("/etc/hostname", self.hostname.as_bytes()));
+        if let Err(err) = res {
+            log::warn!("Could not write /etc/hostname: {}", err);
+        }
         Ok(())
     }
 
 }
+
+/// Keeps the first definition of each variable of an environment
+/// # Arguments
+/// * `env` - "KEY=VALUE" strings, by decreasing precedence
+/// # Returns
+/// The environment, with each variable defined once
+fn dedup_environment(env: Vec<String>) -> Vec<String> {
+    let mut keys = HashSet::new();
+    env.into_iter()
+        .filter(|var| keys.insert(var.split('=').next().unwrap_or_default().to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derive_hostname() {
+        let id = "3f9a1c0e5b7d42a8b6c1d0e9f8a7b6c5";
+        assert_eq!(derive_hostname(id, 12), "3f9a1c0e5b7d");
+        assert_eq!(derive_hostname(id, 8), "3f9a1c0e");
+        assert_eq!(derive_hostname(id, 100), id);
+        // No trailing hyphen after truncation, nor invalid characters
+        assert_eq!(derive_hostname("web-app_01", 4), "web");
+        assert_eq!(derive_hostname("-Web_App-01", 7), "webapp");
+        let long = "a".repeat(100);
+        assert_eq!(derive_hostname(&long, 100).len(), MAX_LABEL_LENGTH);
+    }
+
+    #[test]
+    fn test_kept_capabilities() {
+        let mut options = RuntimeOptions::default();
+        assert_eq!(options.kept_capabilities().unwrap(), None);
+        options.capabilities = Some(vec!["CAP_SYS_NICE".to_string(), "CAP_CHOWN".to_string()]);
+        let chown = CapSet::from_names(&["CAP_CHOWN".to_string()]).unwrap();
+        let sys_nice = CapSet::from_names(&["CAP_SYS_NICE".to_string()]).unwrap();
+        assert_eq!(options.kept_capabilities().unwrap(), Some(chown | sys_nice));
+        options.pin_scheduling = true;
+        assert_eq!(options.kept_capabilities().unwrap(), Some(chown));
+        options.capabilities = None;
+        assert_eq!(options.kept_capabilities().unwrap(), Some(!sys_nice));
+    }
+
+    #[test]
+    fn test_validate_options() {
+        assert!(RuntimeOptions::default().validate().is_ok());
+        let mut options = RuntimeOptions::default();
+        options.delegate_cgroup = true;
+        options.readonly_cgroup = Some(PathBuf::from("/sys/fs/cgroup"));
+        let err = options.validate().unwrap_err().to_string();
+        assert!(err.contains("delegate_cgroup"), "{}", err);
+        let mut options = RuntimeOptions::default();
+        options.hostname = Some("container".to_string());
+        options.namespaces.uts = false;
+        let err = options.validate().unwrap_err().to_string();
+        assert!(err.contains("namespaces.uts"), "{}", err);
+        options.namespaces.uts = true;
+        assert!(options.validate().is_ok());
+        let mut options = RuntimeOptions::default();
+        options.check_pids_limit = true;
+        assert!(options.validate().is_ok());
+        assert!(options.has_accounting());
+        options.hostname = Some(String::new());
+        assert!(options.validate().unwrap_err().to_string().contains("hostname"));
+        options.hostname = None;
+        options.hostname_length = 0;
+        assert!(options.validate().unwrap_err().to_string().contains("hostname_length"));
+        options.hostname_length = 12;
+        options.uid_map = vec![(0, 100000, 65536)];
+        let err = options.validate().unwrap_err().to_string();
+        assert!(err.contains("userns"), "{}", err);
+        options.userns = true;
+        assert!(options.validate().is_ok());
+        options.host_devices = true;
+        let err = options.validate().unwrap_err().to_string();
+        assert!(err.contains("host_devices"), "{}", err);
+        options.host_devices = false;
+        options.host_visible_rootfs = true;
+        let err = options.validate().unwrap_err().to_string();
+        assert!(err.contains("host_visible_rootfs"), "{}", err);
+        let mut options = RuntimeOptions::default();
+        options.delegate_cgroup = true;
+        options.mounts = vec![BindMount {
+            source: PathBuf::from("/sys/fs/cgroup"),
+            destination: PathBuf::from("/host/cgroup"),
+            readonly: false,
+        }];
+        let err = options.validate().unwrap_err().to_string();
+        assert!(err.contains("delegate_cgroup"), "{}", err);
+        options.mounts[0].readonly = true;
+        assert!(options.validate().is_ok());
+    }
+
+    #[test]
+    fn test_dedup_environment() {
+        let env = vec!["PATH=/opt/bin".to_string(), "HOME=/root".to_string(), "PATH=/bin".to_string()];
+        assert_eq!(dedup_environment(env), vec!["PATH=/opt/bin".to_string(), "HOME=/root".to_string()]);
+    }
+
+    #[test]
+    fn test_merge_options() {
+        let mount = |source: &str| BindMount {
+            source: PathBuf::from(source),
+            destination: PathBuf::from("/data"),
+            readonly: true,
+        };
+        let layers = || {
+            let mut base = RuntimeOptions::default();
+            base.hostname = Some("base".to_string());
+            base.env = vec!["A=1".to_string()];
+            base.mounts = vec![mount("/base")];
+            base.tty = true;
+            let mut overrides = RuntimeOptions::default();
+            overrides.hostname = Some("override".to_string());
+            overrides.env = vec!["A=2".to_string(), "B=2".to_string()];
+            overrides.mounts = vec![mount("/override")];
+            overrides.nice = Some(5);
+            overrides.stop_signal = Signal::SIGINT;
+            (base, overrides)
+        };
+        let (base, overrides) = layers();
+        let merged = RuntimeOptions::merge(base, overrides, ListMerge::APPEND);
+        assert_eq!(merged.hostname.as_deref(), Some("override"));
+        assert_eq!(merged.env, vec!["A=2", "B=2"]);
+        assert_eq!(merged.mounts, vec![mount("/base"), mount("/override")]);
+        // Default in the override
+        assert!(merged.tty);
+        assert_eq!(merged.nice, Some(5));
+        assert_eq!(merged.stop_signal, Signal::SIGINT);
+        assert_eq!(merged.forward_signals, syscall::default_forward_signals());
+        let (base, overrides) = layers();
+        let merged = RuntimeOptions::merge(base, overrides, ListMerge::REPLACE);
+        assert_eq!(merged.env, vec!["A=2", "B=2"]);
+        assert_eq!(merged.mounts, vec![mount("/override")]);
+        // Each namespace is merged on its own, the ID maps are never appended
+        let mut base = RuntimeOptions::default();
+        base.namespaces.net = false;
+        base.uid_map = vec![(0, 1000, 1)];
+        let mut overrides = RuntimeOptions::default();
+        overrides.namespaces.pid = false;
+        overrides.uid_map = vec![(0, 2000, 1)];
+        let merged = RuntimeOptions::merge(base, overrides, ListMerge::APPEND);
+        assert!(!merged.namespaces.net && !merged.namespaces.pid);
+        assert!(merged.namespaces.uts && merged.namespaces.ipc);
+        assert_eq!(merged.uid_map, vec![(0, 2000, 1)]);
+    }
+
+    #[test]
+    fn test_core_dump_dir() {
+        assert!(core_dump_dir("core").unwrap_err().to_string().contains("working directory"));
+        assert_eq!(core_dump_dir("/var/crash/core.%e.%p").unwrap(), PathBuf::from("/var/crash"));
+        assert!(core_dump_dir("|/usr/lib/systemd/systemd-coredump %P").is_err());
+        assert!(core_dump_dir("/core.%p").is_err());
+    }
+}
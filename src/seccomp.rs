@@ -0,0 +1,860 @@
+/*
+ * The MIT License
+ * Copyright (c) 2022 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::fs;
+use std::path::Path;
+
+use color_eyre::{Result, eyre};
+use nix::errno::Errno;
+use nix::libc::{self, sock_filter, sock_fprog};
+use serde::{Serialize, Deserialize};
+
+/// Action taken when a syscall matches a rule
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SeccompAction {
+    /// Allow the syscall
+    ALLOW,
+    /// Make the syscall fail with the given errno
+    ERRNO(i32),
+    /// Kill the thread making the syscall
+    KILL,
+    /// Kill the whole process making the syscall
+    KILL_PROCESS,
+    /// Send SIGSYS to the thread making the syscall
+    TRAP,
+    /// Allow the syscall, logging it to the kernel log
+    LOG,
+}
+
+/// Comparison of a syscall argument with a value
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SeccompCompare {
+    NE,
+    LT,
+    LE,
+    EQ,
+    GE,
+    GT,
+    /// `(arg & value) == value_two`
+    MASKED_EQ,
+}
+
+/// Condition on a syscall argument
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeccompArg {
+    /// Position of the argument (0 to 5)
+    pub index: u32,
+    pub value: u64,
+    /// Second value, only used by `MASKED_EQ`
+    pub value_two: u64,
+    pub op: SeccompCompare,
+}
+
+/// Action taken for a syscall, when all the argument conditions (if any) are met
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeccompRule {
+    /// Name of the syscall (e.g. "reboot")
+    pub syscall: String,
+    pub action: SeccompAction,
+    pub args: Vec<SeccompArg>,
+}
+
+/// A seccomp profile: the syscalls the container can make
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeccompProfile {
+    /// Action taken for the syscalls not matching any rule
+    pub default_action: SeccompAction,
+    pub rules: Vec<SeccompRule>,
+}
+
+impl SeccompProfile {
+
+    /// Loads a seccomp profile in the OCI/Docker JSON format (e.g. Docker's default profile)
+    /// # Arguments
+    /// * `path` - Path to the JSON file
+    ///
+    /// Every syscall name of an entry becomes a rule. Entries restricted to other architectures are
+    /// skipped, as are the ones only applying when the container has some capabilities.
+    pub fn from_json(path: &impl AsRef<Path>) -> Result<Self> {
+        let contents = fs::read_to_string(path.as_ref())
+            .map_err(|err| eyre::eyre!("Could not read {}: {}", path.as_ref().display(), err))?;
+        let profile: JsonProfile = serde_json::from_str(&contents)
+            .map_err(|err| eyre::eyre!("Invalid seccomp profile {}: {}", path.as_ref().display(), err))?;
+        let default_errno = profile.default_errno_ret.unwrap_or(nix::libc::EPERM);
+        let mut rules = vec![];
+        for syscall in profile.syscalls {
+            if !syscall.includes.matches(true) || syscall.excludes.matches(false) {
+                log::debug!("Skipping seccomp rule for {:?}: it does not apply", syscall.names);
+                continue;
+            }
+            let action = parse_action(&syscall.action, syscall.errno_ret.unwrap_or(default_errno))?;
+            let args = syscall.args.iter()
+                .map(|arg| Ok(SeccompArg {
+                    index: arg.index,
+                    value: arg.value,
+                    value_two: arg.value_two,
+                    op: parse_compare(&arg.op)?,
+                }))
+                .collect::<Result<Vec<SeccompArg>>>()?;
+            for name in syscall.names {
+                rules.push(SeccompRule { syscall: name, action, args: args.clone() });
+            }
+        }
+        Ok(SeccompProfile {
+            default_action: parse_action(&profile.default_action, default_errno)?,
+            rules,
+        })
+    }
+
+}
+
+/// Name of the architecture the library is built for, as used in the seccomp profiles
+#[cfg(target_arch = "x86_64")]
+const ARCH: &str = "SCMP_ARCH_X86_64";
+#[cfg(target_arch = "x86")]
+const ARCH: &str = "SCMP_ARCH_X86";
+#[cfg(target_arch = "aarch64")]
+const ARCH: &str = "SCMP_ARCH_AARCH64";
+#[cfg(target_arch = "arm")]
+const ARCH: &str = "SCMP_ARCH_ARM";
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64", target_arch = "arm")))]
+const ARCH: &str = "";
+
+/// Seccomp profile in the OCI/Docker JSON format
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonProfile {
+    default_action: String,
+    #[serde(default)]
+    default_errno_ret: Option<i32>,
+    #[serde(default)]
+    syscalls: Vec<JsonSyscall>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonSyscall {
+    names: Vec<String>,
+    action: String,
+    #[serde(default)]
+    errno_ret: Option<i32>,
+    #[serde(default)]
+    args: Vec<JsonArg>,
+    #[serde(default)]
+    includes: JsonFilter,
+    #[serde(default)]
+    excludes: JsonFilter,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonArg {
+    index: u32,
+    value: u64,
+    #[serde(default)]
+    value_two: u64,
+    op: String,
+}
+
+/// Conditions for an entry to be included (or excluded)
+#[derive(Debug, Default, Deserialize)]
+struct JsonFilter {
+    #[serde(default)]
+    arches: Vec<String>,
+    #[serde(default)]
+    caps: Vec<String>,
+}
+
+impl JsonFilter {
+
+    /// Whether the conditions are met, `empty` being the result when there are no conditions
+    fn matches(&self, empty: bool) -> bool {
+        if self.arches.is_empty() && self.caps.is_empty() {
+            return empty;
+        }
+        // The container is not granted any extra capability
+        self.caps.is_empty() && (self.arches.is_empty() || self.arches.iter().any(|arch| arch == ARCH))
+    }
+
+}
+
+fn parse_action(action: &str, errno: i32) -> Result<SeccompAction> {
+    match action {
+        "SCMP_ACT_ALLOW" => Ok(SeccompAction::ALLOW),
+        "SCMP_ACT_ERRNO" => Ok(SeccompAction::ERRNO(errno)),
+        "SCMP_ACT_KILL" | "SCMP_ACT_KILL_THREAD" => Ok(SeccompAction::KILL),
+        "SCMP_ACT_KILL_PROCESS" => Ok(SeccompAction::KILL_PROCESS),
+        "SCMP_ACT_TRAP" => Ok(SeccompAction::TRAP),
+        "SCMP_ACT_LOG" => Ok(SeccompAction::LOG),
+        _ => Err(eyre::eyre!("Unsupported seccomp action {}", action)),
+    }
+}
+
+fn parse_compare(op: &str) -> Result<SeccompCompare> {
+    match op {
+        "SCMP_CMP_NE" => Ok(SeccompCompare::NE),
+        "SCMP_CMP_LT" => Ok(SeccompCompare::LT),
+        "SCMP_CMP_LE" => Ok(SeccompCompare::LE),
+        "SCMP_CMP_EQ" => Ok(SeccompCompare::EQ),
+        "SCMP_CMP_GE" => Ok(SeccompCompare::GE),
+        "SCMP_CMP_GT" => Ok(SeccompCompare::GT),
+        "SCMP_CMP_MASKED_EQ" => Ok(SeccompCompare::MASKED_EQ),
+        _ => Err(eyre::eyre!("Unsupported seccomp comparison {}", op)),
+    }
+}
+
+/// Installs a seccomp filter on the current process, inherited by every process it creates afterwards
+/// # Arguments
+/// * `profile` - The syscalls allowed
+///
+/// The no_new_privs flag is set first, so the filter can be installed without CAP_SYS_ADMIN (and the
+/// processes executed afterwards can't gain privileges through setuid binaries).
+///
+/// Note: filters stack, a syscall is allowed only if every filter installed allows it. A new filter
+/// can tighten the previous ones, but never loosen them.
+/// Note: syscalls unknown on the current architecture (e.g. the 32-bit ones listed by Docker's
+/// default profile) are skipped.
+pub fn apply_seccomp(profile: &SeccompProfile) -> Result<()> {
+    let filter = SeccompFilter::compile(profile)?;
+    filter.install().map_err(|err| eyre::eyre!("Could not install the seccomp filter: {}", err))?;
+    log::debug!("Installed a seccomp filter of {} instructions", filter.program.len());
+    Ok(())
+}
+
+/// A seccomp profile compiled to a filter, to install it later, see `apply_seccomp`
+#[derive(Debug)]
+pub struct SeccompFilter {
+    program: Vec<sock_filter>,
+}
+
+impl SeccompFilter {
+
+    /// Compiles a profile
+    /// # Arguments
+    /// * `profile` - The syscalls allowed
+    pub fn compile(profile: &SeccompProfile) -> Result<Self> {
+        Ok(SeccompFilter { program: compile(profile)? })
+    }
+
+    /// Sets the no_new_privs flag and installs the filter on the current process, see `apply_seccomp`
+    ///
+    /// Note: this function does not allocate, it is safe to call in a child forked by a multithreaded
+    /// process (see `Container::exec_join`)
+    pub fn install(&self) -> nix::Result<()> {
+        let prog = sock_fprog {
+            len: self.program.len() as u16,
+            // Only read by the kernel
+            filter: self.program.as_ptr() as *mut sock_filter,
+        };
+        Errno::result(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+        Errno::result(unsafe {
+            libc::syscall(libc::SYS_seccomp, libc::SECCOMP_SET_MODE_FILTER, 0, &prog as *const sock_fprog)
+        })?;
+        Ok(())
+    }
+
+}
+
+/// Returns the number of a syscall on the current architecture, None if it is unknown
+/// # Arguments
+/// * `name` - Name of the syscall (e.g. "mkdirat")
+pub fn syscall_number(name: &str) -> Option<libc::c_long> {
+    SYSCALLS.iter()
+        .chain(LEGACY_SYSCALLS.iter())
+        .find(|(syscall, _)| *syscall == name)
+        .map(|(_, number)| *number)
+}
+
+/// Fails if the rules name an unknown syscall or can't be compiled, before sending them to a container
+/// # Arguments
+/// * `rules` - The rules to check
+pub(crate) fn check_rules(rules: &[SeccompRule]) -> Result<()> {
+    if let Some(rule) = rules.iter().find(|rule| syscall_number(&rule.syscall).is_none()) {
+        return Err(eyre::eyre!("Unknown syscall {} on this architecture", rule.syscall));
+    }
+    compile(&SeccompProfile { default_action: SeccompAction::ALLOW, rules: rules.to_vec() })?;
+    Ok(())
+}
+
+/// Fails if a profile can't be compiled (e.g. an argument of a rule is out of range, or the filter is too
+/// long), before starting a container with it. Unlike `check_rules`, the unknown syscalls are accepted:
+/// they are skipped, as by `apply_seccomp`
+/// # Arguments
+/// * `profile` - The profile to check
+pub(crate) fn check_profile(profile: &SeccompProfile) -> Result<()> {
+    compile(profile)?;
+    Ok(())
+}
+
+/// Architecture of the syscalls, as reported by the kernel to the filter (AUDIT_ARCH_* in linux/audit.h)
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xC000003E);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xC00000B7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+/// The syscalls of the x32 ABI have this bit set in their number, they are always denied
+const X32_SYSCALL_BIT: u32 = 0x40000000;
+
+/// Offsets of the fields of `struct seccomp_data`
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+const DATA_ARGS: u32 = 16;
+
+/// Destination of a conditional jump inside the instructions of a rule
+#[derive(Clone, Copy)]
+enum Target {
+    /// Skip the given number of instructions
+    Skip(u8),
+    /// Go to the next rule, the syscall doesn't match this one
+    NextRule,
+}
+
+/// An instruction of a rule, before the jumps to the next rule are resolved
+enum Instruction {
+    Stmt(u32, u32),
+    Jump(u32, u32, Target, Target),
+}
+
+fn stmt(code: u32, k: u32) -> sock_filter {
+    sock_filter { code: code as u16, jt: 0, jf: 0, k }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter { code: (libc::BPF_JMP | code | libc::BPF_K) as u16, jt, jf, k }
+}
+
+/// Compiles a profile to a classic BPF program
+///
+/// The program kills the process on a foreign architecture, then checks the rules in order: the first
+/// one matching the syscall (and its arguments) decides. Otherwise, the default action is taken.
+fn compile(profile: &SeccompProfile) -> Result<Vec<sock_filter>> {
+    let arch = AUDIT_ARCH.ok_or_else(|| eyre::eyre!("Seccomp filters are not supported on this architecture"))?;
+    let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+    let kill = libc::BPF_RET | libc::BPF_K;
+    let mut program = vec![
+        stmt(load, DATA_ARCH),
+        jump(libc::BPF_JEQ, arch, 1, 0),
+        stmt(kill, libc::SECCOMP_RET_KILL_PROCESS),
+    ];
+    if cfg!(target_arch = "x86_64") {
+        program.extend([
+            stmt(load, DATA_NR),
+            jump(libc::BPF_JGE, X32_SYSCALL_BIT, 0, 1),
+            stmt(kill, libc::SECCOMP_RET_KILL_PROCESS),
+        ]);
+    }
+    for rule in &profile.rules {
+        let nr = match syscall_number(&rule.syscall) {
+            Some(nr) => nr,
+            None => {
+                log::debug!("Skipping seccomp rule for {}: unknown syscall", rule.syscall);
+                continue;
+            }
+        };
+        program.extend(compile_rule(nr as u32, rule)?);
+    }
+    program.push(stmt(libc::BPF_RET | libc::BPF_K, action_value(profile.default_action)));
+    if program.len() > libc::BPF_MAXINSNS as usize {
+        return Err(eyre::eyre!("The seccomp filter is too long: {} instructions", program.len()));
+    }
+    Ok(program)
+}
+
+/// Compiles a rule: loads the syscall number, compares the arguments, and returns the action if they
+/// all match
+fn compile_rule(nr: u32, rule: &SeccompRule) -> Result<Vec<sock_filter>> {
+    let mut block = vec![
+        Instruction::Stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_NR),
+        Instruction::Jump(libc::BPF_JEQ, nr, Target::Skip(0), Target::NextRule),
+    ];
+    for arg in &rule.args {
+        block.extend(compare_arg(arg)?);
+    }
+    block.push(Instruction::Stmt(libc::BPF_RET | libc::BPF_K, action_value(rule.action)));
+    let len = block.len();
+    let resolve = |target: Target, index: usize| match target {
+        Target::Skip(skip) => Ok(skip),
+        Target::NextRule => u8::try_from(len - index - 1)
+            .map_err(|_| eyre::eyre!("Too many argument conditions in the seccomp rule for {}", rule.syscall)),
+    };
+    block.iter().enumerate()
+        .map(|(index, instruction)| match *instruction {
+            Instruction::Stmt(code, k) => Ok(stmt(code, k)),
+            Instruction::Jump(code, k, jt, jf) => Ok(jump(code, k, resolve(jt, index)?, resolve(jf, index)?)),
+        })
+        .collect()
+}
+
+/// Compares a 64-bit argument with its value, 32 bits at a time (the BPF accumulator is 32-bit). The
+/// comparisons are unsigned, as libseccomp's
+fn compare_arg(arg: &SeccompArg) -> Result<Vec<Instruction>> {
+    if arg.index > 5 {
+        return Err(eyre::eyre!("Invalid seccomp argument index {}", arg.index));
+    }
+    // Little-endian: the low half of the argument comes first
+    let low = DATA_ARGS + 8 * arg.index;
+    let load = |offset: u32| Instruction::Stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset);
+    let and = |mask: u32| Instruction::Stmt(libc::BPF_ALU | libc::BPF_AND | libc::BPF_K, mask);
+    let (high_value, low_value) = ((arg.value >> 32) as u32, arg.value as u32);
+    let (jeq, jgt, jge) = (libc::BPF_JEQ, libc::BPF_JGT, libc::BPF_JGE);
+    use Instruction::Jump;
+    use Target::{NextRule, Skip};
+    Ok(match arg.op {
+        SeccompCompare::EQ => vec![
+            load(low + 4), Jump(jeq, high_value, Skip(0), NextRule),
+            load(low), Jump(jeq, low_value, Skip(0), NextRule),
+        ],
+        SeccompCompare::NE => vec![
+            load(low + 4), Jump(jeq, high_value, Skip(0), Skip(2)),
+            load(low), Jump(jeq, low_value, NextRule, Skip(0)),
+        ],
+        SeccompCompare::GT | SeccompCompare::GE => vec![
+            load(low + 4), Jump(jgt, high_value, Skip(3), Skip(0)), Jump(jeq, high_value, Skip(0), NextRule),
+            load(low), Jump(if arg.op == SeccompCompare::GT { jgt } else { jge }, low_value, Skip(0), NextRule),
+        ],
+        SeccompCompare::LT | SeccompCompare::LE => vec![
+            load(low + 4), Jump(jge, high_value, Skip(0), Skip(3)), Jump(jeq, high_value, Skip(0), NextRule),
+            load(low), Jump(if arg.op == SeccompCompare::LT { jge } else { jgt }, low_value, NextRule, Skip(0)),
+        ],
+        SeccompCompare::MASKED_EQ => vec![
+            load(low + 4), and(high_value), Jump(jeq, (arg.value_two >> 32) as u32, Skip(0), NextRule),
+            load(low), and(low_value), Jump(jeq, arg.value_two as u32, Skip(0), NextRule),
+        ],
+    })
+}
+
+/// Return value of the filter for an action
+fn action_value(action: SeccompAction) -> u32 {
+    match action {
+        SeccompAction::ALLOW => libc::SECCOMP_RET_ALLOW,
+        SeccompAction::ERRNO(errno) => libc::SECCOMP_RET_ERRNO | (errno as u32 & libc::SECCOMP_RET_DATA),
+        SeccompAction::KILL => libc::SECCOMP_RET_KILL_THREAD,
+        SeccompAction::KILL_PROCESS => libc::SECCOMP_RET_KILL_PROCESS,
+        SeccompAction::TRAP => libc::SECCOMP_RET_TRAP,
+        SeccompAction::LOG => libc::SECCOMP_RET_LOG,
+    }
+}
+
+/// Numbers of the syscalls that can be named in a rule, on every supported architecture
+const SYSCALLS: &[(&str, libc::c_long)] = &[
+    ("accept", libc::SYS_accept),
+    ("accept4", libc::SYS_accept4),
+    ("acct", libc::SYS_acct),
+    ("add_key", libc::SYS_add_key),
+    ("adjtimex", libc::SYS_adjtimex),
+    ("bind", libc::SYS_bind),
+    ("bpf", libc::SYS_bpf),
+    ("brk", libc::SYS_brk),
+    ("capget", libc::SYS_capget),
+    ("capset", libc::SYS_capset),
+    ("chdir", libc::SYS_chdir),
+    ("chroot", libc::SYS_chroot),
+    ("clock_adjtime", libc::SYS_clock_adjtime),
+    ("clock_getres", libc::SYS_clock_getres),
+    ("clock_gettime", libc::SYS_clock_gettime),
+    ("clock_nanosleep", libc::SYS_clock_nanosleep),
+    ("clock_settime", libc::SYS_clock_settime),
+    ("clone", libc::SYS_clone),
+    ("clone3", libc::SYS_clone3),
+    ("close", libc::SYS_close),
+    ("close_range", libc::SYS_close_range),
+    ("connect", libc::SYS_connect),
+    ("copy_file_range", libc::SYS_copy_file_range),
+    ("delete_module", libc::SYS_delete_module),
+    ("dup", libc::SYS_dup),
+    ("dup3", libc::SYS_dup3),
+    ("epoll_create1", libc::SYS_epoll_create1),
+    ("epoll_ctl", libc::SYS_epoll_ctl),
+    ("epoll_pwait", libc::SYS_epoll_pwait),
+    ("epoll_pwait2", libc::SYS_epoll_pwait2),
+    ("eventfd2", libc::SYS_eventfd2),
+    ("execve", libc::SYS_execve),
+    ("execveat", libc::SYS_execveat),
+    ("exit", libc::SYS_exit),
+    ("exit_group", libc::SYS_exit_group),
+    ("faccessat", libc::SYS_faccessat),
+    ("faccessat2", libc::SYS_faccessat2),
+    ("fadvise64", libc::SYS_fadvise64),
+    ("fallocate", libc::SYS_fallocate),
+    ("fanotify_init", libc::SYS_fanotify_init),
+    ("fanotify_mark", libc::SYS_fanotify_mark),
+    ("fchdir", libc::SYS_fchdir),
+    ("fchmod", libc::SYS_fchmod),
+    ("fchmodat", libc::SYS_fchmodat),
+    ("fchown", libc::SYS_fchown),
+    ("fchownat", libc::SYS_fchownat),
+    ("fcntl", libc::SYS_fcntl),
+    ("fdatasync", libc::SYS_fdatasync),
+    ("fgetxattr", libc::SYS_fgetxattr),
+    ("finit_module", libc::SYS_finit_module),
+    ("flistxattr", libc::SYS_flistxattr),
+    ("flock", libc::SYS_flock),
+    ("fremovexattr", libc::SYS_fremovexattr),
+    ("fsconfig", libc::SYS_fsconfig),
+    ("fsetxattr", libc::SYS_fsetxattr),
+    ("fsmount", libc::SYS_fsmount),
+    ("fsopen", libc::SYS_fsopen),
+    ("fspick", libc::SYS_fspick),
+    ("fstat", libc::SYS_fstat),
+    ("fstatfs", libc::SYS_fstatfs),
+    ("fsync", libc::SYS_fsync),
+    ("ftruncate", libc::SYS_ftruncate),
+    ("futex", libc::SYS_futex),
+    ("futex_waitv", libc::SYS_futex_waitv),
+    ("get_mempolicy", libc::SYS_get_mempolicy),
+    ("get_robust_list", libc::SYS_get_robust_list),
+    ("getcpu", libc::SYS_getcpu),
+    ("getcwd", libc::SYS_getcwd),
+    ("getdents64", libc::SYS_getdents64),
+    ("getegid", libc::SYS_getegid),
+    ("geteuid", libc::SYS_geteuid),
+    ("getgid", libc::SYS_getgid),
+    ("getgroups", libc::SYS_getgroups),
+    ("getitimer", libc::SYS_getitimer),
+    ("getpeername", libc::SYS_getpeername),
+    ("getpgid", libc::SYS_getpgid),
+    ("getpid", libc::SYS_getpid),
+    ("getppid", libc::SYS_getppid),
+    ("getpriority", libc::SYS_getpriority),
+    ("getrandom", libc::SYS_getrandom),
+    ("getresgid", libc::SYS_getresgid),
+    ("getresuid", libc::SYS_getresuid),
+    ("getrusage", libc::SYS_getrusage),
+    ("getsid", libc::SYS_getsid),
+    ("getsockname", libc::SYS_getsockname),
+    ("getsockopt", libc::SYS_getsockopt),
+    ("gettid", libc::SYS_gettid),
+    ("gettimeofday", libc::SYS_gettimeofday),
+    ("getuid", libc::SYS_getuid),
+    ("getxattr", libc::SYS_getxattr),
+    ("init_module", libc::SYS_init_module),
+    ("inotify_add_watch", libc::SYS_inotify_add_watch),
+    ("inotify_init1", libc::SYS_inotify_init1),
+    ("inotify_rm_watch", libc::SYS_inotify_rm_watch),
+    ("io_cancel", libc::SYS_io_cancel),
+    ("io_destroy", libc::SYS_io_destroy),
+    ("io_getevents", libc::SYS_io_getevents),
+    ("io_setup", libc::SYS_io_setup),
+    ("io_submit", libc::SYS_io_submit),
+    ("io_uring_enter", libc::SYS_io_uring_enter),
+    ("io_uring_register", libc::SYS_io_uring_register),
+    ("io_uring_setup", libc::SYS_io_uring_setup),
+    ("ioctl", libc::SYS_ioctl),
+    ("ioprio_get", libc::SYS_ioprio_get),
+    ("ioprio_set", libc::SYS_ioprio_set),
+    ("kcmp", libc::SYS_kcmp),
+    ("kexec_load", libc::SYS_kexec_load),
+    ("keyctl", libc::SYS_keyctl),
+    ("kill", libc::SYS_kill),
+    ("landlock_add_rule", libc::SYS_landlock_add_rule),
+    ("landlock_create_ruleset", libc::SYS_landlock_create_ruleset),
+    ("landlock_restrict_self", libc::SYS_landlock_restrict_self),
+    ("lgetxattr", libc::SYS_lgetxattr),
+    ("linkat", libc::SYS_linkat),
+    ("listen", libc::SYS_listen),
+    ("listxattr", libc::SYS_listxattr),
+    ("llistxattr", libc::SYS_llistxattr),
+    ("lookup_dcookie", libc::SYS_lookup_dcookie),
+    ("lremovexattr", libc::SYS_lremovexattr),
+    ("lseek", libc::SYS_lseek),
+    ("lsetxattr", libc::SYS_lsetxattr),
+    ("madvise", libc::SYS_madvise),
+    ("mbind", libc::SYS_mbind),
+    ("membarrier", libc::SYS_membarrier),
+    ("memfd_create", libc::SYS_memfd_create),
+    ("memfd_secret", libc::SYS_memfd_secret),
+    ("migrate_pages", libc::SYS_migrate_pages),
+    ("mincore", libc::SYS_mincore),
+    ("mkdirat", libc::SYS_mkdirat),
+    ("mknodat", libc::SYS_mknodat),
+    ("mlock", libc::SYS_mlock),
+    ("mlock2", libc::SYS_mlock2),
+    ("mlockall", libc::SYS_mlockall),
+    ("mmap", libc::SYS_mmap),
+    ("mount", libc::SYS_mount),
+    ("mount_setattr", libc::SYS_mount_setattr),
+    ("move_mount", libc::SYS_move_mount),
+    ("move_pages", libc::SYS_move_pages),
+    ("mprotect", libc::SYS_mprotect),
+    ("mq_getsetattr", libc::SYS_mq_getsetattr),
+    ("mq_notify", libc::SYS_mq_notify),
+    ("mq_open", libc::SYS_mq_open),
+    ("mq_timedreceive", libc::SYS_mq_timedreceive),
+    ("mq_timedsend", libc::SYS_mq_timedsend),
+    ("mq_unlink", libc::SYS_mq_unlink),
+    ("mremap", libc::SYS_mremap),
+    ("mseal", libc::SYS_mseal),
+    ("msgctl", libc::SYS_msgctl),
+    ("msgget", libc::SYS_msgget),
+    ("msgrcv", libc::SYS_msgrcv),
+    ("msgsnd", libc::SYS_msgsnd),
+    ("msync", libc::SYS_msync),
+    ("munlock", libc::SYS_munlock),
+    ("munlockall", libc::SYS_munlockall),
+    ("munmap", libc::SYS_munmap),
+    ("name_to_handle_at", libc::SYS_name_to_handle_at),
+    ("nanosleep", libc::SYS_nanosleep),
+    ("newfstatat", libc::SYS_newfstatat),
+    ("nfsservctl", libc::SYS_nfsservctl),
+    ("open_by_handle_at", libc::SYS_open_by_handle_at),
+    ("open_tree", libc::SYS_open_tree),
+    ("openat", libc::SYS_openat),
+    ("openat2", libc::SYS_openat2),
+    ("perf_event_open", libc::SYS_perf_event_open),
+    ("personality", libc::SYS_personality),
+    ("pidfd_getfd", libc::SYS_pidfd_getfd),
+    ("pidfd_open", libc::SYS_pidfd_open),
+    ("pidfd_send_signal", libc::SYS_pidfd_send_signal),
+    ("pipe2", libc::SYS_pipe2),
+    ("pivot_root", libc::SYS_pivot_root),
+    ("pkey_alloc", libc::SYS_pkey_alloc),
+    ("pkey_free", libc::SYS_pkey_free),
+    ("pkey_mprotect", libc::SYS_pkey_mprotect),
+    ("ppoll", libc::SYS_ppoll),
+    ("prctl", libc::SYS_prctl),
+    ("pread64", libc::SYS_pread64),
+    ("preadv", libc::SYS_preadv),
+    ("preadv2", libc::SYS_preadv2),
+    ("prlimit64", libc::SYS_prlimit64),
+    ("process_madvise", libc::SYS_process_madvise),
+    ("process_mrelease", libc::SYS_process_mrelease),
+    ("process_vm_readv", libc::SYS_process_vm_readv),
+    ("process_vm_writev", libc::SYS_process_vm_writev),
+    ("pselect6", libc::SYS_pselect6),
+    ("ptrace", libc::SYS_ptrace),
+    ("pwrite64", libc::SYS_pwrite64),
+    ("pwritev", libc::SYS_pwritev),
+    ("pwritev2", libc::SYS_pwritev2),
+    ("quotactl", libc::SYS_quotactl),
+    ("quotactl_fd", libc::SYS_quotactl_fd),
+    ("read", libc::SYS_read),
+    ("readahead", libc::SYS_readahead),
+    ("readlinkat", libc::SYS_readlinkat),
+    ("readv", libc::SYS_readv),
+    ("reboot", libc::SYS_reboot),
+    ("recvfrom", libc::SYS_recvfrom),
+    ("recvmmsg", libc::SYS_recvmmsg),
+    ("recvmsg", libc::SYS_recvmsg),
+    ("remap_file_pages", libc::SYS_remap_file_pages),
+    ("removexattr", libc::SYS_removexattr),
+    ("renameat2", libc::SYS_renameat2),
+    ("request_key", libc::SYS_request_key),
+    ("restart_syscall", libc::SYS_restart_syscall),
+    ("rseq", libc::SYS_rseq),
+    ("rt_sigaction", libc::SYS_rt_sigaction),
+    ("rt_sigpending", libc::SYS_rt_sigpending),
+    ("rt_sigprocmask", libc::SYS_rt_sigprocmask),
+    ("rt_sigqueueinfo", libc::SYS_rt_sigqueueinfo),
+    ("rt_sigreturn", libc::SYS_rt_sigreturn),
+    ("rt_sigsuspend", libc::SYS_rt_sigsuspend),
+    ("rt_sigtimedwait", libc::SYS_rt_sigtimedwait),
+    ("rt_tgsigqueueinfo", libc::SYS_rt_tgsigqueueinfo),
+    ("sched_get_priority_max", libc::SYS_sched_get_priority_max),
+    ("sched_get_priority_min", libc::SYS_sched_get_priority_min),
+    ("sched_getaffinity", libc::SYS_sched_getaffinity),
+    ("sched_getattr", libc::SYS_sched_getattr),
+    ("sched_getparam", libc::SYS_sched_getparam),
+    ("sched_getscheduler", libc::SYS_sched_getscheduler),
+    ("sched_rr_get_interval", libc::SYS_sched_rr_get_interval),
+    ("sched_setaffinity", libc::SYS_sched_setaffinity),
+    ("sched_setattr", libc::SYS_sched_setattr),
+    ("sched_setparam", libc::SYS_sched_setparam),
+    ("sched_setscheduler", libc::SYS_sched_setscheduler),
+    ("sched_yield", libc::SYS_sched_yield),
+    ("seccomp", libc::SYS_seccomp),
+    ("semctl", libc::SYS_semctl),
+    ("semget", libc::SYS_semget),
+    ("semop", libc::SYS_semop),
+    ("semtimedop", libc::SYS_semtimedop),
+    ("sendfile", libc::SYS_sendfile),
+    ("sendmmsg", libc::SYS_sendmmsg),
+    ("sendmsg", libc::SYS_sendmsg),
+    ("sendto", libc::SYS_sendto),
+    ("set_mempolicy", libc::SYS_set_mempolicy),
+    ("set_mempolicy_home_node", libc::SYS_set_mempolicy_home_node),
+    ("set_robust_list", libc::SYS_set_robust_list),
+    ("set_tid_address", libc::SYS_set_tid_address),
+    ("setdomainname", libc::SYS_setdomainname),
+    ("setfsgid", libc::SYS_setfsgid),
+    ("setfsuid", libc::SYS_setfsuid),
+    ("setgid", libc::SYS_setgid),
+    ("setgroups", libc::SYS_setgroups),
+    ("sethostname", libc::SYS_sethostname),
+    ("setitimer", libc::SYS_setitimer),
+    ("setns", libc::SYS_setns),
+    ("setpgid", libc::SYS_setpgid),
+    ("setpriority", libc::SYS_setpriority),
+    ("setregid", libc::SYS_setregid),
+    ("setresgid", libc::SYS_setresgid),
+    ("setresuid", libc::SYS_setresuid),
+    ("setreuid", libc::SYS_setreuid),
+    ("setsid", libc::SYS_setsid),
+    ("setsockopt", libc::SYS_setsockopt),
+    ("settimeofday", libc::SYS_settimeofday),
+    ("setuid", libc::SYS_setuid),
+    ("setxattr", libc::SYS_setxattr),
+    ("shmat", libc::SYS_shmat),
+    ("shmctl", libc::SYS_shmctl),
+    ("shmdt", libc::SYS_shmdt),
+    ("shmget", libc::SYS_shmget),
+    ("shutdown", libc::SYS_shutdown),
+    ("sigaltstack", libc::SYS_sigaltstack),
+    ("signalfd4", libc::SYS_signalfd4),
+    ("socket", libc::SYS_socket),
+    ("socketpair", libc::SYS_socketpair),
+    ("splice", libc::SYS_splice),
+    ("statfs", libc::SYS_statfs),
+    ("statx", libc::SYS_statx),
+    ("swapoff", libc::SYS_swapoff),
+    ("swapon", libc::SYS_swapon),
+    ("symlinkat", libc::SYS_symlinkat),
+    ("sync", libc::SYS_sync),
+    ("syncfs", libc::SYS_syncfs),
+    ("sysinfo", libc::SYS_sysinfo),
+    ("syslog", libc::SYS_syslog),
+    ("tee", libc::SYS_tee),
+    ("tgkill", libc::SYS_tgkill),
+    ("timer_create", libc::SYS_timer_create),
+    ("timer_delete", libc::SYS_timer_delete),
+    ("timer_getoverrun", libc::SYS_timer_getoverrun),
+    ("timer_gettime", libc::SYS_timer_gettime),
+    ("timer_settime", libc::SYS_timer_settime),
+    ("timerfd_create", libc::SYS_timerfd_create),
+    ("timerfd_gettime", libc::SYS_timerfd_gettime),
+    ("timerfd_settime", libc::SYS_timerfd_settime),
+    ("times", libc::SYS_times),
+    ("tkill", libc::SYS_tkill),
+    ("truncate", libc::SYS_truncate),
+    ("umask", libc::SYS_umask),
+    ("umount2", libc::SYS_umount2),
+    ("uname", libc::SYS_uname),
+    ("unlinkat", libc::SYS_unlinkat),
+    ("unshare", libc::SYS_unshare),
+    ("userfaultfd", libc::SYS_userfaultfd),
+    ("utimensat", libc::SYS_utimensat),
+    ("vhangup", libc::SYS_vhangup),
+    ("vmsplice", libc::SYS_vmsplice),
+    ("wait4", libc::SYS_wait4),
+    ("waitid", libc::SYS_waitid),
+    ("write", libc::SYS_write),
+    ("writev", libc::SYS_writev),
+];
+
+/// Legacy syscalls, only available on x86_64 (e.g. open, replaced by openat on newer architectures)
+#[cfg(target_arch = "x86_64")]
+const LEGACY_SYSCALLS: &[(&str, libc::c_long)] = &[
+    ("_sysctl", libc::SYS__sysctl),
+    ("access", libc::SYS_access),
+    ("afs_syscall", libc::SYS_afs_syscall),
+    ("alarm", libc::SYS_alarm),
+    ("arch_prctl", libc::SYS_arch_prctl),
+    ("chmod", libc::SYS_chmod),
+    ("chown", libc::SYS_chown),
+    ("creat", libc::SYS_creat),
+    ("dup2", libc::SYS_dup2),
+    ("epoll_create", libc::SYS_epoll_create),
+    ("epoll_ctl_old", libc::SYS_epoll_ctl_old),
+    ("epoll_wait", libc::SYS_epoll_wait),
+    ("epoll_wait_old", libc::SYS_epoll_wait_old),
+    ("eventfd", libc::SYS_eventfd),
+    ("fchmodat2", libc::SYS_fchmodat2),
+    ("fork", libc::SYS_fork),
+    ("futimesat", libc::SYS_futimesat),
+    ("get_thread_area", libc::SYS_get_thread_area),
+    ("getdents", libc::SYS_getdents),
+    ("getpgrp", libc::SYS_getpgrp),
+    ("getpmsg", libc::SYS_getpmsg),
+    ("getrlimit", libc::SYS_getrlimit),
+    ("inotify_init", libc::SYS_inotify_init),
+    ("ioperm", libc::SYS_ioperm),
+    ("iopl", libc::SYS_iopl),
+    ("kexec_file_load", libc::SYS_kexec_file_load),
+    ("lchown", libc::SYS_lchown),
+    ("link", libc::SYS_link),
+    ("lstat", libc::SYS_lstat),
+    ("mkdir", libc::SYS_mkdir),
+    ("mknod", libc::SYS_mknod),
+    ("modify_ldt", libc::SYS_modify_ldt),
+    ("open", libc::SYS_open),
+    ("pause", libc::SYS_pause),
+    ("pipe", libc::SYS_pipe),
+    ("poll", libc::SYS_poll),
+    ("putpmsg", libc::SYS_putpmsg),
+    ("readlink", libc::SYS_readlink),
+    ("rename", libc::SYS_rename),
+    ("renameat", libc::SYS_renameat),
+    ("rmdir", libc::SYS_rmdir),
+    ("security", libc::SYS_security),
+    ("select", libc::SYS_select),
+    ("set_thread_area", libc::SYS_set_thread_area),
+    ("setrlimit", libc::SYS_setrlimit),
+    ("signalfd", libc::SYS_signalfd),
+    ("stat", libc::SYS_stat),
+    ("symlink", libc::SYS_symlink),
+    ("sync_file_range", libc::SYS_sync_file_range),
+    ("sysfs", libc::SYS_sysfs),
+    ("time", libc::SYS_time),
+    ("tuxcall", libc::SYS_tuxcall),
+    ("unlink", libc::SYS_unlink),
+    ("uselib", libc::SYS_uselib),
+    ("ustat", libc::SYS_ustat),
+    ("utime", libc::SYS_utime),
+    ("utimes", libc::SYS_utimes),
+    ("vfork", libc::SYS_vfork),
+    ("vserver", libc::SYS_vserver),
+];
+#[cfg(not(target_arch = "x86_64"))]
+const LEGACY_SYSCALLS: &[(&str, libc::c_long)] = &[];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use nix::unistd::Pid;
+
+    #[test]
+    fn test_seccomp_profile_from_json() {
+        let path = env::temp_dir().join(format!("libcontainer-rs-seccomp-{}.json", Pid::this()));
+        fs::write(&path, r#"{
+            "defaultAction": "SCMP_ACT_ALLOW",
+            "architectures": ["SCMP_ARCH_X86_64", "SCMP_ARCH_AARCH64"],
+            "syscalls": [
+                { "names": ["reboot", "kexec_load"], "action": "SCMP_ACT_ERRNO", "errnoRet": 1 },
+                { "names": ["personality"], "action": "SCMP_ACT_ERRNO",
+                  "args": [{ "index": 0, "value": 8, "op": "SCMP_CMP_NE" }] },
+                { "names": ["mount"], "action": "SCMP_ACT_ALLOW", "includes": { "caps": ["CAP_SYS_ADMIN"] } }
+            ]
+        }"#).unwrap();
+        let profile = SeccompProfile::from_json(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(profile.default_action, SeccompAction::ALLOW);
+        assert_eq!(profile.rules.len(), 3);
+        assert_eq!(profile.rules[0].syscall, "reboot");
+        assert_eq!(profile.rules[0].action, SeccompAction::ERRNO(1));
+        assert_eq!(profile.rules[2].args[0].op, SeccompCompare::NE);
+        assert_eq!(profile.rules[2].action, SeccompAction::ERRNO(nix::libc::EPERM));
+    }
+}
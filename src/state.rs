@@ -0,0 +1,470 @@
+/*
+ * The MIT License
+ * Copyright (c) 2022 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use color_eyre::{Result, eyre};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use nix::unistd::close;
+use serde::{Serialize, Deserialize};
+
+use crate::cgroup::{self, MemoryPressureEvent};
+
+/// Extension of the state files, `<state_root>/<id>.json`
+const STATE_EXTENSION: &str = "json";
+
+/// Status of a container
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Status {
+    /// The container's init process is running
+    RUNNING,
+    /// The container's init process has exited
+    STOPPED,
+}
+
+/// State of a container, persisted in the state directory while the `Container` exists
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct State {
+    /// ID of the container
+    pub id: String,
+    /// PID of the container's init process, while it runs
+    pub pid: Option<i32>,
+    /// Status of the container
+    pub status: Status,
+    /// When the container was started, in seconds since the Unix epoch
+    pub created: u64,
+}
+
+impl State {
+
+    /// State of a container that has just started
+    /// # Arguments
+    /// * `id` - ID of the container
+    /// * `pid` - PID of the container's init process
+    pub fn running(id: &str, pid: i32) -> Self {
+        let created = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+        State {
+            id: id.to_string(),
+            pid: Some(pid),
+            status: Status::RUNNING,
+            created,
+        }
+    }
+
+    /// Writes the state to `<state_root>/<id>.json`, creating the directory if needed.
+    ///
+    /// The file is written next to the final one and renamed over it, so readers (and `watch`) never
+    /// see a partially written state.
+    /// # Arguments
+    /// * `state_root` - The state directory
+    pub fn save(&self, state_root: &Path) -> Result<()> {
+        fs::create_dir_all(state_root)?;
+        // Hidden, so it is not taken for the state of a container
+        let temp = state_root.join(format!(".{}.{}.tmp", self.id, STATE_EXTENSION));
+        fs::write(&temp, serde_json::to_vec(self)?)?;
+        fs::rename(&temp, state_path(state_root, &self.id))?;
+        Ok(())
+    }
+
+    /// Reads the state of a container
+    /// # Arguments
+    /// * `state_root` - The state directory
+    /// * `id` - ID of the container
+    pub fn load(state_root: &Path, id: &str) -> Result<Self> {
+        let path = state_path(state_root, id);
+        let contents = fs::read(&path)
+            .map_err(|err| eyre::eyre!("Could not read the state {}: {}", path.display(), err))?;
+        Ok(serde_json::from_slice(&contents)?)
+    }
+
+    /// Returns whether the state of a container exists
+    /// # Arguments
+    /// * `state_root` - The state directory
+    /// * `id` - ID of the container
+    pub fn exists(state_root: &Path, id: &str) -> bool {
+        state_path(state_root, id).exists()
+    }
+
+    /// Removes the state of a container, if it exists
+    /// # Arguments
+    /// * `state_root` - The state directory
+    /// * `id` - ID of the container
+    pub fn remove(state_root: &Path, id: &str) -> Result<()> {
+        match fs::remove_file(state_path(state_root, id)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+}
+
+/// Path of the state file of a container
+fn state_path(state_root: &Path, id: &str) -> PathBuf {
+    state_root.join(format!("{}.{}", id, STATE_EXTENSION))
+}
+
+/// ID of the container of a state file, None for any other file (e.g. the temporary ones)
+fn state_id(name: &OsStr) -> Option<String> {
+    let path = Path::new(name);
+    let name = name.to_str()?;
+    if name.starts_with('.') || path.extension() != Some(OsStr::new(STATE_EXTENSION)) {
+        return None;
+    }
+    path.file_stem()?.to_str().map(|id| id.to_string())
+}
+
+/// A change of the state directory, or of a container with a state, with the ID of the container
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateEvent {
+    /// A container's state was written for the first time
+    CREATED(String),
+    /// A container's state was replaced
+    UPDATED(String),
+    /// A container's state was removed
+    REMOVED(String),
+    /// A container with a cgroup (and its memory controller enabled) is under memory pressure, e.g. its
+    /// memory usage went over `RuntimeOptions::memory_high`
+    MEMORY_PRESSURE(String, MemoryPressureEvent),
+}
+
+/// Memory pressure of a container, watched along with its state
+struct MemoryWatch {
+    /// Watch of memory.events, which the kernel reports as modified when a counter changes
+    wd: WatchDescriptor,
+    events_path: PathBuf,
+    /// memory.pressure with a PSI trigger, removed when the file is closed
+    pressure: Option<File>,
+    /// Last counters of memory.events, to report only their increases
+    high: u64,
+    max: u64,
+}
+
+impl MemoryWatch {
+
+    /// Reads memory.events
+    /// # Returns
+    /// An event for each counter that increased
+    fn read_counters(&mut self) -> Result<Vec<MemoryPressureEvent>> {
+        let high = cgroup::read_memory_event(&self.events_path, "high")?;
+        let max = cgroup::read_memory_event(&self.events_path, "max")?;
+        let mut events = vec![];
+        if high > self.high {
+            events.push(MemoryPressureEvent::HIGH(high));
+        }
+        if max > self.max {
+            events.push(MemoryPressureEvent::MAX(max));
+        }
+        self.high = high;
+        self.max = max;
+        Ok(events)
+    }
+
+}
+
+/// Iterator over the changes of a state directory, see `watch`
+pub struct StateWatcher {
+    inotify: Inotify,
+    /// Watch of the state directory
+    directory: WatchDescriptor,
+    /// Containers with a state file, to tell creations from updates
+    known: HashSet<String>,
+    /// Memory pressure of the known containers with a cgroup
+    memory: HashMap<String, MemoryWatch>,
+    /// PSI threshold of the memory pressure, as (stall, window)
+    stall: Option<(Duration, Duration)>,
+    /// Events read but not returned yet
+    pending: VecDeque<StateEvent>,
+}
+
+impl StateWatcher {
+
+    /// Same as `next`, giving up after a timeout
+    /// # Arguments
+    /// * `timeout` - Maximum time to wait for a change
+    /// # Returns
+    /// The next change, None if there was none before the timeout or the directory can't be watched
+    /// anymore
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<StateEvent> {
+        self.next_before(Some(Instant::now() + timeout))
+    }
+
+    /// Waits for the next change of the state directory, or of the memory pressure of a container
+    /// # Arguments
+    /// * `deadline` - When to give up, None to wait as long as needed
+    fn next_before(&mut self, deadline: Option<Instant>) -> Option<StateEvent> {
+        while self.pending.is_empty() {
+            let timeout = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()).as_millis().min(i32::MAX as u128) as i32,
+                None => -1,
+            };
+            let stalls: Vec<(String, RawFd)> = self.memory.iter()
+                .filter_map(|(id, memory)| memory.pressure.as_ref().map(|pressure| (id.clone(), pressure.as_raw_fd())))
+                .collect();
+            let mut fds = vec![PollFd::new(self.inotify.as_raw_fd(), PollFlags::POLLIN)];
+            fds.extend(stalls.iter().map(|(_, fd)| PollFd::new(*fd, PollFlags::POLLPRI)));
+            match poll(&mut fds, timeout) {
+                Ok(0) => return None,
+                Ok(_) => {},
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(err) => {
+                    log::warn!("Could not wait for the changes of the state directory: {}", err);
+                    return None;
+                }
+            }
+            for ((id, _), fd) in stalls.into_iter().zip(&fds[1..]) {
+                let revents = fd.revents().unwrap_or_else(PollFlags::empty);
+                // The PSI trigger fails once the cgroup is removed
+                if revents.intersects(PollFlags::POLLERR | PollFlags::POLLNVAL) {
+                    if let Some(memory) = self.memory.get_mut(&id) {
+                        memory.pressure = None;
+                    }
+                } else if revents.contains(PollFlags::POLLPRI) {
+                    self.pending.push_back(StateEvent::MEMORY_PRESSURE(id, MemoryPressureEvent::STALL));
+                }
+            }
+            if !matches!(fds[0].revents(), Some(revents) if revents.contains(PollFlags::POLLIN)) {
+                continue;
+            }
+            let events = match self.inotify.read_events() {
+                Ok(events) => events,
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(err) => {
+                    log::warn!("Could not read the changes of the state directory: {}", err);
+                    return None;
+                }
+            };
+            for event in events {
+                if event.wd != self.directory {
+                    self.memory_changed(event.wd, event.mask);
+                    continue;
+                }
+                if event.mask.intersects(AddWatchFlags::IN_DELETE_SELF | AddWatchFlags::IN_IGNORED) {
+                    return None;
+                }
+                let id = match event.name.as_deref().and_then(state_id) {
+                    Some(id) => id,
+                    None => continue,
+                };
+                if event.mask.intersects(AddWatchFlags::IN_DELETE | AddWatchFlags::IN_MOVED_FROM) {
+                    self.known.remove(&id);
+                    if let Some(memory) = self.memory.remove(&id) {
+                        let _ = self.inotify.rm_watch(memory.wd);
+                    }
+                    self.pending.push_back(StateEvent::REMOVED(id));
+                } else {
+                    let event = match self.known.insert(id.clone()) {
+                        true => StateEvent::CREATED(id.clone()),
+                        false => StateEvent::UPDATED(id.clone()),
+                    };
+                    self.pending.push_back(event);
+                    // Its cgroup may be created after its first state
+                    self.watch_memory(&id, true);
+                }
+            }
+        }
+        self.pending.pop_front()
+    }
+
+    /// Starts watching the memory pressure of a container, if it has a cgroup with the memory controller
+    /// and is not watched yet
+    /// # Arguments
+    /// * `id` - ID of the container
+    /// * `report_existing` - Whether to report the breaches before this call (e.g. those of a container
+    ///   that just started), or only the next ones
+    fn watch_memory(&mut self, id: &str, report_existing: bool) {
+        let events_path = cgroup::cgroup_path(id).join("memory.events");
+        if self.memory.contains_key(id) || !events_path.exists() {
+            return;
+        }
+        let wd = match self.inotify.add_watch(&events_path, AddWatchFlags::IN_MODIFY) {
+            Ok(wd) => wd,
+            Err(err) => {
+                log::warn!("Could not watch {}: {}", events_path.display(), err);
+                return;
+            }
+        };
+        let mut memory = MemoryWatch { wd, events_path, pressure: None, high: 0, max: 0 };
+        // Read after adding the watch, so a breach in between is not lost
+        match memory.read_counters() {
+            Ok(events) if report_existing => self.pending.extend(events.into_iter()
+                .map(|event| StateEvent::MEMORY_PRESSURE(id.to_string(), event))),
+            Ok(_) => {},
+            Err(err) => log::warn!("{}", err),
+        }
+        if let Some((stall, window)) = self.stall {
+            match set_pressure_trigger(&cgroup::cgroup_path(id).join("memory.pressure"), stall, window) {
+                Ok(pressure) => memory.pressure = Some(pressure),
+                Err(err) => log::warn!("{}", err),
+            }
+        }
+        self.memory.insert(id.to_string(), memory);
+    }
+
+    /// Reports the memory pressure of a container, after a change of its memory.events
+    /// # Arguments
+    /// * `wd` - Watch of the memory.events file
+    /// * `mask` - The change
+    fn memory_changed(&mut self, wd: WatchDescriptor, mask: AddWatchFlags) {
+        let id = match self.memory.iter().find(|(_, memory)| memory.wd == wd) {
+            Some((id, _)) => id.clone(),
+            None => return,
+        };
+        // The cgroup was removed
+        if mask.contains(AddWatchFlags::IN_IGNORED) {
+            self.memory.remove(&id);
+            return;
+        }
+        if let Some(memory) = self.memory.get_mut(&id) {
+            if let Ok(events) = memory.read_counters() {
+                self.pending.extend(events.into_iter().map(|event| StateEvent::MEMORY_PRESSURE(id.clone(), event)));
+            }
+        }
+    }
+
+}
+
+impl Iterator for StateWatcher {
+    type Item = StateEvent;
+
+    /// Blocks until the next change of the state directory. The iteration ends if the directory can't be
+    /// watched anymore (e.g. it was removed)
+    fn next(&mut self) -> Option<StateEvent> {
+        self.next_before(None)
+    }
+}
+
+impl Drop for StateWatcher {
+    fn drop(&mut self) {
+        let _ = close(self.inotify.as_raw_fd());
+    }
+}
+
+/// Opens memory.pressure with a PSI trigger
+/// # Arguments
+/// * `path` - The memory.pressure file
+/// * `stall` - Time the processes are stalled waiting for memory, within `window`
+/// * `window` - Duration of the window (between 500ms and 10s, a multiple of 2s without CAP_SYS_RESOURCE)
+/// # Returns
+/// The file, polled for POLLPRI; the trigger is removed when it is closed
+fn set_pressure_trigger(path: &Path, stall: Duration, window: Duration) -> Result<File> {
+    let mut pressure = OpenOptions::new().read(true).write(true)
+        .custom_flags(nix::libc::O_NONBLOCK)
+        .open(path)
+        .map_err(|err| eyre::eyre!("Could not open {}: {}", path.display(), err))?;
+    let trigger = format!("some {} {}", stall.as_micros(), window.as_micros());
+    pressure.write_all(trigger.as_bytes())
+        .map_err(|err| eyre::eyre!("Could not set the PSI trigger \"{}\" in {}: {}", trigger, path.display(), err))?;
+    Ok(pressure)
+}
+
+/// Watches the state directory with inotify, reporting when the state of a container is created, updated
+/// or removed, without polling. The directory is created if needed; the states written before calling
+/// this function are not reported, but their updates are.
+///
+/// The memory pressure of the containers with a cgroup is reported as well: the breaches of memory.high
+/// and memory.max (memory.events), those of a container started before calling this function only if
+/// they happen afterwards.
+/// # Arguments
+/// * `state_root` - The state directory (see `RuntimeOptions::state_root`)
+/// # Returns
+/// A blocking iterator over the changes, see also `StateWatcher::next_timeout`
+pub fn watch(state_root: &Path) -> Result<StateWatcher> {
+    watch_with_memory_stalls(state_root, None)
+}
+
+/// Same as `watch`, also reporting when the processes of a container are stalled waiting for memory
+/// (PSI, memory.pressure) for longer than a threshold
+/// # Arguments
+/// * `state_root` - The state directory (see `RuntimeOptions::state_root`)
+/// * `stall` - PSI threshold as (stall, window), None to only watch memory.events: an event is reported
+///   when some processes were stalled for longer than `stall` during `window` (between 500ms and 10s, a
+///   multiple of 2s without CAP_SYS_RESOURCE)
+pub fn watch_with_memory_stalls(state_root: &Path, stall: Option<(Duration, Duration)>) -> Result<StateWatcher> {
+    fs::create_dir_all(state_root)?;
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC)?;
+    // States are written with a rename, or directly by other tools
+    let flags = AddWatchFlags::IN_MOVED_TO | AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_FROM
+        | AddWatchFlags::IN_DELETE | AddWatchFlags::IN_DELETE_SELF;
+    let directory = inotify.add_watch(state_root, flags)?;
+    let mut watcher = StateWatcher {
+        inotify,
+        directory,
+        known: HashSet::new(),
+        memory: HashMap::new(),
+        stall,
+        pending: VecDeque::new(),
+    };
+    // Listed after adding the watch, so a state written in between is not lost
+    for entry in fs::read_dir(state_root)? {
+        if let Some(id) = state_id(&entry?.file_name()) {
+            watcher.watch_memory(&id, false);
+            watcher.known.insert(id);
+        }
+    }
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_state_id() {
+        assert_eq!(state_id(OsStr::new("abc.json")), Some("abc".to_string()));
+        assert_eq!(state_id(OsStr::new(".abc.json.tmp")), None);
+        assert_eq!(state_id(OsStr::new("abc.txt")), None);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let root = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_state_save");
+        let state = State::running("abc", 42);
+        state.save(&root).unwrap();
+        assert_eq!(State::load(&root, "abc").unwrap(), state);
+        // Only the state is left, not the temporary file
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 1);
+        State::remove(&root, "abc").unwrap();
+        assert!(State::load(&root, "abc").is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_watch_timeout() {
+        let root = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_state_watch");
+        let mut events = watch(&root).unwrap();
+        assert_eq!(events.next_timeout(Duration::from_millis(50)), None);
+        State::running("abc", 42).save(&root).unwrap();
+        assert_eq!(events.next_timeout(Duration::from_secs(5)), Some(StateEvent::CREATED("abc".to_string())));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
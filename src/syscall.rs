@@ -22,18 +22,289 @@
  */
 
 use std::ffi::{CString, CStr};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering};
 use color_eyre::{Result, eyre};
-use nix::libc::SIGCHLD;
+use nix::libc::{self, c_int, c_void, SIGCHLD};
 use nix::mount::{MsFlags, MntFlags, mount, umount2};
-use nix::sched::{clone, CloneFlags};
-use nix::unistd::{pivot_root, chdir, fork, execvpe, ForkResult, Pid, Uid, Gid};
+use nix::pty::{openpty, OpenptyResult, Winsize};
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sched::{clone, setns, unshare, CloneFlags};
+use nix::sys::stat::{major, minor, stat, Mode};
+use nix::sys::resource::{getrlimit, setrlimit, Resource};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::signalfd::{signalfd, SfdFlags, SIGNALFD_NEW};
+use nix::sys::socket::{recvmsg, send, sendmsg, socketpair, AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, SockFlag, SockType};
+use nix::sys::uio::IoVec;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{pivot_root, chdir, close, dup2, fork, execve, execvpe, geteuid, pipe2, read, setpgid, setsid, write, ForkResult, Pid, Uid, Gid};
 use serde::{Serialize, Deserialize};
 
+use crate::procfs;
+use crate::seccomp::{self, SeccompProfile};
+
+/// open_tree flag cloning the mount instead of opening it (include/uapi/linux/mount.h)
+const OPEN_TREE_CLONE: libc::c_uint = 1;
+/// move_mount flag taking the mount from the file descriptor itself (include/uapi/linux/mount.h)
+const MOVE_MOUNT_F_EMPTY_PATH: libc::c_uint = 0x4;
+/// move_mount flag taking the mount point from the target file descriptor itself (include/uapi/linux/mount.h)
+const MOVE_MOUNT_T_EMPTY_PATH: libc::c_uint = 0x40;
+/// mount_setattr attribute making a mount read-only (include/uapi/linux/mount.h)
+const MOUNT_ATTR_RDONLY: u64 = 0x1;
+
+/// Attributes changed by mount_setattr (struct mount_attr in include/uapi/linux/mount.h)
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+/// Version of the capget/capset structures with 64-bit capability sets (include/uapi/linux/capability.h)
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+/// Header of the capget/capset syscalls (struct __user_cap_header_struct)
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: c_int,
+}
+
+/// Capability sets of the capget/capset syscalls, 32 bits at a time (struct __user_cap_data_struct)
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Mount propagation type
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Propagation {
+    /// Mount events are not received nor forwarded
+    PRIVATE,
+    /// Mount events are received from the peer group, but not forwarded
+    SLAVE,
+    /// Mount events are received and forwarded to the peer group
+    SHARED,
+    /// Like `PRIVATE`, and the mount can't be bind mounted
+    UNBINDABLE,
+}
+
+impl Propagation {
+
+    fn flags(&self) -> MsFlags {
+        match self {
+            Propagation::PRIVATE => MsFlags::MS_PRIVATE,
+            Propagation::SLAVE => MsFlags::MS_SLAVE,
+            Propagation::SHARED => MsFlags::MS_SHARED,
+            Propagation::UNBINDABLE => MsFlags::MS_UNBINDABLE,
+        }
+    }
+
+}
+
+/// Options of the bind mount of the new rootfs
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RootfsOptions {
+    /// Mount the rootfs read-only
+    pub readonly: bool,
+    /// Propagation type of the rootfs mount (default: inherited from the source)
+    pub propagation: Option<Propagation>,
+}
+
+/// Namespaces created for the container.
+///
+/// The mount namespace is always created, the container needs it to switch to its own root filesystem.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Namespaces {
+    /// Hostname and domain name
+    pub uts: bool,
+    /// System V IPC and POSIX message queues
+    pub ipc: bool,
+    /// Process IDs, the container's init is PID 1
+    pub pid: bool,
+    /// Network devices, addresses and ports
+    pub net: bool,
+    /// Offsets of the monotonic and boot-time clocks (Linux 5.6), to create a time namespace (default: None)
+    pub time: Option<TimeOffsets>,
+}
+
+impl Default for Namespaces {
+    fn default() -> Self {
+        Namespaces {
+            uts: true,
+            ipc: true,
+            pid: true,
+            net: true,
+            time: None,
+        }
+    }
+}
+
+impl Namespaces {
+
+    /// Flags of the namespaces created when cloning the container process
+    ///
+    /// The time namespace can't be created by `clone`, see `unshare_time_namespace`.
+    pub fn clone_flags(&self) -> CloneFlags {
+        let mut flags = CloneFlags::CLONE_NEWNS;
+        flags.set(CloneFlags::CLONE_NEWUTS, self.uts);
+        flags.set(CloneFlags::CLONE_NEWIPC, self.ipc);
+        flags.set(CloneFlags::CLONE_NEWPID, self.pid);
+        flags.set(CloneFlags::CLONE_NEWNET, self.net);
+        flags
+    }
+
+}
+
+/// Offsets of the clocks of a time namespace, relative to the host's clocks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeOffsets {
+    /// Offset of CLOCK_MONOTONIC, in seconds (it may be negative)
+    pub monotonic: i64,
+    /// Offset of CLOCK_BOOTTIME, in seconds (it may be negative)
+    pub boottime: i64,
+}
+
+/// Creates a time namespace with the given clock offsets.
+///
+/// The calling process stays in its time namespace, its children created afterwards enter the new one
+/// (as does the process itself when it executes a program, on recent kernels).
+/// # Arguments
+/// * `offsets` - Offsets of the clocks
+pub fn unshare_time_namespace(offsets: &TimeOffsets) -> Result<()> {
+    // CLONE_NEWTIME is not known by nix
+    let res = unsafe { libc::unshare(libc::CLONE_NEWTIME) };
+    Errno::result(res).map_err(|err| eyre::eyre!("Could not create a time namespace (Linux 5.6+): {}", err))?;
+    // The offsets can only be set before any process enters the namespace
+    let offsets = format!("monotonic {} 0\nboottime {} 0\n", offsets.monotonic, offsets.boottime);
+    std::fs::write("/proc/self/timens_offsets", offsets)
+        .map_err(|err| eyre::eyre!("Could not set the offsets of the time namespace: {}", err))?;
+    Ok(())
+}
+
+/// A namespace of a process, which can be joined with `enter_namespaces`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Namespace {
+    /// Mount points
+    MNT,
+    /// Hostname and domain name
+    UTS,
+    /// System V IPC and POSIX message queues
+    IPC,
+    /// Network devices, addresses and ports
+    NET,
+    /// Process IDs, only for the children of the caller
+    PID,
+    /// cgroup root directory
+    CGROUP,
+    /// Clock offsets, only for the children of the caller
+    TIME,
+}
+
+impl Namespace {
+
+    /// Name of the namespace in /proc/<pid>/ns
+    pub fn name(&self) -> &'static str {
+        match self {
+            Namespace::MNT => "mnt",
+            Namespace::UTS => "uts",
+            Namespace::IPC => "ipc",
+            Namespace::NET => "net",
+            Namespace::PID => "pid",
+            Namespace::CGROUP => "cgroup",
+            Namespace::TIME => "time",
+        }
+    }
+
+    /// Flag of the namespace for `setns`
+    fn clone_flag(&self) -> CloneFlags {
+        match self {
+            Namespace::MNT => CloneFlags::CLONE_NEWNS,
+            Namespace::UTS => CloneFlags::CLONE_NEWUTS,
+            Namespace::IPC => CloneFlags::CLONE_NEWIPC,
+            Namespace::NET => CloneFlags::CLONE_NEWNET,
+            Namespace::PID => CloneFlags::CLONE_NEWPID,
+            Namespace::CGROUP => CloneFlags::CLONE_NEWCGROUP,
+            // CLONE_NEWTIME is not known by nix, no flag allows any type of namespace
+            Namespace::TIME => CloneFlags::empty(),
+        }
+    }
+
+}
+
+/// Runs a function in namespaces of another process, in a new thread that joins them, so the namespaces of
+/// the caller are left untouched.
+///
+/// Joining the PID and time namespaces only affects the processes forked by the function (e.g. with
+/// `exec` and `ExecType::FORK`), the thread itself keeps its PID and clocks. Joining the mount namespace
+/// also moves the thread to the root directory of the process.
+/// # Arguments
+/// * `pid` - PID of the process
+/// * `namespaces` - The namespaces to join
+/// * `f` - The function
+/// # Returns
+/// The result of the function, or an error if a namespace can't be joined
+pub fn enter_namespaces<F, R>(pid: Pid, namespaces: &[Namespace], f: F) -> Result<R>
+where
+    F: FnOnce() -> R + Send,
+    R: Send,
+{
+    // Opened before joining any, the paths are resolved differently in the mount namespace of the process
+    let mut fds = vec![];
+    for namespace in namespaces {
+        let path = format!("/proc/{}/ns/{}", pid, namespace.name());
+        match open(path.as_str(), OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty()) {
+            Ok(fd) => fds.push((fd, *namespace)),
+            Err(err) => {
+                for (fd, _) in fds {
+                    let _ = close(fd);
+                }
+                return Err(eyre::eyre!("Could not open {}: {}", path, err));
+            }
+        }
+    }
+    let result = std::thread::scope(|scope| {
+        scope.spawn(|| {
+            let mut joined = Ok(());
+            for (fd, namespace) in &fds {
+                // The filesystem attributes (root, cwd) are shared by the threads, the mount namespace
+                // can only be joined after taking a copy
+                if *namespace == Namespace::MNT {
+                    joined = unshare(CloneFlags::CLONE_FS);
+                }
+                joined = joined.and_then(|_| setns(*fd, namespace.clone_flag()));
+                if let Err(err) = joined {
+                    return Err(eyre::eyre!("Could not join the {} namespace of process {}: {}", namespace.name(), pid, err));
+                }
+            }
+            Ok(f())
+        }).join()
+    });
+    for (fd, _) in fds {
+        let _ = close(fd);
+    }
+    match result {
+        Ok(result) => result,
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
+}
+
 /// Switches the current rootfs to `new_root`
 /// # Arguments
 /// * `new_root` - The path to the new rootfs
-pub fn switch_rootfs(new_root: &Path) -> Result<()> {
+/// * `options` - Options of the bind mount of the new rootfs
+pub fn switch_rootfs(new_root: &Path, options: &RootfsOptions) -> Result<()> {
     mount(
         Some(new_root),
         new_root,
@@ -41,6 +312,25 @@ pub fn switch_rootfs(new_root: &Path) -> Result<()> {
         MsFlags::MS_BIND | MsFlags::MS_REC,
         None::<&str>,
     )?;
+    // Propagation and read-only state can't be set on the initial bind, they need their own mount calls
+    if let Some(propagation) = options.propagation {
+        mount(
+            None::<&str>,
+            new_root,
+            None::<&str>,
+            propagation.flags() | MsFlags::MS_REC,
+            None::<&str>,
+        )?;
+    }
+    if options.readonly {
+        mount(
+            None::<&str>,
+            new_root,
+            None::<&str>,
+            MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY,
+            None::<&str>,
+        )?;
+    }
     // https://man7.org/linux/man-pages/man2/pivot_root.2.html
     // pivot_root(".", ".")
     //  new_root and put_old may be the same directory.  In particular,
@@ -58,14 +348,112 @@ pub fn switch_rootfs(new_root: &Path) -> Result<()> {
     //  During the subsequent umount() call, resolution of "."  starts
     //  with new_root and then moves up the list of mounts stacked at /,
     //  with the result that old root mount point is unmounted.
+    let expected = stat(new_root)?;
     chdir(new_root)?;
     pivot_root(".", ".")?;
     umount2(".", MntFlags::MNT_DETACH)?;
+    // A leaked old root would let the container escape, check the new root can't be left with ".."
+    let root = stat("/")?;
+    let parent = stat("/..")?;
+    if (root.st_dev, root.st_ino) != (expected.st_dev, expected.st_ino) {
+        return Err(eyre::eyre!("The root directory is not {} after switching the rootfs", new_root.display()));
+    }
+    if (parent.st_dev, parent.st_ino) != (root.st_dev, root.st_ino) {
+        return Err(eyre::eyre!("The old rootfs is reachable from the new one"));
+    }
+    Ok(())
+}
+
+/// Checks the old root filesystem was detached by `switch_rootfs`: /proc/self/mountinfo must only list the
+/// new root at "/". Requires /proc to be mounted, once the rootfs is switched
+pub fn verify_old_root_detached() -> Result<()> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+    let root = stat("/")?;
+    let expected = (major(root.st_dev), minor(root.st_dev));
+    match procfs::root_mounts(&mountinfo).as_slice() {
+        [device] if *device == expected => Ok(()),
+        devices => Err(eyre::eyre!(
+            "The old rootfs is still mounted: {} mounts at / ({:?}), expected only the new root ({:?})",
+            devices.len(), devices, expected
+        )),
+    }
+}
+
+/// Clones the mount of a path (like a bind mount) into a detached mount, which stays usable after
+/// switching the root filesystem, when the original path is no longer reachable
+/// # Arguments
+/// * `path` - The path (a file or a directory)
+/// # Returns
+/// A file descriptor referring to the detached mount, to attach with `move_mount` (requires Linux 5.2)
+pub fn clone_mount(path: &impl AsRef<Path>) -> Result<RawFd> {
+    let path = CString::new(path.as_ref().as_os_str().as_bytes())?;
+    let fd = unsafe {
+        libc::syscall(libc::SYS_open_tree, libc::AT_FDCWD, path.as_ptr(), OPEN_TREE_CLONE | libc::O_CLOEXEC as libc::c_uint)
+    };
+    Ok(Errno::result(fd)? as RawFd)
+}
+
+/// Clones the mount of a path and its submounts into a detached read-only mount. Unlike a bind mount
+/// remounted read-only, it is never writable once attached, and the submounts are read-only too
+/// # Arguments
+/// * `path` - The path (a file or a directory)
+/// # Returns
+/// A file descriptor referring to the detached mount, to attach with `move_mount` (requires Linux 5.12)
+pub fn clone_mount_readonly(path: &impl AsRef<Path>) -> Result<RawFd> {
+    let path = CString::new(path.as_ref().as_os_str().as_bytes())?;
+    let flags = OPEN_TREE_CLONE | libc::AT_RECURSIVE as libc::c_uint | libc::O_CLOEXEC as libc::c_uint;
+    let fd = Errno::result(unsafe {
+        libc::syscall(libc::SYS_open_tree, libc::AT_FDCWD, path.as_ptr(), flags)
+    })? as RawFd;
+    let attr = MountAttr {
+        attr_set: MOUNT_ATTR_RDONLY,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: 0,
+    };
+    let empty = CString::default();
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr, fd, empty.as_ptr(), libc::AT_EMPTY_PATH | libc::AT_RECURSIVE,
+            &attr as *const MountAttr, mem::size_of::<MountAttr>(),
+        )
+    };
+    if let Err(err) = Errno::result(res) {
+        let _ = close(fd);
+        return Err(err.into());
+    }
+    Ok(fd)
+}
+
+/// Attaches a detached mount (see `clone_mount`) at a path
+/// # Arguments
+/// * `fd` - File descriptor of the detached mount
+/// * `target` - Path of the mount point
+pub fn move_mount(fd: RawFd, target: &impl AsRef<Path>) -> Result<()> {
+    let empty = CString::default();
+    let target = CString::new(target.as_ref().as_os_str().as_bytes())?;
+    let res = unsafe {
+        libc::syscall(libc::SYS_move_mount, fd, empty.as_ptr(), libc::AT_FDCWD, target.as_ptr(), MOVE_MOUNT_F_EMPTY_PATH)
+    };
+    Errno::result(res)?;
+    Ok(())
+}
+
+/// Attaches a detached mount (see `clone_mount`) on top of an opened file or directory
+/// # Arguments
+/// * `fd` - File descriptor of the detached mount
+/// * `target` - File descriptor of the mount point
+pub fn move_mount_onto(fd: RawFd, target: RawFd) -> Result<()> {
+    let empty = CString::default();
+    let res = unsafe {
+        libc::syscall(libc::SYS_move_mount, fd, empty.as_ptr(), target, empty.as_ptr(), MOVE_MOUNT_F_EMPTY_PATH | MOVE_MOUNT_T_EMPTY_PATH)
+    };
+    Errno::result(res)?;
     Ok(())
 }
 
 /// Execution type for a new process inside the container
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ExecType {
     /// Execute a new process as a child of the container
     FORK,
@@ -74,7 +462,7 @@ pub enum ExecType {
 }
 
 /// A command represents a process to be executed inside the container
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Command {
     /// Filename or path to the executable
     pub command: String,
@@ -83,59 +471,1203 @@ pub struct Command {
     /// Environment variables to set
     pub env: Vec<String>,
     /// Execution type for the new process
-    pub exec_type: ExecType
+    pub exec_type: ExecType,
+    /// Name the process gets as argv[0] (optional, default: the command)
+    #[serde(default)]
+    pub argv0: Option<String>,
+    /// Run as a login shell, with argv[0] prefixed with `-`, so the shell reads the login profiles
+    #[serde(default)]
+    pub login: bool,
+    /// Search the command in the PATH when it has no '/' (execvpe). Otherwise the command must be a path,
+    /// absolute or relative to the working directory (execve). Default: true
+    #[serde(default = "default_path_search")]
+    pub path_search: bool,
+    /// Name of a new process group to run the command in (setpgid), so it can be signaled apart from the
+    /// rest of the container (only with `ExecType::FORK`, see `Container::signal_process_group`)
+    #[serde(default)]
+    pub process_group: Option<String>,
+    /// Write the stdout of the command to a pipe handed over by the host through the command-result
+    /// channel, instead of the container's output (only with `ExecType::FORK`, see
+    /// `Container::run_command_streaming`). Its exit is reported to the host as well
+    #[serde(default)]
+    pub stream_stdout: bool,
+}
+
+/// Commands serialized without `path_search` search the PATH, as they did before it was added
+fn default_path_search() -> bool {
+    true
+}
+
+impl Command {
+
+    /// Name of the process, passed as argv[0]
+    /// # Returns
+    /// The `argv0` override or the command, prefixed with `-` if `login` is set (without the
+    /// directories of the command, as `login` does: "/bin/sh" runs as "-sh")
+    pub fn program_name(&self) -> String {
+        let name = self.argv0.clone().unwrap_or_else(|| self.command.clone());
+        if !self.login || name.starts_with('-') {
+            return name;
+        }
+        match self.argv0 {
+            Some(_) => format!("-{}", name),
+            None => format!("-{}", name.rsplit('/').next().unwrap_or(&name)),
+        }
+    }
+
 }
 
 /// Execute a command
 /// # Arguments
 /// * `command` - The command to execute
+/// * `seccomp` - Seccomp profile installed right before executing the command (if any)
+/// * `stdout` - Stdout of the command, instead of the caller's (only with `ExecType::FORK`)
 /// # Returns
-/// The PID of the new process (only if `exec_type` is `ExecType::FORK`)
+/// The PID of the new process (only if `exec_type` is `ExecType::FORK`), to wait for it with `wait_exec`.
+/// A forked process that can't execute its command exits (with 127 if it was not found, 126 otherwise, as
+/// shells do), and the error is returned once it is reaped
 /// 
 /// Note: when `exec_type` is `ExecType::REPLACE`, this function never returns, as the whole process is replaced.
-pub fn exec(command: Command) -> Result<i32> {
+pub fn exec(command: Command, seccomp: Option<&SeccompProfile>, stdout: Option<RawFd>) -> Result<i32> {
     log::debug!("Executing command: {:?}", command);
+    let program_name = CString::new(command.program_name()).unwrap();
     let filename: CString = CString::new(command.command).unwrap();
-    let mut args: Vec<CString> = vec![filename.clone()];
+    let mut args: Vec<CString> = vec![program_name];
     for arg in command.args {
         args.push(CString::new(arg).unwrap());
     }
     let env = &command.env.iter()
         .map(|s| CString::new(s.clone()).unwrap())
         .collect::<Vec<CString>>();
+    let path_search = command.path_search;
+    let process_group = command.process_group.is_some() && matches!(command.exec_type, ExecType::FORK);
+    let execute = || -> Result<()> {
+        // The runtime blocks its stop signal and SIGCHLD (see `handle_signals`), the command must receive them
+        SigSet::empty().thread_set_mask()?;
+        // Last, so the filter doesn't have to allow the syscalls made to prepare the execution
+        if let Some(profile) = seccomp {
+            seccomp::apply_seccomp(profile)?;
+        }
+        if path_search {
+            execvpe(&filename, &args, env)?;
+        } else {
+            execve(&filename, &args, env)?;
+        }
+        Ok(())
+    };
     match command.exec_type {
         ExecType::FORK => {
+            // The child writes its errno if it can't execute the command, the pipe is closed once it does
+            let (status_read, status_write) = pipe2(OFlag::O_CLOEXEC)?;
             // Forking is unsafe ¯\_(ツ)_/¯
-            unsafe {
-                let fork_result = fork()?;
-                match fork_result {
-                    ForkResult::Parent { child } => return Ok(i32::from(child)),
-                    ForkResult::Child => {
-                        execvpe(&filename, &args, &env)?;
-                    },
-                }
+            match unsafe { fork() } {
+                Ok(ForkResult::Parent { child }) => {
+                    let _ = close(status_write);
+                    if process_group {
+                        // Also from the parent, so the group exists once this returns (it fails with
+                        // EACCES if the child already executed its command, having set it itself)
+                        let _ = setpgid(child, child);
+                    }
+                    let failure = read_exec_failure(status_read);
+                    let _ = close(status_read);
+                    if let Some(errno) = failure? {
+                        // The child exits right away
+                        waitpid(child, None)?;
+                        return Err(errno.into());
+                    }
+                    Ok(i32::from(child))
+                },
+                Ok(ForkResult::Child) => {
+                    let _ = close(status_read);
+                    let result = match process_group {
+                        true => setpgid(Pid::from_raw(0), Pid::from_raw(0)).map_err(eyre::Report::from),
+                        false => Ok(()),
+                    }.and_then(|_| match stdout {
+                        Some(fd) => dup2(fd, libc::STDOUT_FILENO).map(|_| ()).map_err(eyre::Report::from),
+                        None => Ok(()),
+                    }).and_then(|_| execute());
+                    // The child must not return: it would go on as a copy of the caller
+                    let errno = match result {
+                        Err(err) => err.downcast_ref::<Errno>().copied().unwrap_or(Errno::UnknownErrno),
+                        Ok(()) => Errno::UnknownErrno,
+                    };
+                    let _ = write(status_write, &(errno as i32).to_ne_bytes());
+                    unsafe { libc::_exit(exec_failure_code(errno)) }
+                },
+                Err(err) => {
+                    let _ = close(status_read);
+                    let _ = close(status_write);
+                    Err(err.into())
+                },
             }
         },
         ExecType::REPLACE => {
-            execvpe(&filename, &args, &env)?;
+            execute()?;
             // On success current process is replaced by the new one
+            Err(eyre::eyre!("Failed to execute command"))
+        }
+    }
+}
+
+/// Reads the errno written by a forked child that could not execute its command, see `exec`
+/// # Returns
+/// None once the child executed its command (the pipe is closed without any data)
+fn read_exec_failure(status: RawFd) -> Result<Option<Errno>> {
+    let mut errno = [0u8; 4];
+    let mut length = 0;
+    while length < errno.len() {
+        match read(status, &mut errno[length..]) {
+            Ok(0) if length == 0 => return Ok(None),
+            Ok(0) => return Err(eyre::eyre!("Truncated status of a forked command")),
+            Ok(count) => length += count,
+            Err(Errno::EINTR) => continue,
+            Err(err) => return Err(err.into()),
         }
     }
-    Err(eyre::eyre!("Failed to execute command"))
+    Ok(Some(Errno::from_i32(i32::from_ne_bytes(errno))))
 }
 
-pub fn create_container<Cb>(callback: Cb) -> Result<Pid> 
+/// Exit code of a forked process that could not execute its command, as shells report it: 127 if the
+/// command was not found, 126 otherwise
+fn exec_failure_code(errno: Errno) -> c_int {
+    match errno {
+        Errno::ENOENT => 127,
+        _ => 126,
+    }
+}
+
+/// How a process ended: the container's workload (see `Container::wait_for_container`), or a command
+/// forked by `exec` (see `wait_exec`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContainerExit {
+    /// Exited with this code
+    EXITED(i32),
+    /// Killed by this signal
+    SIGNALED(Signal),
+}
+
+impl ContainerExit {
+
+    /// Converts a status returned by `waitpid`
+    /// # Returns
+    /// None if the process has not ended (e.g. it is only stopped)
+    pub fn from_wait_status(status: WaitStatus) -> Option<ContainerExit> {
+        match status {
+            WaitStatus::Exited(_, code) => Some(ContainerExit::EXITED(code)),
+            WaitStatus::Signaled(_, signal, _) => Some(ContainerExit::SIGNALED(signal)),
+            _ => None,
+        }
+    }
+
+    /// Whether the process exited with code 0
+    pub fn success(&self) -> bool {
+        *self == ContainerExit::EXITED(0)
+    }
+
+    /// Exit code as reported by shells: the code, or 128 + the number of the signal
+    pub fn code(&self) -> i32 {
+        match self {
+            ContainerExit::EXITED(code) => *code,
+            ContainerExit::SIGNALED(signal) => 128 + *signal as i32,
+        }
+    }
+
+}
+
+/// Waits for a command executed with `ExecType::FORK` to end
+/// # Arguments
+/// * `pid` - PID of the command, returned by `exec`
+///
+/// Note: only the process that called `exec` (the parent of the command) can wait for it
+pub fn wait_exec(pid: Pid) -> Result<ContainerExit> {
+    loop {
+        let status = waitpid(pid, None)?;
+        if let Some(exit) = ContainerExit::from_wait_status(status) {
+            return Ok(exit);
+        }
+    }
+}
+
+/// Outcome of a command executed by the container's runtime, reported to the host through the
+/// command-result channel (see `command_result_channel`), apart from the exit code of the container: a
+/// workload replacing the runtime may exit with any code, including the ones of `runtime::ExitCode`
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandResult {
+    /// A forked command started, with this PID (in the container's PID namespace)
+    STARTED(i32),
+    /// The runtime is about to be replaced by a command (`ExecType::REPLACE`), the container exits with
+    /// the command's code from then on
+    REPLACING,
+    /// A command could not be executed, with this error (`Errno::UnknownErrno` if it was not an errno)
+    FAILED(Errno),
+    /// A forked command with this PID ended, reported for the commands streaming their stdout (see
+    /// `Command::stream_stdout`)
+    EXITED(i32, ContainerExit),
+}
+
+impl CommandResult {
+
+    /// Size of a record: the kind and two values, native-endian `i32`s
+    const SIZE: usize = 12;
+
+    fn encode(&self) -> [u8; CommandResult::SIZE] {
+        let (kind, first, second) = match *self {
+            CommandResult::STARTED(pid) => (1, pid, 0),
+            CommandResult::REPLACING => (2, 0, 0),
+            CommandResult::FAILED(errno) => (3, errno as i32, 0),
+            CommandResult::EXITED(pid, ContainerExit::EXITED(code)) => (4, pid, code),
+            CommandResult::EXITED(pid, ContainerExit::SIGNALED(signal)) => (5, pid, signal as i32),
+        };
+        let mut record = [0u8; CommandResult::SIZE];
+        for (index, value) in [kind, first, second].iter().enumerate() {
+            record[index * 4..(index + 1) * 4].copy_from_slice(&value.to_ne_bytes());
+        }
+        record
+    }
+
+    fn decode(record: &[u8; CommandResult::SIZE]) -> Result<CommandResult> {
+        let value = |index: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&record[index * 4..(index + 1) * 4]);
+            i32::from_ne_bytes(bytes)
+        };
+        match value(0) {
+            1 => Ok(CommandResult::STARTED(value(1))),
+            2 => Ok(CommandResult::REPLACING),
+            3 => Ok(CommandResult::FAILED(Errno::from_i32(value(1)))),
+            4 => Ok(CommandResult::EXITED(value(1), ContainerExit::EXITED(value(2)))),
+            5 => Ok(CommandResult::EXITED(value(1), ContainerExit::SIGNALED(Signal::try_from(value(2))?))),
+            kind => Err(eyre::eyre!("Unknown command result {}", kind)),
+        }
+    }
+
+}
+
+/// Creates the command-result channel of a container, a pair of connected sockets: the runtime writes
+/// the results of its commands to its end (see `report_command_result`), the host reads them from its
+/// end (see `read_command_result`), and hands file descriptors to the runtime (see `send_fd`)
+/// # Returns
+/// The host's and the runtime's ends. Both are closed on exec, so the channel is closed (and the host
+/// reads the end of file) once the runtime exited or was replaced by its workload
+pub fn command_result_channel() -> Result<(RawFd, RawFd)> {
+    Ok(socketpair(AddressFamily::Unix, SockType::SeqPacket, None, SockFlag::SOCK_CLOEXEC)?)
+}
+
+/// Reports the result of a command to the host, see `command_result_channel`
+/// # Arguments
+/// * `fd` - Write end of the channel
+/// * `result` - The result
+///
+/// Note: each record is a packet of its own, so the records of several processes never interleave, and
+/// writing fails instead of raising SIGPIPE once the host closed its end. Async-signal-safe
+pub fn report_command_result(fd: RawFd, result: CommandResult) -> Result<()> {
+    let record = result.encode();
+    loop {
+        match send(fd, &record, no_sigpipe()) {
+            Ok(_) => return Ok(()),
+            Err(Errno::EINTR) => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Reads the next result of a command, see `command_result_channel`
+/// # Arguments
+/// * `fd` - Read end of the channel
+/// * `block` - Wait for a result, otherwise only read one already written
+/// # Returns
+/// The result, None once the channel is closed (or when none is available, if not blocking)
+pub fn read_command_result(fd: RawFd, block: bool) -> Result<Option<CommandResult>> {
+    if !block {
+        let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+        if poll(&mut fds, 0)? == 0 {
+            return Ok(None);
+        }
+    }
+    let mut record = [0u8; CommandResult::SIZE];
+    let mut length = 0;
+    while length < record.len() {
+        match read(fd, &mut record[length..]) {
+            Ok(0) if length == 0 => return Ok(None),
+            Ok(0) => return Err(eyre::eyre!("Truncated command result")),
+            Ok(count) => length += count,
+            Err(Errno::EINTR) => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(Some(CommandResult::decode(&record)?))
+}
+
+/// Flags of the messages sent over the command-result channel: fail with EPIPE instead of raising SIGPIPE
+/// once the other end is closed (MSG_NOSIGNAL is missing from nix's `MsgFlags`)
+fn no_sigpipe() -> MsgFlags {
+    unsafe { MsgFlags::from_bits_unchecked(libc::MSG_NOSIGNAL) }
+}
+
+/// Hands a file descriptor to the other end of the command-result channel, see `receive_fd`
+/// # Arguments
+/// * `socket` - End of the channel
+/// * `fd` - The file descriptor, the other process gets its own copy
+pub fn send_fd(socket: RawFd, fd: RawFd) -> Result<()> {
+    let fds = [fd];
+    let message = [ControlMessage::ScmRights(&fds)];
+    // A message can't carry only ancillary data
+    sendmsg(socket, &[IoVec::from_slice(&[0u8])], &message, no_sigpipe(), None)?;
+    Ok(())
+}
+
+/// Receives a file descriptor sent with `send_fd`, blocking until it arrives
+/// # Arguments
+/// * `socket` - End of the channel
+/// # Returns
+/// The file descriptor, closed on exec
+pub fn receive_fd(socket: RawFd) -> Result<RawFd> {
+    let mut byte = [0u8; 1];
+    let mut space = nix::cmsg_space!([RawFd; 1]);
+    let message = loop {
+        match recvmsg(socket, &[IoVec::from_mut_slice(&mut byte)], Some(&mut space), MsgFlags::MSG_CMSG_CLOEXEC) {
+            Err(Errno::EINTR) => continue,
+            result => break result?,
+        }
+    };
+    for control in message.cmsgs() {
+        if let ControlMessageOwned::ScmRights(fds) = control {
+            if let Some(fd) = fds.first() {
+                return Ok(*fd);
+            }
+        }
+    }
+    Err(eyre::eyre!("No file descriptor received"))
+}
+
+/// Whether the current process is a container process created by `create_container`
+static IN_CONTAINER: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether the current process is the runtime of a container, i.e. it was created by
+/// `create_container` (the processes executed by the container start from scratch and return false)
+pub fn in_container() -> bool {
+    IN_CONTAINER.load(Ordering::SeqCst)
+}
+
+/// Creates the container process, in new namespaces, running `callback`
+/// # Arguments
+/// * `callback` - Entry point of the container, its return value is the exit status
+/// * `namespaces` - Namespaces of the container
+/// * `userns` - Also create a user namespace. The container process starts without any ID mapped (as the
+///   overflow user), the host must write its maps with `setup_userns_mappings` before letting it run
+/// * `panic_code` - Exit status of the container process if the callback panics
+/// # Returns
+/// The PID of the container process
+///
+/// The container process is a copy of the calling one (its memory is not shared), but only the calling
+/// thread is copied: a lock held by another thread at that moment (including the allocator's or the
+/// logger's) stays locked forever in the container. Only async-signal-safe operations are safe in the
+/// callback:
+/// - safe: raw syscalls (`close`, `read`, `write`, `dup2`, ...), `_exit`, returning from the callback
+/// - unsafe: allocating (`Box`, `String`, `format!`, `eyre!`, growing a `Vec`), logging, locking a `Mutex`,
+///   printing, panicking
+///
+/// Waiting for the host (`wait_ready`) doesn't release such a lock. The callback only sticks to the safe
+/// operations until then, so a failed start can always be reported; the rest of the setup (e.g.
+/// `Runtime::run`) allocates and logs, it can deadlock when another thread of the calling process held
+/// the lock at the time of the clone. Create containers from a single-threaded process, or from a
+/// thread when the others don't allocate nor log, to rule it out.
+/// A panic of the callback doesn't unwind past it: the container process exits with `panic_code`.
+///
+/// Note: the callback runs on a 4 MB stack of its own. `clone` expects the address the stack starts
+/// from, which is the end of the buffer on every architecture Rust supports on Linux (x86_64, aarch64,
+/// arm, riscv64, ...), as the stack grows down: `nix::sched::clone` passes the end of the buffer,
+/// aligned to 16 bytes as required by the ABIs of x86_64 and aarch64, so the buffer is passed as is.
+pub fn create_container<Cb>(callback: Cb, namespaces: &Namespaces, userns: bool, panic_code: isize) -> Result<Pid>
 where
     Cb: FnMut() -> isize,
 {
     const STACK_SIZE: usize = 4 * 1024 * 1024; // == 4 MB
-    let ref mut stack: [u8; STACK_SIZE] = [0; STACK_SIZE];
-    let clone_flags = CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWUTS | CloneFlags::CLONE_NEWIPC | CloneFlags::CLONE_NEWPID | CloneFlags::CLONE_NEWNET;
-    let cb = Box::new(callback);
+    // Allocated on the heap, the caller's stack may be smaller than that (e.g. in a thread). The stack
+    // grows down from its end, see above
+    let stack = &mut vec![0u8; STACK_SIZE];
+    let mut clone_flags = namespaces.clone_flags();
+    // Created first by the kernel, it owns the other namespaces
+    clone_flags.set(CloneFlags::CLONE_NEWUSER, userns);
+    let mut callback = callback;
+    let cb = Box::new(move || {
+        // Only the container's copy of the flag is set
+        IN_CONTAINER.store(true, Ordering::SeqCst);
+        // Unwinding out of the callback would cross the clone boundary, into the copy of the host's stack
+        panic::catch_unwind(AssertUnwindSafe(&mut callback)).unwrap_or(panic_code)
+    });
     let pid = clone(cb, stack, clone_flags, Some(SIGCHLD))?;
     Ok(pid)
 }
 
+/// Replaces the environment of the current process
+/// # Arguments
+/// * `env` - The new environment, "KEY=VALUE" strings. The first definition of a variable wins
+///
+/// Note: /proc/<pid>/environ still shows the environment the process was started with, only the commands
+/// it executes afterwards get the new one. Any pointer to a variable obtained before (e.g. from `getenv`)
+/// becomes invalid.
+pub fn replace_environment(env: &[String]) -> Result<()> {
+    let res = unsafe { libc::clearenv() };
+    Errno::result(res)?;
+    for var in env {
+        match var.split_once('=') {
+            // Set by a previous definition
+            Some((key, _)) if std::env::var_os(key).is_some() => {},
+            Some((key, value)) => std::env::set_var(key, value),
+            None => return Err(eyre::eyre!("Invalid environment variable {}, expected KEY=VALUE", var)),
+        }
+    }
+    Ok(())
+}
+
+/// A range of user or group IDs of a user namespace, mapped to IDs of the parent namespace
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdMap {
+    /// First ID inside the namespace
+    pub container_id: u32,
+    /// First ID in the parent namespace
+    pub host_id: u32,
+    /// Number of IDs in the range
+    pub size: u32,
+}
+
+/// Maximum number of ranges of a uid_map or gid_map (since Linux 4.15)
+const MAX_ID_MAPS: usize = 340;
+
+/// Writes the user and group ID maps of a process that created a new user namespace
+/// # Arguments
+/// * `pid` - PID of the process
+/// * `uid_maps` - Ranges of user IDs
+/// * `gid_maps` - Ranges of group IDs
+///
+/// The kernel only accepts each map once, written in a single `write`, so every range is written at once.
+/// Unless the current process runs as root (effective UID 0, which is assumed to have CAP_SETGID),
+/// setgroups is denied first, as the kernel requires to write the gid_map without CAP_SETGID.
+///
+/// Note: the maps must be written before the process does anything depending on its IDs (e.g. mounting
+/// or creating files): until then it runs as the overflow user (65534). The process must block until the
+/// parent has written them, e.g. with `wait_ready`, then switch to the IDs of the namespace with
+/// `switch_to_userns_root`. Mapping IDs other than the caller's own requires CAP_SETUID (and CAP_SETGID)
+/// in the parent namespace.
+pub fn write_id_maps(pid: Pid, uid_maps: &[IdMap], gid_maps: &[IdMap]) -> Result<()> {
+    if !uid_maps.is_empty() {
+        write_id_map(&format!("/proc/{}/uid_map", pid), uid_maps)?;
+    }
+    if !gid_maps.is_empty() {
+        if !geteuid().is_root() {
+            std::fs::write(format!("/proc/{}/setgroups", pid), "deny")?;
+        }
+        write_id_map(&format!("/proc/{}/gid_map", pid), gid_maps)?;
+    }
+    Ok(())
+}
+
+/// Maps the user and group IDs of a process that created a new user namespace (see `create_container`),
+/// with setgroups denied first, so the container can't drop groups that deny it access to files
+/// # Arguments
+/// * `pid` - PID of the process
+/// * `uid_map` - Ranges of user IDs, as (first ID inside the namespace, first ID in the host, size)
+/// * `gid_map` - Ranges of group IDs, as (first ID inside the namespace, first ID in the host, size)
+///
+/// Note: the maps must be written before the process does anything depending on its IDs (e.g. mounting
+/// or creating files): until then it runs as the overflow user (65534), and the maps can't be changed
+/// once written. The process must block until the parent has written them, e.g. with `wait_ready`, then
+/// switch to the IDs of the namespace with `switch_to_userns_root`. See `write_id_maps`.
+pub fn setup_userns_mappings(pid: Pid, uid_map: &[(u32, u32, u32)], gid_map: &[(u32, u32, u32)]) -> Result<()> {
+    let id_maps = |maps: &[(u32, u32, u32)]| -> Vec<IdMap> {
+        maps.iter()
+            .map(|&(container_id, host_id, size)| IdMap { container_id, host_id, size })
+            .collect()
+    };
+    // Even when it could, the container must not drop its supplementary groups
+    let setgroups = format!("/proc/{}/setgroups", pid);
+    std::fs::write(&setgroups, "deny")
+        .map_err(|err| eyre::eyre!("Could not write {}: {}", setgroups, err))?;
+    write_id_maps(pid, &id_maps(uid_map), &id_maps(gid_map))
+}
+
+fn write_id_map(path: &str, maps: &[IdMap]) -> Result<()> {
+    if maps.len() > MAX_ID_MAPS {
+        return Err(eyre::eyre!("{}: at most {} ranges can be mapped, got {}", path, MAX_ID_MAPS, maps.len()));
+    }
+    // Overlapping ranges are rejected by the kernel with a bare EINVAL
+    let overlap = |a: u32, b: u32, size_a: u32, size_b: u32| {
+        (a as u64) < b as u64 + size_b as u64 && (b as u64) < a as u64 + size_a as u64
+    };
+    for (i, a) in maps.iter().enumerate() {
+        for b in &maps[i + 1..] {
+            if overlap(a.container_id, b.container_id, a.size, b.size) || overlap(a.host_id, b.host_id, a.size, b.size) {
+                return Err(eyre::eyre!("{}: ranges {:?} and {:?} overlap", path, a, b));
+            }
+        }
+    }
+    let map: String = maps.iter()
+        .map(|map| format!("{} {} {}\n", map.container_id, map.host_id, map.size))
+        .collect();
+    let fd = nix::fcntl::open(path, nix::fcntl::OFlag::O_WRONLY | nix::fcntl::OFlag::O_CLOEXEC, nix::sys::stat::Mode::empty())?;
+    let res = write(fd, map.as_bytes());
+    close(fd)?;
+    let written = res.map_err(|err| eyre::eyre!("Could not write {}: {}", path, err))?;
+    if written != map.len() {
+        return Err(eyre::eyre!("Could not write {}: partial write ({} of {} bytes)", path, written, map.len()));
+    }
+    Ok(())
+}
+
+/// Returns whether the current process runs in a user namespace other than the initial one
+pub fn in_user_namespace() -> bool {
+    // The initial user namespace maps every ID to itself: "0 0 4294967295"
+    match std::fs::read_to_string("/proc/self/uid_map") {
+        Ok(map) => map.split_whitespace().collect::<Vec<&str>>() != ["0", "0", "4294967295"],
+        Err(_) => false,
+    }
+}
+
+/// Returns whether setgroups is denied in the user namespace of the current process, e.g. because its
+/// gid_map was written without CAP_SETGID (see `write_id_maps`)
+pub fn setgroups_denied() -> bool {
+    in_user_namespace() && matches!(std::fs::read_to_string("/proc/self/setgroups"), Ok(setgroups) if setgroups.trim() == "deny")
+}
+
+/// Opens a file descriptor referring to a process, which becomes readable when the process exits
+/// # Arguments
+/// * `pid` - PID of the process, it must be a child of the current process to be able to poll it
+/// # Returns
+/// The file descriptor (requires Linux 5.3)
+pub fn pidfd_open(pid: Pid) -> Result<RawFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+    Ok(Errno::result(fd)? as RawFd)
+}
+
+/// Whether a child process has exited, without reaping it (it can still be waited for)
+/// # Arguments
+/// * `pid` - PID of the child process
+pub fn has_exited(pid: Pid) -> Result<bool> {
+    let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+    let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+    Errno::result(unsafe { libc::waitid(libc::P_PID, pid.as_raw() as libc::id_t, &mut info, flags) })?;
+    // si_pid is left to 0 while the child is running
+    Ok(unsafe { info.si_pid() } != 0)
+}
+
+/// Signals forwarded to the workload by default.
+///
+/// Every signal except SIGCHLD (handled by the container's init itself), SIGKILL and SIGSTOP (which
+/// can't be caught) and the signals raised by faults in the init process (SIGSEGV, SIGBUS, ...),
+/// as forwarding those would leave the init process looping on the faulting instruction. The stop
+/// signal of the container (`RuntimeOptions::stop_signal`, SIGTERM by default) is listed, but stops the
+/// init instead of being forwarded.
+pub fn default_forward_signals() -> Vec<Signal> {
+    Signal::iterator()
+        .filter(|signal| !matches!(signal,
+            Signal::SIGCHLD | Signal::SIGKILL | Signal::SIGSTOP |
+            Signal::SIGSEGV | Signal::SIGBUS | Signal::SIGILL | Signal::SIGFPE | Signal::SIGTRAP | Signal::SIGSYS
+        ))
+        .collect()
+}
+
+extern "C" fn forward_signal(signal: c_int) {
+    // Inside the container's PID namespace, kill(-1, ...) signals every process except the init itself
+    unsafe {
+        libc::kill(-1, signal);
+    }
+}
+
+/// Forwards the given signals, when received by the container's init, to the workload processes
+/// # Arguments
+/// * `signals` - Signals to forward
+///
+/// Note: this must only be called from the container's init (PID 1 of the container's PID namespace)
+pub fn forward_signals(signals: &[Signal]) -> Result<()> {
+    let action = SigAction::new(SigHandler::Handler(forward_signal), SaFlags::SA_RESTART, SigSet::empty());
+    for signal in signals {
+        log::debug!("Forwarding {} to the workload", signal);
+        unsafe {
+            sigaction(*signal, &action)?;
+        }
+    }
+    Ok(())
+}
+
+/// Sets the SELinux label of the programs executed afterwards by the current thread (and by the
+/// processes it forks)
+/// # Arguments
+/// * `label` - The SELinux context, e.g. "system_u:system_r:container_t:s0:c1,c2"
+pub fn set_exec_label(label: &str) -> Result<()> {
+    std::fs::write("/proc/thread-self/attr/exec", label)
+        .map_err(|err| eyre::eyre!("Could not set the SELinux exec label {}: {}", label, err))
+}
+
+/// Makes the container's init receive its stop signal and SIGCHLD through a file descriptor, instead of
+/// the stop signal being forwarded: the signals are blocked, and read with a signalfd (see `read_signal`)
+/// # Arguments
+/// * `signal` - The stop signal
+/// # Returns
+/// The signalfd, readable once a signal is received (see `ConsumerChannel::receive_unless_readable`)
+///
+/// Note: the threads created afterwards inherit the blocked signals, the commands are executed with them
+/// unblocked (see `exec`)
+pub fn handle_signals(signal: Signal) -> Result<RawFd> {
+    let mut mask = SigSet::empty();
+    mask.add(signal);
+    mask.add(Signal::SIGCHLD);
+    log::debug!("Stopping on {}", signal);
+    mask.thread_block()?;
+    let fd = signalfd(SIGNALFD_NEW, &mask, SfdFlags::SFD_CLOEXEC | SfdFlags::SFD_NONBLOCK)?;
+    Ok(fd)
+}
+
+/// Reads a signal received by a signalfd, see `handle_signals`
+/// # Arguments
+/// * `fd` - The signalfd
+/// # Returns
+/// The signal, none if no signal is pending
+pub fn read_signal(fd: RawFd) -> Result<Option<Signal>> {
+    let mut info = [0u8; mem::size_of::<libc::signalfd_siginfo>()];
+    match read(fd, &mut info) {
+        Ok(length) if length == info.len() => {
+            let info: libc::signalfd_siginfo = unsafe { std::ptr::read_unaligned(info.as_ptr() as *const _) };
+            Ok(Some(Signal::try_from(info.ssi_signo as i32)?))
+        },
+        Ok(_) => Err(eyre::eyre!("Truncated signal information")),
+        Err(Errno::EAGAIN) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Blocks until the other process signals, through a pipe, that it is ready
+/// # Arguments
+/// * `fd` - Read end of the pipe, closed afterwards
+/// # Returns
+/// Whether the other process is ready (false if it closed the pipe without signaling)
+///
+/// Note: this function does not allocate, it is safe to call before the setup of a container (see `create_container`)
+pub fn wait_ready(fd: RawFd) -> nix::Result<bool> {
+    let mut buf = [0u8; 1];
+    let res = loop {
+        match read(fd, &mut buf) {
+            Err(Errno::EINTR) => continue,
+            res => break res,
+        }
+    };
+    close(fd)?;
+    Ok(res? == 1)
+}
+
+/// Makes the kernel send `signal` to the current process when the thread that created it exits
+/// # Arguments
+/// * `signal` - The signal to receive
+///
+/// Note: this function does not allocate, it is safe to call before the setup of a container (see `create_container`)
+pub fn set_parent_death_signal(signal: Signal) -> nix::Result<()> {
+    let res = unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, signal as libc::c_ulong, 0, 0, 0) };
+    Errno::result(res).map(drop)
+}
+
+/// Switches to the root user and group of the user namespace of the current process, once its ID maps are
+/// written (see `write_id_maps`). Until then, the process keeps the IDs of its creator, which are
+/// unmapped unless the maps include them
+///
+/// Note: this function does not allocate, it is safe to call before the setup of a container (see `create_container`)
+pub fn switch_to_userns_root() -> nix::Result<()> {
+    let root = Gid::from_raw(0);
+    nix::unistd::setresgid(root, root, root)?;
+    let root = Uid::from_raw(0);
+    nix::unistd::setresuid(root, root, root)
+}
+
+/// Signals the other process, through a pipe, that this one is ready
+/// # Arguments
+/// * `fd` - Write end of the pipe, closed afterwards
+pub fn notify_ready(fd: RawFd) -> Result<()> {
+    let res = write(fd, &[1]);
+    close(fd)?;
+    res?;
+    Ok(())
+}
+
+nix::ioctl_write_ptr_bad!(tiocswinsz, libc::TIOCSWINSZ, Winsize);
+nix::ioctl_read_bad!(tiocgwinsz, libc::TIOCGWINSZ, Winsize);
+nix::ioctl_write_int_bad!(tiocsctty, libc::TIOCSCTTY);
+
+/// Opens a new pseudo-terminal
+/// # Returns
+/// The master and slave file descriptors
+pub fn open_pty() -> Result<OpenptyResult> {
+    Ok(openpty(None, None)?)
+}
+
+/// Makes the pseudo-terminal `slave` the controlling terminal and the stdin, stdout and stderr of the
+/// current process, which becomes the leader of a new session
+/// # Arguments
+/// * `slave` - Slave side of the pseudo-terminal
+pub fn attach_to_pty(slave: RawFd) -> Result<()> {
+    setsid()?;
+    unsafe {
+        tiocsctty(slave, 0)?;
+    }
+    dup2(slave, libc::STDIN_FILENO)?;
+    dup2(slave, libc::STDOUT_FILENO)?;
+    dup2(slave, libc::STDERR_FILENO)?;
+    if slave > libc::STDERR_FILENO {
+        close(slave)?;
+    }
+    Ok(())
+}
+
+/// Sets the window size of a terminal
+/// # Arguments
+/// * `fd` - The terminal (either side of a pseudo-terminal)
+/// * `rows` - Number of rows
+/// * `cols` - Number of columns
+pub fn set_window_size(fd: RawFd, rows: u16, cols: u16) -> Result<()> {
+    let size = Winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    unsafe {
+        tiocswinsz(fd, &size)?;
+    }
+    Ok(())
+}
+
+/// Gets the window size of a terminal
+/// # Arguments
+/// * `fd` - The terminal (either side of a pseudo-terminal)
+/// # Returns
+/// The number of rows and columns
+pub fn window_size(fd: RawFd) -> Result<(u16, u16)> {
+    let mut size = Winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    unsafe {
+        tiocgwinsz(fd, &mut size)?;
+    }
+    Ok((size.ws_row, size.ws_col))
+}
+
+/// Maximum number of pseudo-terminals following the window size of a host terminal
+const MAX_WINDOW_FOLLOWERS: usize = 64;
+
+/// A free slot of `WINDOW_FOLLOWERS`, only used to initialize it
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE_WINDOW_FOLLOWER: AtomicI64 = AtomicI64::new(-1);
+
+/// Pseudo-terminals following the window size of a host terminal, as `(host << 32) | pty`, -1 for a
+/// free slot. A fixed table of atomics, as it is read by a signal handler
+static WINDOW_FOLLOWERS: [AtomicI64; MAX_WINDOW_FOLLOWERS] = [FREE_WINDOW_FOLLOWER; MAX_WINDOW_FOLLOWERS];
+
+/// SIGWINCH handler replaced by `follow_window_size`, called by `resize_followers`: its address (0 for
+/// SIG_DFL and SIG_IGN), and whether it takes a `siginfo_t` (SA_SIGINFO)
+static REPLACED_RESIZE_HANDLER: AtomicUsize = AtomicUsize::new(0);
+static REPLACED_RESIZE_SIGINFO: AtomicBool = AtomicBool::new(false);
+
+/// SIGWINCH disposition replaced by `follow_window_size`, while `resize_followers` is installed. Restored
+/// once no pseudo-terminal follows a host terminal
+static REPLACED_RESIZE_ACTION: Mutex<Option<libc::sigaction>> = Mutex::new(None);
+
+extern "C" fn resize_followers(signal: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    // Only async-signal-safe calls, errno is restored for the interrupted code
+    let errno = Errno::last();
+    for slot in &WINDOW_FOLLOWERS {
+        let pair = slot.load(Ordering::SeqCst);
+        if pair < 0 {
+            continue;
+        }
+        let (host, pty) = ((pair >> 32) as RawFd, pair as u32 as RawFd);
+        let mut size = Winsize { ws_row: 0, ws_col: 0, ws_xpixel: 0, ws_ypixel: 0 };
+        unsafe {
+            if libc::ioctl(host, libc::TIOCGWINSZ, &mut size) == 0 {
+                libc::ioctl(pty, libc::TIOCSWINSZ, &size);
+            }
+        }
+    }
+    // The application keeps handling the signal as before
+    let replaced = REPLACED_RESIZE_HANDLER.load(Ordering::SeqCst);
+    if replaced != 0 {
+        unsafe {
+            if REPLACED_RESIZE_SIGINFO.load(Ordering::SeqCst) {
+                let handler: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) = mem::transmute(replaced);
+                handler(signal, info, context);
+            } else {
+                let handler: extern "C" fn(c_int) = mem::transmute(replaced);
+                handler(signal);
+            }
+        }
+    }
+    unsafe {
+        *libc::__errno_location() = errno as c_int;
+    }
+}
+
+/// Gives a pseudo-terminal the window size of a host terminal, and keeps it in sync: the size is
+/// copied again whenever the host receives SIGWINCH
+/// # Arguments
+/// * `host` - The terminal of the host (e.g. stdin)
+/// * `pty` - Master side of the pseudo-terminal
+///
+/// Note: while a pseudo-terminal follows a host terminal, SIGWINCH is handled by this module. The handler
+/// it replaces is still called after resizing the pseudo-terminals, and it is restored once the last one
+/// stops following (see `unfollow_window_size`). The application must not change the handler meanwhile.
+pub fn follow_window_size(host: RawFd, pty: RawFd) -> Result<()> {
+    let (rows, cols) = window_size(host)?;
+    set_window_size(pty, rows, cols)?;
+    let mut replaced = REPLACED_RESIZE_ACTION.lock().unwrap();
+    let pair = ((host as i64) << 32) | pty as u32 as i64;
+    let slot = match WINDOW_FOLLOWERS.iter().find(|slot| slot.compare_exchange(-1, pair, Ordering::SeqCst, Ordering::SeqCst).is_ok()) {
+        Some(slot) => slot,
+        None => return Err(eyre::eyre!("Too many pseudo-terminals following the host terminal, at most {}", MAX_WINDOW_FOLLOWERS)),
+    };
+    if replaced.is_none() {
+        let res = install_resize_handler();
+        match res {
+            Ok(action) => *replaced = Some(action),
+            Err(err) => {
+                slot.store(-1, Ordering::SeqCst);
+                return Err(err);
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Installs `resize_followers` as the SIGWINCH handler
+/// # Returns
+/// The disposition it replaces, which `resize_followers` chains to
+fn install_resize_handler() -> Result<libc::sigaction> {
+    let mut replaced: libc::sigaction = unsafe { mem::zeroed() };
+    // Read first, so the replaced handler is known as soon as ours runs
+    let res = unsafe { libc::sigaction(libc::SIGWINCH, std::ptr::null(), &mut replaced) };
+    Errno::result(res)?;
+    let handler = match replaced.sa_sigaction {
+        libc::SIG_DFL | libc::SIG_IGN => 0,
+        handler => handler,
+    };
+    REPLACED_RESIZE_SIGINFO.store(replaced.sa_flags & libc::SA_SIGINFO != 0, Ordering::SeqCst);
+    REPLACED_RESIZE_HANDLER.store(handler, Ordering::SeqCst);
+    let action = SigAction::new(SigHandler::SigAction(resize_followers), SaFlags::SA_RESTART | SaFlags::SA_SIGINFO, SigSet::empty());
+    if let Err(err) = unsafe { sigaction(Signal::SIGWINCH, &action) } {
+        REPLACED_RESIZE_HANDLER.store(0, Ordering::SeqCst);
+        return Err(err.into());
+    }
+    Ok(replaced)
+}
+
+/// Stops keeping a pseudo-terminal in sync with the window size of the host terminal. The SIGWINCH
+/// handler replaced by `follow_window_size` is restored once no pseudo-terminal follows a host terminal
+/// # Arguments
+/// * `pty` - Master side of the pseudo-terminal, see `follow_window_size`
+pub fn unfollow_window_size(pty: RawFd) {
+    let mut replaced = REPLACED_RESIZE_ACTION.lock().unwrap();
+    for slot in &WINDOW_FOLLOWERS {
+        let pair = slot.load(Ordering::SeqCst);
+        if pair >= 0 && pair as u32 as RawFd == pty {
+            let _ = slot.compare_exchange(pair, -1, Ordering::SeqCst, Ordering::SeqCst);
+        }
+    }
+    if WINDOW_FOLLOWERS.iter().any(|slot| slot.load(Ordering::SeqCst) >= 0) {
+        return;
+    }
+    if let Some(action) = replaced.take() {
+        let res = unsafe { libc::sigaction(libc::SIGWINCH, &action, std::ptr::null_mut()) };
+        if let Err(err) = Errno::result(res) {
+            log::warn!("Could not restore the SIGWINCH handler: {}", err);
+        }
+        REPLACED_RESIZE_HANDLER.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Scheduling policy of the container processes
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SchedPolicy {
+    /// Default time-sharing policy
+    OTHER,
+    /// Time-sharing for CPU-bound, non-interactive processes
+    BATCH,
+    /// Only runs when the CPU would otherwise be idle
+    IDLE,
+    /// Real-time first-in first-out, with a priority between 1 and 99
+    FIFO(i32),
+    /// Real-time round-robin, with a priority between 1 and 99
+    RR(i32),
+}
+
+impl SchedPolicy {
+
+    fn policy(&self) -> c_int {
+        match self {
+            SchedPolicy::OTHER => libc::SCHED_OTHER,
+            SchedPolicy::BATCH => libc::SCHED_BATCH,
+            SchedPolicy::IDLE => libc::SCHED_IDLE,
+            SchedPolicy::FIFO(_) => libc::SCHED_FIFO,
+            SchedPolicy::RR(_) => libc::SCHED_RR,
+        }
+    }
+
+    fn priority(&self) -> c_int {
+        match self {
+            SchedPolicy::FIFO(priority) | SchedPolicy::RR(priority) => *priority,
+            _ => 0,
+        }
+    }
+
+}
+
+/// Sets the scheduling policy of the current process
+/// # Arguments
+/// * `policy` - The scheduling policy
+///
+/// Note: real-time policies require CAP_SYS_NICE, or a RLIMIT_RTPRIO at least as high as the priority
+pub fn set_sched_policy(policy: &SchedPolicy) -> Result<()> {
+    let priority = policy.priority();
+    if let SchedPolicy::FIFO(_) | SchedPolicy::RR(_) = policy {
+        if !(1..=99).contains(&priority) {
+            return Err(eyre::eyre!("Real-time priority must be between 1 and 99, got {}", priority));
+        }
+        let (rtprio, _) = getrlimit(Resource::RLIMIT_RTPRIO)?;
+        let allowed = match rtprio {
+            Some(limit) => priority as u64 <= limit,
+            None => true,
+        };
+        if !geteuid().is_root() && !allowed {
+            return Err(eyre::eyre!("Real-time policy {:?} requires CAP_SYS_NICE or a higher RLIMIT_RTPRIO", policy));
+        }
+    }
+    let param = libc::sched_param { sched_priority: priority };
+    let res = unsafe { libc::sched_setscheduler(0, policy.policy(), &param) };
+    Errno::result(res)?;
+    Ok(())
+}
+
+/// Sets the nice value of the current process
+/// # Arguments
+/// * `nice` - The nice value, between -20 (highest priority) and 19 (lowest priority)
+pub fn set_nice(nice: i32) -> Result<()> {
+    if !(-20..=19).contains(&nice) {
+        return Err(eyre::eyre!("Nice value must be between -20 and 19, got {}", nice));
+    }
+    let res = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) };
+    Errno::result(res)?;
+    Ok(())
+}
+
+/// Returns the nice value and real-time priority (0 with the other policies) of the current process
+fn scheduling_priority() -> Result<(i32, i32)> {
+    // -1 is a valid nice value, only errno tells the errors apart
+    Errno::clear();
+    let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+    if nice == -1 && Errno::last() != Errno::UnknownErrno {
+        return Err(Errno::last().into());
+    }
+    let mut param = libc::sched_param { sched_priority: 0 };
+    Errno::result(unsafe { libc::sched_getparam(0, &mut param) })?;
+    Ok((nice, param.sched_priority))
+}
+
+/// Checks that a scheduling priority can be pinned by `pin_scheduling_priority` once applied, before
+/// applying it: the pinned RLIMIT_RTPRIO and RLIMIT_NICE can't be raised above the current hard limits
+/// without CAP_SYS_RESOURCE
+/// # Arguments
+/// * `policy` - The scheduling policy to apply (None: the current one)
+/// * `nice` - The nice value to apply (None: the current one)
+pub fn check_pinnable_priority(policy: Option<&SchedPolicy>, nice: Option<i32>) -> Result<()> {
+    if geteuid().is_root() {
+        return Ok(());
+    }
+    let (current_nice, current_priority) = scheduling_priority()?;
+    let priority = policy.map(SchedPolicy::priority).unwrap_or(current_priority);
+    let (_, rtprio_limit) = getrlimit(Resource::RLIMIT_RTPRIO)?;
+    let (_, nice_limit) = getrlimit(Resource::RLIMIT_NICE)?;
+    check_pinned_limits(priority, nice.unwrap_or(current_nice), rtprio_limit, nice_limit)
+}
+
+/// Checks a real-time priority and a nice value against the hard RLIMIT_RTPRIO and RLIMIT_NICE, see
+/// `check_pinnable_priority`
+/// # Arguments
+/// * `priority` - Real-time priority, 0 with the other policies
+/// * `nice` - Nice value
+/// * `rtprio_limit` - Hard RLIMIT_RTPRIO (None: unlimited)
+/// * `nice_limit` - Hard RLIMIT_NICE, the ceiling of 20 - nice (None: unlimited)
+fn check_pinned_limits(priority: i32, nice: i32, rtprio_limit: Option<u64>, nice_limit: Option<u64>) -> Result<()> {
+    if let Some(limit) = rtprio_limit.filter(|limit| priority as u64 > *limit) {
+        return Err(eyre::eyre!("Can't pin the real-time priority {} above the RLIMIT_RTPRIO of {}", priority, limit));
+    }
+    if let Some(limit) = nice_limit.filter(|limit| (20 - nice) as u64 > *limit) {
+        return Err(eyre::eyre!("Can't pin the nice value {} below the RLIMIT_NICE of {} (nice {})", nice, limit, 20 - limit as i64));
+    }
+    Ok(())
+}
+
+/// Pins the scheduling priority of the current process and of its children: RLIMIT_NICE and RLIMIT_RTPRIO
+/// (soft and hard) are set to its current nice value and real-time priority, so they can't be raised
+/// anymore, only lowered (and raised back up to the pinned values). See `check_pinnable_priority`
+///
+/// Note: processes with CAP_SYS_NICE are not bound by these limits, it must be dropped (as the runtime
+/// does with `RuntimeOptions::pin_scheduling`) for the pinning to be effective
+pub fn pin_scheduling_priority() -> Result<()> {
+    let (nice, priority) = scheduling_priority()?;
+    // RLIMIT_NICE is the ceiling of 20 - nice, as nice values can be negative
+    let nice_limit = (20 - nice) as u64;
+    let rtprio_limit = priority as u64;
+    setrlimit(Resource::RLIMIT_NICE, Some(nice_limit), Some(nice_limit))?;
+    setrlimit(Resource::RLIMIT_RTPRIO, Some(rtprio_limit), Some(rtprio_limit))?;
+    Ok(())
+}
+
+/// Drops every capability of the current process but the ones to keep, from every set: bounding, so
+/// the processes it executes can't gain them back (even setuid root binaries), ambient, effective,
+/// permitted and inheritable. Requires CAP_SETPCAP
+/// # Arguments
+/// * `keep` - The capabilities to keep, as a bit mask (see `CapSet::from_names`)
+///
+/// Note: the capabilities to keep that the process doesn't have are not gained
+pub fn drop_capabilities(keep: u64) -> Result<()> {
+    drop_bounding_capabilities(keep, last_capability()?)
+        .map_err(|err| eyre::eyre!("Could not drop the capabilities from the bounding set: {}", err))?;
+    limit_capabilities(keep).map_err(|err| eyre::eyre!("Could not set the capabilities: {}", err))?;
+    Ok(())
+}
+
+/// Returns the highest capability known to the kernel, see `drop_bounding_capabilities`
+pub fn last_capability() -> Result<u32> {
+    Ok(std::fs::read_to_string("/proc/sys/kernel/cap_last_cap")?.trim().parse()?)
+}
+
+/// Drops every capability but the ones to keep from the bounding set, so the processes executed
+/// afterwards can't gain them (even setuid root binaries), and clears the ambient ones. Requires CAP_SETPCAP
+/// # Arguments
+/// * `keep` - The capabilities to keep, as a bit mask (see `CapSet::from_names`)
+/// * `last_cap` - The highest capability, see `last_capability`
+///
+/// Note: this function does not allocate, it is safe to call in a child forked by a multithreaded process
+/// (see `Container::exec_join`)
+pub fn drop_bounding_capabilities(keep: u64, last_cap: u32) -> nix::Result<()> {
+    for cap in (0..=last_cap).filter(|cap| keep & (1 << cap) == 0) {
+        Errno::result(unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) })?;
+    }
+    let res = unsafe { libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL as libc::c_ulong, 0, 0, 0) };
+    // EINVAL: no ambient capabilities before Linux 4.3
+    match Errno::result(res) {
+        Err(Errno::EINVAL) => Ok(()),
+        res => res.map(|_| ()),
+    }
+}
+
+/// Drops every capability but the ones to keep from the effective, permitted and inheritable sets of
+/// the current process
+/// # Arguments
+/// * `keep` - The capabilities to keep, as a bit mask (see `CapSet::from_names`)
+///
+/// Note: this function does not allocate, it is safe to call in a child forked by a multithreaded process
+/// (see `Container::exec_join`)
+pub fn limit_capabilities(keep: u64) -> nix::Result<()> {
+    let mut header = CapUserHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    let mut data = [CapUserData::default(); 2];
+    Errno::result(unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) })?;
+    for (i, set) in data.iter_mut().enumerate() {
+        let mask = (keep >> (32 * i)) as u32;
+        set.effective &= mask;
+        set.permitted &= mask;
+        set.inheritable &= mask;
+    }
+    Errno::result(unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) })?;
+    Ok(())
+}
+
+/// I/O scheduling class and priority of the container processes, see ioprio_set(2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IoPriority {
+    /// Served first, with a level between 0 (highest) and 7 (lowest). Requires CAP_SYS_ADMIN
+    REALTIME(u8),
+    /// Default class, with a level between 0 (highest) and 7 (lowest)
+    BESTEFFORT(u8),
+    /// Only served when no other process needs the disk
+    IDLE,
+}
+
+impl IoPriority {
+
+    const CLASS_SHIFT: u32 = 13;
+    const LEVEL_MASK: c_int = (1 << Self::CLASS_SHIFT) - 1;
+    const IOPRIO_WHO_PROCESS: c_int = 1;
+
+    /// Value of the priority for ioprio_set
+    fn value(&self) -> c_int {
+        let (class, level) = match self {
+            IoPriority::REALTIME(level) => (1, *level),
+            IoPriority::BESTEFFORT(level) => (2, *level),
+            IoPriority::IDLE => (3, 0),
+        };
+        (class << Self::CLASS_SHIFT) | level as c_int
+    }
+
+    /// Priority of a value returned by ioprio_get (None for processes without a priority)
+    fn from_value(value: c_int) -> Option<IoPriority> {
+        let level = (value & Self::LEVEL_MASK) as u8;
+        match value >> Self::CLASS_SHIFT {
+            1 => Some(IoPriority::REALTIME(level)),
+            2 => Some(IoPriority::BESTEFFORT(level)),
+            3 => Some(IoPriority::IDLE),
+            _ => None,
+        }
+    }
+
+}
+
+/// Sets the I/O priority of the current process, inherited by its children
+/// # Arguments
+/// * `priority` - The I/O priority
+pub fn set_io_priority(priority: &IoPriority) -> Result<()> {
+    if let IoPriority::REALTIME(level) | IoPriority::BESTEFFORT(level) = priority {
+        if *level > 7 {
+            return Err(eyre::eyre!("I/O priority level must be between 0 and 7, got {}", level));
+        }
+    }
+    let res = unsafe { libc::syscall(libc::SYS_ioprio_set, IoPriority::IOPRIO_WHO_PROCESS, 0, priority.value()) };
+    Errno::result(res)?;
+    Ok(())
+}
+
+/// Returns the I/O priority of a process
+/// # Arguments
+/// * `pid` - PID of the process
+/// # Returns
+/// The I/O priority, None if the process has no I/O class (before Linux 6.0, when it was never set: the
+/// process is served according to its nice value)
+pub fn io_priority(pid: Pid) -> Result<Option<IoPriority>> {
+    let res = unsafe { libc::syscall(libc::SYS_ioprio_get, IoPriority::IOPRIO_WHO_PROCESS, pid.as_raw()) };
+    let value = Errno::result(res)?;
+    Ok(IoPriority::from_value(value as c_int))
+}
+
+/// Sets a resource limit of another process
+/// # Arguments
+/// * `pid` - PID of the process
+/// * `resource` - The resource to limit (e.g. `Resource::RLIMIT_NOFILE`)
+/// * `soft` - Soft limit, enforced by the kernel (None: unlimited)
+/// * `hard` - Ceiling of the soft limit (None: unlimited), raising it requires CAP_SYS_RESOURCE
+pub fn set_process_limit(pid: Pid, resource: Resource, soft: Option<u64>, hard: Option<u64>) -> Result<()> {
+    if let (Some(soft), Some(hard)) = (soft, hard) {
+        if soft > hard {
+            return Err(eyre::eyre!("Soft limit {} is greater than the hard limit {}", soft, hard));
+        }
+    }
+    let limit = libc::rlimit {
+        rlim_cur: soft.unwrap_or(libc::RLIM_INFINITY),
+        rlim_max: hard.unwrap_or(libc::RLIM_INFINITY),
+    };
+    let res = unsafe { libc::prlimit(pid.as_raw(), resource as _, &limit, std::ptr::null_mut()) };
+    Errno::result(res)?;
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct UserInfo {
     pub name: String,
@@ -149,11 +1681,21 @@ pub struct UserInfo {
 
 impl UserInfo {
 
+    /// Looks up a user by name, or by UID if `name` is numeric
     pub fn from_name<S: Into<String>>(name: S) -> Result<UserInfo> {
+        let name = name.into();
         let user_info = unsafe {
-            let n = CString::new(name.into()).unwrap();
-            nix::libc::getpwnam(n.as_ptr())
+            match name.parse::<u32>() {
+                Ok(uid) => nix::libc::getpwuid(uid),
+                Err(_) => {
+                    let n = CString::new(name.clone()).unwrap();
+                    nix::libc::getpwnam(n.as_ptr())
+                }
+            }
         };
+        if user_info.is_null() {
+            return Err(eyre::eyre!("User {} not found", name));
+        }
         let passwd: UserInfo = unsafe {
             UserInfo {
                 name: CStr::from_ptr((*user_info).pw_name).to_string_lossy().to_string(),
@@ -169,3 +1711,430 @@ impl UserInfo {
     }
 
 }
+
+#[derive(Debug)]
+pub struct GroupInfo {
+    pub name: String,
+    pub passwd: String,
+    pub gid: Gid,
+    pub members: Vec<String>,
+}
+
+impl GroupInfo {
+
+    /// Looks up a group by name, or by GID if `name` is numeric
+    ///
+    /// Note: a numeric GID without an entry in /etc/group is valid (e.g. the GIDs of OCI configurations),
+    /// the group is named after it
+    pub fn from_name_or_id(name: &str) -> Result<GroupInfo> {
+        let group_info = unsafe {
+            match name.parse::<u32>() {
+                Ok(gid) => nix::libc::getgrgid(gid),
+                Err(_) => {
+                    let n = CString::new(name).map_err(|_| eyre::eyre!("Invalid group name {:?}", name))?;
+                    nix::libc::getgrnam(n.as_ptr())
+                }
+            }
+        };
+        if group_info.is_null() {
+            return match name.parse::<u32>() {
+                Ok(gid) => Ok(GroupInfo {
+                    name: name.to_string(),
+                    passwd: String::new(),
+                    gid: Gid::from_raw(gid),
+                    members: vec![],
+                }),
+                Err(_) => Err(eyre::eyre!("Group {} not found", name)),
+            };
+        }
+        let group = unsafe {
+            // gr_mem is a null-terminated array of names
+            let mut members = vec![];
+            let mut member = (*group_info).gr_mem;
+            while !member.is_null() && !(*member).is_null() {
+                members.push(CStr::from_ptr(*member).to_string_lossy().to_string());
+                member = member.add(1);
+            }
+            GroupInfo {
+                name: CStr::from_ptr((*group_info).gr_name).to_string_lossy().to_string(),
+                passwd: CStr::from_ptr((*group_info).gr_passwd).to_string_lossy().to_string(),
+                gid: Gid::from_raw((*group_info).gr_gid),
+                members,
+            }
+        };
+        Ok(group)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use crate::filesystem;
+    use nix::sched::{unshare, CloneFlags};
+    use nix::sys::wait::{waitpid, WaitStatus};
+
+    #[test]
+    fn test_group_from_name_or_id() {
+        let root = GroupInfo::from_name_or_id("root").unwrap();
+        assert_eq!(root.gid, Gid::from_raw(0));
+        assert_eq!(GroupInfo::from_name_or_id("0").unwrap().name, "root");
+        // Not in /etc/group
+        let group = GroupInfo::from_name_or_id("4242").unwrap();
+        assert_eq!((group.name.as_str(), group.gid), ("4242", Gid::from_raw(4242)));
+        assert!(GroupInfo::from_name_or_id("no-such-group").is_err());
+    }
+
+    #[test]
+    fn test_has_exited() {
+        let mut child = std::process::Command::new("sleep").arg("10").spawn().unwrap();
+        let pid = Pid::from_raw(child.id() as i32);
+        assert!(!has_exited(pid).unwrap());
+        child.kill().unwrap();
+        while !has_exited(pid).unwrap() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        // Not reaped
+        assert!(!child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_forward_only_configured_signals() {
+        // Install the handlers in a child process, a signal sent to the test process would be forwarded to
+        // every process of the host
+        match unsafe { fork().unwrap() } {
+            ForkResult::Child => {
+                let default = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+                let code = match forward_signals(&[Signal::SIGTERM]) {
+                    Ok(()) => {
+                        let sigterm = unsafe { sigaction(Signal::SIGTERM, &default) };
+                        let sigusr1 = unsafe { sigaction(Signal::SIGUSR1, &default) };
+                        match (sigterm, sigusr1) {
+                            (Ok(sigterm), Ok(sigusr1)) if sigterm.handler() == SigHandler::Handler(forward_signal)
+                                && sigusr1.handler() == SigHandler::SigDfl => 0,
+                            _ => 1,
+                        }
+                    },
+                    Err(_) => 2,
+                };
+                unsafe { libc::_exit(code) };
+            },
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            },
+        }
+    }
+
+    #[test]
+    fn test_container_panic_exit_code() {
+        let namespaces = Namespaces { uts: false, ipc: false, pid: false, net: false, time: None };
+        let pid = create_container(|| panic!("setup failed"), &namespaces, false, 125).unwrap();
+        assert_eq!(waitpid(pid, None).unwrap(), WaitStatus::Exited(pid, 125));
+    }
+
+    #[test]
+    fn test_container_deep_recursion() {
+        // Each call takes 1 to 3 KB of stack (more in debug builds), up to about 3 MB of the container's 4 MB
+        fn sum(depth: u64) -> u64 {
+            let frame = std::hint::black_box([depth as u8; 1024]);
+            match depth {
+                0 => 0,
+                _ => depth + sum(depth - 1) + u64::from(frame[depth as usize % 1024] != depth as u8),
+            }
+        }
+        let namespaces = Namespaces { uts: false, ipc: false, pid: false, net: false, time: None };
+        // A stack starting at the wrong end of the buffer would overflow it at once
+        let pid = create_container(|| if sum(1000) == 1000 * 1001 / 2 { 0 } else { 1 }, &namespaces, false, 125).unwrap();
+        assert_eq!(waitpid(pid, None).unwrap(), WaitStatus::Exited(pid, 0));
+    }
+
+    #[test]
+    fn test_switch_to_userns_root() {
+        let (ready_read, ready_write) = nix::unistd::pipe().unwrap();
+        let namespaces = Namespaces { uts: false, ipc: false, pid: false, net: false, time: None };
+        let pid = create_container(|| {
+            // Only root once the maps are written
+            let ready = close(ready_write).and_then(|_| wait_ready(ready_read));
+            if switch_to_userns_root().is_err() {
+                return 2;
+            }
+            match ready {
+                Ok(true) if geteuid().is_root() && nix::unistd::getegid() == Gid::from_raw(0) => 0,
+                _ => 1,
+            }
+        }, &namespaces, true, 125).unwrap();
+        close(ready_read).unwrap();
+        let maps = [(0, 100000, 65536)];
+        setup_userns_mappings(pid, &maps, &maps).unwrap();
+        let uid_map = std::fs::read_to_string(format!("/proc/{}/uid_map", pid)).unwrap();
+        assert_eq!(uid_map.split_whitespace().collect::<Vec<&str>>(), ["0", "100000", "65536"]);
+        // Denied even though the test may run as root
+        assert_eq!(std::fs::read_to_string(format!("/proc/{}/setgroups", pid)).unwrap().trim(), "deny");
+        // Only once
+        let maps = [(0, 200000, 1)];
+        assert!(setup_userns_mappings(pid, &maps, &maps).is_err());
+        notify_ready(ready_write).unwrap();
+        assert_eq!(waitpid(pid, None).unwrap(), WaitStatus::Exited(pid, 0));
+    }
+
+    #[test]
+    fn test_switch_to_readonly_rootfs() {
+        let rootfs = env::temp_dir().join(format!("libcontainer-rs-rootfs-{}", Pid::this()));
+        fs::create_dir_all(&rootfs).unwrap();
+        // Switch the rootfs in a child process, with its own mount namespace
+        match unsafe { fork().unwrap() } {
+            ForkResult::Child => {
+                let options = RootfsOptions { readonly: true, propagation: Some(Propagation::PRIVATE) };
+                let res = unshare(CloneFlags::CLONE_NEWNS)
+                    .map_err(eyre::Report::from)
+                    .and_then(|_| crate::filesystem::mount_rootfs_private())
+                    .and_then(|_| switch_rootfs(&rootfs, &options));
+                let code = match res {
+                    Ok(_) if fs::write("/file", "data").is_err() => 0,
+                    _ => 1,
+                };
+                unsafe { libc::_exit(code) };
+            },
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            },
+        }
+        fs::remove_dir_all(&rootfs).unwrap();
+    }
+
+    #[test]
+    fn test_resize_pty() {
+        let pty = open_pty().unwrap();
+        set_window_size(pty.master, 42, 132).unwrap();
+        assert_eq!(window_size(pty.slave).unwrap(), (42, 132));
+        close(pty.master).unwrap();
+        close(pty.slave).unwrap();
+    }
+
+    #[test]
+    fn test_set_nice() {
+        // Renice a child process, so the test process keeps its priority
+        match unsafe { fork().unwrap() } {
+            ForkResult::Child => {
+                let res = set_nice(10).and_then(|_| Ok(fs::read_to_string("/proc/self/stat")?));
+                let code = match res {
+                    // The nice value is the 19th field, the 17th after the command name
+                    Ok(stat) if stat.rsplit(')').next().unwrap().split_whitespace().nth(16) == Some("10") => 0,
+                    _ => 1,
+                };
+                unsafe { libc::_exit(code) };
+            },
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            },
+        }
+        assert!(set_nice(20).is_err());
+    }
+
+    #[test]
+    fn test_check_pinned_limits() {
+        assert!(check_pinned_limits(0, 0, None, None).is_ok());
+        assert!(check_pinned_limits(10, 5, Some(10), Some(15)).is_ok());
+        let err = check_pinned_limits(11, 5, Some(10), Some(15)).unwrap_err().to_string();
+        assert!(err.contains("RLIMIT_RTPRIO"), "{}", err);
+        let err = check_pinned_limits(0, 4, None, Some(15)).unwrap_err().to_string();
+        assert!(err.contains("RLIMIT_NICE"), "{}", err);
+    }
+
+    #[test]
+    fn test_wait_exec() {
+        let command = |script: &str| Command {
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            env: vec!["PATH=/bin:/usr/bin".to_string()],
+            exec_type: ExecType::FORK,
+            argv0: None,
+            login: false,
+            path_search: true,
+            process_group: None,
+            stream_stdout: false,
+        };
+        let pid = Pid::from_raw(exec(command("exit 3"), None, None).unwrap());
+        assert_eq!(wait_exec(pid).unwrap(), ContainerExit::EXITED(3));
+        let pid = Pid::from_raw(exec(command("kill -TERM $$"), None, None).unwrap());
+        let exit = wait_exec(pid).unwrap();
+        assert_eq!(exit, ContainerExit::SIGNALED(Signal::SIGTERM));
+        assert_eq!(exit.code(), 128 + 15);
+        assert!(!exit.success());
+    }
+
+    #[test]
+    fn test_exec_failure_of_forked_command() {
+        let command = Command {
+            command: "/does/not/exist".to_string(),
+            args: vec![],
+            env: vec![],
+            exec_type: ExecType::FORK,
+            argv0: None,
+            login: false,
+            path_search: false,
+            process_group: None,
+            stream_stdout: false,
+        };
+        // The child exits instead of going on as a copy of the test
+        let err = exec(command, None, None).unwrap_err();
+        assert_eq!(err.downcast_ref::<Errno>(), Some(&Errno::ENOENT));
+    }
+
+    #[test]
+    fn test_command_result_channel() {
+        let (read_end, write_end) = command_result_channel().unwrap();
+        assert_eq!(read_command_result(read_end, false).unwrap(), None);
+        let results = [
+            CommandResult::STARTED(42),
+            CommandResult::REPLACING,
+            CommandResult::FAILED(Errno::ENOENT),
+            CommandResult::EXITED(42, ContainerExit::EXITED(3)),
+            CommandResult::EXITED(43, ContainerExit::SIGNALED(Signal::SIGKILL)),
+        ];
+        for result in results {
+            report_command_result(write_end, result).unwrap();
+        }
+        close(write_end).unwrap();
+        for result in results {
+            assert_eq!(read_command_result(read_end, true).unwrap(), Some(result));
+        }
+        assert_eq!(read_command_result(read_end, true).unwrap(), None);
+        close(read_end).unwrap();
+    }
+
+    #[test]
+    fn test_send_fd() {
+        let (host, runtime) = command_result_channel().unwrap();
+        let (read_end, write_end) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        send_fd(host, write_end).unwrap();
+        close(write_end).unwrap();
+        let received = receive_fd(runtime).unwrap();
+        write(received, b"through the copy").unwrap();
+        close(received).unwrap();
+        let mut buffer = [0u8; 16];
+        assert_eq!(read(read_end, &mut buffer).unwrap(), 16);
+        assert_eq!(&buffer, b"through the copy");
+        // Every copy of the write end is closed
+        assert_eq!(read(read_end, &mut buffer).unwrap(), 0);
+        for fd in [read_end, host, runtime] {
+            close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_command_defaults() {
+        let command: Command = serde_json::from_str(r#"{"command": "sh", "args": [], "env": [], "exec_type": "FORK"}"#).unwrap();
+        assert_eq!(command.argv0, None);
+        assert!(!command.login);
+        assert!(command.path_search);
+        assert_eq!(command.process_group, None);
+    }
+
+    #[test]
+    fn test_io_priority_values() {
+        for priority in [IoPriority::REALTIME(0), IoPriority::BESTEFFORT(7), IoPriority::IDLE] {
+            assert_eq!(IoPriority::from_value(priority.value()), Some(priority));
+        }
+        assert_eq!(IoPriority::from_value(0), None);
+        assert!(set_io_priority(&IoPriority::BESTEFFORT(8)).is_err());
+    }
+
+    #[test]
+    fn test_default_forward_signals() {
+        let signals = default_forward_signals();
+        assert!(signals.contains(&Signal::SIGTERM));
+        assert!(!signals.contains(&Signal::SIGCHLD));
+        assert!(!signals.contains(&Signal::SIGKILL));
+        assert!(!signals.contains(&Signal::SIGSTOP));
+    }
+
+    #[test]
+    fn test_write_id_maps_with_multiple_ranges() {
+        let (ready_read, ready_write) = nix::unistd::pipe().unwrap();
+        let (done_read, done_write) = nix::unistd::pipe().unwrap();
+        let child = match unsafe { fork().unwrap() } {
+            ForkResult::Child => {
+                let ok = unshare(CloneFlags::CLONE_NEWUSER).is_ok();
+                notify_ready(ready_write).unwrap();
+                // Wait for the maps to be written
+                let _ = wait_ready(done_read);
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+            },
+            ForkResult::Parent { child } => child,
+        };
+        close(ready_write).unwrap();
+        close(done_read).unwrap();
+        assert!(wait_ready(ready_read).unwrap());
+        let maps = [
+            IdMap { container_id: 0, host_id: 100000, size: 1 },
+            IdMap { container_id: 1, host_id: 200001, size: 999 },
+            IdMap { container_id: 1000, host_id: 1000, size: 1 },
+        ];
+        let overlapping = [IdMap { container_id: 0, host_id: 0, size: 10 }, IdMap { container_id: 5, host_id: 100, size: 1 }];
+        assert!(write_id_maps(child, &overlapping, &[]).is_err());
+        write_id_maps(child, &maps, &maps).unwrap();
+        let uid_map = std::fs::read_to_string(format!("/proc/{}/uid_map", child)).unwrap();
+        let gid_map = std::fs::read_to_string(format!("/proc/{}/gid_map", child)).unwrap();
+        notify_ready(done_write).unwrap();
+        assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+        for map in [uid_map, gid_map] {
+            let ranges: Vec<Vec<u32>> = map.lines()
+                .map(|line| line.split_whitespace().map(|id| id.parse().unwrap()).collect())
+                .collect();
+            assert_eq!(ranges, vec![vec![0, 100000, 1], vec![1, 200001, 999], vec![1000, 1000, 1]]);
+        }
+    }
+
+    #[test]
+    fn test_program_name() {
+        let mut command = Command {
+            command: "/bin/sh".to_string(),
+            args: vec![],
+            env: vec![],
+            exec_type: ExecType::REPLACE,
+            argv0: None,
+            login: false,
+            path_search: true,
+            process_group: None,
+            stream_stdout: false,
+        };
+        assert_eq!(command.program_name(), "/bin/sh");
+        command.login = true;
+        assert_eq!(command.program_name(), "-sh");
+        command.argv0 = Some("ash".to_string());
+        assert_eq!(command.program_name(), "-ash");
+        command.login = false;
+        assert_eq!(command.program_name(), "ash");
+    }
+
+    #[test]
+    fn test_switch_rootfs_detaches_old_root() {
+        let new_root = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/test_switch_rootfs");
+        fs::create_dir_all(&new_root).unwrap();
+        match unsafe { fork().unwrap() } {
+            ForkResult::Child => {
+                let switched = unshare(CloneFlags::CLONE_NEWNS).is_ok()
+                    && filesystem::mount_rootfs_private().is_ok()
+                    && filesystem::mount_tmpfs(&new_root, 0o755, None).is_ok()
+                    && fs::write(new_root.join("marker"), "").is_ok()
+                    && fs::create_dir(new_root.join("proc")).is_ok()
+                    && switch_rootfs(&new_root, &RootfsOptions::default()).is_ok();
+                // ".." from the root is the root itself, the host's files are unreachable
+                let ok = switched
+                    && Path::new("/../../marker").exists()
+                    && !Path::new("/../../").join(new_root.strip_prefix("/").unwrap()).exists()
+                    && filesystem::mount_procfs().is_ok()
+                    && verify_old_root_detached().is_ok();
+                unsafe { nix::libc::_exit(if ok { 0 } else { 1 }) };
+            },
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            },
+        }
+        fs::remove_dir_all(new_root).unwrap();
+    }
+}
@@ -0,0 +1,32 @@
+//! Runs the examples end-to-end, as a user would
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Runs the alpine example in a fresh directory, with the host's root filesystem as its image
+/// # Returns
+/// The exit code of the example
+fn run_alpine_example(name: &str, args: &[&str]) -> Option<i32> {
+    let manifest = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let dir = manifest.join("tests").join(name);
+    fs::create_dir_all(&dir).unwrap();
+    let status = Command::new(env!("CARGO"))
+        .args(["run", "--quiet", "--manifest-path"])
+        .arg(manifest.join("Cargo.toml"))
+        .args(["--example", "alpine", "--", "/"])
+        .args(args)
+        .current_dir(&dir)
+        .status()
+        .unwrap();
+    let _ = fs::remove_dir_all(dir);
+    status.code()
+}
+
+#[test]
+fn test_alpine_example_exit_code() {
+    assert_eq!(run_alpine_example("test_example_exit", &["sh", "-c", "exit 7"]), Some(7));
+    assert_eq!(run_alpine_example("test_example_success", &["true"]), Some(0));
+    // The container fails before running a command that doesn't exist
+    assert_eq!(run_alpine_example("test_example_not_found", &["not-a-command"]), Some(127));
+}